@@ -0,0 +1,414 @@
+//! Async client for a running SpecterTTY session's `--socket`: typed
+//! frames in, typed commands out, with keepalive pings answered and lost
+//! connections resumed, so orchestrators need not speak the NDJSON
+//! protocol by hand.
+//!
+//! ```no_run
+//! use spectertty_client::FrameType;
+//! use std::time::Duration;
+//!
+//! # async fn demo() -> anyhow::Result<()> {
+//! let mut client = spectertty_client::connect_unix("/tmp/agent.sock").await?;
+//! client.send_input("cargo test\n").await?;
+//! let found = client.expect(r"test result: (\w+)", Duration::from_secs(300)).await?;
+//! println!("{:?}", found.captures);
+//! while let Some(frame) = client.next_frame().await? {
+//!     if frame.frame_type == FrameType::Exit {
+//!         break;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use futures::Stream;
+use serde_json::json;
+use spectertty::keys;
+use spectertty::termios::TermiosChange;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tracing::{debug, info, warn};
+
+pub use spectertty::cli::FrameFormat;
+pub use spectertty::{Frame, FrameType};
+
+/// Reconnection attempts made when a connection drops, unless
+/// `Options::reconnect` says otherwise.
+pub const RECONNECT_ATTEMPTS: u32 = 10;
+pub const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl Endpoint {
+    async fn connect(&self) -> Result<(Reader, Writer)> {
+        match self {
+            Endpoint::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("Failed to connect to {:?}", path))?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Endpoint::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", addr))?;
+                stream.set_nodelay(true)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// How to connect: the credentials and `--format` of the session's
+/// socket, and how hard to try when the connection drops.
+#[derive(Debug, Clone)]
+pub struct Options {
+    auth: Option<(String, String)>,
+    format: FrameFormat,
+    attempts: u32,
+    delay: Duration,
+    resume_from: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            auth: None,
+            format: FrameFormat::Json,
+            attempts: RECONNECT_ATTEMPTS,
+            delay: RECONNECT_DELAY,
+            resume_from: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Authenticate as `client` with one of the session's `--auth-token`s.
+    pub fn auth(mut self, client: impl Into<String>, token: impl Into<String>) -> Self {
+        self.auth = Some((client.into(), token.into()));
+        self
+    }
+
+    /// The socket's `--format`; frames are JSON unless it says otherwise.
+    pub fn format(mut self, format: FrameFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Try `attempts` times, `delay` apart, to reconnect a dropped
+    /// connection; 0 gives up at the first drop.
+    pub fn reconnect(mut self, attempts: u32, delay: Duration) -> Self {
+        self.attempts = attempts;
+        self.delay = delay;
+        self
+    }
+
+    /// Replay the session's buffered frames after `seq` on connecting;
+    /// 0 replays all it still holds.
+    pub fn resume_from(mut self, seq: u64) -> Self {
+        self.resume_from = Some(seq);
+        self
+    }
+
+    pub async fn connect_unix(self, path: impl AsRef<Path>) -> Result<Client> {
+        Client::open(Endpoint::Unix(path.as_ref().to_path_buf()), self).await
+    }
+
+    /// Connect over TCP to the session serving `--bind addr`.
+    pub async fn connect_tcp(self, addr: impl Into<String>) -> Result<Client> {
+        Client::open(Endpoint::Tcp(addr.into()), self).await
+    }
+}
+
+/// Connect to the session serving `--socket path`, with default options.
+pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Client> {
+    Options::new().connect_unix(path).await
+}
+
+/// Connect to the session serving `--bind addr`, with default options.
+pub async fn connect_tcp(addr: impl Into<String>) -> Result<Client> {
+    Options::new().connect_tcp(addr).await
+}
+
+/// A connection to one session. Frames come out in `seq` order without
+/// repeats, across reconnections: a dropped connection is reopened,
+/// authenticated again and resumed from the last frame seen. Keepalive
+/// pings are answered and not returned.
+pub struct Client {
+    endpoint: Endpoint,
+    options: Options,
+    reader: Reader,
+    writer: Writer,
+    /// Bytes read but not yet a whole frame
+    pending: Vec<u8>,
+    /// Frames read while waiting for something else, returned first
+    backlog: VecDeque<Frame>,
+    last_seq: u64,
+    exited: bool,
+    expects: u64,
+}
+
+impl Client {
+    async fn open(endpoint: Endpoint, options: Options) -> Result<Self> {
+        let (reader, writer) = endpoint.connect().await?;
+        let resume_from = options.resume_from;
+        let mut client = Self {
+            endpoint,
+            options,
+            reader,
+            writer,
+            pending: Vec::new(),
+            backlog: VecDeque::new(),
+            last_seq: 0,
+            exited: false,
+            expects: 0,
+        };
+        client.hello(resume_from).await?;
+        Ok(client)
+    }
+
+    /// What starts every connection: the credentials, then the replay.
+    async fn hello(&mut self, resume_from: Option<u64>) -> Result<()> {
+        if let Some((ref client, ref token)) = self.options.auth {
+            let auth = json!({"type": "auth", "client": client, "token": token});
+            write_command(&mut self.writer, &auth).await?;
+        }
+        if let Some(seq) = resume_from {
+            write_command(&mut self.writer, &json!({"type": "resume_from", "seq": seq})).await?;
+        }
+        Ok(())
+    }
+
+    /// The `seq` of the last frame returned or held back, which a new
+    /// client can `resume_from`.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Send a control command as it is, for those without a method here.
+    pub async fn send(&mut self, command: serde_json::Value) -> Result<()> {
+        if let Err(e) = write_command(&mut self.writer, &command).await {
+            if !self.reconnect().await? {
+                return Err(e);
+            }
+            write_command(&mut self.writer, &command).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_input(&mut self, data: impl Into<String>) -> Result<()> {
+        self.send(json!({"type": "input", "data": data.into()})).await
+    }
+
+    /// Paste `text`: bracketed when the program has asked for that,
+    /// otherwise a line at a time, `line_delay` apart.
+    pub async fn paste(&mut self, text: impl Into<String>, line_delay: Option<Duration>) -> Result<()> {
+        let mut command = json!({"type": "paste", "data": text.into()});
+        if let Some(delay) = line_delay {
+            command["line_delay_ms"] = json!(delay.as_millis() as u64);
+        }
+        self.send(command).await
+    }
+
+    /// Type `keys` in order, each a key name such as `Enter`, `Up`, `F5`,
+    /// a chord such as `Ctrl+C`, `Alt+b` or `Ctrl+Shift+Up`, or else text
+    /// typed as it is.
+    pub async fn send_keys(&mut self, keys: &[&str]) -> Result<()> {
+        let data: String = keys.iter().map(|key| keys::key_bytes(key)).collect();
+        self.send_input(data).await
+    }
+
+    /// Type `keys` as `send_keys` does, one keystroke every `delay` give
+    /// or take up to `jitter`, for programs that react to key timing.
+    pub async fn type_keys(&mut self, keys: &[&str], delay: Duration, jitter: Duration) -> Result<()> {
+        self.send(json!({
+            "type": "send_keys",
+            "keys": keys,
+            "delay_ms": delay.as_millis() as u64,
+            "jitter_ms": jitter.as_millis() as u64,
+        }))
+        .await
+    }
+
+    /// Send a password or other secret with the terminal's echo off; the
+    /// stream shows `[REDACTED]` wherever it would appear.
+    pub async fn send_secret(&mut self, secret: &str) -> Result<()> {
+        self.send(json!({"type": "send_secret", "data": secret})).await
+    }
+
+    /// Send `response` whenever output matches `regex`, at most `limit`
+    /// times if given; `cancel` with the same `id` withdraws it.
+    pub async fn auto_respond(
+        &mut self,
+        regex: &str,
+        response: &str,
+        limit: Option<u32>,
+        id: Option<&str>,
+    ) -> Result<()> {
+        self.send(json!({"type": "auto_respond", "regex": regex, "response": response, "limit": limit, "id": id}))
+            .await
+    }
+
+    /// End the command's input, as Ctrl-D does at a terminal.
+    pub async fn eof(&mut self) -> Result<()> {
+        self.send(json!({"type": "eof"})).await
+    }
+
+    pub async fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        self.send(json!({"type": "resize", "cols": cols, "rows": rows})).await
+    }
+
+    /// Change echo, raw mode and other flags of the command's terminal;
+    /// a `termios` frame reports the settings it leaves.
+    pub async fn set_termios(&mut self, change: TermiosChange) -> Result<()> {
+        let mut command = serde_json::to_value(change)?;
+        command["type"] = json!("set_termios");
+        self.send(command).await
+    }
+
+    /// Send the session's process a signal by name, such as `SIGINT`.
+    pub async fn signal(&mut self, name: &str) -> Result<()> {
+        self.send(json!({"type": "signal", "name": name})).await
+    }
+
+    /// Withdraw a pending expect or held input.
+    pub async fn cancel(&mut self, id: &str) -> Result<()> {
+        self.send(json!({"type": "cancel", "id": id})).await
+    }
+
+    /// Wait for the session's output to match `regex`, returning the
+    /// `match` frame. Frames that arrive meanwhile are kept for
+    /// `next_frame`. Timing out, a `cancel` and the session ending are
+    /// errors.
+    pub async fn expect(&mut self, regex: &str, timeout: Duration) -> Result<Frame> {
+        self.expects += 1;
+        let id = format!("client-expect-{}", self.expects);
+        let timeout_ms = timeout.as_millis() as u64;
+        self.send(json!({"type": "expect", "regex": regex, "timeout_ms": timeout_ms, "id": id}))
+            .await?;
+
+        let mut seen = Vec::new();
+        let outcome = loop {
+            let Some(frame) = self.read_frame().await? else {
+                break Err(anyhow!("session ended while waiting for /{}/", regex));
+            };
+            if frame.id.as_deref() == Some(id.as_str()) {
+                match frame.frame_type {
+                    FrameType::Match => break Ok(frame),
+                    FrameType::ExpectTimeout => break Err(anyhow!("/{}/ did not match within {}ms", regex, timeout_ms)),
+                    FrameType::Cancelled => break Err(anyhow!("wait for /{}/ was cancelled", regex)),
+                    _ => {}
+                }
+            }
+            seen.push(frame);
+        };
+        self.backlog.extend(seen);
+        outcome
+    }
+
+    /// The next frame from the session, or None once it has exited and
+    /// closed the connection, or the connection is lost for good.
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match self.backlog.pop_front() {
+            Some(frame) => Ok(Some(frame)),
+            None => self.read_frame().await,
+        }
+    }
+
+    /// The session's frames as a stream, ending as `next_frame` does or at
+    /// the first error.
+    pub fn frames(self) -> impl Stream<Item = Result<Frame>> {
+        futures::stream::try_unfold(self, |mut client| async move {
+            Ok(client.next_frame().await?.map(|frame| (frame, client)))
+        })
+    }
+
+    async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            while let Some((frame, used)) = Frame::decode(self.options.format, &self.pending)
+                .context("Unexpected frame from the session; does the format match its --format?")?
+            {
+                self.pending.drain(..used);
+                // Replayed frames may repeat ones already returned
+                if let Some(seq) = frame.seq {
+                    if seq <= self.last_seq {
+                        continue;
+                    }
+                    self.last_seq = seq;
+                }
+                match frame.frame_type {
+                    FrameType::Ping => {
+                        let pong = json!({"type": "pong", "id": frame.id});
+                        write_command(&mut self.writer, &pong).await?;
+                        continue;
+                    }
+                    FrameType::Exit => self.exited = true,
+                    _ => {}
+                }
+                return Ok(Some(frame));
+            }
+
+            let read = match self.reader.read_buf(&mut self.pending).await {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Lost the session's socket: {}", e);
+                    0
+                }
+            };
+            if read == 0 && (self.exited || !self.reconnect().await?) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reopen a dropped connection and resume after the last frame seen,
+    /// returning false if every attempt failed.
+    async fn reconnect(&mut self) -> Result<bool> {
+        for attempt in 1..=self.options.attempts {
+            tokio::time::sleep(self.options.delay).await;
+            match self.endpoint.connect().await {
+                Ok((reader, writer)) => {
+                    self.reader = reader;
+                    self.writer = writer;
+                    self.pending.clear();
+                    // Nothing seen yet means nothing to resume after
+                    let resume_from = (self.last_seq > 0).then_some(self.last_seq).or(self.options.resume_from);
+                    self.hello(resume_from).await?;
+                    info!("Reconnected to {:?} after {} attempt(s)", self.endpoint, attempt);
+                    return Ok(true);
+                }
+                Err(e) => debug!("Reconnect attempt {} failed: {:#}", attempt, e),
+            }
+        }
+        if self.options.attempts > 0 {
+            warn!("Gave up reconnecting to {:?}", self.endpoint);
+        }
+        Ok(false)
+    }
+}
+
+async fn write_command(writer: &mut Writer, command: &serde_json::Value) -> Result<()> {
+    let mut line = command.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
@@ -0,0 +1,123 @@
+//! The client against a real session socket, with the test standing in
+//! for the session loop.
+
+use futures::StreamExt;
+use spectertty::chaos::parse_chaos;
+use spectertty::control::{ControlCommand, ControlEvent};
+use spectertty::transport::{self, SocketOptions, SocketServer};
+use spectertty_client::{Frame, FrameFormat, FrameType, Options};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn output(seq: u64, text: &str) -> Frame {
+    Frame::new(FrameType::Stdout).with_data(text.to_string()).with_seq(seq)
+}
+
+#[tokio::test]
+async fn client_sends_typed_commands_and_waits_on_expects() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.sock");
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::unbounded_channel();
+    let tokens = transport::parse_auth_tokens(&["agent=secret".to_string()]).unwrap();
+    let server = SocketServer::bind(&path, tokens, control_tx, FrameFormat::Json).unwrap();
+    let mut client = Options::new().auth("agent", "secret").connect_unix(&path).await.unwrap();
+    let mut command = async || {
+        let event = tokio::time::timeout(TIMEOUT, control_rx.recv()).await.unwrap().unwrap();
+        let ControlEvent::Command(message) = event else {
+            panic!("rejected: {:?}", event);
+        };
+        assert_eq!(message.client.name.as_deref(), Some("agent"));
+        message.command
+    };
+
+    client.send_keys(&["ls", "Space", "-l", "Enter", "Up", "Ctrl+C", "Alt+b"]).await.unwrap();
+    let ControlCommand::Input { data } = command().await else {
+        panic!("expected input");
+    };
+    assert_eq!(data, "ls -l\r\x1b[A\x03\x1bb");
+
+    // The expect is answered while other frames go by
+    let session = async {
+        let ControlCommand::Expect { regex, id, .. } = command().await else {
+            panic!("expected expect");
+        };
+        assert_eq!(regex, "ready");
+        server.broadcast(&output(1, "loading"));
+        server.broadcast(&output(2, "ready"));
+        server.broadcast(&Frame::new(FrameType::Match).with_id(id.unwrap()).with_seq(3));
+        server.broadcast(&output(4, "$ "));
+    };
+    let (found, ()) = tokio::join!(client.expect("ready", TIMEOUT), session);
+    assert_eq!(found.unwrap().seq, Some(3));
+    let mut seen = Vec::new();
+    for _ in 0..3 {
+        let frame = tokio::time::timeout(TIMEOUT, client.next_frame()).await.unwrap().unwrap().unwrap();
+        seen.push(frame.seq.unwrap());
+    }
+    assert_eq!(seen, [1, 2, 4]);
+
+    let session = async {
+        let ControlCommand::Expect { id, .. } = command().await else {
+            panic!("expected expect");
+        };
+        server.broadcast(&Frame::new(FrameType::ExpectTimeout).with_id(id.unwrap()).with_seq(5));
+    };
+    let (timed_out, ()) = tokio::join!(client.expect("never", Duration::from_millis(10)), session);
+    assert!(timed_out.unwrap_err().to_string().contains("did not match"));
+}
+
+#[tokio::test]
+async fn client_resumes_across_dropped_connections() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flaky.sock");
+    let (control_tx, _control_rx) = tokio::sync::mpsc::unbounded_channel();
+    let tokens = transport::parse_auth_tokens(&[]).unwrap();
+    let chaos = parse_chaos("disconnect:0.3").unwrap();
+    let server = SocketServer::bind_with_chaos(&path, tokens, control_tx, FrameFormat::Json, chaos, 11).unwrap();
+    let client = Options::new()
+        .reconnect(50, Duration::from_millis(10))
+        .connect_unix(&path)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    for seq in 1..=20 {
+        server.broadcast(&output(seq, "x"));
+    }
+    server.broadcast(&Frame::new(FrameType::Exit).with_exit_code(0).with_seq(21));
+
+    let frames = client.frames().map(Result::unwrap);
+    let until_exit = frames.take_while(|frame| std::future::ready(frame.frame_type != FrameType::Exit));
+    let frames: Vec<Frame> = tokio::time::timeout(TIMEOUT, until_exit.collect()).await.unwrap();
+    let seqs: Vec<u64> = frames.iter().filter_map(|frame| frame.seq).collect();
+    assert_eq!(seqs, (1..=20).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn client_drives_a_session_over_tcp() {
+    let (control_tx, mut control_rx) = tokio::sync::mpsc::unbounded_channel();
+    let tokens = transport::parse_auth_tokens(&["agent=secret".to_string()]).unwrap();
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let server = SocketServer::bind_tcp(addr, tokens, control_tx, FrameFormat::Json, SocketOptions::default()).unwrap();
+    let addr = server.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    // Frames from before the connection come back on resume
+    server.broadcast(&output(1, "$ "));
+    let options = Options::new().auth("agent", "secret").resume_from(0);
+    let mut client = options.connect_tcp(addr.to_string()).await.unwrap();
+    let frame = tokio::time::timeout(TIMEOUT, client.next_frame()).await.unwrap().unwrap().unwrap();
+    assert_eq!((frame.seq, frame.data.as_deref()), (Some(1), Some("$ ")));
+
+    client.send_input("ls\n").await.unwrap();
+    let event = tokio::time::timeout(TIMEOUT, control_rx.recv()).await.unwrap().unwrap();
+    let ControlEvent::Command(message) = event else {
+        panic!("rejected: {:?}", event);
+    };
+    assert_eq!(message.client.name.as_deref(), Some("agent"));
+    assert!(matches!(message.command, ControlCommand::Input { data } if data == "ls\n"));
+
+    server.broadcast(&output(2, "Cargo.toml"));
+    let frame = tokio::time::timeout(TIMEOUT, client.next_frame()).await.unwrap().unwrap().unwrap();
+    assert_eq!(frame.seq, Some(2));
+}
@@ -0,0 +1,31 @@
+"""Minimal full-screen program for the curses_snapshot example and tests.
+
+Draws a centred banner with the window size, redraws on resize and exits
+on 'q'.
+"""
+import curses
+import os
+
+
+def main(screen):
+    curses.curs_set(0)
+    # Poll the size as well as waiting for KEY_RESIZE, since SIGWINCH can
+    # arrive before ncurses is ready to notice it
+    screen.timeout(100)
+    drawn = None
+    while True:
+        cols, rows = os.get_terminal_size(0)
+        if (cols, rows) != screen.getmaxyx()[::-1]:
+            curses.resizeterm(rows, cols)
+        if drawn != (cols, rows):
+            screen.erase()
+            banner = "spectertty curses demo {}x{}".format(cols, rows)
+            screen.addstr(rows // 2, max(0, (cols - len(banner)) // 2), banner)
+            screen.addstr(rows - 1, 0, "press q to quit")
+            screen.refresh()
+            drawn = (cols, rows)
+        if screen.getch() == ord("q"):
+            break
+
+
+curses.wrapper(main)
@@ -0,0 +1,46 @@
+//! Watch a full-screen program through parsed mode: while the alternate
+//! screen is active the processor reports the whole grid as `snapshot`
+//! frames, which follow resizes.
+//!
+//! ```text
+//! cargo run --example curses_snapshot
+//! ```
+
+use spectertty::cli::TokenMode;
+use spectertty::processor::OutputProcessor;
+use spectertty::{FrameType, Session};
+
+const DEMO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/curses_demo.py");
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let (cols, rows) = (60, 12);
+    let mut session = Session::builder("env")
+        .args(["TERM=xterm-256color", "python3", DEMO])
+        .size(cols, rows)
+        .spawn()
+        .await?;
+    let mut processor = OutputProcessor::new(TokenMode::Parsed, cols, rows);
+
+    let mut resized = false;
+    while let Some(frame) = session.next_frame().await {
+        for frame in processor.process_frame(frame).await? {
+            if !matches!(frame.frame_type, FrameType::Snapshot) {
+                continue;
+            }
+            let screen = frame.data.unwrap_or_default();
+            println!("--- snapshot {}x{} ---", frame.cols.unwrap_or(0), frame.rows.unwrap_or(0));
+            println!("{}", screen.trim_end());
+
+            if !resized && screen.contains("demo 60x12") {
+                session.resize(80, 16)?;
+                resized = true;
+            } else if screen.contains("demo 80x16") {
+                session.send("q")?;
+            }
+        }
+    }
+
+    println!("exit code {:?}", session.exit_code());
+    Ok(())
+}
@@ -0,0 +1,28 @@
+//! Evaluate expressions in a Python REPL and read the results back.
+//!
+//! ```text
+//! cargo run --example python_repl
+//! ```
+
+use spectertty::Session;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut session = Session::spawn("python3", ["-q"]).await?;
+    session.wait_for(">>> ", TIMEOUT).await?;
+
+    for expression in ["sum(range(10))", "2 ** 16", "len('spectertty')"] {
+        session.send(format!("{}\n", expression))?;
+        // The echoed expression never matches a line of digits
+        let result = session.wait_for(r"(?m)^(?P<value>\d+)\r?$", TIMEOUT).await?;
+        session.wait_for(">>> ", TIMEOUT).await?;
+        println!("{} = {}", expression, result.captures.unwrap_or_default()["value"]);
+    }
+
+    session.send("exit()\n")?;
+    println!("exit code {:?}", session.wait().await);
+    Ok(())
+}
@@ -0,0 +1,38 @@
+//! Record a session to asciicast v2 and read the recording back.
+//!
+//! ```text
+//! cargo run --example record_session -- /tmp/demo.cast
+//! ```
+
+use spectertty::asciicast::Reader;
+use spectertty::cli::RecordingFormat;
+use spectertty::recorder::RecordingManager;
+use spectertty::Session;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("spectertty-example.cast"));
+
+    let mut recording = RecordingManager::new();
+    recording.start_recording(&path, 80, 24, Some("bash -c ...".to_string()), RecordingFormat::Cast)?;
+
+    let mut session = Session::spawn("bash", ["-c", "echo one; sleep 0.2; echo two"]).await?;
+    while let Some(frame) = session.next_frame().await {
+        recording.record_frame(&frame)?;
+    }
+    recording.stop_recording()?;
+
+    let (header, events) = Reader::new(BufReader::new(File::open(&path)?))?;
+    println!("{}: {}x{}", path.display(), header.width, header.height);
+    for event in events {
+        let event = event?;
+        println!("{:>8.3} {} {:?}", event.time(), event.code(), event.data());
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Drive an interactive bash session with expect-style waits.
+//!
+//! ```text
+//! cargo run --example shell_expect
+//! ```
+
+use spectertty::Session;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut session = Session::spawn("bash", ["--norc", "--noprofile"]).await?;
+
+    // A prompt the echoed command line cannot match
+    session.send("PS1='READY''> '\n")?;
+    session.wait_for("READY> ", TIMEOUT).await?;
+
+    session.send("echo $((6 * 7))\n")?;
+    // The echoed command line ends in "))", so only the result matches
+    let answer = session.wait_for(r"(?P<answer>\d+)\r?\n", TIMEOUT).await?;
+    let captures = answer.captures.unwrap_or_default();
+    println!("bash says {}", captures["answer"]);
+
+    session.send("exit 3\n")?;
+    println!("exit code {:?}", session.wait().await);
+    Ok(())
+}
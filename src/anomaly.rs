@@ -0,0 +1,162 @@
+use crate::frame::{Frame, FrameType};
+use std::time::{Duration, Instant};
+
+/// Width of the buckets output volume is measured in.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bucket volumes below this never count as a spike, so a quiet session
+/// that prints one screenful is not flagged.
+const MIN_SPIKE_BYTES: usize = 64 * 1024;
+
+/// Chunks shorter than this are too small for a meaningful entropy estimate.
+const MIN_ENTROPY_BYTES: usize = 256;
+
+/// Share of U+FFFD replacement characters that marks a chunk as binary.
+const BINARY_RATIO: f64 = 0.1;
+
+const ALT_SCREEN_ENTER: &[&str] = &["\x1b[?1049h", "\x1b[?1047h", "\x1b[?47h"];
+const ALT_SCREEN_EXIT: &[&str] = &["\x1b[?1049l", "\x1b[?1047l", "\x1b[?47l"];
+
+/// Flags output that looks like misbehaving automation: sudden volume
+/// spikes, high-entropy or binary payloads, and alternate-screen entry.
+pub struct AnomalyDetector {
+    spike_factor: f64,
+    entropy_threshold: f64,
+    window_start: Instant,
+    window_bytes: usize,
+    baseline: Option<f64>,
+    spike_reported: bool,
+    alt_screen: bool,
+}
+
+impl AnomalyDetector {
+    pub fn new(spike_factor: f64, entropy_threshold: f64) -> Self {
+        Self {
+            spike_factor,
+            entropy_threshold,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            baseline: None,
+            spike_reported: false,
+            alt_screen: false,
+        }
+    }
+
+    pub fn observe(&mut self, frame: &Frame) -> Vec<Frame> {
+        let mut anomalies = Vec::new();
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return anomalies;
+        }
+        let Some(data) = frame.text() else {
+            return anomalies;
+        };
+
+        if let Some(anomaly) = self.check_rate(data.len()) {
+            anomalies.push(anomaly);
+        }
+        if let Some(anomaly) = self.check_content(&data) {
+            anomalies.push(anomaly);
+        }
+        if let Some(anomaly) = self.check_alt_screen(&data) {
+            anomalies.push(anomaly);
+        }
+
+        anomalies
+    }
+
+    fn check_rate(&mut self, len: usize) -> Option<Frame> {
+        if self.window_start.elapsed() >= RATE_WINDOW {
+            // Fold the finished bucket into an exponentially weighted baseline
+            let finished = self.window_bytes as f64;
+            self.baseline = Some(match self.baseline {
+                Some(baseline) => baseline * 0.8 + finished * 0.2,
+                None => finished,
+            });
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+            self.spike_reported = false;
+        }
+
+        self.window_bytes += len;
+        if self.spike_reported || self.window_bytes < MIN_SPIKE_BYTES {
+            return None;
+        }
+
+        let baseline = self.baseline?;
+        let current = self.window_bytes as f64;
+        if current <= baseline.max(1.0) * self.spike_factor {
+            return None;
+        }
+
+        self.spike_reported = true;
+        Some(
+            Frame::new(FrameType::Anomaly)
+                .with_reason("output_spike".to_string())
+                .with_value(current)
+                .with_data(format!(
+                    "{} bytes/s against a baseline of {:.0} bytes/s",
+                    self.window_bytes, baseline
+                )),
+        )
+    }
+
+    fn check_content(&self, data: &str) -> Option<Frame> {
+        if data.len() < MIN_ENTROPY_BYTES {
+            return None;
+        }
+
+        let replacements = data.chars().filter(|&c| c == char::REPLACEMENT_CHARACTER).count();
+        let ratio = replacements as f64 / data.chars().count() as f64;
+        if ratio >= BINARY_RATIO {
+            return Some(
+                Frame::new(FrameType::Anomaly)
+                    .with_reason("binary_output".to_string())
+                    .with_value(ratio),
+            );
+        }
+
+        let entropy = shannon_entropy(data.as_bytes());
+        if entropy >= self.entropy_threshold {
+            return Some(
+                Frame::new(FrameType::Anomaly)
+                    .with_reason("high_entropy".to_string())
+                    .with_value(entropy),
+            );
+        }
+
+        None
+    }
+
+    fn check_alt_screen(&mut self, data: &str) -> Option<Frame> {
+        let entered = ALT_SCREEN_ENTER.iter().any(|seq| data.contains(seq));
+        let exited = ALT_SCREEN_EXIT.iter().any(|seq| data.contains(seq));
+
+        let report = entered && !self.alt_screen;
+        if entered {
+            self.alt_screen = true;
+        }
+        if exited {
+            self.alt_screen = false;
+        }
+
+        report.then(|| Frame::new(FrameType::Anomaly).with_reason("alt_screen".to_string()))
+    }
+}
+
+/// Shannon entropy in bits per byte (0.0 to 8.0).
+fn shannon_entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
@@ -0,0 +1,307 @@
+use crate::frame::{Frame, FrameType};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// First line of an asciicast file, in the v2 layout whichever `version`
+/// it is written as.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u8,
+    pub width: u16,
+    pub height: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_time_limit: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Env>,
+    /// Labels of the recording (`--label`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Provenance: the session ID and `--meta` pairs such as the commit
+    /// of the automation that ran the session
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Env {
+    #[serde(rename = "SHELL", default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(rename = "TERM", default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+}
+
+/// Header line of an asciicast v3 file, where the window size and `TERM`
+/// moved under `term`.
+#[derive(Serialize, Deserialize)]
+struct HeaderV3 {
+    version: u8,
+    term: Term,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idle_time_limit: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    env: Option<Env>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    meta: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Term {
+    cols: u16,
+    rows: u16,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+}
+
+impl Header {
+    /// Header for a recording starting now of a `width`x`height` terminal
+    /// running `command`.
+    pub fn for_session(version: u8, width: u16, height: u16, command: Option<String>) -> Self {
+        Self {
+            version,
+            width,
+            height,
+            timestamp: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            ),
+            idle_time_limit: None,
+            title: Some("SpecterTTY Recording".to_string()),
+            command,
+            env: Some(Env {
+                shell: Some(std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())),
+                term: Some(std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string())),
+            }),
+            tags: Vec::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+
+    /// The header line, laid out for `version` 2 or 3.
+    pub fn to_line(&self) -> Result<String> {
+        if self.version != 3 {
+            return Ok(serde_json::to_string(self)?);
+        }
+        let env = self.env.clone();
+        let header = HeaderV3 {
+            version: 3,
+            term: Term {
+                cols: self.width,
+                rows: self.height,
+                kind: env.as_ref().and_then(|env| env.term.clone()),
+            },
+            timestamp: self.timestamp,
+            idle_time_limit: self.idle_time_limit,
+            title: self.title.clone(),
+            command: self.command.clone(),
+            env: env.map(|env| Env { term: None, ..env }),
+            tags: self.tags.clone(),
+            meta: self.meta.clone(),
+        };
+        Ok(serde_json::to_string(&header)?)
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        match value.get("version").and_then(serde_json::Value::as_u64) {
+            Some(2) => Ok(serde_json::from_value(value)?),
+            Some(3) => {
+                let header: HeaderV3 = serde_json::from_value(value)?;
+                let env = match (header.env, header.term.kind) {
+                    (Some(env), kind) => Some(Env { term: kind.or(env.term), ..env }),
+                    (None, Some(kind)) => Some(Env { shell: None, term: Some(kind) }),
+                    (None, None) => None,
+                };
+                Ok(Self {
+                    version: 3,
+                    width: header.term.cols,
+                    height: header.term.rows,
+                    timestamp: header.timestamp,
+                    idle_time_limit: header.idle_time_limit,
+                    title: header.title,
+                    command: header.command,
+                    env,
+                    tags: header.tags,
+                    meta: header.meta,
+                })
+            }
+            Some(version) => Err(anyhow!("Unsupported asciicast version {}", version)),
+            None => Err(anyhow!("Missing version")),
+        }
+    }
+}
+
+/// Stamps events for a recording of `version`: v2 stores each event's time
+/// since the start, v3 the interval since the previous event.
+pub struct EventClock {
+    version: u8,
+    last: f64,
+}
+
+impl EventClock {
+    pub fn new(version: u8) -> Self {
+        Self { version, last: 0.0 }
+    }
+
+    /// Event at `time` seconds since the start.
+    pub fn event(&mut self, time: f64, code: &str, data: String) -> Event {
+        let stamp = match self.version {
+            // Microseconds, as subtracting leaves float noise
+            3 => ((time - self.last).max(0.0) * 1e6).round() / 1e6,
+            _ => time,
+        };
+        self.last = self.last.max(time);
+        Event(stamp, code.to_string(), data)
+    }
+}
+
+/// One `[time, code, data]` event line: `o` output, `i` input, `r` resize
+/// (`COLSxROWS`), `m` marker or, in v3, `x` exit status.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event(pub f64, pub String, pub String);
+
+impl Event {
+    pub fn time(&self) -> f64 {
+        self.0
+    }
+
+    pub fn code(&self) -> &str {
+        &self.1
+    }
+
+    pub fn data(&self) -> &str {
+        &self.2
+    }
+
+    /// Window size carried by a resize event.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        if self.code() != "r" {
+            return None;
+        }
+        let (cols, rows) = self.data().split_once('x')?;
+        Some((cols.trim().parse().ok()?, rows.trim().parse().ok()?))
+    }
+}
+
+/// Event code and data representing a frame, for the frame types an
+/// asciicast `version` can carry. stderr is folded into output like a real
+/// terminal does; sidecar output never appeared on the terminal and is left
+/// out. Expect matches become markers labelled with their id or pattern.
+pub fn frame_event(frame: &Frame, version: u8) -> Option<(&'static str, String)> {
+    if frame.source.is_some() {
+        return None;
+    }
+    match (&frame.frame_type, frame.cols, frame.rows) {
+        (FrameType::Stdout | FrameType::Stderr, _, _) => Some(("o", frame.text().unwrap_or_default().into_owned())),
+        (FrameType::Stdin, _, _) => Some(("i", frame.text().unwrap_or_default().into_owned())),
+        (FrameType::Resize, Some(cols), Some(rows)) => Some(("r", format!("{}x{}", cols, rows))),
+        (FrameType::Match, _, _) => Some(("m", frame.id.clone().or_else(|| frame.regex.clone()).unwrap_or_default())),
+        (FrameType::Exit, _, _) if version >= 3 => frame.code.map(|code| ("x", code.to_string())),
+        _ => None,
+    }
+}
+
+/// Frame for an event of a recording that began at `start` (epoch seconds).
+pub fn event_frame(event: &Event, start: f64) -> Option<Frame> {
+    let frame = match event.code() {
+        "o" => Frame::new(FrameType::Stdout).with_data(event.data().to_string()),
+        "i" => Frame::new(FrameType::Stdin).with_data(event.data().to_string()),
+        "r" => {
+            let (cols, rows) = event.size()?;
+            Frame::new(FrameType::Resize).with_size(cols, rows)
+        }
+        "m" => Frame::new(FrameType::Match).with_id(event.data().to_string()),
+        "x" => Frame::new(FrameType::Exit).with_exit_code(event.data().trim().parse().ok()?),
+        _ => return None,
+    };
+    Some(frame.with_timestamp(start + event.time()))
+}
+
+/// Event lines as written by spectertty before it produced v2 arrays.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EventLine {
+    Array(Event),
+    Legacy {
+        time: f64,
+        event_type: String,
+        data: String,
+    },
+}
+
+/// Streaming reader over an asciicast v2 or v3 recording. Events from
+/// either carry their time since the start.
+pub struct Reader<R> {
+    lines: std::io::Lines<R>,
+    line_no: usize,
+    /// v3 times are intervals, summed here
+    elapsed: Option<f64>,
+}
+
+impl<R: BufRead> Reader<R> {
+    /// Read and validate the header line.
+    pub fn new(input: R) -> Result<(Header, Self)> {
+        let mut lines = input.lines();
+        let first = lines
+            .next()
+            .ok_or_else(|| anyhow!("Empty recording"))?
+            .context("Failed to read recording header")?;
+        let header = Header::parse(&first).context("Invalid asciicast header")?;
+        let elapsed = (header.version == 3).then_some(0.0);
+        Ok((header, Self { lines, line_no: 1, elapsed }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line_no += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let event = serde_json::from_str(&line)
+                .map(|event| match event {
+                    EventLine::Array(event) => event,
+                    EventLine::Legacy {
+                        time,
+                        event_type,
+                        data,
+                    } => Event(time, event_type, data),
+                })
+                .map(|mut event| {
+                    if let Some(ref mut elapsed) = self.elapsed {
+                        *elapsed += event.0;
+                        event.0 = *elapsed;
+                    }
+                    event
+                })
+                .with_context(|| format!("Invalid asciicast event on line {}", self.line_no));
+            return Some(event);
+        }
+    }
+}
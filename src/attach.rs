@@ -0,0 +1,153 @@
+use crate::cli::AttachArgs;
+use crate::frame::{Frame, FrameType};
+use crate::host::{self, HostTerminal, RawMode};
+use crate::registry::Registry;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Ctrl-], as telnet uses it.
+const DETACH_KEY: u8 = 0x1d;
+
+/// How an attach ended.
+enum Ending {
+    Detached,
+    Exited(Option<i32>),
+    Closed,
+}
+
+/// Connect this terminal to a running session through its `--socket`:
+/// keystrokes are sent as `input` commands and the session's output is
+/// written back untouched, until Ctrl-] detaches. Other clients of the
+/// socket are not disturbed.
+pub async fn run(args: &AttachArgs) -> Result<()> {
+    if !io::stdin().is_terminal() {
+        bail!("attach needs a terminal on stdin");
+    }
+    let socket = resolve(&args.socket)?;
+    let stream = UnixStream::connect(&socket)
+        .await
+        .with_context(|| format!("Failed to connect to {:?}", socket))?;
+    let (mut reader, mut writer) = stream.into_split();
+    if let (Some(client), Some(token)) = (&args.client, &args.token) {
+        send(&mut writer, json!({"type": "auth", "client": client, "token": token})).await?;
+    }
+    // Repaint what the session printed recently before going live
+    send(&mut writer, json!({"type": "resume_from", "seq": 0})).await?;
+
+    let host = HostTerminal::open();
+    let mut winch = signal(SignalKind::window_change())?;
+    if args.resize {
+        send_size(&mut writer, host.as_ref()).await?;
+    }
+
+    let raw = RawMode::enable()?;
+    let mut stdout = tokio::io::stdout();
+    stdout
+        .write_all(format!("[attached to {}; Ctrl-] detaches]\r\n", args.socket.display()).as_bytes())
+        .await?;
+    stdout.flush().await?;
+
+    let mut keys = host::spawn_key_reader();
+    let mut pending = Vec::new();
+    // Keystrokes cut inside a UTF-8 character
+    let mut partial = Vec::new();
+    let mut last_seq = 0;
+    let ending = 'session: loop {
+        tokio::select! {
+            read = reader.read_buf(&mut pending) => {
+                if read? == 0 {
+                    break Ending::Closed;
+                }
+                while let Some((frame, used)) = Frame::decode(args.format, &pending)
+                    .context("Unexpected frame from the session; does --format match its --format?")?
+                {
+                    pending.drain(..used);
+                    // Replayed frames may repeat ones already shown live
+                    if let Some(seq) = frame.seq {
+                        if seq <= last_seq {
+                            continue;
+                        }
+                        last_seq = seq;
+                    }
+                    match frame.frame_type {
+                        FrameType::Stdout | FrameType::Stderr => {
+                            if let Some(data) = frame.bytes() {
+                                stdout.write_all(&data).await?;
+                            }
+                        }
+                        FrameType::Ping => send(&mut writer, json!({"type": "pong", "id": frame.id})).await?,
+                        FrameType::Exit => break 'session Ending::Exited(frame.code),
+                        _ => {}
+                    }
+                }
+                stdout.flush().await?;
+            }
+            data = keys.recv() => {
+                let Some(data) = data else { break Ending::Detached };
+                let (data, detach) = match data.iter().position(|&byte| byte == DETACH_KEY) {
+                    Some(at) => (&data[..at], true),
+                    None => (&data[..], false),
+                };
+                partial.extend_from_slice(data);
+                let complete = match std::str::from_utf8(&partial) {
+                    Ok(_) => partial.len(),
+                    Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                    Err(_) => partial.len(),
+                };
+                let text = String::from_utf8_lossy(&partial[..complete]).into_owned();
+                partial.drain(..complete);
+                if !text.is_empty() {
+                    send(&mut writer, json!({"type": "input", "data": text})).await?;
+                }
+                if detach {
+                    break Ending::Detached;
+                }
+            }
+            _ = winch.recv(), if args.resize => send_size(&mut writer, host.as_ref()).await?,
+        }
+    };
+    drop(raw);
+
+    let notice = match ending {
+        Ending::Detached => "[detached]".to_string(),
+        Ending::Exited(Some(code)) => format!("[session exited with code {}]", code),
+        Ending::Exited(None) => "[session exited]".to_string(),
+        Ending::Closed => "[session closed the connection]".to_string(),
+    };
+    stdout.write_all(format!("\r\n{}\r\n", notice).as_bytes()).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// The socket path itself, or the `--socket` of the session with that
+/// name or ID.
+fn resolve(target: &Path) -> Result<PathBuf> {
+    if target.exists() {
+        return Ok(target.to_path_buf());
+    }
+    let key = target.to_string_lossy();
+    let entry = Registry::open(Registry::default_dir()).find(&key)?;
+    entry
+        .socket
+        .ok_or_else(|| anyhow!("Session '{}' has no --socket to attach through", key))
+}
+
+async fn send(writer: &mut OwnedWriteHalf, command: serde_json::Value) -> Result<()> {
+    let mut line = command.to_string();
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+async fn send_size(writer: &mut OwnedWriteHalf, host: Option<&HostTerminal>) -> Result<()> {
+    match host.and_then(HostTerminal::size) {
+        Some((cols, rows)) => send(writer, json!({"type": "resize", "cols": cols, "rows": rows})).await,
+        None => Ok(()),
+    }
+}
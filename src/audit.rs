@@ -0,0 +1,26 @@
+use crate::frame::Frame;
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Append-only NDJSON log of security-relevant frames.
+pub struct AuditLog {
+    writer: BufWriter<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        writeln!(self.writer, "{}", frame.to_json()?)?;
+        // Audit entries must survive a crash of the wrapper.
+        self.writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+/// Where the scanner is in the escape-sequence grammar.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum State {
+    #[default]
+    Ground,
+    /// After ESC
+    Escape,
+    /// Inside an OSC, DCS, APC, PM or SOS string, where BEL is a terminator
+    String,
+    /// After ESC inside a string, possibly the ST terminator
+    StringEscape,
+}
+
+/// Counts terminal bells (BEL) in command output.
+///
+/// BEL also ends OSC strings such as window titles and OSC 52 copies, so
+/// only bells outside those strings are counted. State carries across
+/// reads, so a string split between two chunks is still recognised.
+#[derive(Debug, Default)]
+pub struct BellDetector {
+    state: State,
+}
+
+impl BellDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bells rung in `data`.
+    pub fn observe(&mut self, data: &[u8]) -> usize {
+        let mut bells = 0;
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (State::Ground, 0x07) => {
+                    bells += 1;
+                    State::Ground
+                }
+                (State::Ground, 0x1b) => State::Escape,
+                (State::Ground, _) => State::Ground,
+                (State::Escape, b']' | b'P' | b'_' | b'^' | b'X') => State::String,
+                (State::Escape, 0x1b) => State::Escape,
+                (State::Escape, 0x07) => {
+                    bells += 1;
+                    State::Ground
+                }
+                (State::Escape, _) => State::Ground,
+                (State::String, 0x07) => State::Ground,
+                (State::String, 0x1b) => State::StringEscape,
+                (State::String, _) => State::String,
+                (State::StringEscape, b'\\') => State::Ground,
+                // Another escape cuts the string short
+                (State::StringEscape, b']' | b'P' | b'_' | b'^' | b'X') => State::String,
+                (State::StringEscape, 0x1b) => State::Escape,
+                (State::StringEscape, _) => State::Ground,
+            };
+        }
+        bells
+    }
+}
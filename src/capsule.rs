@@ -0,0 +1,84 @@
+use crate::error::SessionError;
+use crate::frame::{Frame, FrameType};
+
+/// Exit status capsule-run uses when the sandbox itself could not be set up.
+const SANDBOX_SETUP_FAILED: i32 = 125;
+
+/// Wraps the target command in capsule-run and interprets how it ended.
+pub struct Capsule {
+    bin: String,
+    profile: Option<String>,
+}
+
+impl Capsule {
+    pub fn new(bin: String, profile: Option<String>) -> Self {
+        Self { bin, profile }
+    }
+
+    /// Rewrite `command args...` into a capsule-run invocation.
+    pub fn wrap(&self, command: &str, args: &[String]) -> (String, Vec<String>) {
+        let mut wrapped = Vec::new();
+        if let Some(ref profile) = self.profile {
+            wrapped.push("--profile".to_string());
+            wrapped.push(profile.clone());
+        }
+        wrapped.push("--".to_string());
+        wrapped.push(command.to_string());
+        wrapped.extend(args.iter().cloned());
+        (self.bin.clone(), wrapped)
+    }
+
+    /// Translate the session's Exit frame into a CapsuleKill frame (when the
+    /// sandbox terminated the command) followed by the Exit frame carrying
+    /// the sandbox's exit reason. Other frames pass through untouched.
+    pub fn observe(&self, frame: Frame) -> Vec<Frame> {
+        if !matches!(frame.frame_type, FrameType::Exit) {
+            return vec![frame];
+        }
+
+        let code = frame.code.unwrap_or(0);
+        let reason = exit_reason(code);
+        let mut frames = Vec::new();
+
+        if let Some(signal) = killing_signal(code) {
+            frames.push(
+                Frame::new(FrameType::CapsuleKill)
+                    .with_exit_code(code)
+                    .with_signal(signal.to_string())
+                    .with_reason(reason.to_string()),
+            );
+        }
+
+        if code == SANDBOX_SETUP_FAILED {
+            frames.push(
+                SessionError::Sandbox("capsule-run could not set up the sandbox".to_string()).to_frame(),
+            );
+        }
+
+        frames.push(frame.with_reason(reason.to_string()));
+        frames
+    }
+}
+
+/// Signal behind a 128+N exit status that indicates a sandbox kill.
+fn killing_signal(code: i32) -> Option<&'static str> {
+    match code {
+        137 => Some("SIGKILL"),
+        152 => Some("SIGXCPU"),
+        153 => Some("SIGXFSZ"),
+        159 => Some("SIGSYS"),
+        _ => None,
+    }
+}
+
+fn exit_reason(code: i32) -> &'static str {
+    match code {
+        0 => "exited",
+        137 => "resource_limit",
+        152 => "cpu_limit",
+        153 => "file_size_limit",
+        159 => "syscall_violation",
+        SANDBOX_SETUP_FAILED => "sandbox_error",
+        _ => "failed",
+    }
+}
@@ -0,0 +1,240 @@
+use crate::frame::{Frame, FrameType};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+/// How often IO counters are read to tell whether the command is throttled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of a limit a command must be using to count as throttled.
+const THROTTLED_RATIO: f64 = 0.9;
+
+/// Read and write bandwidth caps for the command, in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoLimits {
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+}
+
+impl IoLimits {
+    pub fn is_empty(&self) -> bool {
+        self.rbps.is_none() && self.wbps.is_none()
+    }
+}
+
+/// Bytes the cgroup has read and written so far, over all devices.
+#[derive(Debug, Clone, Copy, Default)]
+struct IoTotals {
+    read: u64,
+    written: u64,
+}
+
+/// A cgroup v2 group of its own for the command, with `io.max` capping
+/// its bandwidth on every block device.
+///
+/// The group is created under spectertty's own cgroup, which must have the
+/// io controller delegated to it. A cgroup with processes in it cannot
+/// hand controllers down, so spectertty first moves itself into a leaf
+/// beside the command's group when needed. The group is removed on drop.
+pub struct IoCgroup {
+    dir: PathBuf,
+    limits: IoLimits,
+    interval: Interval,
+    last: Option<(Instant, IoTotals)>,
+    throttled: bool,
+}
+
+impl IoCgroup {
+    pub fn create(name: &str, limits: IoLimits) -> Result<Self> {
+        let parent = own_cgroup()?;
+        let controllers = fs::read_to_string(parent.join("cgroup.controllers")).unwrap_or_default();
+        if !controllers.split_whitespace().any(|controller| controller == "io") {
+            return Err(anyhow!(
+                "The io controller is not available in cgroup {}; it must be delegated to spectertty",
+                parent.display()
+            ));
+        }
+        enable_io(&parent)?;
+
+        let dir = parent.join(name);
+        match fs::create_dir(&dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to create cgroup {}", dir.display())),
+        }
+
+        let mut line = String::new();
+        if let Some(rbps) = limits.rbps {
+            line.push_str(&format!(" rbps={}", rbps));
+        }
+        if let Some(wbps) = limits.wbps {
+            line.push_str(&format!(" wbps={}", wbps));
+        }
+        let mut limited = 0;
+        for device in block_devices() {
+            match fs::write(dir.join("io.max"), format!("{}{}", device, line)) {
+                Ok(()) => limited += 1,
+                Err(e) => debug!("Cannot limit IO on device {}: {}", device, e),
+            }
+        }
+        if limited == 0 {
+            let _ = fs::remove_dir(&dir);
+            return Err(anyhow!("No block device accepted an IO limit in cgroup {}", dir.display()));
+        }
+
+        let mut interval = tokio::time::interval_at(Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            dir,
+            limits,
+            interval,
+            last: None,
+            throttled: false,
+        })
+    }
+
+    /// Directory of the group, for `Scheduling::cgroup`.
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Wait for the next time to read the counters.
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// A `resource` frame when the command has started or stopped running
+    /// up against a limit since the last check.
+    pub fn check(&mut self) -> Option<Frame> {
+        let totals = match self.totals() {
+            Ok(totals) => totals,
+            Err(e) => {
+                debug!("Failed to read io.stat: {}", e);
+                return None;
+            }
+        };
+        let now = Instant::now();
+        let (then, before) = self.last.replace((now, totals))?;
+        let elapsed = (now - then).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let read_bps = (totals.read.saturating_sub(before.read) as f64 / elapsed) as u64;
+        let write_bps = (totals.written.saturating_sub(before.written) as f64 / elapsed) as u64;
+
+        let at_limit =
+            |rate: u64, limit: Option<u64>| limit.is_some_and(|limit| rate as f64 >= limit as f64 * THROTTLED_RATIO);
+        let throttled = at_limit(read_bps, self.limits.rbps) || at_limit(write_bps, self.limits.wbps);
+        if throttled == self.throttled {
+            return None;
+        }
+        self.throttled = throttled;
+
+        let mut stats = BTreeMap::new();
+        stats.insert("read_bps".to_string(), read_bps);
+        stats.insert("write_bps".to_string(), write_bps);
+        stats.insert("read_bytes".to_string(), totals.read);
+        stats.insert("write_bytes".to_string(), totals.written);
+        if let Some(rbps) = self.limits.rbps {
+            stats.insert("read_limit_bps".to_string(), rbps);
+        }
+        if let Some(wbps) = self.limits.wbps {
+            stats.insert("write_limit_bps".to_string(), wbps);
+        }
+        let reason = if throttled { "io_throttled" } else { "io_unthrottled" };
+        Some(Frame::new(FrameType::Resource).with_reason(reason.to_string()).with_stats(stats))
+    }
+
+    fn totals(&self) -> Result<IoTotals> {
+        let stat = fs::read_to_string(self.dir.join("io.stat"))?;
+        let mut totals = IoTotals::default();
+        for field in stat.split_whitespace() {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value: u64 = value.parse().unwrap_or(0);
+            match key {
+                "rbytes" => totals.read += value,
+                "wbytes" => totals.written += value,
+                _ => {}
+            }
+        }
+        Ok(totals)
+    }
+}
+
+impl Drop for IoCgroup {
+    fn drop(&mut self) {
+        // Only empty groups can be removed; anything the command left
+        // running keeps it
+        if let Err(e) = fs::remove_dir(&self.dir) {
+            warn!("Failed to remove cgroup {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Directory of the cgroup v2 group this process is in.
+fn own_cgroup() -> Result<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").context("Failed to read /proc/self/mountinfo")?;
+    let mount = mountinfo
+        .lines()
+        .find_map(|line| {
+            let (fields, rest) = line.split_once(" - ")?;
+            (rest.split_whitespace().next()? == "cgroup2").then(|| fields.split_whitespace().nth(4))?
+        })
+        .ok_or_else(|| anyhow!("No cgroup v2 hierarchy is mounted"))?;
+
+    let cgroups = fs::read_to_string("/proc/self/cgroup").context("Failed to read /proc/self/cgroup")?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("This process is not in a cgroup v2 group"))?;
+    match path.trim_start_matches('/') {
+        "" => Ok(PathBuf::from(mount)),
+        path => Ok(Path::new(mount).join(path)),
+    }
+}
+
+/// Turn on the io controller for `parent`'s children, first moving this
+/// process into a leaf of its own if `parent` may not have both.
+fn enable_io(parent: &Path) -> Result<()> {
+    let subtree = parent.join("cgroup.subtree_control");
+    let enabled = fs::read_to_string(&subtree).unwrap_or_default();
+    if enabled.split_whitespace().any(|controller| controller == "io") {
+        return Ok(());
+    }
+    match fs::write(&subtree, "+io") {
+        Ok(()) => return Ok(()),
+        Err(e) if e.raw_os_error() != Some(nix::errno::Errno::EBUSY as i32) => {
+            return Err(e).with_context(|| format!("Failed to enable the io controller in {}", parent.display()));
+        }
+        Err(_) => {}
+    }
+
+    let leaf = parent.join(format!("spectertty-{}", std::process::id()));
+    match fs::create_dir(&leaf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create cgroup {}", leaf.display())),
+    }
+    fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+        .with_context(|| format!("Failed to move spectertty into cgroup {}", leaf.display()))?;
+    fs::write(&subtree, "+io").with_context(|| format!("Failed to enable the io controller in {}", parent.display()))
+}
+
+/// `major:minor` of every whole disk; `io.max` takes no partitions.
+fn block_devices() -> Vec<String> {
+    let Ok(entries) = fs::read_dir("/sys/block") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| fs::read_to_string(entry.path().join("dev")).ok())
+        .map(|dev| dev.trim().to_string())
+        .collect()
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+/// Faults injected into the frame stream of the `--chaos-socket`, so
+/// client authors can exercise their resume and reordering logic.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosSpec {
+    /// Chance that a frame is never sent
+    pub drop: f64,
+    /// Chance that a frame is sent twice
+    pub dup: f64,
+    /// Longest a frame is held before it is sent
+    pub delay: Option<Duration>,
+    /// Chance, per frame, that the connection is closed instead
+    pub disconnect: f64,
+}
+
+/// Parse a fault list such as `drop:0.01,delay:50ms,dup:0.001`.
+pub fn parse_chaos(spec: &str) -> Result<ChaosSpec, String> {
+    let mut chaos = ChaosSpec::default();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (fault, value) = part
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' is not FAULT:VALUE, e.g. drop:0.01 or delay:50ms", part))?;
+        let chance = || match value.trim().parse::<f64>() {
+            Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+            _ => Err(format!("{} needs a probability from 0 to 1, got '{}'", fault, value)),
+        };
+        match fault.trim() {
+            "drop" => chaos.drop = chance()?,
+            "dup" => chaos.dup = chance()?,
+            "disconnect" => chaos.disconnect = chance()?,
+            "delay" => chaos.delay = Some(crate::cli::parse_duration(value)?),
+            other => {
+                return Err(format!(
+                    "Unknown fault '{}', expected drop, dup, delay or disconnect",
+                    other
+                ))
+            }
+        }
+    }
+    Ok(chaos)
+}
+
+/// What to do with the next frame on a perturbed connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Send the frame this many times (0 drops it) after waiting
+    Send { copies: usize, delay: Duration },
+    Disconnect,
+}
+
+/// Per-connection fault injector; each connection rolls its own dice,
+/// and the same `seed` rolls the same faults.
+pub struct Chaos {
+    spec: ChaosSpec,
+    rng: fastrand::Rng,
+}
+
+impl Chaos {
+    pub fn new(spec: ChaosSpec, seed: u64) -> Self {
+        Self {
+            spec,
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    pub fn next_fault(&mut self) -> Fault {
+        if self.roll(self.spec.disconnect) {
+            return Fault::Disconnect;
+        }
+        let copies = if self.roll(self.spec.drop) {
+            0
+        } else if self.roll(self.spec.dup) {
+            2
+        } else {
+            1
+        };
+        let delay = self
+            .spec
+            .delay
+            .map_or(Duration::ZERO, |max| max.mul_f64(self.rng.f64()));
+        Fault::Send { copies, delay }
+    }
+
+    fn roll(&mut self, chance: f64) -> bool {
+        chance > 0.0 && self.rng.f64() < chance
+    }
+}
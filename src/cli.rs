@@ -1,4 +1,19 @@
-use clap::{Parser, ValueEnum};
+use crate::env::CommandEnv;
+use crate::cgroup::IoLimits;
+use crate::chaos::{parse_chaos, ChaosSpec};
+use crate::recorder::Rotation;
+use crate::prompt::parse_named_prompt;
+use crate::remote::RecordUrl;
+use crate::respond::parse_auto_respond;
+use crate::restart::{parse_restart, RestartPolicy};
+use crate::sched::{parse_cpuset, Scheduling};
+use crate::termios::TermiosChange;
+use crate::tokens::Tokenizer;
+use crate::transport::Keepalive;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -6,15 +21,73 @@ use std::time::Duration;
 #[command(name = "spectertty")]
 #[command(about = "AI-Native Terminal Automation Platform")]
 #[command(version)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub subcommand: Option<Commands>,
+
     #[arg(long, help = "Output frames to stdout")]
     pub json: bool,
 
+    #[arg(long, conflicts_with = "format", help = "Output frames to stdout as a readable, colored timeline")]
+    pub pretty: bool,
+
+    #[arg(long, requires = "json", conflicts_with = "pretty", help = "Keep stdout to one JSON frame per line, stopping the session if anything else is written to it")]
+    pub ndjson_strict: bool,
+
     #[arg(long, help = "Unix socket transport")]
     pub socket: Option<PathBuf>,
 
-    #[arg(long, help = "TCP transport (HOST:PORT)")]
-    pub bind: Option<String>,
+    #[arg(long, value_enum, default_value = "json", help = "Encoding of frames on stdout and the socket")]
+    pub format: FrameFormat,
+
+    #[arg(long, value_enum, default_value = "wall", help = "Timestamps frames carry: wall-clock ts, monotonic elapsed time, or both")]
+    pub ts_format: TsFormat,
+
+    #[arg(long, value_name = "PATH", requires = "chaos", help = "Extra Unix socket whose frame stream has --chaos faults injected")]
+    pub chaos_socket: Option<PathBuf>,
+
+    #[arg(long, value_name = "FAULTS", requires = "chaos_socket", value_parser = parse_chaos, help = "Faults for --chaos-socket clients, e.g. drop:0.01,delay:50ms,dup:0.001,disconnect:0.0001")]
+    pub chaos: Option<ChaosSpec>,
+
+    #[arg(long, value_name = "N", env = "SPECTERTTY_SEED", help = "Seed for randomized behavior such as --chaos faults (default: random, reported in session_start)")]
+    pub seed: Option<u64>,
+
+    #[arg(long, value_name = "INTERVAL", value_parser = parse_duration, help = "Send socket clients a ping frame every INTERVAL and close those that stop answering")]
+    pub keepalive: Option<Duration>,
+
+    #[arg(long, value_name = "DURATION", requires = "keepalive", value_parser = parse_duration, help = "How long a socket client may stay silent under --keepalive (default: three intervals)")]
+    pub keepalive_timeout: Option<Duration>,
+
+    #[arg(long, env = "SPECTERTTY_AUTH_TOKENS", value_delimiter = ',', help = "Controller credential NAME=TOKEN (repeatable)")]
+    pub auth_token: Vec<String>,
+
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, help = "Serve the socket protocol over TCP on [HOST:]PORT as well (default host 127.0.0.1)")]
+    pub bind: Option<SocketAddr>,
+
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, help = "Serve the session to ttyd and gotty browser clients on [HOST:]PORT (default host 127.0.0.1)")]
+    pub ttyd: Option<SocketAddr>,
+
+    #[arg(long, requires = "ttyd", help = "Let --ttyd clients type into and resize the session")]
+    pub ttyd_writable: bool,
+
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, conflicts_with_all = ["command", "explore"], help = "Instead of running a command, serve the gRPC API for creating and driving sessions on [HOST:]PORT (default host 127.0.0.1)")]
+    pub grpc: Option<SocketAddr>,
+
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, conflicts_with_all = ["command", "explore"], help = "Instead of running a command, serve sessions over HTTP, with their frames as Server-Sent Events, on [HOST:]PORT (default host 127.0.0.1)")]
+    pub http: Option<SocketAddr>,
+
+    #[arg(long, value_name = "N", default_value = "64", help = "Most sessions --grpc and --http run at once")]
+    pub max_sessions: usize,
+
+    #[arg(long, value_name = "N", default_value = "0", help = "Keep N sessions of --pool-command started ahead of time for --grpc and --http clients")]
+    pub pool_size: usize,
+
+    #[arg(long, value_name = "COMMAND", default_value = "bash -l", help = "Command line of the sessions --pool-size keeps warm")]
+    pub pool_command: String,
+
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr, help = "Serve Prometheus metrics at /metrics on [HOST:]PORT (default host 127.0.0.1)")]
+    pub metrics_addr: Option<SocketAddr>,
 
     #[arg(long, default_value = "120", help = "Initial window columns")]
     pub cols: u16,
@@ -28,47 +101,696 @@ pub struct Cli {
     #[arg(long, value_enum, default_value = "raw", help = "Token processing mode")]
     pub token_mode: TokenMode,
 
+    #[arg(long, help = "In compact mode, keep colors and text attributes as <bold red>...</> markup")]
+    pub preserve_color: bool,
+
+    #[arg(long, help = "In compact mode, collapse runs of lines that differ only in their numbers into one frame with a repeat_count")]
+    pub dedupe_lines: bool,
+
+    #[arg(long, value_name = "INTERVAL", default_value = "1s", value_parser = parse_duration, help = "In compact mode, send a full-screen application's screen as a snapshot at most this often instead of its redraws")]
+    pub snapshot_interval: Duration,
+
+    #[arg(long, value_name = "MS", help = "Merge stdout frames arriving within this many milliseconds of the first into one frame")]
+    pub coalesce_ms: Option<u64>,
+
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = parse_byte_size, requires = "coalesce_ms", help = "Largest payload of a frame merged by --coalesce-ms")]
+    pub coalesce_max: u64,
+
     #[arg(long, help = "Register prompt matcher (repeatable)")]
     pub prompt_regex: Vec<String>,
 
-    #[arg(long, default_value = "8388608", help = "Max in-mem queue before back-pressure (bytes)")]
+    #[arg(long, value_name = "NAME:REGEX", value_parser = parse_named_prompt, help = "Register a named prompt matcher, e.g. 'python:>>> ?$'; prompt frames carry the name in id (repeatable)")]
+    pub prompt: Vec<(String, String)>,
+
+    #[arg(long, value_name = "REGEX=>RESPONSE", value_parser = parse_auto_respond, help = "Send RESPONSE whenever output matches REGEX, e.g. '\\[y/N\\]=>y\\n' (repeatable)")]
+    pub auto_respond: Vec<(String, String)>,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), help = "Answer each --auto-respond pattern at most N times")]
+    pub auto_respond_limit: Option<u32>,
+
+    #[arg(long, value_name = "MS", help = "Send EOF and finish once output has been idle this long (and ends at a --prompt-regex match, if any)")]
+    pub exit_on_idle: Option<u64>,
+
+    #[arg(long, help = "Emit command_start and command_end frames around each command entered at a prompt (see --prompt-regex)")]
+    pub segment_commands: bool,
+
+    #[arg(long, help = "Hook a bash, zsh or fish shell to report each command's start, end and exit status")]
+    pub shell_integration: bool,
+
+    #[arg(long, help = "Session ID for frames and {session_id} in paths (default: a new ULID)")]
+    pub session_id: Option<String>,
+
+    #[arg(long, help = "Name to find the session by with list, kill and attach")]
+    pub name: Option<String>,
+
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var, help = "Set an environment variable for the command (repeatable)")]
+    pub env: Vec<(String, String)>,
+
+    #[arg(long, value_name = "FILE", help = "Load KEY=VALUE lines into the command's environment (repeatable)")]
+    pub env_file: Vec<PathBuf>,
+
+    #[arg(long, help = "Start the command with only the variables from --env and --env-file")]
+    pub clear_env: bool,
+
+    #[arg(long, value_name = "DIR", help = "Working directory for the command (default: the current one)")]
+    pub cwd: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub pii: PiiArgs,
+
+    #[command(flatten)]
+    pub redact: RedactArgs,
+
+    #[arg(long, value_name = "COMMAND", help = "Merge a read-only command's output into the stream, e.g. 'tail -f app.log' (repeatable)")]
+    pub sidecar: Vec<String>,
+
+    #[arg(long, help = "Forward SIGINT/SIGTERM/SIGHUP to the command instead of shutting down")]
+    pub forward_signals: bool,
+
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "session", help = "What to do with processes the command leaves running when it ends: terminate its whole session, only its process group, or none")]
+    pub kill_children: KillChildren,
+
+    #[arg(long, value_name = "POLICY", default_value = "no", value_parser = parse_restart, help = "Start the command again when it exits: no, on-failure[:N] (non-zero exits, at most N times) or always, with backoff")]
+    pub restart: RestartPolicy,
+
+    #[arg(long, help = "Capture stderr separately and emit it as stderr frames")]
+    pub split_stderr: bool,
+
+    #[arg(long, help = "Start the command's terminal with echo off, so input is not reflected in its output")]
+    pub no_echo: bool,
+
+    #[arg(long, help = "Start the command's terminal in raw mode: no line editing, echo or signal keys")]
+    pub raw: bool,
+
+    #[arg(long, value_name = "RATE", value_parser = parse_input_rate, help = "Pace all input to the command at RATE, e.g. 10cps or 90cpm, for consoles that drop fast input")]
+    pub throttle_input: Option<Duration>,
+
+    #[arg(long, value_name = "BYTES", value_parser = parse_write_rate, help = "Write input to the command at most BYTES a second (e.g. 64K), in pieces")]
+    pub write_rate: Option<u64>,
+
+    #[arg(long, value_name = "CPUS", help = "Run the command only on these CPUs (e.g. 2-3 or 0,4-7)")]
+    pub cpuset: Option<String>,
+
+    #[arg(long, value_enum, help = "Scheduling class for the command, so heavy builds yield to the rest of the host")]
+    pub sched: Option<SchedPolicy>,
+
+    #[arg(long, value_enum, value_name = "CLASS", help = "IO priority class for the command")]
+    pub ionice: Option<IoClass>,
+
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, help = "Cap the command's disk reads at SIZE per second (e.g. 20M) through a cgroup v2 io controller")]
+    pub limit_io_rbps: Option<u64>,
+
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, help = "Cap the command's disk writes at SIZE per second (e.g. 20M) through a cgroup v2 io controller")]
+    pub limit_io_wbps: Option<u64>,
+
+    #[arg(long, default_value = "8388608", help = "Unread output that sets off the degradation ladder (bytes)")]
     pub buffer: usize,
 
-    #[arg(long, default_value = "5000", help = "Grace before SIGKILL on overflow (ms)")]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), help = "Drop output frames past N a second, sending summaries of them instead")]
+    pub max_frames_per_sec: Option<u32>,
+
+    #[arg(long, value_name = "N", help = "Drop output after the first N frames of the session, sending summaries of it instead")]
+    pub sample_after: Option<u64>,
+
+    #[arg(long, help = "Annotate frames with an estimate of the tokens their data costs")]
+    pub count_tokens: bool,
+
+    #[arg(long, value_enum, default_value = "chars", help = "How tokens are estimated: chars (4 a token), words (4 tokens per 3) or bytes (3 a token)")]
+    pub tokenizer: Tokenizer,
+
+    #[arg(long, value_name = "TOKENS", help = "Summarize output instead of passing it once it would cost more than this many tokens")]
+    pub token_budget: Option<u64>,
+
+    #[arg(long, value_name = "COMMAND", help = "Shell command that condenses long runs of output, read on its stdin, into summary frames")]
+    pub summarize_cmd: Option<String>,
+
+    #[arg(long, value_name = "SIZE", default_value = "4K", value_parser = parse_byte_size, requires = "summarize_cmd", help = "Output without a pause that --summarize-cmd takes over from, and the size of the chunks it is given")]
+    pub summarize_after: u64,
+
+    #[arg(long, default_value = "5000", help = "Grace before SIGKILL once the degradation ladder has paused the command (ms)")]
     pub overflow_timeout: u64,
 
-    #[arg(long, help = "asciinema v2 output file")]
+    #[arg(long, help = "Record the session to this file (see --record-format)")]
     pub record: Option<PathBuf>,
 
+    #[arg(long, value_enum, default_value = "cast", help = "Format of the --record file: cast (asciicast v2), cast-v3, ttyrec or raw (typescript and FILE.timing)")]
+    pub record_format: RecordingFormat,
+
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_meta, help = "Record provenance such as git_commit=$(git rev-parse HEAD) in asciicast headers (repeatable)")]
+    pub meta: Vec<(String, String)>,
+
+    #[arg(long, value_name = "NAME", help = "Label asciicast recordings with NAME (repeatable)")]
+    pub label: Vec<String>,
+
+    #[arg(long, value_name = "SECS", value_parser = parse_positive, help = "Cap silences in recordings at SECS seconds, rewriting the timeline")]
+    pub record_idle_limit: Option<f64>,
+
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, requires = "record", help = "Split the --record file into numbered segments of about SIZE (e.g. 100M)")]
+    pub record_max_size: Option<u64>,
+
+    #[arg(long, value_name = "N", requires = "record_max_size", help = "Keep only the newest N --record segments")]
+    pub record_rotate: Option<usize>,
+
+    #[arg(long, value_name = "URL", help = "Stream the recording to a collector while the session runs (http, https, ws or wss URL)")]
+    pub record_url: Option<String>,
+
+    #[arg(long, value_name = "NAME:VALUE", value_parser = parse_header, requires = "record_url", help = "Send a header with --record-url requests, e.g. 'Authorization: Bearer ...' (repeatable)")]
+    pub record_header: Vec<(String, String)>,
+
     #[arg(long, help = "Run target via capsule-run")]
     pub capsule: bool,
 
-    #[arg(long, help = "Sandbox profile")]
+    #[arg(long, requires = "capsule", help = "Sandbox profile")]
     pub sandbox_profile: Option<String>,
 
-    #[arg(long, help = "Enable session resurrection")]
+    #[arg(long, env = "CAPSULE_RUN", default_value = "capsule-run", help = "capsule-run executable")]
+    pub capsule_bin: String,
+
+    #[arg(long, env = "SPECTERTTY_STATE_DIR", help = "Keep a journal of every frame under this directory, for `spectertty search`")]
     pub state_dir: Option<PathBuf>,
 
+    #[arg(long, value_name = "SOURCE", env = "SPECTERTTY_STATE_KEY", requires = "state_dir", help = "Encrypt the --state-dir journal with the key from env:VAR, file:PATH, keyring:SERVICE/USER or cmd:COMMAND (e.g. a KMS client)")]
+    pub state_key: Option<String>,
+
+    #[arg(long, value_name = "INTERVAL", requires = "state_dir", value_parser = parse_duration, help = "Checkpoint the screen and scrollback this often (e.g. 5m) and delete the journal it replaces")]
+    pub state_checkpoint: Option<Duration>,
+
     #[arg(long, value_enum, default_value = "none", help = "Compress frame payloads")]
     pub compress: CompressionMode,
 
+    #[arg(long, help = "Require two approvals before writing destructive input")]
+    pub two_person: bool,
+
+    #[arg(long, help = "Destructive input pattern for --two-person (repeatable)")]
+    pub destructive_regex: Vec<String>,
+
+    #[arg(long, value_name = "FILE", help = "Write every frame, with its seq, to this NDJSON file")]
+    pub frame_log: Option<PathBuf>,
+
+    #[arg(long, help = "Append approval trail to this NDJSON file")]
+    pub audit_log: Option<PathBuf>,
+
+    #[arg(long, help = "Send every frame to the local syslog socket as a structured message")]
+    pub syslog: bool,
+
+    #[arg(long, help = "Send every frame to journald with the session ID and type as fields")]
+    pub journald: bool,
+
+    #[arg(long, value_name = "COMMAND", help = "Run this shell command when the session needs attention (see --notify-on)")]
+    pub notify_cmd: Option<String>,
+
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "bell,failure,approval,exit", help = "Events that run --notify-cmd")]
+    pub notify_on: Vec<NotifyEvent>,
+
+    #[arg(long, value_name = "REGEX", help = "Output that counts as a failure for --notify-cmd (repeatable)")]
+    pub notify_regex: Vec<String>,
+
+    #[arg(long, value_name = "SINK=TYPES", help = "Route frame types to a sink: json, socket, recording or audit (repeatable)")]
+    pub route: Vec<String>,
+
+    #[arg(long, value_name = "TYPES", help = "Frame types sent to stdout and the socket, e.g. stdout,prompt,exit; recordings and logs keep everything")]
+    pub emit: Option<String>,
+
+    #[arg(long, help = "Emit anomaly frames for output spikes, high entropy and alt-screen entry")]
+    pub detect_anomalies: bool,
+
+    #[arg(long, default_value = "10", help = "Output rate multiple over baseline that counts as a spike")]
+    pub anomaly_spike_factor: f64,
+
+    #[arg(long, default_value = "5.8", help = "Entropy (bits/byte) above which output is flagged")]
+    pub anomaly_entropy: f64,
+
+    #[arg(long, help = "Emit self-health frames every MS milliseconds")]
+    pub health_interval: Option<u64>,
+
+    #[arg(long, value_parser = parse_byte_size, help = "Degrade to raw mode past this resident memory (e.g. 512M)")]
+    pub self_mem_limit: Option<u64>,
+
+    #[arg(long, value_parser = parse_duration, help = "Terminate the session after this long (e.g. 2h)")]
+    pub session_ttl: Option<Duration>,
+
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "session_ttl", help = "Explore for this long (e.g. 10m) in a recorded shell, then report the commands run, files touched and exit status")]
+    pub explore: Option<Duration>,
+
+    #[arg(long, value_name = "FILE", requires = "explore", help = "Write the --explore report here instead of as a structured frame")]
+    pub explore_report: Option<PathBuf>,
+
+    #[arg(long, value_parser = parse_duration, help = "Terminate the session after this long without input or output (e.g. 15m)")]
+    pub reap_idle: Option<Duration>,
+
+    #[arg(long, value_parser = parse_duration, help = "Stop the command after this long and exit with status 124 (e.g. 10m)")]
+    pub timeout: Option<Duration>,
+
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, help = "Stop the command once it has written more than SIZE (e.g. 50M) and exit with status 125")]
+    pub max_output_bytes: Option<u64>,
+
     #[arg(long, short, help = "Verbose logging")]
     pub verbose: bool,
 
-    #[arg(help = "Command to execute")]
-    pub command: String,
+    #[arg(long, value_enum, default_value = "text", help = "Log line format")]
+    pub log_format: LogFormat,
+
+    #[arg(long, value_name = "FILE", help = "Append logs to this file instead of stdout, keeping them out of the frame stream")]
+    pub log_file: Option<PathBuf>,
+
+    #[arg(required_unless_present_any = ["explore", "grpc", "http"], help = "Command to execute (default with --explore: $SHELL)")]
+    pub command: Option<String>,
+
+    #[arg(help = "Arguments for the command")]
+    pub args: Vec<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Run a send/expect script against a command and report each step
+    Script(ScriptArgs),
+    /// Replay an asciicast v2 recording to the terminal
+    Play(PlayArgs),
+    /// Convert between asciicast v2, NDJSON frame logs, ttyrec and typescripts
+    Convert(ConvertArgs),
+    /// Print a recording as a cleaned, timestamped text transcript
+    Transcript(TranscriptArgs),
+    /// Copy a file into or out of a session through its --socket
+    Cp(CpArgs),
+    /// Connect this terminal to a session through its --socket (Ctrl-] detaches)
+    Attach(AttachArgs),
+    /// Run a command for the person at this terminal, recording it in the background
+    Shadow(ShadowArgs),
+    /// List the sessions running on this machine
+    List(ListArgs),
+    /// Stop a running session by name or ID
+    Kill(KillArgs),
+    /// Print a frame log as a readable, colored timeline
+    Pretty(PrettyArgs),
+    /// Run a command or script repeatedly and report where the runs differ
+    Flake(FlakeArgs),
+    /// Find output in the journals of a --state-dir
+    Search(SearchArgs),
+    /// Bundle a session's journal and screen to move it to another host
+    ExportState(ExportStateArgs),
+    /// Add a session exported with export-state to this host
+    ImportState(ImportStateArgs),
+    /// Print the JSON Schema of frames and control commands
+    Schema(SchemaArgs),
+}
+
+#[derive(Args)]
+pub struct ScriptArgs {
+    #[arg(help = "Script file (YAML or JSON)")]
+    pub file: PathBuf,
+
+    #[arg(long, help = "Report steps as NDJSON instead of TAP")]
+    pub json: bool,
+
+    #[arg(long, value_name = "RATE", value_parser = parse_input_rate, help = "Pace input to the command at RATE, e.g. 10cps or 90cpm")]
+    pub throttle_input: Option<Duration>,
+}
+
+#[derive(Args)]
+pub struct PlayArgs {
+    #[arg(help = "Recording to replay (.cast)")]
+    pub file: PathBuf,
+
+    #[arg(long, default_value = "1.0", value_parser = parse_positive, help = "Playback speed multiplier")]
+    pub speed: f64,
+
+    #[arg(long, value_name = "SECS", value_parser = parse_positive, help = "Cap pauses between events at this many seconds")]
+    pub idle_limit: Option<f64>,
+}
+
+#[derive(Args)]
+pub struct ConvertArgs {
+    #[arg(help = "Recording to convert")]
+    pub input: PathBuf,
+
+    #[arg(short, long, help = "Output file (default: stdout)")]
+    pub output: Option<PathBuf>,
+
+    #[arg(long, value_enum, help = "Input format (default: from the file extension)")]
+    pub from: Option<RecordingFormat>,
+
+    #[arg(long, value_enum, help = "Output format (default: from the output extension, else frames)")]
+    pub to: Option<RecordingFormat>,
+
+    #[arg(long, value_enum, default_value = "raw", help = "Token processing applied to the output")]
+    pub token_mode: TokenMode,
+
+    #[arg(long, help = "Window columns when the input does not record them")]
+    pub cols: Option<u16>,
+
+    #[arg(long, help = "Window rows when the input does not record them")]
+    pub rows: Option<u16>,
+
+    #[command(flatten)]
+    pub pii: PiiArgs,
+
+    #[command(flatten)]
+    pub redact: RedactArgs,
+}
+
+#[derive(Args)]
+pub struct TranscriptArgs {
+    #[arg(help = "Recording or frame log to transcribe")]
+    pub input: PathBuf,
+
+    #[arg(short, long, help = "Output file (default: stdout)")]
+    pub output: Option<PathBuf>,
+
+    #[arg(long, value_enum, help = "Input format (default: from the file extension)")]
+    pub from: Option<RecordingFormat>,
+
+    #[arg(long, value_enum, default_value = "parsed", help = "Token processing applied first: compact or parsed")]
+    pub token_mode: TokenMode,
+
+    #[arg(long, help = "Window columns when the input does not record them")]
+    pub cols: Option<u16>,
+
+    #[arg(long, help = "Window rows when the input does not record them")]
+    pub rows: Option<u16>,
+
+    #[command(flatten)]
+    pub pii: PiiArgs,
+
+    #[command(flatten)]
+    pub redact: RedactArgs,
+}
+
+#[derive(Args)]
+pub struct CpArgs {
+    #[arg(help = "Source: a local file or SOCKET:PATH in a session")]
+    pub source: String,
+
+    #[arg(help = "Destination: a local file or SOCKET:PATH in a session")]
+    pub dest: String,
+
+    #[arg(long, help = "Controller name to authenticate as")]
+    pub client: Option<String>,
+
+    #[arg(long, env = "SPECTERTTY_TOKEN", hide_env_values = true, help = "Token for --client")]
+    pub token: Option<String>,
+
+    #[arg(long, default_value = "60s", value_parser = parse_duration, help = "Give up if the copy takes longer")]
+    pub timeout: Duration,
+
+    #[arg(long, value_enum, default_value = "json", help = "Frame encoding of the session's socket (its --format)")]
+    pub format: FrameFormat,
+}
+
+#[derive(Args)]
+pub struct AttachArgs {
+    #[arg(help = "The session's --socket, or its --name or ID")]
+    pub socket: PathBuf,
+
+    #[arg(long, help = "Controller name to authenticate as")]
+    pub client: Option<String>,
+
+    #[arg(long, env = "SPECTERTTY_TOKEN", hide_env_values = true, help = "Token for --client")]
+    pub token: Option<String>,
+
+    #[arg(long, help = "Resize the session to follow this terminal")]
+    pub resize: bool,
+
+    #[arg(long, value_enum, default_value = "json", help = "Frame encoding of the session's socket (its --format)")]
+    pub format: FrameFormat,
+}
+
+#[derive(Args)]
+pub struct ShadowArgs {
+    #[arg(help = "Command to run (default: $SHELL)")]
+    pub command: Option<String>,
 
     #[arg(help = "Arguments for the command")]
     pub args: Vec<String>,
 }
 
+#[derive(Args)]
+pub struct ListArgs {
+    #[arg(long, help = "Print one JSON object per session")]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct KillArgs {
+    #[arg(help = "Name or ID of the session")]
+    pub session: String,
+
+    #[arg(long, default_value = "TERM", help = "Signal to send to the session's spectertty process")]
+    pub signal: String,
+}
+
+#[derive(Args)]
+pub struct PrettyArgs {
+    #[arg(help = "Frame log to print (default: stdin)")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SchemaArgs {
+    #[arg(short, long, help = "Output file (default: stdout)")]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SearchArgs {
+    #[arg(help = "Text to find in output, ignoring case")]
+    pub query: String,
+
+    #[arg(long, env = "SPECTERTTY_STATE_DIR", help = "State directory the sessions journaled to")]
+    pub state_dir: PathBuf,
+
+    #[arg(long, value_name = "SOURCE", env = "SPECTERTTY_STATE_KEY", help = "Key for encrypted journals: env:VAR, file:PATH, keyring:SERVICE/USER or cmd:COMMAND")]
+    pub state_key: Option<String>,
+
+    #[arg(long, value_name = "AGE", value_parser = parse_duration, help = "Only output from the last AGE, e.g. 30m or 2d")]
+    pub since: Option<Duration>,
+
+    #[arg(long, help = "Stop after this many matches")]
+    pub limit: Option<usize>,
+
+    #[arg(long, help = "Print matches as NDJSON")]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct ExportStateArgs {
+    #[arg(help = "Name or ID of the session")]
+    pub session: String,
+
+    #[arg(long, env = "SPECTERTTY_STATE_DIR", help = "State directory the session journals to")]
+    pub state_dir: PathBuf,
+
+    #[arg(long, value_name = "SOURCE", env = "SPECTERTTY_STATE_KEY", help = "Key of an encrypted journal: env:VAR, file:PATH, keyring:SERVICE/USER or cmd:COMMAND")]
+    pub state_key: Option<String>,
+
+    #[arg(long, short, value_name = "FILE", help = "Write the bundle here instead of stdout")]
+    pub output: Option<PathBuf>,
+
+    #[arg(long, help = "Include a CRIU image of the running session, stopping it here (needs criu and root)")]
+    pub criu: bool,
+}
+
+#[derive(Args)]
+pub struct ImportStateArgs {
+    #[arg(help = "Bundle from export-state (default: stdin)")]
+    pub bundle: Option<PathBuf>,
+
+    #[arg(long, env = "SPECTERTTY_STATE_DIR", help = "State directory to add the session to")]
+    pub state_dir: PathBuf,
+
+    #[arg(long, value_name = "SOURCE", env = "SPECTERTTY_STATE_KEY", help = "Key of an encrypted journal: env:VAR, file:PATH, keyring:SERVICE/USER or cmd:COMMAND")]
+    pub state_key: Option<String>,
+
+    #[arg(long, help = "Restore the session's process from the bundle's CRIU image (needs criu and root)")]
+    pub criu: bool,
+}
+
+#[derive(Args)]
+pub struct FlakeArgs {
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u32).range(2..), help = "How many times to run")]
+    pub runs: u32,
+
+    #[arg(long, value_name = "FILE", conflicts_with = "command", help = "Play this script (as for `spectertty script`) on every run")]
+    pub script: Option<PathBuf>,
+
+    #[arg(long, value_name = "REGEX", help = "Mask text matching REGEX before comparing runs (repeatable)")]
+    pub normalize: Vec<String>,
+
+    #[arg(long, help = "Compare output as printed, without masking times, IDs and durations")]
+    pub exact: bool,
+
+    #[arg(long, default_value = "60s", value_parser = parse_duration, help = "Stop a run that takes longer")]
+    pub timeout: Duration,
+
+    #[arg(long, help = "Report as JSON instead of text")]
+    pub json: bool,
+
+    #[arg(required_unless_present = "script", trailing_var_arg = true, help = "Command to run, after --")]
+    pub command: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct PiiArgs {
+    #[arg(long, help = "Mask emails, phone numbers, IBANs and IP addresses in every frame")]
+    pub pii_scrub: bool,
+
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "intl,us", help = "Phone number formats to detect with --pii-scrub")]
+    pub pii_locale: Vec<PhoneLocale>,
+}
+
+#[derive(Args)]
+pub struct RedactArgs {
+    #[arg(long, value_name = "PATTERN", help = "Mask matches of this regex in output and input (repeatable)")]
+    pub redact: Vec<String>,
+
+    #[arg(long, help = "Mask AWS keys, bearer tokens and private key blocks in output and input")]
+    pub redact_secrets: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PhoneLocale {
+    /// Numbers with a +country prefix
+    Intl,
+    /// North American numbers, e.g. (555) 123-4567
+    Us,
+    /// UK national numbers, e.g. 020 7946 0958
+    Uk,
+    /// German national numbers, e.g. 030 1234567
+    De,
+}
+
 #[derive(Clone, Copy, ValueEnum)]
+pub enum RecordingFormat {
+    /// asciicast v2 (.cast)
+    Cast,
+    /// asciicast v3, with interval timing and exit events
+    CastV3,
+    /// Frame log, as emitted by --json in any --format (.ndjson, .jsonl, .msgpack, .cbor)
+    Frames,
+    /// ttyrec (.ttyrec, .tty)
+    Ttyrec,
+    /// script(1) typescript, with its timing in FILE.timing
+    Raw,
+}
+
+/// How spectertty's own log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// Readable lines
+    Text,
+    /// One JSON object per line, with the session's ID, PID and command
+    Json,
+}
+
+/// Encoding of frame streams written to stdout and the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum FrameFormat {
+    /// Newline-delimited JSON
+    #[default]
+    Json,
+    /// Concatenated MessagePack maps
+    Msgpack,
+    /// A CBOR sequence of maps
+    Cbor,
+}
+
+/// Timestamps on frames. `ts` stays wall-clock time in recordings, the
+/// journal and logs, which need a date; the choice is about the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum TsFormat {
+    /// `ts` in Unix seconds, as the wall clock reads
+    #[default]
+    Wall,
+    /// `elapsed_ms`, with `ts` on stdout and the socket in seconds since
+    /// the session started, both on the monotonic clock
+    Elapsed,
+    /// Wall-clock `ts` and `elapsed_ms`
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum TokenMode {
     Raw,
     Compact,
     Parsed,
 }
 
+impl TokenMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TokenMode::Raw => "raw",
+            TokenMode::Compact => "compact",
+            TokenMode::Parsed => "parsed",
+        }
+    }
+}
+
+/// Situations that run --notify-cmd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum NotifyEvent {
+    /// The command rang the terminal bell
+    Bell,
+    /// Output matched a --notify-regex
+    Failure,
+    /// Input is held for --two-person approval
+    Approval,
+    /// The command exited
+    Exit,
+}
+
+impl NotifyEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotifyEvent::Bell => "bell",
+            NotifyEvent::Failure => "failure",
+            NotifyEvent::Approval => "approval",
+            NotifyEvent::Exit => "exit",
+        }
+    }
+}
+
+/// Which processes the command left running are terminated at the end.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum KillChildren {
+    /// Leave them running; they are still reported
+    None,
+    /// The command's own process group, but not jobs a shell moved into
+    /// groups of their own
+    Group,
+    /// Everything in the command's terminal session
+    Session,
+}
+
+impl KillChildren {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KillChildren::None => "none",
+            KillChildren::Group => "group",
+            KillChildren::Session => "session",
+        }
+    }
+}
+
+/// Linux scheduling class for the command.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum SchedPolicy {
+    /// The default time-sharing class
+    Other,
+    /// Time-sharing for non-interactive, CPU-bound work
+    Batch,
+    /// Only runs when nothing else wants the CPU
+    Idle,
+}
+
+/// Linux IO scheduling class for the command.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum IoClass {
+    /// Best-effort at the lowest priority
+    BestEffort,
+    /// Only gets disk time when nothing else wants it
+    Idle,
+}
+
 #[derive(Clone, Copy, ValueEnum)]
 pub enum CompressionMode {
     None,
@@ -81,6 +803,104 @@ impl Cli {
         Duration::from_millis(self.idle)
     }
 
+    /// Environment for the command: env files in order, then `--env`.
+    pub fn command_env(&self) -> anyhow::Result<CommandEnv> {
+        let mut vars = Vec::new();
+        for path in &self.env_file {
+            vars.extend(crate::env::load_env_file(path)?);
+        }
+        vars.extend(self.env.iter().cloned());
+        Ok(CommandEnv {
+            clear: self.clear_env,
+            vars,
+            cwd: self.cwd.clone(),
+        })
+    }
+
+    /// CPUs, scheduling class and IO priority for the command.
+    pub fn scheduling(&self) -> anyhow::Result<Scheduling> {
+        let cpus = match self.cpuset {
+            Some(ref spec) => parse_cpuset(spec).map_err(|e| anyhow::anyhow!("Invalid --cpuset: {}", e))?,
+            None => Vec::new(),
+        };
+        Ok(Scheduling {
+            cpus,
+            policy: self.sched,
+            io_class: self.ionice,
+            cgroup: None,
+        })
+    }
+
+    /// Every prompt pattern, from --prompt-regex and then --prompt, for
+    /// matching prompts without regard to their names.
+    pub fn prompt_patterns(&self) -> Vec<String> {
+        let named = self.prompt.iter().map(|(_, pattern)| pattern.clone());
+        self.prompt_regex.iter().cloned().chain(named).collect()
+    }
+
+    /// Terminal modes from --raw and --no-echo.
+    pub fn termios(&self) -> TermiosChange {
+        TermiosChange {
+            raw: self.raw.then_some(true),
+            echo: self.no_echo.then_some(false),
+            ..Default::default()
+        }
+    }
+
+    pub fn io_limits(&self) -> IoLimits {
+        IoLimits {
+            rbps: self.limit_io_rbps,
+            wbps: self.limit_io_wbps,
+        }
+    }
+
+    /// Frame routing from --route and --emit.
+    pub fn routes(&self) -> anyhow::Result<crate::routing::RoutingTable> {
+        let routes = crate::routing::RoutingTable::parse(&self.route)?;
+        match self.emit {
+            Some(ref types) => routes.with_emit(types),
+            None => Ok(routes),
+        }
+    }
+
+    /// Socket keepalive, if --keepalive is on.
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive.map(|interval| Keepalive {
+            interval,
+            timeout: self.keepalive_timeout.unwrap_or(interval * 3),
+        })
+    }
+
+    /// The warm pool --grpc and --http keep, if --pool-size asks for one.
+    /// The window size is the default a client gets when it names none.
+    pub fn pool(&self) -> Option<crate::hub::PoolSpec> {
+        let mut words = self.pool_command.split_whitespace().map(str::to_string);
+        let command = words.next()?;
+        (self.pool_size > 0).then(|| crate::hub::PoolSpec {
+            template: crate::hub::SessionSpec {
+                command,
+                args: words.collect(),
+                env: Default::default(),
+                cwd: None,
+                cols: self.cols,
+                rows: self.rows,
+            },
+            size: self.pool_size,
+        })
+    }
+
+    /// How the --record file is split into segments, if at all.
+    pub fn record_rotation(&self) -> Option<Rotation> {
+        self.record_max_size.map(|max_size| Rotation {
+            max_size,
+            keep: self.record_rotate,
+        })
+    }
+
+    pub fn health_interval(&self) -> Option<Duration> {
+        self.health_interval.map(Duration::from_millis)
+    }
+
     pub fn overflow_timeout(&self) -> Duration {
         Duration::from_millis(self.overflow_timeout)
     }
@@ -98,6 +918,84 @@ impl Cli {
             return Err(anyhow::anyhow!("Buffer size must be greater than 0"));
         }
 
+        if self.anomaly_spike_factor <= 1.0 {
+            return Err(anyhow::anyhow!("Anomaly spike factor must be greater than 1"));
+        }
+
+        if !(0.0..=8.0).contains(&self.anomaly_entropy) {
+            return Err(anyhow::anyhow!("Anomaly entropy must be between 0 and 8 bits per byte"));
+        }
+
+        if self.max_sessions == 0 {
+            return Err(anyhow::anyhow!("--max-sessions must be greater than 0"));
+        }
+
+        if self.pool_size > 0 && self.grpc.is_none() && self.http.is_none() {
+            return Err(anyhow::anyhow!("--pool-size needs --grpc or --http"));
+        }
+
+        if self.health_interval == Some(0) {
+            return Err(anyhow::anyhow!("Health interval must be greater than 0"));
+        }
+
+        self.scheduling()?;
+
+        if self.ndjson_strict && self.format != FrameFormat::Json {
+            return Err(anyhow::anyhow!("--ndjson-strict needs --format json"));
+        }
+
+        if self.preserve_color && !matches!(self.token_mode, TokenMode::Compact) {
+            return Err(anyhow::anyhow!("--preserve-color needs --token-mode compact"));
+        }
+
+        if self.dedupe_lines && !matches!(self.token_mode, TokenMode::Compact) {
+            return Err(anyhow::anyhow!("--dedupe-lines needs --token-mode compact"));
+        }
+
+        if self.limit_io_rbps == Some(0) || self.limit_io_wbps == Some(0) {
+            return Err(anyhow::anyhow!("IO limits must be greater than 0"));
+        }
+
+        if self.two_person {
+            if self.socket.is_none() {
+                return Err(anyhow::anyhow!("--two-person requires --socket for approving clients"));
+            }
+            if self.auth_token.len() < 2 {
+                return Err(anyhow::anyhow!("--two-person requires at least two --auth-token credentials"));
+            }
+        }
+
+        self.routes()?;
+
+        if let Some(ref id) = self.session_id {
+            crate::ids::validate_session_id(id)?;
+        }
+        if let Some(ref name) = self.name {
+            crate::ids::validate_session_id(name).map_err(|_| {
+                anyhow::anyhow!("Invalid --name '{}': use up to 128 letters, digits, '-', '_' or '.'", name)
+            })?;
+        }
+
+        for pattern in &self.destructive_regex {
+            regex::Regex::new(pattern)
+                .map_err(|e| anyhow::anyhow!("Invalid destructive regex '{}': {}", pattern, e))?;
+        }
+
+        if matches!(self.record_format, RecordingFormat::Frames) {
+            return Err(anyhow::anyhow!("--record-format must be cast, cast-v3, ttyrec or raw"));
+        }
+
+        if self.record_max_size == Some(0) || self.record_rotate == Some(0) {
+            return Err(anyhow::anyhow!("--record-max-size and --record-rotate must be greater than 0"));
+        }
+
+        if let Some(ref url) = self.record_url {
+            RecordUrl::parse(url)?;
+            if !matches!(self.record_format, RecordingFormat::Cast | RecordingFormat::CastV3) {
+                return Err(anyhow::anyhow!("--record-url streams asciicast, so --record-format must be cast or cast-v3"));
+            }
+        }
+
         // Validate prompt regexes
         for pattern in &self.prompt_regex {
             regex::Regex::new(pattern)
@@ -106,4 +1004,118 @@ impl Cli {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Parse a finite number greater than zero, e.g. a speed multiplier.
+fn parse_positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() && number > 0.0 => Ok(number),
+        _ => Err(format!("'{}' must be a number greater than 0", value)),
+    }
+}
+
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    crate::env::parse_assignment(value).map_err(|e| e.to_string())
+}
+
+fn parse_meta(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", value)),
+    }
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME:VALUE, got '{}'", value))?;
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_".contains(&byte)) {
+        return Err(format!("invalid header name '{}'", name));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err("header values cannot span lines".to_string());
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parse an address to listen on; a bare port listens on localhost only.
+fn parse_listen_addr(value: &str) -> Result<SocketAddr, String> {
+    let value = value.trim();
+    if let Ok(port) = value.parse::<u16>() {
+        return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+    value
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("invalid address '{}', expected PORT or HOST:PORT", value))
+}
+
+/// Parse a duration with an ms/s/m/h/d suffix; bare numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration '{}', expected e.g. 500ms, 90s, 15m or 2h", value))?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(count),
+        "" | "s" => Duration::from_secs(count),
+        "m" => Duration::from_secs(count.saturating_mul(60)),
+        "h" => Duration::from_secs(count.saturating_mul(60 * 60)),
+        "d" => Duration::from_secs(count.saturating_mul(24 * 60 * 60)),
+        _ => return Err(format!("invalid duration unit in '{}', expected ms, s, m, h or d", value)),
+    };
+    if duration.is_zero() {
+        return Err(format!("duration '{}' must be greater than 0", value));
+    }
+    Ok(duration)
+}
+
+/// Parse an input rate such as `10cps` or `90cpm` into the pause between
+/// characters.
+pub fn parse_input_rate(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("invalid input rate '{}', expected e.g. 10cps or 90cpm", value);
+    let (count, per) = if let Some(count) = value.strip_suffix("cps") {
+        (count, 1.0)
+    } else if let Some(count) = value.strip_suffix("cpm") {
+        (count, 60.0)
+    } else {
+        return Err(invalid());
+    };
+    match count.trim().parse::<f64>() {
+        Ok(count) if count.is_finite() && count > 0.0 => {
+            Duration::try_from_secs_f64(per / count).map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_write_rate(value: &str) -> Result<u64, String> {
+    match parse_byte_size(value)? {
+        0 => Err(format!("write rate '{}' must be greater than 0", value)),
+        rate => Ok(rate),
+    }
+}
+
+/// Parse a byte count with an optional K/M/G (binary) suffix.
+pub fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1024),
+        Some((i, 'm' | 'M')) => (&value[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}', expected e.g. 4096, 64K, 512M or 2G", value))?;
+    count
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
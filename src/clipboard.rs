@@ -0,0 +1,112 @@
+use base64::prelude::*;
+
+const OSC52: &str = "\x1b]52;";
+
+/// Longest unterminated sequence held while waiting for the rest of it.
+const MAX_SEQUENCE_BYTES: usize = 8 * 1024 * 1024;
+
+/// A clipboard request the command made through OSC 52.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClipboardRequest {
+    /// The command copied text
+    Set(String),
+    /// The command asked for the clipboard, e.g. to paste
+    Query,
+}
+
+/// Session clipboard shared between the command and controllers.
+///
+/// Text the command copies with OSC 52 is captured from its output, and
+/// OSC 52 queries are answered with the current contents, the way a
+/// terminal with clipboard access would. Selections (`c`, `p`, ...) are
+/// not told apart.
+#[derive(Debug, Default)]
+pub struct Clipboard {
+    contents: Option<String>,
+    /// Start of a sequence split across reads
+    pending: String,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> Option<&str> {
+        self.contents.as_deref()
+    }
+
+    pub fn set(&mut self, text: impl Into<String>) {
+        self.contents = Some(text.into());
+    }
+
+    /// Scan command output for OSC 52 sequences, updating the contents
+    /// for each copy.
+    pub fn observe(&mut self, data: &str) -> Vec<ClipboardRequest> {
+        if self.pending.is_empty() && !data.contains('\x1b') {
+            return Vec::new();
+        }
+        let mut text = std::mem::take(&mut self.pending);
+        text.push_str(data);
+
+        let mut requests = Vec::new();
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find(OSC52) {
+            let body = &rest[start + OSC52.len()..];
+            let Some((end, terminator)) = find_terminator(body) else {
+                if rest.len() - start <= MAX_SEQUENCE_BYTES {
+                    self.pending = rest[start..].to_string();
+                }
+                return requests;
+            };
+            // A sequence cut short by another escape is ignored
+            let request = match terminator {
+                0 => None,
+                _ => parse(&body[..end]),
+            };
+            if let Some(request) = request {
+                if let ClipboardRequest::Set(ref text) = request {
+                    self.contents = Some(text.clone());
+                }
+                requests.push(request);
+            }
+            rest = &body[end + terminator..];
+        }
+
+        // Keep a trailing partial introducer for the next read
+        for len in (1..OSC52.len()).rev() {
+            if rest.ends_with(&OSC52[..len]) {
+                self.pending = OSC52[..len].to_string();
+                break;
+            }
+        }
+        requests
+    }
+
+    /// OSC 52 reply carrying the contents, for answering a query.
+    pub fn reply(&self) -> String {
+        let encoded = BASE64_STANDARD.encode(self.contents.as_deref().unwrap_or_default());
+        format!("{}c;{}\x07", OSC52, encoded)
+    }
+}
+
+/// Offset and length of the BEL or ST ending a sequence body; a length
+/// of 0 marks an ESC that is not ST, which aborts the sequence.
+fn find_terminator(body: &str) -> Option<(usize, usize)> {
+    let end = body.find(['\x07', '\x1b'])?;
+    match &body[end..] {
+        "\x1b" => None,
+        tail if tail.starts_with('\x07') => Some((end, 1)),
+        tail if tail.starts_with("\x1b\\") => Some((end, 2)),
+        _ => Some((end, 0)),
+    }
+}
+
+fn parse(body: &str) -> Option<ClipboardRequest> {
+    let (_selection, payload) = body.split_once(';')?;
+    if payload == "?" {
+        return Some(ClipboardRequest::Query);
+    }
+    let bytes = BASE64_STANDARD.decode(payload).ok()?;
+    Some(ClipboardRequest::Set(String::from_utf8_lossy(&bytes).into_owned()))
+}
@@ -0,0 +1,106 @@
+use crate::cli::TokenMode;
+use crate::error::SessionError;
+use crate::registers::Via;
+use crate::termios::TermiosChange;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// Commands accepted from controllers as NDJSON, one object per line.
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlCommand {
+    Auth { client: String, token: String },
+    Input { data: String },
+    /// Multi-line input sent as one bracketed paste, or line by line
+    Paste { data: String, line_delay_ms: Option<u64> },
+    /// Named keys, chords and text, typed a keystroke at a time when
+    /// given a delay
+    SendKeys {
+        keys: Vec<String>,
+        delay_ms: Option<u64>,
+        jitter_ms: Option<u64>,
+    },
+    /// A password or the like, written with echo off and never shown
+    SendSecret { data: String },
+    /// End the command's input, as Ctrl-D at the start of a line does
+    Eof,
+    Resize { cols: u16, rows: u16 },
+    /// Echo, raw mode and other flags of the command's terminal; with
+    /// none given, only report them
+    SetTermios(TermiosChange),
+    Approve { id: String },
+    Deny { id: String },
+    Expect {
+        regex: String,
+        timeout_ms: Option<u64>,
+        id: Option<String>,
+    },
+    /// Answer output matching `regex` with `response` from now on
+    AutoRespond {
+        regex: String,
+        response: String,
+        limit: Option<u32>,
+        id: Option<String>,
+    },
+    /// Abandon the pending `expect`, auto-responder or held input
+    /// registered under `id`
+    Cancel { id: String },
+    SetMode { mode: TokenMode },
+    Signal { name: String },
+    ClipboardSet { data: String },
+    ClipboardGet,
+    ResumeFrom { seq: u64 },
+    /// Ask for a `pong` on this connection, to check it is still served
+    Ping { id: Option<String> },
+    /// Answer to a keepalive `ping` frame
+    Pong { id: Option<String> },
+    Subscribe {
+        sample_rate: Option<f64>,
+        #[serde(default)]
+        summary_only: bool,
+    },
+    RegisterPut { name: String, data: String },
+    SendRegister {
+        name: String,
+        via: Option<Via>,
+        command: Option<String>,
+    },
+}
+
+impl ControlCommand {
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Identity of the controller a command came from.
+#[derive(Debug, Clone)]
+pub struct ClientId {
+    pub conn: u64,
+    pub name: Option<String>,
+}
+
+impl ClientId {
+    pub fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("conn-{}", self.conn),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ControlMessage {
+    pub client: ClientId,
+    pub command: ControlCommand,
+}
+
+/// What a transport delivers to the session loop.
+#[derive(Debug)]
+pub enum ControlEvent {
+    Command(ControlMessage),
+    /// A line the transport refused, reported back as an `error` frame
+    Rejected { client: ClientId, error: SessionError },
+    /// A socket or ttyd client's connection closed
+    Disconnected(ClientId),
+}
@@ -0,0 +1,298 @@
+use crate::control::{ClientId, ControlCommand, ControlEvent, ControlMessage};
+use crate::error::SessionError;
+use crate::expect::DEFAULT_EXPECT_TIMEOUT;
+use crate::frame::{Frame, FrameType};
+use crate::integrity::ApprovalGate;
+use crate::keys;
+use crate::processor::OutputProcessor;
+use crate::registers::{self, Registers};
+use crate::session::Session;
+use crate::sink::FrameSinks;
+use anyhow::Result;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Applies controller commands to the running session.
+pub struct Controller {
+    gate: Option<ApprovalGate>,
+    registers: Registers,
+    /// Jitter for paced keystrokes
+    rng: fastrand::Rng,
+}
+
+impl Controller {
+    pub fn new(gate: Option<ApprovalGate>, seed: u64) -> Self {
+        Self {
+            gate,
+            registers: Registers::new(),
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        event: ControlEvent,
+        session: &mut Session,
+        processor: &mut OutputProcessor,
+        sinks: &mut FrameSinks,
+    ) -> Result<()> {
+        let ControlMessage { client, command } = match event {
+            ControlEvent::Command(message) => message,
+            ControlEvent::Rejected { client, error } => {
+                return sinks.emit(&error.to_frame().with_client(client.label()))
+            }
+            ControlEvent::Disconnected(client) => {
+                if let Some(ref mut gate) = self.gate {
+                    gate.forget(&client);
+                }
+                return Ok(());
+            }
+        };
+
+        match command {
+            ControlCommand::Input { data } => self.send_input(&client, vec![(Duration::ZERO, data)], session, sinks),
+            ControlCommand::SendKeys {
+                keys,
+                delay_ms: None,
+                jitter_ms: None,
+            } => {
+                let data = keys.iter().map(|key| keys::key_bytes(key)).collect();
+                self.send_input(&client, vec![(Duration::ZERO, data)], session, sinks)
+            }
+            ControlCommand::SendKeys {
+                keys,
+                delay_ms,
+                jitter_ms,
+            } => {
+                // Each keystroke waits the delay, give or take the jitter
+                let delay = delay_ms.unwrap_or(0);
+                let jitter = jitter_ms.unwrap_or(0);
+                let strokes = keys::keystrokes(&keys)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, stroke)| {
+                        let wobble = self.rng.u64(..=2 * jitter) as i64 - jitter as i64;
+                        let wait = if i == 0 { 0 } else { (delay as i64 + wobble).max(0) as u64 };
+                        (Duration::from_millis(wait), stroke)
+                    })
+                    .collect();
+                self.send_input(&client, strokes, session, sinks)
+            }
+            ControlCommand::Paste { data, line_delay_ms } => {
+                // Programs that bracket pastes take the text as typed rather
+                // than acting on each line; others get it a line at a time
+                if session.bracketed_paste() {
+                    let Some(paste) = registers::bracketed(&data) else {
+                        let error = SessionError::InvalidRequest(
+                            "paste contains the bracketed paste end marker".to_string(),
+                        );
+                        return report(sinks, &client, Err(error));
+                    };
+                    return self.send_input(&client, vec![(Duration::ZERO, paste)], session, sinks);
+                }
+                let delay = Duration::from_millis(line_delay_ms.unwrap_or(0));
+                let lines = data
+                    .split_inclusive('\n')
+                    .enumerate()
+                    .map(|(i, line)| (if i == 0 { Duration::ZERO } else { delay }, line.to_string()))
+                    .collect();
+                self.send_input(&client, lines, session, sinks)
+            }
+            ControlCommand::SendSecret { data } => {
+                // Held input is shown to the approvers, so a secret that
+                // needs approval is refused instead
+                if self.gate.as_ref().is_some_and(|gate| gate.requires_approval(&data)) {
+                    let refused = SessionError::InvalidRequest("secret input matches an approval pattern".to_string());
+                    return report(sinks, &client, Err(refused));
+                }
+                info!("{} sent a secret ({} bytes)", client.label(), data.len());
+                // Should the command print it, as typed without the Enter
+                sinks.add_secret(data.trim_end_matches(['\r', '\n']));
+                report(sinks, &client, session.send_secret(data))
+            }
+            ControlCommand::Eof => {
+                info!("{} ended the command's input", client.label());
+                report(sinks, &client, session.eof())
+            }
+            ControlCommand::Resize { cols, rows } => report(sinks, &client, session.resize(cols, rows)),
+            ControlCommand::SetTermios(change) => {
+                info!("{} changed terminal modes: {:?}", client.label(), change);
+                report(sinks, &client, session.set_termios(change))
+            }
+            ControlCommand::Signal { name } => {
+                info!("{} sent {}", client.label(), name);
+                report(sinks, &client, session.signal(&name))
+            }
+            ControlCommand::ClipboardSet { data } => {
+                info!("{} set the clipboard ({} bytes)", client.label(), data.len());
+                session.set_clipboard(data);
+                sinks.emit(
+                    &Frame::new(FrameType::Clipboard)
+                        .with_reason("set".to_string())
+                        .with_client(client.label()),
+                )
+            }
+            ControlCommand::ClipboardGet => {
+                let mut frame = Frame::new(FrameType::Clipboard)
+                    .with_reason("get".to_string())
+                    .with_client(client.label());
+                if let Some(text) = session.clipboard() {
+                    frame = frame.with_data(text.to_string());
+                }
+                sinks.emit(&frame)
+            }
+            ControlCommand::RegisterPut { name, data } => {
+                let bytes = data.len();
+                if let Err(e) = self.registers.put(&name, data) {
+                    return report(sinks, &client, Err(e));
+                }
+                info!("{} stored register {} ({} bytes)", client.label(), name, bytes);
+                sinks.emit(
+                    &Frame::new(FrameType::Register)
+                        .with_id(name)
+                        .with_reason("put".to_string())
+                        .with_value(bytes as f64)
+                        .with_client(client.label()),
+                )
+            }
+            ControlCommand::SendRegister { name, via, command } => {
+                let delivery = match self.registers.delivery(&name, via, command.as_deref()) {
+                    Ok(delivery) => delivery,
+                    Err(e) => return report(sinks, &client, Err(e)),
+                };
+                info!("{} sent register {} as {}", client.label(), name, delivery.via.name());
+                let bytes = self.registers.get(&name).map_or(0, str::len);
+                sinks.emit(
+                    &Frame::new(FrameType::Register)
+                        .with_id(name)
+                        .with_reason("sent".to_string())
+                        .with_mode(delivery.via.name().to_string())
+                        .with_value(bytes as f64)
+                        .with_client(client.label()),
+                )?;
+                let chunks = delivery.chunks.into_iter().map(|chunk| (Duration::ZERO, chunk)).collect();
+                self.send_input(&client, chunks, session, sinks)
+            }
+            ControlCommand::Approve { ref id } | ControlCommand::Deny { ref id } => {
+                let Some(ref mut gate) = self.gate else {
+                    let error = SessionError::Policy("two-person mode is disabled".to_string());
+                    return report(sinks, &client, Err(error));
+                };
+
+                let approve = matches!(command, ControlCommand::Approve { .. });
+                let outcome = gate.vote(&client, id, approve);
+                for frame in &outcome.frames {
+                    sinks.emit(frame)?;
+                }
+                match outcome.release {
+                    Some(data) => report(sinks, &client, session.send(data)),
+                    None => Ok(()),
+                }
+            }
+            ControlCommand::Expect {
+                regex,
+                timeout_ms,
+                id,
+            } => {
+                let timeout = timeout_ms.map_or(DEFAULT_EXPECT_TIMEOUT, Duration::from_millis);
+                session.expect(id, &regex, timeout);
+                Ok(())
+            }
+            ControlCommand::AutoRespond {
+                regex,
+                response,
+                limit,
+                id,
+            } => {
+                // Responses go out without anyone approving them
+                if self.gate.as_ref().is_some_and(|gate| gate.requires_approval(&response)) {
+                    let refused = SessionError::InvalidRequest("auto-response matches an approval pattern".to_string());
+                    return report(sinks, &client, Err(refused));
+                }
+                let added = session.auto_respond(id, &regex, response, limit);
+                if let Ok(ref id) = added {
+                    info!("{} added auto-responder {} for {}", client.label(), id, regex);
+                }
+                report(sinks, &client, added.map(drop))
+            }
+            ControlCommand::Cancel { id } => {
+                let mut cancelled = session.cancel(&id);
+                if let (None, Some(ref mut gate)) = (&cancelled, &mut self.gate) {
+                    cancelled = match gate.cancel(&client, &id) {
+                        Ok(frame) => frame,
+                        Err(e) => return report(sinks, &client, Err(e)),
+                    };
+                }
+                match cancelled {
+                    Some(frame) => {
+                        info!("{} cancelled {}", client.label(), id);
+                        sinks.emit(&frame.with_client(client.label()))
+                    }
+                    None => {
+                        let error = SessionError::InvalidRequest(format!("nothing pending under id {}", id));
+                        report(sinks, &client, Err(error))
+                    }
+                }
+            }
+            ControlCommand::SetMode { mode } => {
+                let previous = processor.mode();
+                for frame in processor.set_mode(mode) {
+                    sinks.emit(&frame)?;
+                }
+                info!("{} switched token mode from {} to {}", client.label(), previous.name(), mode.name());
+                sinks.emit(
+                    &Frame::new(FrameType::ModeChange)
+                        .with_mode(mode.name().to_string())
+                        .with_reason(format!("from {}", previous.name()))
+                        .with_client(client.label()),
+                )
+            }
+            // Resolved by the transport before dispatch
+            ControlCommand::Auth { .. }
+            | ControlCommand::ResumeFrom { .. }
+            | ControlCommand::Subscribe { .. }
+            | ControlCommand::Ping { .. }
+            | ControlCommand::Pong { .. } => Ok(()),
+        }
+    }
+
+    /// Send input in `chunks`, each after its delay, or hold all of it
+    /// when two-person mode flags it as destructive.
+    fn send_input(
+        &mut self,
+        client: &ClientId,
+        chunks: Vec<(Duration, String)>,
+        session: &mut Session,
+        sinks: &mut FrameSinks,
+    ) -> Result<()> {
+        if let Some(ref mut gate) = self.gate {
+            let data: String = chunks.iter().map(|(_, chunk)| chunk.as_str()).collect();
+            if let Some(frame) = gate.check(client, &data) {
+                info!("Holding input from {} for approval", client.label());
+                return sinks.emit(&frame);
+            }
+        }
+        for (delay, chunk) in chunks {
+            let sent = if delay.is_zero() {
+                session.send(chunk)
+            } else {
+                session.send_after(delay, chunk)
+            };
+            if let Err(e) = sent {
+                return report(sinks, client, Err(e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Surface a failed session operation to every sink as an `error` frame.
+fn report(sinks: &mut FrameSinks, client: &ClientId, result: Result<(), SessionError>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            warn!("Command from {} failed: {}", client.label(), e);
+            sinks.emit(&e.to_frame().with_client(client.label()))
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::asciicast::{self, EventClock, Header, Reader};
+use crate::cli::{ConvertArgs, FrameFormat, PiiArgs, RecordingFormat, RedactArgs, TokenMode};
+use crate::frame::{Frame, FrameType};
+use crate::processor::OutputProcessor;
+use crate::redact::SecretRedactor;
+use crate::scrub::PiiScrubber;
+use crate::{ttyrec, typescript};
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Window size assumed when the source does not record one.
+pub const DEFAULT_SIZE: (u16, u16) = (120, 40);
+
+impl RecordingFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "cast" => Some(RecordingFormat::Cast),
+            "ndjson" | "jsonl" | "json" | "msgpack" | "cbor" => Some(RecordingFormat::Frames),
+            "ttyrec" | "tty" => Some(RecordingFormat::Ttyrec),
+            _ => None,
+        }
+    }
+}
+
+/// Convert a recording between asciicast v2 or v3, frame logs, ttyrec and
+/// script(1) typescripts,
+/// optionally passing its output through the token processor, the secret
+/// redactor and the PII scrubber.
+pub async fn run(args: &ConvertArgs) -> Result<()> {
+    let to = match args.to {
+        Some(format) => format,
+        None => match args.output {
+            Some(ref path) => RecordingFormat::from_path(path)
+                .ok_or_else(|| anyhow!("Cannot tell the format of {:?}, pass --to", path))?,
+            None => RecordingFormat::Frames,
+        },
+    };
+
+    let (frames, recorded_size) = load(&args.input, args.from)?;
+    let (cols, rows) = recorded_size.unwrap_or(DEFAULT_SIZE);
+    let size = (args.cols.unwrap_or(cols), args.rows.unwrap_or(rows));
+    let mut processed = process(frames, args.token_mode, size).await?;
+    clean(&mut processed, &args.redact, &args.pii)?;
+
+    if let RecordingFormat::Raw = to {
+        let path = args
+            .output
+            .as_ref()
+            .ok_or_else(|| anyhow!("--to raw needs --output, as the timing is written beside it"))?;
+        return write_typescript(path, &processed, size);
+    }
+
+    let mut output: Box<dyn Write> = match args.output {
+        Some(ref path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {:?}", path))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    match to {
+        RecordingFormat::Cast => write_cast(&mut output, &processed, size, 2)?,
+        RecordingFormat::CastV3 => write_cast(&mut output, &processed, size, 3)?,
+        RecordingFormat::Frames => write_frames(&mut output, &processed)?,
+        RecordingFormat::Ttyrec => write_ttyrec(&mut output, &processed)?,
+        RecordingFormat::Raw => unreachable!("typescripts are written above"),
+    }
+    output.flush()?;
+    Ok(())
+}
+
+type Loaded = (Vec<Frame>, Option<(u16, u16)>);
+
+/// Read a recording in `from` format, or the one its extension names, as
+/// frames and the window size it records, if any.
+pub fn load(path: &Path, from: Option<RecordingFormat>) -> Result<Loaded> {
+    let from = from
+        .or_else(|| RecordingFormat::from_path(path))
+        .ok_or_else(|| anyhow!("Cannot tell the format of {:?}, pass --from", path))?;
+    let input = BufReader::new(File::open(path).with_context(|| format!("Failed to open {:?}", path))?);
+    match from {
+        RecordingFormat::Cast | RecordingFormat::CastV3 => read_cast(input),
+        RecordingFormat::Frames => read_frames(input),
+        RecordingFormat::Ttyrec => read_ttyrec(input),
+        RecordingFormat::Raw => read_typescript(input, path),
+    }
+}
+
+/// Pass recorded frames through the token processor for a window of
+/// `(cols, rows)`, keeping the time each was recorded at.
+pub async fn process(frames: Vec<Frame>, mode: TokenMode, (cols, rows): (u16, u16)) -> Result<Vec<Frame>> {
+    let mut processor = OutputProcessor::new(mode, cols, rows);
+    let mut processed = Vec::with_capacity(frames.len());
+    let mut last_ts = 0.0;
+    for frame in frames {
+        // The processor stamps what it emits with the current time; use
+        // the time the triggering output was recorded instead
+        last_ts = frame.ts;
+        let emitted = processor.process_frame(frame).await?;
+        processed.extend(emitted.into_iter().map(|frame| frame.with_timestamp(last_ts)));
+    }
+    processed.extend(
+        processor
+            .flush_buffer()
+            .into_iter()
+            .map(|frame| frame.with_timestamp(last_ts)),
+    );
+    Ok(processed)
+}
+
+/// Apply `--redact-secrets`/`--redact` and `--pii-scrub` to processed frames.
+pub fn clean(frames: &mut [Frame], redact: &RedactArgs, pii: &PiiArgs) -> Result<()> {
+    if let Some(mut redactor) = SecretRedactor::from_args(redact)? {
+        for frame in frames.iter_mut() {
+            if let Some(redacted) = redactor.redact_frame(frame) {
+                *frame = redacted;
+            }
+        }
+    }
+    if let Some(scrubber) = PiiScrubber::from_args(pii) {
+        for frame in frames.iter_mut() {
+            if let Some(scrubbed) = scrubber.scrub_frame(frame) {
+                *frame = scrubbed;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_cast(input: impl BufRead) -> Result<Loaded> {
+    let (header, events) = Reader::new(input)?;
+    let start = header.timestamp.unwrap_or(0) as f64;
+
+    let mut frames = Vec::new();
+    for event in events {
+        if let Some(frame) = asciicast::event_frame(&event?, start) {
+            frames.push(frame);
+        }
+    }
+    Ok((frames, Some((header.width, header.height))))
+}
+
+/// Read a frame log written with any `--format`, telling them apart by
+/// the first byte: a JSON object, a MessagePack map or a CBOR map.
+fn read_frames(mut input: impl BufRead) -> Result<Loaded> {
+    let format = input.fill_buf()?.first().map_or(FrameFormat::Json, |&first| FrameFormat::detect(first));
+
+    let mut frames = Vec::new();
+    if format == FrameFormat::Json {
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            frames.push(Frame::from_json(&line).with_context(|| format!("Invalid frame on line {}", i + 1))?);
+        }
+    } else {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let (frame, used) = Frame::decode(format, rest)
+                .with_context(|| format!("Invalid frame {}", frames.len() + 1))?
+                .ok_or_else(|| anyhow!("Frame log ends partway through frame {}", frames.len() + 1))?;
+            frames.push(frame);
+            rest = &rest[used..];
+        }
+    }
+
+    // The first resize describes the window the session started with
+    let size = frames.iter().find_map(|frame| match (&frame.frame_type, frame.cols, frame.rows) {
+        (FrameType::Resize, Some(cols), Some(rows)) => Some((cols, rows)),
+        _ => None,
+    });
+    Ok((frames, size))
+}
+
+fn read_ttyrec(input: impl BufRead) -> Result<Loaded> {
+    let frames = ttyrec::read_records(input)?
+        .into_iter()
+        .map(|record| {
+            Frame::new(FrameType::Stdout)
+                .with_data(String::from_utf8_lossy(&record.data).into_owned())
+                .with_timestamp(record.ts)
+        })
+        .collect();
+    Ok((frames, None))
+}
+
+fn read_typescript(input: impl BufRead, path: &Path) -> Result<Loaded> {
+    let timing_path = typescript::timing_path(path);
+    let timing = BufReader::new(
+        File::open(&timing_path).with_context(|| format!("Failed to open timing file {:?}", timing_path))?,
+    );
+    let frames = typescript::read_records(input, timing)?
+        .into_iter()
+        .map(|record| {
+            Frame::new(FrameType::Stdout)
+                .with_data(String::from_utf8_lossy(&record.data).into_owned())
+                .with_timestamp(record.ts)
+        })
+        .collect();
+    Ok((frames, None))
+}
+
+fn write_cast(output: &mut dyn Write, frames: &[Frame], (width, height): (u16, u16), version: u8) -> Result<()> {
+    let start = frames.first().map_or(0.0, |frame| frame.ts);
+    let header = Header {
+        version,
+        width,
+        height,
+        timestamp: Some(start as u64),
+        idle_time_limit: None,
+        title: None,
+        command: None,
+        env: None,
+        tags: Vec::new(),
+        meta: BTreeMap::new(),
+    };
+    writeln!(output, "{}", header.to_line()?)?;
+
+    let mut clock = EventClock::new(version);
+    for frame in frames {
+        if let Some((code, data)) = asciicast::frame_event(frame, version) {
+            let event = clock.event((frame.ts - start).max(0.0), code, data);
+            writeln!(output, "{}", serde_json::to_string(&event)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_frames(output: &mut dyn Write, frames: &[Frame]) -> Result<()> {
+    for frame in frames {
+        writeln!(output, "{}", frame.to_json()?)?;
+    }
+    Ok(())
+}
+
+fn write_ttyrec(output: &mut dyn Write, frames: &[Frame]) -> Result<()> {
+    for frame in frames {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            continue;
+        }
+        if let Some(data) = frame.bytes() {
+            ttyrec::write_record(output, frame.ts, &data)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_typescript(path: &Path, frames: &[Frame], (width, height): (u16, u16)) -> Result<()> {
+    let create = |path: &Path| -> Result<BufWriter<File>> {
+        Ok(BufWriter::new(
+            File::create(path).with_context(|| format!("Failed to create {:?}", path))?,
+        ))
+    };
+    let mut log = create(path)?;
+    let mut timing = create(&typescript::timing_path(path))?;
+    log.write_all(typescript::header(None, width, height).as_bytes())?;
+
+    let mut last_ts = frames.first().map_or(0.0, |frame| frame.ts);
+    let mut exit_code = None;
+    for frame in frames {
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                if let Some(data) = frame.bytes() {
+                    typescript::write_chunk(&mut log, &mut timing, frame.ts - last_ts, &data)?;
+                    last_ts = frame.ts;
+                }
+            }
+            FrameType::Exit => exit_code = frame.code,
+            _ => {}
+        }
+    }
+    log.write_all(typescript::trailer(exit_code).as_bytes())?;
+    log.flush()?;
+    timing.flush()?;
+    Ok(())
+}
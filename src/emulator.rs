@@ -0,0 +1,534 @@
+use serde::{Deserialize, Serialize};
+use vte::{Params, Parser, Perform};
+
+const TAB_WIDTH: usize = 8;
+
+/// One screen buffer: a character grid plus its cursor.
+#[derive(Clone)]
+struct Grid {
+    cells: Vec<Vec<char>>,
+    // Row ends in an automatic wrap onto the next row
+    wrapped: Vec<bool>,
+    cursor_row: usize,
+    cursor_col: usize,
+    saved_cursor: (usize, usize),
+}
+
+impl Grid {
+    fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cells: vec![vec![' '; cols]; rows],
+            wrapped: vec![false; rows],
+            cursor_row: 0,
+            cursor_col: 0,
+            saved_cursor: (0, 0),
+        }
+    }
+
+    fn resize(&mut self, cols: usize, rows: usize) {
+        for row in &mut self.cells {
+            row.resize(cols, ' ');
+        }
+        // Keep the rows nearest the cursor when shrinking
+        while self.cells.len() > rows {
+            if self.cursor_row > 0 {
+                self.cells.remove(0);
+                self.wrapped.remove(0);
+                self.cursor_row -= 1;
+            } else {
+                self.cells.pop();
+                self.wrapped.pop();
+            }
+        }
+        while self.cells.len() < rows {
+            self.cells.push(vec![' '; cols]);
+            self.wrapped.push(false);
+        }
+        self.cursor_row = self.cursor_row.min(rows - 1);
+        self.cursor_col = self.cursor_col.min(cols - 1);
+    }
+
+    fn clear_row(&mut self, row: usize, from: usize, to: usize) {
+        let line = &mut self.cells[row];
+        let to = to.min(line.len());
+        for cell in &mut line[from.min(to)..to] {
+            *cell = ' ';
+        }
+    }
+
+    fn row_text(&self, row: usize) -> String {
+        let text: String = self.cells[row].iter().collect();
+        text.trim_end().to_string()
+    }
+
+    /// Text of the logical line ending at `row`, joining soft-wrapped rows.
+    fn logical_line(&self, row: usize) -> String {
+        let mut start = row;
+        while start > 0 && self.wrapped[start - 1] {
+            start -= 1;
+        }
+        let mut text: String = self.cells[start..row]
+            .iter()
+            .flat_map(|line| line.iter())
+            .collect();
+        text.push_str(&self.row_text(row));
+        text
+    }
+
+    fn contents(&self) -> String {
+        self.rows_text().join("\n").trim_end().to_string()
+    }
+
+    fn rows_text(&self) -> Vec<String> {
+        (0..self.cells.len()).map(|row| self.row_text(row)).collect()
+    }
+
+    /// Fill the grid from saved row text, cutting what no longer fits.
+    fn load(&mut self, rows: &[String]) {
+        for (cells, text) in self.cells.iter_mut().zip(rows) {
+            for (cell, c) in cells.iter_mut().zip(text.chars()) {
+                *cell = c;
+            }
+        }
+    }
+}
+
+struct TerminalState {
+    cols: usize,
+    rows: usize,
+    primary: Grid,
+    alternate: Grid,
+    alt_active: bool,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    wrap_pending: bool,
+    committed: Vec<String>,
+}
+
+impl TerminalState {
+    fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cols,
+            rows,
+            primary: Grid::new(cols, rows),
+            alternate: Grid::new(cols, rows),
+            alt_active: false,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            wrap_pending: false,
+            committed: Vec::new(),
+        }
+    }
+
+    fn grid(&self) -> &Grid {
+        if self.alt_active {
+            &self.alternate
+        } else {
+            &self.primary
+        }
+    }
+
+    fn grid_mut(&mut self) -> &mut Grid {
+        if self.alt_active {
+            &mut self.alternate
+        } else {
+            &mut self.primary
+        }
+    }
+
+    fn resize(&mut self, cols: usize, rows: usize) {
+        self.cols = cols;
+        self.rows = rows;
+        self.primary.resize(cols, rows);
+        self.alternate.resize(cols, rows);
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.wrap_pending = false;
+    }
+
+    fn put_char(&mut self, c: char) {
+        if self.wrap_pending {
+            self.wrap_pending = false;
+            let row = self.grid().cursor_row;
+            self.grid_mut().wrapped[row] = true;
+            self.index();
+            self.grid_mut().cursor_col = 0;
+        }
+
+        let cols = self.cols;
+        let grid = self.grid_mut();
+        let (row, col) = (grid.cursor_row, grid.cursor_col);
+        grid.cells[row][col] = c;
+        if col + 1 >= cols {
+            self.wrap_pending = true;
+        } else {
+            grid.cursor_col += 1;
+        }
+    }
+
+    /// Hard line feed; on the primary screen this settles the line.
+    fn line_feed(&mut self) {
+        if !self.alt_active {
+            let line = self.primary.logical_line(self.primary.cursor_row);
+            self.committed.push(line);
+        }
+        self.wrap_pending = false;
+        self.index();
+    }
+
+    /// Move the cursor down one row, scrolling at the bottom margin.
+    fn index(&mut self) {
+        let (bottom, rows) = (self.scroll_bottom, self.rows);
+        let row = self.grid().cursor_row;
+        if row == bottom {
+            self.scroll_up(1);
+        } else if row + 1 < rows {
+            self.grid_mut().cursor_row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        let row = self.grid().cursor_row;
+        if row == self.scroll_top {
+            self.scroll_down(1);
+        } else if row > 0 {
+            self.grid_mut().cursor_row -= 1;
+        }
+    }
+
+    fn scroll_up(&mut self, count: usize) {
+        let (top, bottom, cols) = (self.scroll_top, self.scroll_bottom, self.cols);
+        let grid = self.grid_mut();
+        for _ in 0..count.min(bottom - top + 1) {
+            grid.cells.remove(top);
+            grid.wrapped.remove(top);
+            grid.cells.insert(bottom, vec![' '; cols]);
+            grid.wrapped.insert(bottom, false);
+        }
+    }
+
+    fn scroll_down(&mut self, count: usize) {
+        let (top, bottom, cols) = (self.scroll_top, self.scroll_bottom, self.cols);
+        let grid = self.grid_mut();
+        for _ in 0..count.min(bottom - top + 1) {
+            grid.cells.remove(bottom);
+            grid.wrapped.remove(bottom);
+            grid.cells.insert(top, vec![' '; cols]);
+            grid.wrapped.insert(top, false);
+        }
+    }
+
+    fn move_cursor(&mut self, row: usize, col: usize) {
+        let (rows, cols) = (self.rows, self.cols);
+        let grid = self.grid_mut();
+        grid.cursor_row = row.min(rows - 1);
+        grid.cursor_col = col.min(cols - 1);
+        self.wrap_pending = false;
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let (rows, cols) = (self.rows, self.cols);
+        let grid = self.grid_mut();
+        let (row, col) = (grid.cursor_row, grid.cursor_col);
+        match mode {
+            0 => {
+                grid.clear_row(row, col, cols);
+                (row + 1..rows).for_each(|r| grid.clear_row(r, 0, cols));
+            }
+            1 => {
+                (0..row).for_each(|r| grid.clear_row(r, 0, cols));
+                grid.clear_row(row, 0, col + 1);
+            }
+            _ => (0..rows).for_each(|r| grid.clear_row(r, 0, cols)),
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let cols = self.cols;
+        let grid = self.grid_mut();
+        let (row, col) = (grid.cursor_row, grid.cursor_col);
+        match mode {
+            0 => grid.clear_row(row, col, cols),
+            1 => grid.clear_row(row, 0, col + 1),
+            _ => grid.clear_row(row, 0, cols),
+        }
+    }
+
+    fn insert_lines(&mut self, count: usize) {
+        let row = self.grid().cursor_row;
+        if row < self.scroll_top || row > self.scroll_bottom {
+            return;
+        }
+        let top = self.scroll_top;
+        self.scroll_top = row;
+        self.scroll_down(count);
+        self.scroll_top = top;
+    }
+
+    fn delete_lines(&mut self, count: usize) {
+        let row = self.grid().cursor_row;
+        if row < self.scroll_top || row > self.scroll_bottom {
+            return;
+        }
+        let top = self.scroll_top;
+        self.scroll_top = row;
+        self.scroll_up(count);
+        self.scroll_top = top;
+    }
+
+    fn insert_chars(&mut self, count: usize) {
+        let grid = self.grid_mut();
+        let (row, col) = (grid.cursor_row, grid.cursor_col);
+        let line = &mut grid.cells[row];
+        for _ in 0..count.min(line.len() - col) {
+            line.pop();
+            line.insert(col, ' ');
+        }
+    }
+
+    fn delete_chars(&mut self, count: usize) {
+        let grid = self.grid_mut();
+        let (row, col) = (grid.cursor_row, grid.cursor_col);
+        let line = &mut grid.cells[row];
+        for _ in 0..count.min(line.len() - col) {
+            line.remove(col);
+            line.push(' ');
+        }
+    }
+
+    fn set_alternate_screen(&mut self, enable: bool, save_cursor: bool) {
+        if enable == self.alt_active {
+            return;
+        }
+        if enable {
+            if save_cursor {
+                self.primary.saved_cursor = (self.primary.cursor_row, self.primary.cursor_col);
+            }
+            self.alternate = Grid::new(self.cols, self.rows);
+        } else if save_cursor {
+            let (row, col) = self.primary.saved_cursor;
+            self.primary.cursor_row = row;
+            self.primary.cursor_col = col;
+        }
+        self.alt_active = enable;
+        self.wrap_pending = false;
+    }
+
+    fn save_cursor(&mut self) {
+        let grid = self.grid_mut();
+        grid.saved_cursor = (grid.cursor_row, grid.cursor_col);
+    }
+
+    fn restore_cursor(&mut self) {
+        let (row, col) = self.grid().saved_cursor;
+        self.move_cursor(row, col);
+    }
+}
+
+/// Parameter `index` of a CSI sequence, with 0/missing mapped to `default`.
+fn param(params: &Params, index: usize, default: u16) -> u16 {
+    match params.iter().nth(index).and_then(|p| p.first()) {
+        Some(&0) | None => default,
+        Some(&value) => value,
+    }
+}
+
+impl Perform for TerminalState {
+    fn print(&mut self, c: char) {
+        self.put_char(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            b'\n' | 0x0b | 0x0c => self.line_feed(),
+            b'\r' => {
+                self.grid_mut().cursor_col = 0;
+                self.wrap_pending = false;
+            }
+            0x08 => {
+                let grid = self.grid_mut();
+                grid.cursor_col = grid.cursor_col.saturating_sub(1);
+                self.wrap_pending = false;
+            }
+            b'\t' => {
+                let col = self.grid().cursor_col;
+                let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.grid_mut().cursor_col = next.min(self.cols - 1);
+            }
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            return;
+        }
+
+        let n = param(params, 0, 1) as usize;
+        let (row, col) = (self.grid().cursor_row, self.grid().cursor_col);
+
+        if intermediates == b"?" {
+            let enable = match action {
+                'h' => true,
+                'l' => false,
+                _ => return,
+            };
+            for mode in params.iter().filter_map(|p| p.first()) {
+                match mode {
+                    1049 => self.set_alternate_screen(enable, true),
+                    47 | 1047 => self.set_alternate_screen(enable, false),
+                    _ => {}
+                }
+            }
+            return;
+        }
+        if !intermediates.is_empty() {
+            return;
+        }
+
+        match action {
+            'A' => self.move_cursor(row.saturating_sub(n), col),
+            'B' | 'e' => self.move_cursor(row + n, col),
+            'C' | 'a' => self.move_cursor(row, col + n),
+            'D' => self.move_cursor(row, col.saturating_sub(n)),
+            'E' => self.move_cursor(row + n, 0),
+            'F' => self.move_cursor(row.saturating_sub(n), 0),
+            'G' | '`' => self.move_cursor(row, n - 1),
+            'd' => self.move_cursor(n - 1, col),
+            'H' | 'f' => {
+                let target_col = param(params, 1, 1) as usize;
+                self.move_cursor(n - 1, target_col - 1);
+            }
+            'J' => self.erase_display(param(params, 0, 0)),
+            'K' => self.erase_line(param(params, 0, 0)),
+            'L' => self.insert_lines(n),
+            'M' => self.delete_lines(n),
+            '@' => self.insert_chars(n),
+            'P' => self.delete_chars(n),
+            'X' => {
+                let cols = self.cols;
+                self.grid_mut().clear_row(row, col, (col + n).min(cols));
+            }
+            'S' => self.scroll_up(n),
+            'T' => self.scroll_down(n),
+            'r' => {
+                let top = param(params, 0, 1) as usize - 1;
+                let bottom = (param(params, 1, self.rows as u16) as usize).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.move_cursor(0, 0);
+                }
+            }
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if !intermediates.is_empty() {
+            return;
+        }
+        match byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.index(),
+            b'E' => {
+                self.line_feed();
+                self.grid_mut().cursor_col = 0;
+            }
+            b'M' => self.reverse_index(),
+            b'c' => {
+                let committed = std::mem::take(&mut self.committed);
+                *self = TerminalState::new(self.cols, self.rows);
+                self.committed = committed;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The text of an emulator's screens and its cursor: enough to repaint a
+/// terminal, without attributes or the parser's state mid-sequence.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScreenState {
+    pub cols: u16,
+    pub rows: u16,
+    /// Rows of the primary screen, trailing blanks trimmed
+    pub primary: Vec<String>,
+    /// Rows of the alternate screen, while a full-screen program has it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alternate: Option<Vec<String>>,
+    /// Row and column of the cursor on the screen in use
+    pub cursor: (u16, u16),
+}
+
+/// In-memory VT100/xterm screen model driven by a vte state machine.
+pub struct Emulator {
+    parser: Parser,
+    state: TerminalState,
+}
+
+impl Emulator {
+    pub fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            parser: Parser::new(),
+            state: TerminalState::new(cols.max(1) as usize, rows.max(1) as usize),
+        }
+    }
+
+    pub fn process(&mut self, data: &[u8]) {
+        self.parser.advance(&mut self.state, data);
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.state.resize(cols.max(1) as usize, rows.max(1) as usize);
+    }
+
+    /// Lines settled by a line feed on the primary screen since the last call.
+    pub fn take_committed_lines(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.state.committed)
+    }
+
+    /// The unfinished line under the cursor on the primary screen.
+    pub fn current_line(&self) -> String {
+        self.state.primary.logical_line(self.state.primary.cursor_row)
+    }
+
+    pub fn is_alternate_screen(&self) -> bool {
+        self.state.alt_active
+    }
+
+    pub fn screen_contents(&self) -> String {
+        self.state.grid().contents()
+    }
+
+    pub fn state(&self) -> ScreenState {
+        let state = &self.state;
+        let grid = state.grid();
+        ScreenState {
+            cols: state.cols as u16,
+            rows: state.rows as u16,
+            primary: state.primary.rows_text(),
+            alternate: state.alt_active.then(|| state.alternate.rows_text()),
+            cursor: (grid.cursor_row as u16, grid.cursor_col as u16),
+        }
+    }
+
+    /// An emulator showing a saved screen.
+    pub fn restore(saved: &ScreenState) -> Self {
+        let mut emulator = Self::new(saved.cols, saved.rows);
+        let state = &mut emulator.state;
+        state.primary.load(&saved.primary);
+        if let Some(ref rows) = saved.alternate {
+            state.alternate.load(rows);
+            state.alt_active = true;
+        }
+        let (cols, rows) = (state.cols, state.rows);
+        let grid = state.grid_mut();
+        grid.cursor_row = (saved.cursor.0 as usize).min(rows - 1);
+        grid.cursor_col = (saved.cursor.1 as usize).min(cols - 1);
+        emulator
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Context, Result};
+use portable_pty::CommandBuilder;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Environment and working directory the command is spawned with.
+#[derive(Debug, Clone, Default)]
+pub struct CommandEnv {
+    /// Start from an empty environment instead of inheriting ours
+    pub clear: bool,
+    /// Variables set on top, later entries winning
+    pub vars: Vec<(String, String)>,
+    /// Working directory (default: ours)
+    pub cwd: Option<PathBuf>,
+}
+
+impl CommandEnv {
+    /// The variables the command starts with: ours unless `clear`, then
+    /// `vars` on top.
+    pub fn resolved_vars(&self) -> BTreeMap<String, String> {
+        let mut env: BTreeMap<String, String> = if self.clear {
+            BTreeMap::new()
+        } else {
+            std::env::vars_os()
+                .map(|(key, value)| (key.to_string_lossy().into_owned(), value.to_string_lossy().into_owned()))
+                .collect()
+        };
+        env.extend(self.vars.iter().cloned());
+        env
+    }
+
+    /// Where the command starts, `cwd` or ours.
+    pub fn working_dir(&self) -> Option<PathBuf> {
+        self.cwd.clone().or_else(|| std::env::current_dir().ok())
+    }
+
+    pub(crate) fn apply(&self, cmd: &mut CommandBuilder) -> std::io::Result<()> {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for (key, value) in &self.vars {
+            cmd.env(key, value);
+        }
+
+        // portable-pty falls back to $HOME for a missing directory, so
+        // check it here rather than run the command somewhere unexpected
+        let cwd = match self.cwd {
+            Some(ref dir) => {
+                if !dir.is_dir() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("working directory {} does not exist", dir.display()),
+                    ));
+                }
+                dir.clone()
+            }
+            None => std::env::current_dir()?,
+        };
+        cmd.cwd(cwd);
+        Ok(())
+    }
+}
+
+/// Parse a `KEY=VALUE` assignment.
+pub fn parse_assignment(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE, got '{}'", s))?;
+    let valid = key
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if key.is_empty() || !valid {
+        return Err(anyhow!("invalid variable name '{}'", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Read a dotenv-style file: one `KEY=VALUE` per line, with blank lines,
+/// `#` comments and an optional `export ` prefix allowed. Values may be
+/// wrapped in single or double quotes, which are removed; nothing inside
+/// them is expanded.
+pub fn load_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read env file {}", path.display()))?;
+
+    let mut vars = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_assignment(line)
+            .with_context(|| format!("{}:{}", path.display(), n + 1))?;
+        vars.push((key, unquote(value.trim()).to_string()));
+    }
+    Ok(vars)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
@@ -0,0 +1,133 @@
+use crate::frame::{Frame, FrameType, PROTOCOL_VERSION};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tokio::time::Instant;
+
+/// What a `session_start` frame describes: the command as given, where
+/// and how it runs, and the spectertty and host running it.
+pub struct SessionInfo<'a> {
+    pub name: Option<&'a str>,
+    pub command: &'a str,
+    pub args: &'a [String],
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    pub cols: u16,
+    pub rows: u16,
+    /// What `--seed` replays this run
+    pub seed: u64,
+}
+
+impl SessionInfo<'_> {
+    pub fn frame(&self) -> Frame {
+        let mut host = serde_json::Map::new();
+        if let Some(name) = hostname() {
+            host.insert("hostname".to_string(), json!(name));
+        }
+        host.insert("os".to_string(), json!(std::env::consts::OS));
+        host.insert("arch".to_string(), json!(std::env::consts::ARCH));
+        if let Some(kernel) = read_proc("/proc/sys/kernel/osrelease") {
+            host.insert("kernel".to_string(), json!(kernel));
+        }
+        host.insert("pid".to_string(), json!(std::process::id()));
+
+        let mut info = json!({
+            "command": self.command,
+            "args": self.args,
+            "cwd": self.cwd.as_ref().map(|dir| dir.display().to_string()),
+            "env_hash": env_hash(&self.env),
+            "version": env!("CARGO_PKG_VERSION"),
+            "host": host,
+            "seed": self.seed,
+        });
+        if let Some(name) = self.name {
+            info["name"] = json!(name);
+        }
+        let mut frame = Frame::new(FrameType::SessionStart)
+            .with_size(self.cols, self.rows)
+            .with_structured(info);
+        frame.protocol_version = Some(PROTOCOL_VERSION);
+        frame
+    }
+}
+
+/// Fingerprint of an environment, so runs can be grouped by it without
+/// the values, which may be secret, leaving the host: FNV-1a over the
+/// sorted `KEY=VALUE` pairs.
+pub fn env_hash(env: &BTreeMap<String, String>) -> String {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = OFFSET;
+    for (key, value) in env {
+        for byte in key.bytes().chain([b'=']).chain(value.bytes()).chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    }
+    format!("fnv1a64:{:016x}", hash)
+}
+
+/// Running totals for the `session_end` summary, fed the session's frames
+/// as they come out of the PTY.
+pub struct SessionTally {
+    started: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    exit: Option<Frame>,
+}
+
+impl SessionTally {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            exit: None,
+        }
+    }
+
+    pub fn observe(&mut self, frame: &Frame) {
+        let size = || frame.bytes().map_or(0, |data| data.len() as u64);
+        match frame.frame_type {
+            FrameType::Stdin => self.bytes_in += size(),
+            FrameType::Stdout | FrameType::Stderr => self.bytes_out += size(),
+            FrameType::Exit => self.exit = Some(frame.clone()),
+            _ => {}
+        }
+    }
+
+    /// The summary; without an `exit` frame the command was still running
+    /// when spectertty stopped, and `code` is absent.
+    pub fn end_frame(&self) -> Frame {
+        let stats = BTreeMap::from([
+            ("bytes_in".to_string(), self.bytes_in),
+            ("bytes_out".to_string(), self.bytes_out),
+        ]);
+        let mut frame = Frame::new(FrameType::SessionEnd)
+            .with_duration(self.started.elapsed().as_millis() as u64)
+            .with_stats(stats);
+        if let Some(ref exit) = self.exit {
+            frame.code = exit.code;
+            frame.signal = exit.signal.clone();
+            frame.reason = exit.reason.clone();
+        }
+        frame
+    }
+}
+
+impl Default for SessionTally {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// This machine's name.
+pub fn hostname() -> Option<String> {
+    read_proc("/proc/sys/kernel/hostname")
+}
+
+fn read_proc(path: &str) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
@@ -0,0 +1,74 @@
+use crate::frame::{Frame, FrameType};
+use thiserror::Error;
+
+/// Session failures, reported to controllers as `error` frames carrying a
+/// category in `reason` and a stable numeric `code` for programmatic
+/// recovery.
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("failed to spawn command: {0}")]
+    SpawnFailed(String),
+    #[error("PTY I/O error: {0}")]
+    PtyIo(String),
+    #[error("transport error: {0}")]
+    Transport(String),
+    #[error("sandbox error: {0}")]
+    Sandbox(String),
+    #[error("policy violation: {0}")]
+    Policy(String),
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+}
+
+impl SessionError {
+    pub fn category(&self) -> &'static str {
+        match self {
+            SessionError::SpawnFailed(_) => "spawn_failed",
+            SessionError::PtyIo(_) => "pty_io",
+            SessionError::Transport(_) => "transport",
+            SessionError::Sandbox(_) => "sandbox",
+            SessionError::Policy(_) => "policy",
+            SessionError::Timeout(_) => "timeout",
+            SessionError::InvalidRequest(_) => "invalid_request",
+        }
+    }
+
+    /// Stable identifier for the category; never renumber these.
+    pub fn code(&self) -> i32 {
+        match self {
+            SessionError::SpawnFailed(_) => 1,
+            SessionError::PtyIo(_) => 2,
+            SessionError::Transport(_) => 3,
+            SessionError::Sandbox(_) => 4,
+            SessionError::Policy(_) => 5,
+            SessionError::Timeout(_) => 6,
+            SessionError::InvalidRequest(_) => 7,
+        }
+    }
+
+    /// Exit status to report when the session never started, following the
+    /// shell convention of 126 for "not executable" and 127 for "not found".
+    pub fn exit_status(&self) -> i32 {
+        match self {
+            // portable-pty only reports the errno in its message
+            SessionError::SpawnFailed(message) if message.contains("EACCES") => 126,
+            SessionError::SpawnFailed(_) => 127,
+            _ => 1,
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        Frame::new(FrameType::Error)
+            .with_exit_code(self.code())
+            .with_reason(self.category().to_string())
+            .with_data(self.to_string())
+    }
+}
+
+impl From<std::io::Error> for SessionError {
+    fn from(e: std::io::Error) -> Self {
+        SessionError::PtyIo(e.to_string())
+    }
+}
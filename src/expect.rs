@@ -0,0 +1,261 @@
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Timeout applied when an expect command does not give one.
+pub const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Unconsumed output kept for matching; older text is discarded.
+const MAX_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Longest escape sequence held back while waiting for the read that ends it.
+const MAX_SEQUENCE_BYTES: usize = 4096;
+
+struct PendingExpect {
+    id: Option<String>,
+    regex: Regex,
+    started: Instant,
+    deadline: Instant,
+}
+
+/// Expect-style waiters over the session output. Output accumulates in a
+/// shared buffer (ANSI escapes removed) and each match consumes the buffer
+/// up to the end of the matched text, so output that arrives before an
+/// expect is registered is still seen by it.
+pub struct ExpectManager {
+    buffer: String,
+    escapes: EscapeStripper,
+    pending: Vec<PendingExpect>,
+}
+
+impl Default for ExpectManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpectManager {
+    pub fn new() -> Self {
+        Self {
+            buffer: String::new(),
+            escapes: EscapeStripper::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Register a waiter, resolving it immediately if buffered output matches.
+    pub fn expect(&mut self, id: Option<String>, pattern: &str, timeout: Duration) -> Vec<Frame> {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                let mut frame = Frame::new(FrameType::ExpectTimeout)
+                    .with_regex(pattern.to_string())
+                    .with_reason(format!("invalid regex: {}", e));
+                if let Some(id) = id {
+                    frame = frame.with_id(id);
+                }
+                return vec![frame];
+            }
+        };
+
+        let started = Instant::now();
+        self.pending.push(PendingExpect {
+            id,
+            regex,
+            started,
+            deadline: started + timeout,
+        });
+        self.resolve()
+    }
+
+    pub fn feed(&mut self, frame: &Frame) -> Vec<Frame> {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Vec::new();
+        }
+        let Some(data) = frame.text() else {
+            return Vec::new();
+        };
+
+        self.buffer.push_str(&self.escapes.strip(&data));
+        if self.buffer.len() > MAX_BUFFER_BYTES {
+            let mut cut = self.buffer.len() - MAX_BUFFER_BYTES;
+            while !self.buffer.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.buffer.drain(..cut);
+        }
+
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+        self.resolve()
+    }
+
+    /// Resolve waiters in registration order against the buffer.
+    fn resolve(&mut self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut index = 0;
+
+        while index < self.pending.len() {
+            let waiter = &self.pending[index];
+            let Some(captures) = waiter.regex.captures(&self.buffer) else {
+                index += 1;
+                continue;
+            };
+
+            let whole = captures.get(0).expect("group 0 always participates");
+            let mut named = BTreeMap::new();
+            for (i, group) in captures.iter().enumerate() {
+                if let Some(group) = group {
+                    named.insert(i.to_string(), group.as_str().to_string());
+                }
+            }
+            for name in waiter.regex.capture_names().flatten() {
+                if let Some(group) = captures.name(name) {
+                    named.insert(name.to_string(), group.as_str().to_string());
+                }
+            }
+
+            let mut frame = Frame::new(FrameType::Match)
+                .with_regex(waiter.regex.as_str().to_string())
+                .with_data(whole.as_str().to_string())
+                .with_captures(named)
+                .with_duration(waiter.started.elapsed().as_millis() as u64);
+            if let Some(ref id) = waiter.id {
+                frame = frame.with_id(id.clone());
+            }
+            frames.push(frame);
+
+            let end = whole.end();
+            self.buffer.drain(..end);
+            self.pending.remove(index);
+            // The buffer changed, so earlier waiters get another chance
+            index = 0;
+        }
+
+        frames
+    }
+
+    /// Wait until the earliest waiter times out; never resolves when idle.
+    pub async fn next_timeout(&self) {
+        match self.pending.iter().map(|waiter| waiter.deadline).min() {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn take_expired(&mut self) -> Vec<Frame> {
+        let now = Instant::now();
+        let (expired, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|waiter| waiter.deadline <= now);
+        self.pending = pending;
+
+        expired.into_iter().map(timeout_frame).collect()
+    }
+
+    /// Withdraw the waiters registered under `id`, returning the
+    /// `cancelled` frame, or None when no waiter has that id.
+    pub fn cancel(&mut self, id: &str) -> Option<Frame> {
+        let index = self.pending.iter().position(|waiter| waiter.id.as_deref() == Some(id))?;
+        let waiter = self.pending.remove(index);
+        self.pending.retain(|other| other.id.as_deref() != Some(id));
+        Some(
+            Frame::new(FrameType::Cancelled)
+                .with_id(id.to_string())
+                .with_reason("expect".to_string())
+                .with_regex(waiter.regex.as_str().to_string())
+                .with_duration(waiter.started.elapsed().as_millis() as u64),
+        )
+    }
+
+    /// Give up on every waiter, e.g. because no more output will arrive.
+    pub fn cancel_all(&mut self, reason: &str) -> Vec<Frame> {
+        self.pending
+            .drain(..)
+            .map(|waiter| timeout_frame(waiter).with_reason(reason.to_string()))
+            .collect()
+    }
+}
+
+fn timeout_frame(waiter: PendingExpect) -> Frame {
+    let mut frame = Frame::new(FrameType::ExpectTimeout)
+        .with_regex(waiter.regex.as_str().to_string())
+        .with_duration(waiter.started.elapsed().as_millis() as u64);
+    if let Some(id) = waiter.id {
+        frame = frame.with_id(id);
+    }
+    frame
+}
+
+/// Removes escape sequences from output as it arrives. A sequence split
+/// across two reads, such as `\x1b[3` then `2mPassword:`, is held back
+/// until the rest of it comes, so it is still removed whole.
+#[derive(Default)]
+pub(crate) struct EscapeStripper {
+    /// Start of a sequence the next read finishes
+    carry: String,
+}
+
+impl EscapeStripper {
+    /// `data` as plain text, less any sequence it leaves unfinished.
+    pub(crate) fn strip(&mut self, data: &str) -> String {
+        let mut text = std::mem::take(&mut self.carry);
+        text.push_str(data);
+        self.carry = split_unfinished(&mut text);
+        strip_escapes(&text)
+    }
+}
+
+/// Split off the end of `text` that starts an escape sequence the next
+/// read will finish, and return it: a lone ESC, a CSI still short of its
+/// final byte, or an OSC, DCS or other string not yet terminated.
+pub(crate) fn split_unfinished(text: &mut String) -> String {
+    let mut from = 0;
+    while let Some(offset) = text[from..].find('\x1b') {
+        let start = from + offset;
+        match sequence_len(&text[start..]) {
+            Some(len) => from = start + len,
+            None if text.len() - start < MAX_SEQUENCE_BYTES => return text.split_off(start),
+            None => break,
+        }
+    }
+    String::new()
+}
+
+/// Length of the escape sequence `text` starts with, or None if it does
+/// not end within `text`.
+fn sequence_len(text: &str) -> Option<usize> {
+    let introducer = text[1..].chars().next()?;
+    let body = 1 + introducer.len_utf8();
+    match introducer {
+        '[' => text[body..]
+            .char_indices()
+            // Parameters and intermediates, up to the final byte
+            .find(|&(_, c)| !('\x20'..='\x3f').contains(&c))
+            .map(|(i, c)| body + i + c.len_utf8()),
+        ']' | 'P' | 'X' | '^' | '_' => {
+            let end = text[body..].find(|c| c == '\x1b' || (introducer == ']' && c == '\x07'))? + body;
+            match text[end..].chars().nth(1) {
+                _ if text[end..].starts_with('\x07') => Some(end + 1),
+                Some('\\') => Some(end + 2),
+                // Cut short by another sequence
+                Some(_) => Some(end),
+                None => None,
+            }
+        }
+        _ => Some(body),
+    }
+}
+
+/// Remove CSI, OSC and other escape sequences so patterns see plain text.
+pub(crate) fn strip_escapes(data: &str) -> String {
+    static ESCAPES: OnceLock<Regex> = OnceLock::new();
+    let escapes = ESCAPES.get_or_init(|| {
+        Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(\x07|\x1b\\)|\x1b[PX^_][^\x1b]*\x1b\\|\x1b[@-Z\\-_]")
+            .unwrap()
+    });
+    escapes.replace_all(data, "").to_string()
+}
@@ -0,0 +1,200 @@
+use crate::expect::EscapeStripper;
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long an expired session gets to exit after SIGHUP, or SIGTERM
+/// for the failing limits, before it is torn down regardless.
+pub const REAP_GRACE: Duration = Duration::from_secs(5);
+
+/// Exit status after the command was stopped by `--timeout`.
+pub const TIMEOUT_STATUS: i32 = 124;
+
+/// Exit status after the command was stopped by `--max-output-bytes`.
+pub const OUTPUT_LIMIT_STATUS: i32 = 125;
+
+/// Session lifetime limits: an absolute TTL and an idle limit measured
+/// from the last input or output, plus the hard `timeout` and output
+/// limits that end a run as a failure. Fires at most once.
+pub struct Expiry {
+    ttl: Option<Duration>,
+    idle: Option<Duration>,
+    timeout: Option<Duration>,
+    max_output: Option<u64>,
+    output_bytes: u64,
+    started: Instant,
+    last_activity: Instant,
+    fired: bool,
+}
+
+impl Expiry {
+    pub fn new(ttl: Option<Duration>, idle: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            ttl,
+            idle,
+            timeout: None,
+            max_output: None,
+            output_bytes: 0,
+            started: now,
+            last_activity: now,
+            fired: false,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_output(mut self, max_output: Option<u64>) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// Exit status spectertty ends with when `frame` is an `expired` frame
+    /// for a limit that fails the run.
+    pub fn exit_status(frame: &Frame) -> Option<i32> {
+        match frame.reason.as_deref()? {
+            "timeout" => Some(TIMEOUT_STATUS),
+            "output_limit" => Some(OUTPUT_LIMIT_STATUS),
+            _ => None,
+        }
+    }
+
+    /// Note session activity; only input and output reset the idle limit,
+    /// and only output counts towards the output limit.
+    pub fn observe(&mut self, frame: &Frame) {
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                let len = frame.bytes().map_or(0, |bytes| bytes.len());
+                self.output_bytes = self.output_bytes.saturating_add(len as u64);
+            }
+            FrameType::Stdin => {}
+            _ => return,
+        }
+        self.last_activity = Instant::now();
+    }
+
+    /// Wait for the earliest limit and return the `expired` frame; never
+    /// resolves when no limit is set or one has already fired.
+    pub async fn expired(&mut self) -> Frame {
+        if self.fired {
+            return std::future::pending().await;
+        }
+        if self.max_output.is_some_and(|max| self.output_bytes > max) {
+            self.fired = true;
+            return Frame::new(FrameType::Expired)
+                .with_reason("output_limit".to_string())
+                .with_value(self.output_bytes as f64)
+                .with_duration(self.started.elapsed().as_millis() as u64);
+        }
+
+        let deadline = [
+            self.timeout.map(|timeout| (self.started + timeout, "timeout")),
+            self.ttl.map(|ttl| (self.started + ttl, "ttl")),
+            self.idle.map(|idle| (self.last_activity + idle, "idle")),
+        ]
+        .into_iter()
+        .flatten()
+        .min_by_key(|(deadline, _)| *deadline);
+
+        let Some((deadline, reason)) = deadline else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(deadline).await;
+
+        self.fired = true;
+        let since = match reason {
+            "idle" => self.last_activity,
+            _ => self.started,
+        };
+        Frame::new(FrameType::Expired)
+            .with_reason(reason.to_string())
+            .with_duration(since.elapsed().as_millis() as u64)
+    }
+}
+
+/// Trailing output kept for prompt detection.
+const PROMPT_TAIL_BYTES: usize = 4096;
+
+/// `--exit-on-idle`: decides when a batch run is over, i.e. output has
+/// been quiet for a while with no input since and, when prompt patterns
+/// are given, what was last printed is a prompt. Fires at most once.
+pub struct IdleExit {
+    quiet: Option<Duration>,
+    prompts: Vec<Regex>,
+    last_activity: Instant,
+    tail: String,
+    escapes: EscapeStripper,
+    fired: bool,
+}
+
+impl IdleExit {
+    pub fn new(quiet: Option<Duration>, prompts: &[String]) -> anyhow::Result<Self> {
+        let prompts = prompts
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            quiet,
+            prompts,
+            last_activity: Instant::now(),
+            tail: String::new(),
+            escapes: EscapeStripper::default(),
+            fired: false,
+        })
+    }
+
+    pub fn observe(&mut self, frame: &Frame) {
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                if let Some(data) = frame.text() {
+                    self.tail.push_str(&self.escapes.strip(&data));
+                    if self.tail.len() > PROMPT_TAIL_BYTES {
+                        let mut cut = self.tail.len() - PROMPT_TAIL_BYTES;
+                        while !self.tail.is_char_boundary(cut) {
+                            cut += 1;
+                        }
+                        self.tail.drain(..cut);
+                    }
+                }
+            }
+            // Output from before the input cannot be the prompt it answers
+            FrameType::Stdin => self.tail.clear(),
+            _ => return,
+        }
+        self.last_activity = Instant::now();
+    }
+
+    fn at_prompt(&self) -> bool {
+        if self.prompts.is_empty() {
+            return true;
+        }
+        self.prompts.iter().any(|prompt| {
+            prompt
+                .find_iter(&self.tail)
+                .last()
+                .is_some_and(|found| self.tail[found.end()..].trim().is_empty())
+        })
+    }
+
+    /// Wait until the run looks finished and return the `idle` frame
+    /// announcing the exit; stays pending while disabled or while the
+    /// output does not end at a prompt.
+    pub async fn ready(&mut self) -> Frame {
+        let Some(quiet) = self.quiet.filter(|_| !self.fired) else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(self.last_activity + quiet).await;
+        if !self.at_prompt() {
+            return std::future::pending().await;
+        }
+
+        self.fired = true;
+        Frame::new(FrameType::Idle)
+            .with_reason("exit_on_idle".to_string())
+            .with_duration(self.last_activity.elapsed().as_millis() as u64)
+    }
+}
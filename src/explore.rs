@@ -0,0 +1,298 @@
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Where `--explore` records the session when `--record` does not say.
+pub const DEFAULT_RECORDING: &str = "explore-{session_id}.cast";
+
+/// The shell `--explore` runs when no command is given.
+pub fn shell() -> String {
+    std::env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+/// Files looked at before the walk gives up, so exploring a huge tree
+/// does not stall the start of the session.
+pub const MAX_FILES: usize = 100_000;
+
+/// Directories left out of the walk: version control internals churn on
+/// every command and are not what a reviewer means by files touched.
+const SKIPPED_DIRS: &[&str] = &[".git", ".hg", ".svn"];
+
+/// What a file looked like, enough to tell that it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    mtime: (i64, i64),
+    mode: u32,
+}
+
+/// Every file under a directory, by path relative to it.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    files: BTreeMap<PathBuf, FileState>,
+    truncated: bool,
+}
+
+impl Tree {
+    /// Walk `root` without following symlinks. Unreadable directories are
+    /// skipped; past `MAX_FILES` the walk stops and the tree is marked
+    /// truncated.
+    pub fn scan(root: &Path) -> Self {
+        let mut tree = Self {
+            files: BTreeMap::new(),
+            truncated: false,
+        };
+        let mut pending = vec![PathBuf::new()];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(root.join(&dir)) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Not exploring {:?}: {}", root.join(&dir), e);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = dir.join(entry.file_name());
+                let Ok(metadata) = entry.metadata() else {
+                    continue;
+                };
+                if metadata.is_dir() {
+                    if !SKIPPED_DIRS.iter().any(|skipped| entry.file_name() == *skipped) {
+                        pending.push(path);
+                    }
+                    continue;
+                }
+                if tree.files.len() >= MAX_FILES {
+                    tree.truncated = true;
+                    return tree;
+                }
+                let state = FileState {
+                    len: metadata.len(),
+                    mtime: (metadata.mtime(), metadata.mtime_nsec()),
+                    mode: metadata.mode(),
+                };
+                tree.files.insert(path, state);
+            }
+        }
+        tree
+    }
+
+    /// What changed between this tree and `after`, leaving out `ignored`
+    /// paths such as spectertty's own recording.
+    pub fn diff(&self, after: &Tree, ignored: &[PathBuf]) -> FsDiff {
+        let mut diff = FsDiff {
+            truncated: self.truncated || after.truncated,
+            ..FsDiff::default()
+        };
+        let shown = |path: &PathBuf| (!ignored.contains(path)).then(|| path.to_string_lossy().into_owned());
+        for (path, state) in &after.files {
+            match self.files.get(path) {
+                None => diff.created.extend(shown(path)),
+                Some(before) if before != state => diff.modified.extend(shown(path)),
+                Some(_) => {}
+            }
+        }
+        for path in self.files.keys() {
+            if !after.files.contains_key(path) {
+                diff.deleted.extend(shown(path));
+            }
+        }
+        diff
+    }
+}
+
+/// Files created, modified and deleted during the session.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FsDiff {
+    pub created: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    /// The directory held more than `MAX_FILES` files, so changes past
+    /// them were not seen
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// A command line entered at the shell.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRun {
+    pub command: String,
+    /// Time into the session it was entered
+    pub at_ms: u64,
+}
+
+/// How the session ended.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Ending {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+    /// `ttl` when `--explore` ran out, else as `--session-ttl` and friends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expired: Option<String>,
+}
+
+/// The `--explore` report: what was done in the session, for review.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub session_id: String,
+    pub command: String,
+    pub cwd: String,
+    /// Unix time the session started
+    pub started: f64,
+    pub dur_ms: u64,
+    pub commands: Vec<CommandRun>,
+    pub files: FsDiff,
+    pub exit: Ending,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording: Option<String>,
+}
+
+/// Follows an `--explore` session: the files under its directory when it
+/// started, and the command lines typed into it.
+pub struct Explorer {
+    session_id: String,
+    command: String,
+    root: PathBuf,
+    before: Tree,
+    started: f64,
+    commands: Vec<CommandRun>,
+    line: LineEditor,
+    expired: Option<String>,
+    recording: Option<PathBuf>,
+    report_path: Option<PathBuf>,
+}
+
+impl Explorer {
+    /// Take the "before" picture of `root`, where `command` runs, off the
+    /// runtime's workers as the walk blocks. `recording` and `report_path`
+    /// are what spectertty itself writes, and are left out of the diff.
+    pub async fn start(
+        session_id: String,
+        command: String,
+        root: PathBuf,
+        recording: Option<PathBuf>,
+        report_path: Option<PathBuf>,
+    ) -> Result<Self> {
+        let root = std::path::absolute(&root)?;
+        let walked = root.clone();
+        let before = tokio::task::spawn_blocking(move || Tree::scan(&walked)).await?;
+        Ok(Self {
+            session_id,
+            command,
+            root,
+            before,
+            started: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+            commands: Vec::new(),
+            line: LineEditor::default(),
+            expired: None,
+            recording,
+            report_path,
+        })
+    }
+
+    pub fn observe(&mut self, frame: &Frame) {
+        match frame.frame_type {
+            FrameType::Stdin => {
+                let at_ms = ((frame.ts - self.started).max(0.0) * 1000.0) as u64;
+                for command in self.line.feed(frame.text().as_deref().unwrap_or_default()) {
+                    self.commands.push(CommandRun { command, at_ms });
+                }
+            }
+            FrameType::Expired => self.expired = frame.reason.clone(),
+            _ => {}
+        }
+    }
+
+    /// Compare the directory with how it started and write the report to
+    /// `--explore-report`, or return it as a `structured` frame
+    /// (`reason: explore`) to go out before `end`, the `session_end` frame.
+    pub async fn finish(self, end: &Frame) -> Result<Option<Frame>> {
+        let walked = self.root.clone();
+        let after = tokio::task::spawn_blocking(move || Tree::scan(&walked)).await?;
+        let ignored: Vec<PathBuf> = [&self.recording, &self.report_path]
+            .into_iter()
+            .flatten()
+            .filter_map(|path| std::path::absolute(path).ok())
+            .filter_map(|path| path.strip_prefix(&self.root).ok().map(Path::to_path_buf))
+            .collect();
+
+        let report = Report {
+            session_id: self.session_id,
+            command: self.command,
+            cwd: self.root.to_string_lossy().into_owned(),
+            started: self.started,
+            dur_ms: end.dur_ms.unwrap_or_default(),
+            commands: self.commands,
+            files: self.before.diff(&after, &ignored),
+            exit: Ending {
+                code: end.code,
+                signal: end.signal.clone(),
+                expired: self.expired,
+            },
+            recording: self.recording.map(|path| path.to_string_lossy().into_owned()),
+        };
+        match self.report_path {
+            Some(ref path) => {
+                let json = serde_json::to_vec_pretty(&report)?;
+                fs::write(path, json).with_context(|| format!("Failed to write explore report {:?}", path))?;
+                Ok(None)
+            }
+            None => Ok(Some(
+                Frame::new(FrameType::Structured)
+                    .with_reason("explore".to_string())
+                    .with_structured(serde_json::to_value(&report)?),
+            )),
+        }
+    }
+}
+
+/// Rebuilds command lines from keystrokes, as far as a plain line editor
+/// would: backspace, Ctrl-U and Ctrl-C are applied and cursor keys
+/// ignored. What shell completion or history recall filled in is not seen.
+#[derive(Default)]
+struct LineEditor {
+    line: String,
+    escape: bool,
+}
+
+impl LineEditor {
+    fn feed(&mut self, input: &str) -> Vec<String> {
+        let mut entered = Vec::new();
+        for c in input.chars() {
+            if self.escape {
+                // CSI and SS3 keys end with a letter or `~`
+                if c != '[' && c != 'O' && ('@'..='~').contains(&c) {
+                    self.escape = false;
+                }
+                continue;
+            }
+            match c {
+                '\r' | '\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if !line.trim().is_empty() {
+                        entered.push(line.trim().to_string());
+                    }
+                }
+                '\x7f' | '\x08' => {
+                    self.line.pop();
+                }
+                '\x03' | '\x15' => self.line.clear(),
+                '\x1b' => self.escape = true,
+                c if c.is_control() => {}
+                c => self.line.push(c),
+            }
+        }
+        entered
+    }
+}
@@ -0,0 +1,318 @@
+use crate::cli::FlakeArgs;
+use crate::expect::strip_escapes;
+use crate::frame::{Frame, FrameType};
+use crate::script::Playbook;
+use crate::session::Session;
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Volatile text masked before runs are compared, most specific first.
+const VOLATILE: &[(&str, &str)] = &[
+    (r"\b[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\b", "<uuid>"),
+    (
+        r"\b\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}(:\d{2}(\.\d+)?)?(Z|[+-]\d{2}:?\d{2})?",
+        "<time>",
+    ),
+    (r"\b\d{1,2}:\d{2}:\d{2}(\.\d+)?\b", "<time>"),
+    (r"\b(0x)?[0-9a-f]{8,}\b", "<hex>"),
+    (r"\b\d+(\.\d+)?\s?(ns|us|µs|ms|s|secs?|seconds?)\b", "<duration>"),
+];
+
+/// A line of a run's normalized output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    /// Playbook step running when the line started, counted from 1
+    pub step: Option<usize>,
+    pub text: String,
+}
+
+/// What one run printed and how it ended.
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub lines: Vec<Line>,
+    /// `exit 0`, the failing step, or a timeout
+    pub outcome: String,
+}
+
+impl Run {
+    fn same_as(&self, other: &Run) -> bool {
+        self.outcome == other.outcome
+            && self.lines.len() == other.lines.len()
+            && self.lines.iter().zip(&other.lines).all(|(a, b)| a.text == b.text)
+    }
+}
+
+/// Where a cluster's output first differs from the most common one.
+#[derive(Debug, Clone, Serialize)]
+pub struct Divergence {
+    /// Line number from 1; None when only the outcome differs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_name: Option<String>,
+    /// The common output's line, None past its end
+    pub expected: Option<String>,
+    /// This cluster's line, None past its end
+    pub actual: Option<String>,
+}
+
+/// Runs that produced the same normalized output and outcome.
+#[derive(Debug, Clone, Serialize)]
+pub struct Cluster {
+    /// Run numbers, from 1
+    pub runs: Vec<usize>,
+    pub outcome: String,
+    /// Unset for the first, most common cluster the others are compared to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divergence: Option<Divergence>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    runs: usize,
+    stable: bool,
+    clusters: &'a [Cluster],
+}
+
+/// Masks applied to each line of output so that runs differing only in
+/// timestamps, IDs and timings compare equal.
+pub struct Normalizer {
+    masks: Vec<(Regex, String)>,
+}
+
+impl Normalizer {
+    /// Built-in masks unless `exact`, then one `<*>` mask per pattern.
+    pub fn new(exact: bool, patterns: &[String]) -> Result<Self> {
+        let mut masks = Vec::new();
+        if !exact {
+            for (pattern, mask) in VOLATILE {
+                masks.push((Regex::new(pattern)?, mask.to_string()));
+            }
+        }
+        for pattern in patterns {
+            let regex = Regex::new(pattern).with_context(|| format!("Invalid --normalize pattern {:?}", pattern))?;
+            masks.push((regex, "<*>".to_string()));
+        }
+        Ok(Self { masks })
+    }
+
+    /// Split output, tagged with the step it arrived during, into masked
+    /// lines. Escape sequences are dropped, a carriage return keeps only
+    /// what was written over the line after it, and trailing blank lines
+    /// are ignored.
+    pub fn lines(&self, output: &[(Option<usize>, Vec<u8>)]) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut current: Option<Line> = None;
+        for (step, data) in output {
+            let text = strip_escapes(&String::from_utf8_lossy(data));
+            let mut parts = text.split('\n').peekable();
+            while let Some(part) = parts.next() {
+                let line = current.get_or_insert_with(|| Line {
+                    step: *step,
+                    text: String::new(),
+                });
+                line.text.push_str(part);
+                if parts.peek().is_some() {
+                    lines.push(current.take().unwrap());
+                }
+            }
+        }
+        lines.extend(current);
+
+        for line in &mut lines {
+            let text = line.text.trim_end_matches('\r');
+            let text = text.rsplit('\r').next().unwrap_or_default().trim_end();
+            let mut text = text.to_string();
+            for (regex, mask) in &self.masks {
+                text = regex.replace_all(&text, mask.as_str()).into_owned();
+            }
+            line.text = text;
+        }
+        while lines.last().is_some_and(|line| line.text.is_empty()) {
+            lines.pop();
+        }
+        lines
+    }
+}
+
+/// Group identical runs, most common first, and locate where every other
+/// group departs from the most common one.
+pub fn cluster(runs: &[Run]) -> Vec<Cluster> {
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (i, run) in runs.iter().enumerate() {
+        match groups.iter_mut().find(|(first, _)| runs[*first].same_as(run)) {
+            Some((_, members)) => members.push(i + 1),
+            None => groups.push((i, vec![i + 1])),
+        }
+    }
+    // Stable, so ties keep the order their first runs happened in
+    groups.sort_by_key(|(_, members)| std::cmp::Reverse(members.len()));
+
+    let Some(&(baseline, _)) = groups.first() else {
+        return Vec::new();
+    };
+    groups
+        .into_iter()
+        .map(|(first, members)| Cluster {
+            runs: members,
+            outcome: runs[first].outcome.clone(),
+            divergence: (first != baseline).then(|| diverge(&runs[baseline], &runs[first])),
+        })
+        .collect()
+}
+
+fn diverge(expected: &Run, actual: &Run) -> Divergence {
+    let longest = expected.lines.len().max(actual.lines.len());
+    let at = (0..longest).find(|&i| {
+        expected.lines.get(i).map(|line| &line.text) != actual.lines.get(i).map(|line| &line.text)
+    });
+    match at {
+        Some(i) => Divergence {
+            line: Some(i + 1),
+            step: actual.lines.get(i).or_else(|| expected.lines.get(i)).and_then(|line| line.step),
+            step_name: None,
+            expected: expected.lines.get(i).map(|line| line.text.clone()),
+            actual: actual.lines.get(i).map(|line| line.text.clone()),
+        },
+        None => Divergence {
+            line: None,
+            step: None,
+            step_name: None,
+            expected: Some(expected.outcome.clone()),
+            actual: Some(actual.outcome.clone()),
+        },
+    }
+}
+
+/// Run the command or playbook `--runs` times and report how the runs
+/// differ. Returns whether every run came out the same.
+pub async fn run(args: &FlakeArgs) -> Result<bool> {
+    let playbook = args.script.as_deref().map(Playbook::load).transpose()?;
+    let normalizer = Normalizer::new(args.exact, &args.normalize)?;
+
+    let mut runs = Vec::new();
+    for _ in 0..args.runs {
+        let (output, outcome) = match playbook {
+            Some(ref playbook) => play(playbook, args.timeout).await?,
+            None => execute(&args.command, args.timeout).await?,
+        };
+        runs.push(Run {
+            lines: normalizer.lines(&output),
+            outcome,
+        });
+    }
+
+    let mut clusters = cluster(&runs);
+    if let Some(ref playbook) = playbook {
+        for divergence in clusters.iter_mut().filter_map(|cluster| cluster.divergence.as_mut()) {
+            divergence.step_name = divergence.step.map(|step| playbook.step_name(step - 1));
+        }
+    }
+    let stable = clusters.len() <= 1;
+
+    if args.json {
+        let report = Report {
+            runs: runs.len(),
+            stable,
+            clusters: &clusters,
+        };
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(stable);
+    }
+
+    let noun = if clusters.len() == 1 { "outcome" } else { "outcomes" };
+    println!("{} runs, {} distinct {}", runs.len(), clusters.len(), noun);
+    for cluster in &clusters {
+        let members: Vec<String> = cluster.runs.iter().map(usize::to_string).collect();
+        let noun = if cluster.runs.len() == 1 { "run" } else { "runs" };
+        println!("  {} {} ({}): {}", cluster.runs.len(), noun, members.join(" "), cluster.outcome);
+        let Some(ref divergence) = cluster.divergence else {
+            continue;
+        };
+        match divergence.line {
+            Some(line) => {
+                let step = match (divergence.step, &divergence.step_name) {
+                    (Some(step), Some(name)) => format!(", in step {} ({})", step, name),
+                    _ => String::new(),
+                };
+                println!("    diverges at line {}{}:", line, step);
+            }
+            None => println!("    same output, different outcome:"),
+        }
+        let show = |text: &Option<String>| text.clone().unwrap_or_else(|| "<end of output>".to_string());
+        println!("      expected: {}", show(&divergence.expected));
+        println!("      actual:   {}", show(&divergence.actual));
+    }
+    Ok(stable)
+}
+
+type Output = Vec<(Option<usize>, Vec<u8>)>;
+
+/// Append a frame's output to the segment for `step`.
+fn collect(output: &mut Output, step: Option<usize>, frame: &Frame) {
+    if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+        return;
+    }
+    let Some(data) = frame.bytes() else {
+        return;
+    };
+    match output.last_mut() {
+        Some((last, segment)) if *last == step => segment.extend_from_slice(&data),
+        _ => output.push((step, data.into_owned())),
+    }
+}
+
+/// One run of a plain command, to its exit or `timeout`.
+async fn execute(command: &[String], timeout: Duration) -> Result<(Output, String)> {
+    let (program, args) = command.split_first().ok_or_else(|| anyhow!("No command to run"))?;
+    let mut session = Session::builder(program.clone()).args(args.iter().cloned()).spawn().await?;
+    let mut output = Output::new();
+    let outcome = match tokio::time::timeout(timeout, session.wait_with(|frame| collect(&mut output, None, frame))).await {
+        Ok(Some(code)) => format!("exit {}", code),
+        Ok(None) => "ended without an exit code".to_string(),
+        Err(_) => format!("still running after {}s", timeout.as_secs()),
+    };
+    finish(session, &mut output, None);
+    Ok((output, outcome))
+}
+
+/// One run of the playbook: its steps up to the first failure, within
+/// `timeout` overall.
+async fn play(playbook: &Playbook, timeout: Duration) -> Result<(Output, String)> {
+    let mut session = playbook.spawn(None).await?;
+    let mut output = Output::new();
+    let mut last = None;
+    let steps = async {
+        for i in 0..playbook.len() {
+            last = Some(i + 1);
+            let step = last;
+            if let Err(e) = playbook.perform(i, &mut session, |frame| collect(&mut output, step, frame)).await {
+                return Some(format!("step {} failed: {}", i + 1, e));
+            }
+        }
+        None
+    };
+    let failure = match tokio::time::timeout(timeout, steps).await {
+        Ok(failure) => failure,
+        Err(_) => Some(format!("still running after {}s", timeout.as_secs())),
+    };
+    let outcome = match (failure, session.exit_code()) {
+        (Some(failure), _) => failure,
+        (None, Some(code)) => format!("exit {}", code),
+        (None, None) => "passed".to_string(),
+    };
+    finish(session, &mut output, last);
+    Ok((output, outcome))
+}
+
+/// Stop the session and keep output it produced that was not read.
+fn finish(mut session: Session, output: &mut Output, step: Option<usize>) {
+    let _ = session.kill();
+    for frame in session.close() {
+        collect(output, step, &frame);
+    }
+}
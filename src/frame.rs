@@ -1,8 +1,20 @@
+use crate::cli::FrameFormat;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{Cursor, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 use base64::prelude::*;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Version of the frame and control protocol, carried by `session_start`
+/// frames and the schema. Fields and types are only ever added within a
+/// version; renaming or removing one, or changing its meaning, bumps it.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum FrameType {
     Stdout,
@@ -12,9 +24,15 @@ pub enum FrameType {
     Resize,
     ResizeAck,
     Prompt,
+    CommandStart,
+    CommandEnd,
     Idle,
     LineUpdate,
+    SpinnerStart,
+    SpinnerEnd,
     Overflow,
+    Summary,
+    BudgetExceeded,
     Signal,
     Exit,
     Stopped,
@@ -22,11 +40,50 @@ pub enum FrameType {
     CapsuleKill,
     Ping,
     Pong,
+    ApprovalRequired,
+    ApprovalVote,
+    ApprovalGranted,
+    ApprovalDenied,
+    Anomaly,
+    Snapshot,
+    Health,
+    Match,
+    ExpectTimeout,
+    AutoResponse,
+    Cancelled,
+    ModeChange,
+    Termios,
+    Error,
+    WriteError,
+    Expired,
+    SidecarExit,
+    Orphans,
+    Clipboard,
+    Link,
+    Title,
+    Register,
+    Transfer,
+    Structured,
+    Bell,
+    Reconnect,
+    Resource,
+    SessionStart,
+    SessionEnd,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Frame {
     pub ts: f64,
+    /// When the frame was made on the host's monotonic clock, in
+    /// nanoseconds; unlike `ts` it never ties or goes backwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mono_ns: Option<u64>,
+    /// Milliseconds since the session started, from `mono_ns`; set by
+    /// `--ts-format elapsed` or `both`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
     #[serde(rename = "type")]
     pub frame_type: FrameType,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -47,12 +104,50 @@ pub struct Frame {
     pub dur_ms: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captures: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub structured: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Target of a hyperlink the command printed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// `PROTOCOL_VERSION` of the stream, on its `session_start` frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+    /// Estimated tokens in `data`, by `--tokenizer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u64>,
+    /// Times the line in `data` repeated after the last frame, collapsed
+    /// into this one by `--dedupe-lines`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_count: Option<u64>,
+    /// How far along the progress line in a `line_update` is, from the
+    /// last `N%` it shows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
 }
 
 impl Frame {
     pub fn new(frame_type: FrameType) -> Self {
         Self {
             ts: current_timestamp(),
+            mono_ns: Some(monotonic_ns()),
+            elapsed_ms: None,
+            seq: None,
             frame_type,
             data: None,
             binary: None,
@@ -63,9 +158,28 @@ impl Frame {
             regex: None,
             dur_ms: None,
             reason: None,
+            id: None,
+            client: None,
+            value: None,
+            stats: None,
+            captures: None,
+            structured: None,
+            mode: None,
+            session_id: None,
+            source: None,
+            url: None,
+            protocol_version: None,
+            tokens: None,
+            repeat_count: None,
+            percent: None,
         }
     }
 
+    pub fn with_timestamp(mut self, ts: f64) -> Self {
+        self.ts = ts;
+        self
+    }
+
     pub fn with_data(mut self, data: String) -> Self {
         self.data = Some(data);
         self
@@ -93,6 +207,11 @@ impl Frame {
         self
     }
 
+    pub fn with_url(mut self, url: String) -> Self {
+        self.url = Some(url);
+        self
+    }
+
     pub fn with_regex(mut self, regex: String) -> Self {
         self.regex = Some(regex);
         self
@@ -108,6 +227,73 @@ impl Frame {
         self
     }
 
+    pub fn with_id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn with_client(mut self, client: String) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn with_value(mut self, value: f64) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn with_stats(mut self, stats: BTreeMap<String, u64>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn with_captures(mut self, captures: BTreeMap<String, String>) -> Self {
+        self.captures = Some(captures);
+        self
+    }
+
+    pub fn with_structured(mut self, structured: serde_json::Value) -> Self {
+        self.structured = Some(structured);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: String) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn with_session_id(mut self, session_id: String) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// The payload's bytes, base64-decoded for binary frames.
+    pub fn bytes(&self) -> Option<Cow<'_, [u8]>> {
+        let data = self.data.as_ref()?;
+        match self.binary {
+            Some(true) => BASE64_STANDARD.decode(data).ok().map(Cow::Owned),
+            _ => Some(Cow::Borrowed(data.as_bytes())),
+        }
+    }
+
+    /// The payload as text; binary data is decoded lossily.
+    pub fn text(&self) -> Option<Cow<'_, str>> {
+        match self.binary {
+            Some(true) => self.bytes().map(|bytes| Cow::Owned(String::from_utf8_lossy(&bytes).into_owned())),
+            _ => self.data.as_deref().map(Cow::Borrowed),
+        }
+    }
+
     pub fn to_json(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(self)?)
     }
@@ -115,6 +301,136 @@ impl Frame {
     pub fn from_json(json: &str) -> anyhow::Result<Self> {
         Ok(serde_json::from_str(json)?)
     }
+
+    /// MessagePack map with the same keys as the JSON form; binary `data`
+    /// is carried as raw bytes instead of base64.
+    pub fn to_msgpack(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(&self.packed())?)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(rmp_serde::from_slice::<Unpacked>(bytes)?.into_frame())
+    }
+
+    /// CBOR map laid out like `to_msgpack`.
+    pub fn to_cbor(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&self.packed(), &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_cbor(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(ciborium::from_reader::<Unpacked, _>(bytes)?.into_frame())
+    }
+
+    /// The frame as one element of a stream: a JSON line, or a
+    /// self-delimiting MessagePack or CBOR value.
+    pub fn encode(&self, format: FrameFormat) -> anyhow::Result<Vec<u8>> {
+        match format {
+            FrameFormat::Json => {
+                let mut line = serde_json::to_vec(self)?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            FrameFormat::Msgpack => self.to_msgpack(),
+            FrameFormat::Cbor => self.to_cbor(),
+        }
+    }
+
+    /// Decode the first frame of a stream written with `encode`, returning
+    /// it with the number of bytes it took, or None while `buf` holds only
+    /// part of it.
+    pub fn decode(format: FrameFormat, buf: &[u8]) -> anyhow::Result<Option<(Self, usize)>> {
+        match format {
+            FrameFormat::Json => {
+                let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+                    return Ok(None);
+                };
+                Ok(Some((serde_json::from_slice(&buf[..end])?, end + 1)))
+            }
+            FrameFormat::Msgpack => {
+                let mut cursor = Cursor::new(buf);
+                match rmp_serde::from_read::<_, Unpacked>(&mut cursor) {
+                    Ok(unpacked) => Ok(Some((unpacked.into_frame(), cursor.position() as usize))),
+                    Err(
+                        rmp_serde::decode::Error::InvalidMarkerRead(ref e)
+                        | rmp_serde::decode::Error::InvalidDataRead(ref e),
+                    ) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            FrameFormat::Cbor => {
+                let mut cursor = Cursor::new(buf);
+                match ciborium::from_reader::<Unpacked, _>(&mut cursor) {
+                    Ok(unpacked) => Ok(Some((unpacked.into_frame(), cursor.position() as usize))),
+                    Err(ciborium::de::Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        }
+    }
+
+    fn packed(&self) -> Packed {
+        let mut frame = self.clone();
+        let data = frame.data.take().map(|data| match frame.binary {
+            Some(true) => match BASE64_STANDARD.decode(&data) {
+                Ok(bytes) => Payload::Bytes(ByteBuf::from(bytes)),
+                Err(_) => Payload::Text(data),
+            },
+            _ => Payload::Text(data),
+        });
+        Packed { frame, data }
+    }
+}
+
+/// Frame laid out for the binary encodings, with `data` split off so
+/// binary payloads can be written as bytes.
+#[derive(Serialize)]
+struct Packed {
+    #[serde(flatten)]
+    frame: Frame,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Payload>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Text(String),
+    Bytes(ByteBuf),
+}
+
+#[derive(Deserialize)]
+struct Unpacked {
+    #[serde(flatten)]
+    frame: Frame,
+    #[serde(default)]
+    data: Option<Payload>,
+}
+
+impl Unpacked {
+    fn into_frame(self) -> Frame {
+        let mut frame = self.frame;
+        frame.data = self.data.map(|data| match (data, frame.binary) {
+            (Payload::Text(text), Some(true)) => BASE64_STANDARD.encode(text),
+            (Payload::Bytes(bytes), Some(true)) => BASE64_STANDARD.encode(bytes),
+            (Payload::Text(text), _) => text,
+            (Payload::Bytes(bytes), _) => String::from_utf8_lossy(&bytes).into_owned(),
+        });
+        frame
+    }
+}
+
+impl FrameFormat {
+    /// Format of a frame stream starting with `first`: MessagePack and
+    /// CBOR frames open with a map, anything else is taken for JSON.
+    pub fn detect(first: u8) -> Self {
+        match first {
+            0x80..=0x8f | 0xde | 0xdf => FrameFormat::Msgpack,
+            0xa0..=0xbb | 0xbf => FrameFormat::Cbor,
+            _ => FrameFormat::Json,
+        }
+    }
 }
 
 fn current_timestamp() -> f64 {
@@ -122,4 +438,26 @@ fn current_timestamp() -> f64 {
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs_f64()
+}
+
+/// CLOCK_MONOTONIC in nanoseconds, which every process on the host shares,
+/// made strictly increasing within this one: frames made in the same
+/// nanosecond, or on a coarse clock, still get distinct values in the
+/// order they were made.
+fn monotonic_ns() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: clock_gettime only writes the timespec it is given
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let now = now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64;
+
+    let mut last = LAST.load(Ordering::Relaxed);
+    loop {
+        let next = now.max(last + 1);
+        match LAST.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(seen) => last = seen,
+        }
+    }
 }
\ No newline at end of file
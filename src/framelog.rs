@@ -0,0 +1,47 @@
+use crate::frame::Frame;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Every frame of a session as NDJSON, in the order and with the seq
+/// numbers they were emitted with. Unlike a recording, which only keeps
+/// what a terminal can replay, this is the complete machine-readable
+/// account of the session and is not subject to `--route`.
+pub struct FrameLog {
+    writer: BufWriter<File>,
+}
+
+impl FrameLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Failed to create frame log {}", path.display()))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Write to a file already opened.
+    pub fn from_file(file: File) -> Self {
+        Self {
+            writer: BufWriter::new(file),
+        }
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        self.record_json(&frame.to_json()?)
+    }
+
+    /// Record a frame already encoded as JSON.
+    pub fn record_json(&mut self, json: &str) -> Result<()> {
+        writeln!(self.writer, "{}", json)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+// Handlers return tonic's Status, large as it is, as tonic expects
+#![allow(clippy::result_large_err)]
+
+use crate::error::SessionError;
+use crate::hub::{SessionHub, SessionSpec, Subscription};
+use crate::transport::{require_auth_off_loopback, token_matches, AuthTokens};
+use anyhow::{Context, Result};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::net::TcpListener;
+use tonic::codegen::{http, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info, warn};
+
+/// The messages of `proto/spectertty.proto`, declared by hand so that
+/// building needs no protoc; keep the two in step.
+pub mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateSessionRequest {
+        #[prost(string, tag = "1")]
+        pub command: String,
+        #[prost(string, repeated, tag = "2")]
+        pub args: Vec<String>,
+        #[prost(map = "string, string", tag = "3")]
+        pub env: HashMap<String, String>,
+        #[prost(string, tag = "4")]
+        pub cwd: String,
+        #[prost(uint32, tag = "5")]
+        pub cols: u32,
+        #[prost(uint32, tag = "6")]
+        pub rows: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateSessionResponse {
+        #[prost(string, tag = "1")]
+        pub session_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SendInputRequest {
+        #[prost(string, tag = "1")]
+        pub session_id: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SendInputResponse {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResizeRequest {
+        #[prost(string, tag = "1")]
+        pub session_id: String,
+        #[prost(uint32, tag = "2")]
+        pub cols: u32,
+        #[prost(uint32, tag = "3")]
+        pub rows: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResizeResponse {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SignalRequest {
+        #[prost(string, tag = "1")]
+        pub session_id: String,
+        #[prost(string, tag = "2")]
+        pub signal: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SignalResponse {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StreamFramesRequest {
+        #[prost(string, tag = "1")]
+        pub session_id: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub input: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Frame {
+        #[prost(string, tag = "1")]
+        pub r#type: String,
+        #[prost(double, tag = "2")]
+        pub ts: f64,
+        #[prost(uint64, tag = "3")]
+        pub seq: u64,
+        #[prost(string, tag = "4")]
+        pub session_id: String,
+        #[prost(bytes = "vec", tag = "5")]
+        pub data: Vec<u8>,
+        #[prost(int32, optional, tag = "6")]
+        pub code: Option<i32>,
+        #[prost(string, tag = "7")]
+        pub id: String,
+        #[prost(string, tag = "8")]
+        pub json: String,
+    }
+
+    impl From<&crate::Frame> for Frame {
+        fn from(frame: &crate::Frame) -> Self {
+            let json = frame.to_json().unwrap_or_default();
+            let r#type = serde_json::to_value(&frame.frame_type)
+                .ok()
+                .and_then(|value| value.as_str().map(str::to_string))
+                .unwrap_or_default();
+            Self {
+                r#type,
+                ts: frame.ts,
+                seq: frame.seq.unwrap_or_default(),
+                session_id: frame.session_id.clone().unwrap_or_default(),
+                data: frame.bytes().map(|data| data.into_owned()).unwrap_or_default(),
+                code: frame.code,
+                id: frame.id.clone().unwrap_or_default(),
+                json,
+            }
+        }
+    }
+}
+
+/// Fully qualified name of the service, as in its method paths.
+pub const SERVICE_NAME: &str = "spectertty.v1.Spectertty";
+
+/// Window size of a session created without one.
+const DEFAULT_SIZE: (u16, u16) = (120, 40);
+
+/// Serves the `spectertty.v1.Spectertty` gRPC service, through which
+/// clients create any number of sessions, write to, resize and signal
+/// them, and stream their frames.
+///
+/// With auth tokens configured, every call must carry an
+/// `authorization: Bearer NAME:TOKEN` metadata entry; without them, only
+/// the loopback interface is served.
+pub struct GrpcServer {
+    addr: SocketAddr,
+    incoming: TcpIncoming,
+    service: SpecterttyService,
+}
+
+impl GrpcServer {
+    pub fn bind(addr: SocketAddr, hub: Arc<SessionHub>, tokens: AuthTokens) -> Result<Self> {
+        require_auth_off_loopback(addr, &tokens, "gRPC")?;
+        let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+        let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(Self {
+            addr,
+            incoming,
+            service: SpecterttyService { hub, tokens },
+        })
+    }
+
+    /// The address clients connect to, with the port picked if it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Serve until `shutdown` completes.
+    pub async fn serve(self, shutdown: impl std::future::Future<Output = ()>) -> Result<()> {
+        info!("Serving gRPC on {}", self.addr);
+        tonic::transport::Server::builder()
+            .add_service(self.service)
+            .serve_with_incoming_shutdown(self.incoming, shutdown)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct SpecterttyService {
+    hub: Arc<SessionHub>,
+    tokens: AuthTokens,
+}
+
+impl SpecterttyService {
+    /// Check the call's credential, returning the client's name.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<Option<String>, Status> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let credential = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|value| value.trim().split_once(':'));
+        match credential {
+            Some((name, token)) if token_matches(&self.tokens, name, token) => Ok(Some(name.to_string())),
+            _ => {
+                warn!("gRPC call without a valid credential");
+                Err(Status::unauthenticated("expected authorization: Bearer NAME:TOKEN"))
+            }
+        }
+    }
+
+    fn session(&self, id: &str) -> Result<(), Status> {
+        match self.hub.contains(id) {
+            true => Ok(()),
+            false => Err(Status::not_found(format!("no session '{}'", id))),
+        }
+    }
+
+    async fn create_session(
+        self,
+        request: Request<proto::CreateSessionRequest>,
+    ) -> Result<Response<proto::CreateSessionResponse>, Status> {
+        let client = self.authorize(&request)?;
+        let request = request.into_inner();
+        if request.command.is_empty() {
+            return Err(Status::invalid_argument("no command given"));
+        }
+        let size = |value: u32, default: u16| match value {
+            0 => Ok(default),
+            value => u16::try_from(value).map_err(|_| Status::invalid_argument(format!("window size {} too large", value))),
+        };
+        let spec = SessionSpec {
+            command: request.command,
+            args: request.args,
+            env: request.env.into_iter().collect(),
+            cwd: (!request.cwd.is_empty()).then(|| PathBuf::from(request.cwd)),
+            cols: size(request.cols, DEFAULT_SIZE.0)?,
+            rows: size(request.rows, DEFAULT_SIZE.1)?,
+        };
+        let session_id = self.hub.create(spec).await.map_err(status)?;
+        info!("gRPC client {} created session {}", client.as_deref().unwrap_or("-"), session_id);
+        Ok(Response::new(proto::CreateSessionResponse { session_id }))
+    }
+
+    async fn send_input(self, request: Request<proto::SendInputRequest>) -> Result<Response<proto::SendInputResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        self.session(&request.session_id)?;
+        self.hub.send(&request.session_id, request.data).map_err(status)?;
+        Ok(Response::new(proto::SendInputResponse {}))
+    }
+
+    async fn resize(self, request: Request<proto::ResizeRequest>) -> Result<Response<proto::ResizeResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        self.session(&request.session_id)?;
+        let (Ok(cols), Ok(rows)) = (u16::try_from(request.cols), u16::try_from(request.rows)) else {
+            return Err(Status::invalid_argument("window size too large"));
+        };
+        self.hub.resize(&request.session_id, cols, rows).map_err(status)?;
+        Ok(Response::new(proto::ResizeResponse {}))
+    }
+
+    async fn signal(self, request: Request<proto::SignalRequest>) -> Result<Response<proto::SignalResponse>, Status> {
+        self.authorize(&request)?;
+        let request = request.into_inner();
+        self.session(&request.session_id)?;
+        self.hub.signal(&request.session_id, &request.signal).map_err(status)?;
+        Ok(Response::new(proto::SignalResponse {}))
+    }
+
+    async fn stream_frames(
+        self,
+        request: Request<Streaming<proto::StreamFramesRequest>>,
+    ) -> Result<Response<FrameStream>, Status> {
+        self.authorize(&request)?;
+        let mut requests = request.into_inner();
+        let Some(first) = requests.message().await? else {
+            return Err(Status::invalid_argument("the first message must name the session"));
+        };
+        self.session(&first.session_id)?;
+        let subscription = self.hub.subscribe(&first.session_id).map_err(status)?;
+
+        // Input is written as it arrives, until the client closes its side
+        let hub = self.hub.clone();
+        let session_id = first.session_id;
+        let mut input = Some(first.input);
+        tokio::spawn(async move {
+            loop {
+                if let Some(data) = input.take().filter(|data| !data.is_empty()) {
+                    if let Err(e) = hub.send(&session_id, data) {
+                        debug!("gRPC stream input for {}: {}", session_id, e);
+                        break;
+                    }
+                }
+                match requests.message().await {
+                    Ok(Some(request)) => input = Some(request.input),
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("gRPC stream for {} closed: {}", session_id, e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let frames = futures::stream::unfold(subscription, |mut subscription: Subscription| async move {
+            let frame = subscription.next().await?;
+            Some((Ok(proto::Frame::from(&frame)), subscription))
+        });
+        Ok(Response::new(Box::pin(frames)))
+    }
+}
+
+type FrameStream = tonic::codegen::BoxStream<proto::Frame>;
+
+fn status(error: SessionError) -> Status {
+    match error {
+        SessionError::InvalidRequest(message) => Status::invalid_argument(message),
+        SessionError::SpawnFailed(message) => Status::failed_precondition(message),
+        SessionError::Policy(message) => Status::permission_denied(message),
+        SessionError::Timeout(message) => Status::deadline_exceeded(message),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+type Handler<Req, Resp> = fn(SpecterttyService, Request<Req>) -> BoxFuture<Response<Resp>, Status>;
+
+/// One method of the service as the `tonic::server` machinery calls it.
+struct Method<Req, Resp> {
+    service: SpecterttyService,
+    handler: Handler<Req, Resp>,
+}
+
+impl<Req, Resp> Service<Request<Req>> for Method<Req, Resp> {
+    type Response = Response<Resp>;
+    type Error = Status;
+    type Future = BoxFuture<Response<Resp>, Status>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        (self.handler)(self.service.clone(), request)
+    }
+}
+
+/// Route a unary call to `handler`.
+macro_rules! unary {
+    ($service:expr, $request:expr, $handler:ident) => {{
+        let method = Method {
+            service: $service.clone(),
+            handler: |service, request| Box::pin(service.$handler(request)),
+        };
+        Box::pin(async move { Ok(Grpc::new(tonic::codec::ProstCodec::default()).unary(method, $request).await) })
+    }};
+}
+
+impl<B> Service<http::Request<B>> for SpecterttyService
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = request.uri().path().strip_prefix(&format!("/{}/", SERVICE_NAME)).unwrap_or_default();
+        match method {
+            "CreateSession" => unary!(self, request, create_session),
+            "SendInput" => unary!(self, request, send_input),
+            "Resize" => unary!(self, request, resize),
+            "Signal" => unary!(self, request, signal),
+            "StreamFrames" => {
+                let method = Method {
+                    service: self.clone(),
+                    handler: |service, request| Box::pin(service.stream_frames(request)),
+                };
+                Box::pin(async move { Ok(Grpc::new(tonic::codec::ProstCodec::default()).streaming(method, request).await) })
+            }
+            _ => Box::pin(async move { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+impl NamedService for SpecterttyService {
+    const NAME: &'static str = SERVICE_NAME;
+}
@@ -0,0 +1,219 @@
+use crate::cli::{ExportStateArgs, ImportStateArgs};
+use crate::ids;
+use crate::registry::{Entry, Registry};
+use crate::seal::StateKey;
+use crate::state::{Replica, StateDir};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `format` of every bundle.
+pub const BUNDLE_FORMAT: &str = "spectertty-state";
+
+/// Bundle layout this build writes and reads.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// A session packed by `export-state` for `import-state` on another host:
+/// its state directory files, its registry entry and, optionally, a CRIU
+/// image of its processes. File contents are base64.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    pub session: String,
+    /// Unix time of the export
+    pub exported: f64,
+    /// The session as `spectertty list` showed it, if it was running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<Entry>,
+    /// Journal segments and checkpoint
+    pub files: BTreeMap<String, String>,
+    /// Image files of `criu dump`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub criu: BTreeMap<String, String>,
+}
+
+impl Bundle {
+    fn decode(files: &BTreeMap<String, String>) -> Result<BTreeMap<String, Vec<u8>>> {
+        files
+            .iter()
+            .map(|(name, data)| {
+                let bytes = BASE64_STANDARD
+                    .decode(data)
+                    .map_err(|_| anyhow!("Bundle file '{}' is not valid base64", name))?;
+                Ok((name.clone(), bytes))
+            })
+            .collect()
+    }
+}
+
+fn encode(files: BTreeMap<String, Vec<u8>>) -> BTreeMap<String, String> {
+    files.into_iter().map(|(name, bytes)| (name, BASE64_STANDARD.encode(bytes))).collect()
+}
+
+/// Pack a session of `state`, running (with its `entry`) or not.
+pub fn export(state: &StateDir, session_id: &str, entry: Option<Entry>) -> Result<Bundle> {
+    Ok(Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        session: session_id.to_string(),
+        exported: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        entry,
+        files: encode(state.export(session_id)?),
+        criu: BTreeMap::new(),
+    })
+}
+
+/// Unpack a bundle into `state`, returning the session's screen as it was
+/// exported.
+pub fn import(state: &StateDir, bundle: &Bundle) -> Result<Replica> {
+    if bundle.format != BUNDLE_FORMAT {
+        bail!("Not a spectertty state bundle");
+    }
+    if bundle.version > BUNDLE_VERSION {
+        bail!("Bundle version {} is newer than this spectertty supports ({})", bundle.version, BUNDLE_VERSION);
+    }
+    ids::validate_session_id(&bundle.session)?;
+    state.import(&bundle.session, &Bundle::decode(&bundle.files)?)
+}
+
+fn open_state(state_dir: &Path, state_key: Option<&str>) -> Result<StateDir> {
+    let key = state_key.map(StateKey::load).transpose()?;
+    Ok(StateDir::open(state_dir).with_key(key))
+}
+
+/// Write a bundle of the session to `--output` or stdout. With `--criu`
+/// the session's processes are dumped first, which stops them, so the
+/// journal bundled is complete.
+pub fn export_state(args: &ExportStateArgs) -> Result<()> {
+    let state = open_state(&args.state_dir, args.state_key.as_deref())?;
+    // A running session is found by name too; one that has ended by ID
+    let entry = Registry::open(Registry::default_dir()).find(&args.session).ok();
+    let session_id = entry.as_ref().map_or_else(|| args.session.clone(), |entry| entry.id.clone());
+    // Checked before --criu stops the session, not after
+    if state.segments(&session_id).map_or(true, |segments| segments.is_empty()) {
+        bail!("Session '{}' has no journal in {}", session_id, args.state_dir.display());
+    }
+    let criu = match (args.criu, &entry) {
+        (true, Some(entry)) => criu_dump(entry)?,
+        (true, None) => bail!("--criu needs the session to be running"),
+        (false, _) => BTreeMap::new(),
+    };
+    let mut bundle = export(&state, &session_id, entry)?;
+    bundle.criu = encode(criu);
+
+    let json = serde_json::to_vec(&bundle)?;
+    match args.output {
+        Some(ref path) => {
+            fs::write(path, json).with_context(|| format!("Failed to write {:?}", path))?;
+            println!("Exported session {} to {}", session_id, path.display());
+        }
+        None => io::stdout().write_all(&json)?,
+    }
+    Ok(())
+}
+
+/// Add the bundled session to the state directory and, with `--criu`,
+/// bring its processes back and register it so `attach` finds it again.
+pub fn import_state(args: &ImportStateArgs) -> Result<()> {
+    let json = match args.bundle {
+        Some(ref path) => fs::read(path).with_context(|| format!("Failed to read {:?}", path))?,
+        None => {
+            let mut json = Vec::new();
+            io::stdin().read_to_end(&mut json)?;
+            json
+        }
+    };
+    let bundle: Bundle = serde_json::from_slice(&json).context("Not a spectertty state bundle")?;
+    if args.criu && bundle.criu.is_empty() {
+        bail!("The bundle has no CRIU image; export it with --criu");
+    }
+    let state = open_state(&args.state_dir, args.state_key.as_deref())?;
+    let replica = import(&state, &bundle)?;
+    let screen = replica.screen();
+    println!(
+        "Imported session {} at seq {} ({}x{}) into {}",
+        bundle.session,
+        replica.seq,
+        screen.cols,
+        screen.rows,
+        args.state_dir.display()
+    );
+
+    if args.criu {
+        criu_restore(&bundle.session, &Bundle::decode(&bundle.criu)?)?;
+        if let Some(ref entry) = bundle.entry {
+            Registry::open(Registry::default_dir()).register(entry)?.keep();
+        }
+        println!("Restored the session's processes");
+    }
+    Ok(())
+}
+
+/// A private scratch directory for CRIU images.
+fn images_dir(session_id: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("spectertty-criu-{}", session_id));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {:?}", dir))?;
+    Ok(dir)
+}
+
+fn criu(action: &str, dir: &Path, extra: &[&str]) -> Result<()> {
+    let status = Command::new("criu")
+        .arg(action)
+        .arg("--images-dir")
+        .arg(dir)
+        // The command's terminal and socket clients live outside the tree
+        .args(["--shell-job", "--ext-unix-sk"])
+        .args(extra)
+        .status()
+        .context("Failed to run criu; is it installed?")?;
+    if !status.success() {
+        bail!("criu {} failed with {}", action, status);
+    }
+    Ok(())
+}
+
+/// Dump the spectertty process serving `entry` and its command.
+fn criu_dump(entry: &Entry) -> Result<BTreeMap<String, Vec<u8>>> {
+    let dir = images_dir(&entry.id)?;
+    let dumped = criu("dump", &dir, &["--tree", &entry.pid.to_string()]).and_then(|()| {
+        let mut images = BTreeMap::new();
+        for file in fs::read_dir(&dir)?.flatten() {
+            if let Some(name) = file.file_name().to_str() {
+                images.insert(name.to_string(), fs::read(file.path())?);
+            }
+        }
+        Ok(images)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    dumped
+}
+
+fn criu_restore(session_id: &str, images: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+    let dir = images_dir(session_id)?;
+    let restored = images
+        .iter()
+        .try_for_each(|(name, bytes)| {
+            // Names come from elsewhere, so no paths
+            if name.contains('/') || name.starts_with('.') {
+                bail!("Unexpected CRIU image '{}'", name);
+            }
+            fs::write(dir.join(name), bytes).map_err(Into::into)
+        })
+        .and_then(|()| criu("restore", &dir, &["--restore-detached"]));
+    let _ = fs::remove_dir_all(&dir);
+    restored
+}
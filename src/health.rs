@@ -0,0 +1,109 @@
+use crate::frame::{Frame, FrameType};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::warn;
+
+/// Tick used for --self-mem-limit checks when no health interval is set.
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Point-in-time view of spectertty's own load.
+pub struct HealthSample {
+    pub frame_queue: usize,
+    pub control_queue: usize,
+    pub dropped_frames: u64,
+}
+
+pub struct HealthReport {
+    pub frame: Option<Frame>,
+    pub degrade: bool,
+}
+
+/// Watches the wrapper itself: event-loop lag, queue depths, dropped frames
+/// and resident memory, degrading gracefully past --self-mem-limit.
+pub struct Watchdog {
+    interval: Option<Interval>,
+    emit_frames: bool,
+    mem_limit: Option<u64>,
+    degraded: bool,
+}
+
+impl Watchdog {
+    pub fn new(health_interval: Option<Duration>, mem_limit: Option<u64>) -> Self {
+        let period = match (health_interval, mem_limit) {
+            (Some(period), _) => Some(period),
+            (None, Some(_)) => Some(DEFAULT_CHECK_INTERVAL),
+            (None, None) => None,
+        };
+
+        let interval = period.map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            // Lag is measured against the schedule, so never burst to catch up
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        Self {
+            interval,
+            emit_frames: health_interval.is_some(),
+            mem_limit,
+            degraded: false,
+        }
+    }
+
+    /// Wait for the next check; never resolves when the watchdog is off.
+    /// Returns how late the tick fired relative to its schedule.
+    pub async fn tick(&mut self) -> Duration {
+        match self.interval {
+            Some(ref mut interval) => {
+                let scheduled = interval.tick().await;
+                Instant::now().saturating_duration_since(scheduled)
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn check(&mut self, lag: Duration, sample: HealthSample) -> HealthReport {
+        let rss = resident_memory();
+
+        let mut degrade = false;
+        if let (Some(limit), Some(rss)) = (self.mem_limit, rss) {
+            if rss > limit && !self.degraded {
+                warn!("Resident memory {} exceeds --self-mem-limit {}, degrading", rss, limit);
+                self.degraded = true;
+                degrade = true;
+            }
+        }
+
+        if !self.emit_frames && !degrade {
+            return HealthReport { frame: None, degrade };
+        }
+
+        let mut stats = BTreeMap::new();
+        stats.insert("lag_ms".to_string(), lag.as_millis() as u64);
+        stats.insert("frame_queue".to_string(), sample.frame_queue as u64);
+        stats.insert("control_queue".to_string(), sample.control_queue as u64);
+        stats.insert("dropped_frames".to_string(), sample.dropped_frames);
+        if let Some(rss) = rss {
+            stats.insert("rss_bytes".to_string(), rss);
+        }
+
+        let mut frame = Frame::new(FrameType::Health).with_stats(stats);
+        if degrade {
+            frame = frame.with_reason("mem_limit_degraded".to_string());
+        }
+
+        HealthReport {
+            frame: Some(frame),
+            degrade,
+        }
+    }
+}
+
+/// Resident set size of this process, from /proc/self/status.
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
@@ -0,0 +1,233 @@
+use anyhow::{Context, Result};
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Shells `--shell-integration` knows how to hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// The shell `command` runs, by its file name (a leading `-`, as for
+    /// login shells, is ignored).
+    pub fn detect(command: &str) -> Option<Self> {
+        let name = Path::new(command).file_name()?.to_str()?.trim_start_matches('-');
+        match name {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+        }
+    }
+}
+
+/// Before each prompt: the last command's `D` mark with its status, then
+/// `A`. Before each command, from the DEBUG trap: its line as a 633 `E`
+/// mark, then `C`. The trap also fires for PROMPT_COMMAND, the user's
+/// included, so it only marks the first command after a prompt.
+const BASH_HOOKS: &str = r#"
+__spectertty_escape() {
+    local s=${1//\\/\\\\}
+    s=${s//;/\\x3b}
+    printf '%s' "${s//$'\n'/\\x0a}"
+}
+__spectertty_status() {
+    local code=$?
+    [ -n "$__spectertty_running" ] && printf '\e]133;D;%s\a' "$code"
+    __spectertty_running=
+    __spectertty_ready=
+}
+__spectertty_prompt() {
+    printf '\e]133;A\a'
+    __spectertty_ready=1
+}
+__spectertty_preexec() {
+    [ -n "$__spectertty_ready" ] && [ -z "$COMP_LINE" ] && [ "$BASH_COMMAND" != __spectertty_status ] || return 0
+    __spectertty_ready=
+    __spectertty_running=1
+    local line
+    line=$(HISTTIMEFORMAT= builtin history 1)
+    printf '\e]633;E;%s\a\e]133;C\a' "$(__spectertty_escape "${line#*[0-9]  }")"
+}
+PROMPT_COMMAND="__spectertty_status"$'\n'"$PROMPT_COMMAND"$'\n'"__spectertty_prompt"
+trap '__spectertty_preexec' DEBUG
+"#;
+
+const ZSH_HOOKS: &str = r#"
+__spectertty_precmd() {
+    local code=$?
+    [[ -n $__spectertty_running ]] && printf '\e]133;D;%s\a' $code
+    __spectertty_running=
+    printf '\e]133;A\a'
+}
+__spectertty_preexec() {
+    __spectertty_running=1
+    local s=${1//\\/\\\\}
+    s=${s//;/\\x3b}
+    printf '\e]633;E;%s\a\e]133;C\a' "${s//$'\n'/\\x0a}"
+}
+precmd_functions=(__spectertty_precmd $precmd_functions)
+preexec_functions+=(__spectertty_preexec)
+"#;
+
+const FISH_HOOKS: &str = r#"
+function __spectertty_preexec --on-event fish_preexec
+    set -l line (string split \n -- $argv[1] | string replace -a '\\' '\\\\' | string replace -a ';' '\\x3b' | string join '\x0a')
+    printf '\e]633;E;%s\a\e]133;C\a' "$line"
+end
+function __spectertty_postexec --on-event fish_postexec
+    printf '\e]133;D;%s\a' $status
+end
+function __spectertty_prompt --on-event fish_prompt
+    printf '\e]133;A\a'
+end
+"#;
+
+/// Hooks for `--shell-integration`: the interactive shell is started so it
+/// marks each prompt and command with OSC 133, and each command line with
+/// OSC 633, on top of the user's own startup files. The marks are read
+/// back into `command_start` and `command_end` frames with exit statuses.
+pub struct ShellHooks {
+    pub shell: Shell,
+    /// The shell's arguments, with what loads the hooks added
+    pub args: Vec<String>,
+    /// Variables to set for the shell
+    pub env: Vec<(String, String)>,
+    /// Startup files written for the shell, removed with the hooks
+    dir: Option<PathBuf>,
+}
+
+impl ShellHooks {
+    /// Hook `command` when it is bash, zsh or fish started as an
+    /// interactive shell, writing any startup files it needs under a
+    /// private directory named for `session_id`. Anything else is run
+    /// as it is, with a warning.
+    pub fn install(command: &str, args: &[String], session_id: &str) -> Result<Option<Self>> {
+        let Some(shell) = Shell::detect(command) else {
+            warn!("--shell-integration: {} is not bash, zsh or fish, so it is not hooked", command);
+            return Ok(None);
+        };
+        // A script or -c runs commands without prompts to mark
+        if args.iter().any(|arg| arg == "-c" || !arg.starts_with('-')) {
+            warn!("--shell-integration: {} is not run as an interactive shell, so it is not hooked", command);
+            return Ok(None);
+        }
+
+        let hooks = match shell {
+            Shell::Bash => {
+                let dir = private_dir(session_id)?;
+                let rcfile = dir.join("bashrc");
+                // --rcfile replaces ~/.bashrc, so it is read from there
+                // unless --norc asked for no startup file
+                let mut rc = String::new();
+                if !args.iter().any(|arg| arg == "--norc") {
+                    rc.push_str("[ -f ~/.bashrc ] && . ~/.bashrc\n");
+                }
+                rc.push_str(BASH_HOOKS);
+                write(&rcfile, &rc)?;
+                let mut hooked = vec!["--rcfile".to_string(), rcfile.to_string_lossy().into_owned()];
+                hooked.extend(args.iter().filter(|arg| *arg != "--norc").cloned());
+                Self {
+                    shell,
+                    args: hooked,
+                    env: Vec::new(),
+                    dir: Some(dir),
+                }
+            }
+            Shell::Zsh => {
+                // zsh reads its startup files from ZDOTDIR, pointed at ours,
+                // which read the user's in turn and leave ZDOTDIR as they set it
+                let dir = private_dir(session_id)?;
+                let user = std::env::var("ZDOTDIR").map_or_else(|_| "$HOME".to_string(), |dir| quote(&dir));
+                let zshenv = format!(
+                    concat!(
+                        "__spectertty_hooks=$ZDOTDIR\n",
+                        "ZDOTDIR={}\n",
+                        "{}\n",
+                        "__spectertty_zdotdir=$ZDOTDIR\n",
+                        "ZDOTDIR=$__spectertty_hooks\n",
+                    ),
+                    user,
+                    source_zsh(".zshenv")
+                );
+                write(&dir.join(".zshenv"), &zshenv)?;
+                write(&dir.join(".zprofile"), &source_zsh(".zprofile").replace("$ZDOTDIR", "$__spectertty_zdotdir"))?;
+                write(
+                    &dir.join(".zshrc"),
+                    &format!(
+                        "ZDOTDIR=$__spectertty_zdotdir\nunset __spectertty_hooks __spectertty_zdotdir\n{}\n{}",
+                        source_zsh(".zshrc"),
+                        ZSH_HOOKS
+                    ),
+                )?;
+                Self {
+                    shell,
+                    args: args.to_vec(),
+                    env: vec![("ZDOTDIR".to_string(), dir.to_string_lossy().into_owned())],
+                    dir: Some(dir),
+                }
+            }
+            Shell::Fish => {
+                let mut hooked = vec!["--init-command".to_string(), FISH_HOOKS.to_string()];
+                hooked.extend(args.iter().cloned());
+                Self {
+                    shell,
+                    args: hooked,
+                    env: Vec::new(),
+                    dir: None,
+                }
+            }
+        };
+        info!("Hooked {} for shell integration", shell.name());
+        Ok(Some(hooks))
+    }
+}
+
+impl Drop for ShellHooks {
+    fn drop(&mut self) {
+        if let Some(ref dir) = self.dir {
+            if let Err(e) = fs::remove_dir_all(dir) {
+                warn!("Failed to remove {:?}: {}", dir, e);
+            }
+        }
+    }
+}
+
+/// A directory only we can read, for the shell's startup files.
+fn private_dir(session_id: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("spectertty-shell-{}", session_id));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {:?}", dir))?;
+    Ok(dir)
+}
+
+fn source_zsh(file: &str) -> String {
+    format!("[[ -f $ZDOTDIR/{0} ]] && . $ZDOTDIR/{0}", file)
+}
+
+/// `text` as one shell word.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))
+}
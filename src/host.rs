@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use nix::pty::Winsize;
+use nix::sys::termios::{self, SetArg, Termios};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd};
+use tokio::sync::mpsc;
+
+nix::ioctl_read_bad!(get_window_size, nix::libc::TIOCGWINSZ, Winsize);
+
+/// The terminal spectertty itself is attached to, whose size the session
+/// follows so interactive programs see window changes.
+pub struct HostTerminal {
+    tty: File,
+}
+
+impl HostTerminal {
+    /// None when spectertty has no controlling terminal, e.g. under a
+    /// supervisor or in CI.
+    pub fn open() -> Option<Self> {
+        File::open("/dev/tty").ok().map(|tty| Self { tty })
+    }
+
+    /// Current size as (cols, rows).
+    pub fn size(&self) -> Option<(u16, u16)> {
+        let mut size = Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCGWINSZ only writes a winsize into `size`
+        unsafe { get_window_size(self.tty.as_raw_fd(), &mut size) }.ok()?;
+        (size.ws_col > 0 && size.ws_row > 0).then_some((size.ws_col, size.ws_row))
+    }
+}
+
+/// Keystrokes from stdin, read on a thread of their own: a read blocked
+/// on the terminal must not hold up shutdown.
+pub fn spawn_key_reader() -> mpsc::UnboundedReceiver<Vec<u8>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buf = [0u8; 1024];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// The local terminal in raw mode, so every key reaches the session,
+/// restored on drop.
+pub struct RawMode {
+    saved: Termios,
+}
+
+impl RawMode {
+    pub fn enable() -> Result<Self> {
+        let stdin = io::stdin();
+        let saved = termios::tcgetattr(stdin.as_fd()).context("Failed to read the terminal settings")?;
+        let mut raw = saved.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(stdin.as_fd(), SetArg::TCSADRAIN, &raw).context("Failed to put the terminal in raw mode")?;
+        Ok(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin().as_fd(), SetArg::TCSADRAIN, &self.saved);
+    }
+}
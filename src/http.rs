@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest HTTP head accepted.
+pub const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+/// Most headers a head may have.
+const MAX_HEADERS: usize = 64;
+
+/// A parsed HTTP request head.
+pub struct Request {
+    pub method: String,
+    /// Target without its query string
+    pub path: String,
+    /// Names lowercased
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Length of the body that follows, 0 without a `Content-Length`.
+    pub fn content_length(&self) -> io::Result<usize> {
+        match self.header("content-length") {
+            Some(length) => length.parse().map_err(|_| invalid("invalid Content-Length")),
+            None => Ok(0),
+        }
+    }
+}
+
+/// A parsed HTTP response head.
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.reason)
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// The request head in `head`, or `None` if it is not complete yet.
+fn parse_request(head: &[u8]) -> io::Result<Option<Request>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(invalid(&format!("malformed request head: {}", e))),
+    }
+    let target = request.path.unwrap_or("/");
+    Ok(Some(Request {
+        method: request.method.unwrap_or_default().to_string(),
+        path: target.split('?').next().unwrap_or("/").to_string(),
+        headers: request
+            .headers
+            .iter()
+            .map(|header| {
+                let value = String::from_utf8_lossy(header.value).trim().to_string();
+                (header.name.to_ascii_lowercase(), value)
+            })
+            .collect(),
+    }))
+}
+
+/// The response head in `head`, or `None` if it is not complete yet.
+fn parse_response(head: &[u8]) -> io::Result<Option<Response>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(invalid(&format!("malformed response head: {}", e))),
+    }
+    Ok(Some(Response {
+        status: response.code.unwrap_or_default(),
+        reason: response.reason.unwrap_or_default().to_string(),
+    }))
+}
+
+/// Read a request head, up to the blank line that ends it, leaving any
+/// body in `reader`. A head longer than `MAX_HEAD_BYTES`, including one
+/// endless line, is refused with `InvalidData` without being buffered.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Request> {
+    let mut limited = (&mut *reader).take(MAX_HEAD_BYTES as u64);
+    let mut head = Vec::new();
+    loop {
+        if limited.read_until(b'\n', &mut head).await? == 0 || !head.ends_with(b"\n") {
+            return Err(invalid("incomplete request head"));
+        }
+        if let Some(request) = parse_request(&head)? {
+            return Ok(request);
+        }
+    }
+}
+
+/// Read a response head from a blocking, unbuffered stream a byte at a
+/// time, so that nothing after it, such as a WebSocket's first frame, is
+/// taken from the stream.
+pub fn read_response<R: Read>(stream: &mut R) -> io::Result<Response> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            let reason = if head.is_empty() { "connection closed without a response" } else { "incomplete response head" };
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, reason));
+        }
+        head.push(byte[0]);
+        if head.len() > MAX_HEAD_BYTES {
+            return Err(invalid("response head too long"));
+        }
+        if byte[0] == b'\n' {
+            if let Some(response) = parse_response(&head)? {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Write a response head with these headers.
+pub async fn write_head<W: AsyncWrite + Unpin>(writer: &mut W, status: &str, headers: &[(&str, &str)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await
+}
+
+/// Write a whole response and close the connection.
+pub async fn respond<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    let length = body.len().to_string();
+    let headers = [headers, &[("Content-Length", length.as_str()), ("Connection", "close")]].concat();
+    write_head(writer, status, &headers).await?;
+    writer.write_all(body).await?;
+    writer.shutdown().await
+}
@@ -0,0 +1,360 @@
+use crate::emulator::{Emulator, ScreenState};
+use crate::error::SessionError;
+use crate::frame::{Frame, FrameType};
+use crate::pty::PtyHandle;
+use crate::session::Session;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Frames kept per session for subscribers that join late or fall behind.
+const HISTORY_FRAMES: usize = 4096;
+
+/// How long a finished session stays around for clients to read its end.
+const FINISHED_LINGER: Duration = Duration::from_secs(300);
+
+/// Sessions a hub runs at once unless told otherwise.
+pub const DEFAULT_MAX_SESSIONS: usize = 64;
+
+/// What to run in a session started on a client's request.
+#[derive(Debug, Clone)]
+pub struct SessionSpec {
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<PathBuf>,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl SessionSpec {
+    /// Whether a session started from `template` is what this asks for,
+    /// apart from its window size. A running shell cannot take on other
+    /// variables or another directory, so asking for those never matches.
+    fn served_by(&self, template: &SessionSpec) -> bool {
+        self.command == template.command && self.args == template.args && self.env.is_empty() && self.cwd.is_none()
+    }
+}
+
+/// Sessions the hub keeps started ahead of time, so that a client asking
+/// for `template` gets one whose rc files and virtualenv activation have
+/// already run.
+#[derive(Debug, Clone)]
+pub struct PoolSpec {
+    pub template: SessionSpec,
+    pub size: usize,
+}
+
+#[derive(Default)]
+struct Pool {
+    /// Started sessions no client has asked for yet, oldest first
+    warm: VecDeque<(String, Arc<Hosted>)>,
+    /// Sessions being started to top the pool up
+    starting: usize,
+}
+
+/// One session run by the hub: its input, and its frames numbered by
+/// `seq` and fanned out to every subscriber.
+struct Hosted {
+    /// Handed out to a client, and so counted against the limit
+    claimed: AtomicBool,
+    handle: PtyHandle,
+    /// Gone once the session has ended, which ends the subscriptions
+    frames: Mutex<Option<broadcast::Sender<Frame>>>,
+    history: Mutex<VecDeque<Frame>>,
+    /// The session's screen, as its output has drawn it
+    screen: Mutex<Emulator>,
+}
+
+/// Sessions started on request by the servers that manage several at once
+/// (`--grpc`, `--http`), each read on a task of its own so that clients
+/// can come and go. A finished session is kept for a while so a client
+/// can still read how it ended. At most `max_sessions` run at once, not
+/// counting those waiting in the warm pool.
+pub struct SessionHub {
+    sessions: Mutex<HashMap<String, Arc<Hosted>>>,
+    max_sessions: usize,
+    /// Sessions running or being started, counted against the limit
+    running: AtomicUsize,
+    pool_spec: Option<PoolSpec>,
+    pool: Mutex<Pool>,
+}
+
+impl SessionHub {
+    pub fn new() -> Arc<Self> {
+        Self::with_limit(DEFAULT_MAX_SESSIONS)
+    }
+
+    /// A hub that refuses to start more than `max_sessions` at once.
+    pub fn with_limit(max_sessions: usize) -> Arc<Self> {
+        Self::with_pool(max_sessions, None)
+    }
+
+    /// A hub that also keeps a warm pool of sessions, filled by `fill_pool`
+    /// and topped up each time one is handed out.
+    pub fn with_pool(max_sessions: usize, pool: Option<PoolSpec>) -> Arc<Self> {
+        Arc::new(Self {
+            sessions: Mutex::default(),
+            max_sessions,
+            running: AtomicUsize::new(0),
+            pool_spec: pool,
+            pool: Mutex::default(),
+        })
+    }
+
+    /// Start sessions until the warm pool is full. A session that fails to
+    /// start stops the filling, which the next one handed out retries.
+    pub async fn fill_pool(self: &Arc<Self>) {
+        let Some(ref spec) = self.pool_spec else { return };
+        loop {
+            {
+                let mut pool = self.pool.lock().unwrap();
+                if pool.warm.len() + pool.starting >= spec.size {
+                    return;
+                }
+                pool.starting += 1;
+            }
+            let started = self.spawn(spec.template.clone(), false).await;
+            let mut pool = self.pool.lock().unwrap();
+            pool.starting -= 1;
+            match started {
+                Ok(warm) => pool.warm.push_back(warm),
+                Err(e) => {
+                    warn!("Failed to start a pooled session: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sessions waiting in the warm pool.
+    pub fn pooled(&self) -> usize {
+        self.pool.lock().unwrap().warm.len()
+    }
+
+    /// Start a session, returning its ID.
+    pub async fn create(self: &Arc<Self>, spec: SessionSpec) -> Result<String, SessionError> {
+        let reserved = self
+            .running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                (running < self.max_sessions).then_some(running + 1)
+            });
+        if reserved.is_err() {
+            return Err(SessionError::Policy(format!(
+                "{} sessions are already running",
+                self.max_sessions
+            )));
+        }
+        if let Some(id) = self.claim(&spec) {
+            return Ok(id);
+        }
+        let result = self.spawn(spec, true).await;
+        let (id, hosted) = match result {
+            Ok(started) => started,
+            Err(e) => {
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        self.sessions.lock().unwrap().insert(id.clone(), hosted);
+        info!("Started session {}", id);
+        Ok(id)
+    }
+
+    /// Hand out a warm session if the pool has one for `spec`, sized as it
+    /// asks, and start its replacement.
+    fn claim(self: &Arc<Self>, spec: &SessionSpec) -> Option<String> {
+        if !self.pool_spec.as_ref().is_some_and(|pool| spec.served_by(&pool.template)) {
+            return None;
+        }
+        let (id, hosted) = self.pool.lock().unwrap().warm.pop_front()?;
+        hosted.claimed.store(true, Ordering::SeqCst);
+        if let Err(e) = hosted.handle.resize(spec.cols, spec.rows) {
+            warn!("Failed to resize pooled session {}: {}", id, e);
+        }
+        hosted.screen.lock().unwrap().resize(spec.cols, spec.rows);
+        self.sessions.lock().unwrap().insert(id.clone(), hosted);
+        info!("Handed out pooled session {}", id);
+        let hub = self.clone();
+        tokio::spawn(async move { hub.fill_pool().await });
+        Some(id)
+    }
+
+    /// Start a session and the task reading its frames, which keeps a
+    /// finished session around a while before forgetting it.
+    async fn spawn(self: &Arc<Self>, spec: SessionSpec, claimed: bool) -> Result<(String, Arc<Hosted>), SessionError> {
+        let mut builder = Session::builder(spec.command).args(spec.args).envs(spec.env).size(spec.cols, spec.rows);
+        if let Some(cwd) = spec.cwd {
+            builder = builder.cwd(cwd);
+        }
+        let mut session = builder.spawn().await?;
+        let id = session.id().to_string();
+        let (frames, _) = broadcast::channel(1024);
+        let hosted = Arc::new(Hosted {
+            claimed: AtomicBool::new(claimed),
+            handle: session.handle(),
+            frames: Mutex::new(Some(frames)),
+            history: Mutex::new(VecDeque::new()),
+            screen: Mutex::new(Emulator::new(spec.cols, spec.rows)),
+        });
+
+        let hub = self.clone();
+        let session_id = id.clone();
+        let reading = hosted.clone();
+        tokio::spawn(async move {
+            let hosted = reading;
+            let mut seq = 0;
+            while let Some(frame) = session.next_frame().await {
+                seq += 1;
+                hosted.publish(frame.with_seq(seq));
+            }
+            hosted.frames.lock().unwrap().take();
+            if hosted.claimed.load(Ordering::SeqCst) {
+                hub.running.fetch_sub(1, Ordering::SeqCst);
+            } else {
+                warn!("Pooled session {} ended before it was handed out", session_id);
+                hub.pool.lock().unwrap().warm.retain(|(id, _)| *id != session_id);
+            }
+            info!("Session {} ended", session_id);
+            tokio::time::sleep(FINISHED_LINGER).await;
+            hub.sessions.lock().unwrap().remove(&session_id);
+        });
+        Ok((id, hosted))
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<Hosted>, SessionError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| SessionError::InvalidRequest(format!("no session '{}'", id)))
+    }
+
+    /// Whether the hub has a session `id`, running or recently finished.
+    pub fn contains(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().contains_key(id)
+    }
+
+    /// End a session and forget it at once, rather than keeping it around
+    /// after its command exits. Its subscribers see the end of the stream.
+    pub fn remove(&self, id: &str) -> Result<(), SessionError> {
+        let hosted = self
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(id)
+            .ok_or_else(|| SessionError::InvalidRequest(format!("no session '{}'", id)))?;
+        // A session that already finished has nothing left to kill
+        let _ = hosted.handle.kill();
+        info!("Removed session {}", id);
+        Ok(())
+    }
+
+    pub fn send(&self, id: &str, data: Vec<u8>) -> Result<(), SessionError> {
+        self.get(id)?.handle.write(data)
+    }
+
+    pub fn resize(&self, id: &str, cols: u16, rows: u16) -> Result<(), SessionError> {
+        let hosted = self.get(id)?;
+        hosted.handle.resize(cols, rows)?;
+        hosted.screen.lock().unwrap().resize(cols, rows);
+        Ok(())
+    }
+
+    /// What the session's screen shows now.
+    pub fn screen(&self, id: &str) -> Result<ScreenState, SessionError> {
+        Ok(self.get(id)?.screen.lock().unwrap().state())
+    }
+
+    /// Send a signal by name (`SIGINT`, `TERM`, ...) to the session's
+    /// foreground process group.
+    pub fn signal(&self, id: &str, name: &str) -> Result<(), SessionError> {
+        self.get(id)?.handle.signal(crate::pty::parse_signal(name)?)
+    }
+
+    /// Follow a session's frames from the oldest one still kept.
+    pub fn subscribe(&self, id: &str) -> Result<Subscription, SessionError> {
+        let hosted = self.get(id)?;
+        // Under the history lock, so each frame is either replayed or live
+        let history = hosted.history.lock().unwrap();
+        let rx = hosted.frames.lock().unwrap().as_ref().map(broadcast::Sender::subscribe);
+        Ok(Subscription {
+            backlog: history.clone(),
+            rx,
+            hosted: hosted.clone(),
+            last_seq: 0,
+        })
+    }
+}
+
+impl Hosted {
+    fn publish(&self, frame: Frame) {
+        if matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            if let Some(data) = frame.bytes() {
+                self.screen.lock().unwrap().process(&data);
+            }
+        }
+        let mut history = self.history.lock().unwrap();
+        history.push_back(frame.clone());
+        if history.len() > HISTORY_FRAMES {
+            history.pop_front();
+        }
+        if let Some(ref frames) = *self.frames.lock().unwrap() {
+            let _ = frames.send(frame);
+        }
+    }
+}
+
+/// A client's copy of one session's frames.
+pub struct Subscription {
+    backlog: VecDeque<Frame>,
+    rx: Option<broadcast::Receiver<Frame>>,
+    hosted: Arc<Hosted>,
+    last_seq: u64,
+}
+
+impl Subscription {
+    /// Skip the frames up to and including `seq`, already seen by a
+    /// client that is resuming.
+    pub fn after(mut self, seq: u64) -> Self {
+        self.last_seq = seq;
+        self
+    }
+
+    /// The next frame, or None once the session has ended and every frame
+    /// has been read. A subscriber that falls behind catches up from the
+    /// history, with a `reconnect` frame counting what it missed.
+    pub async fn next(&mut self) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.backlog.pop_front() {
+                if frame.seq.is_some_and(|seq| seq <= self.last_seq) {
+                    continue;
+                }
+                self.last_seq = frame.seq.unwrap_or(self.last_seq);
+                return Some(frame);
+            }
+            match self.rx.as_mut()?.recv().await {
+                Ok(frame) => self.backlog.push_back(frame),
+                Err(broadcast::error::RecvError::Closed) => self.rx = None,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    let history = self.hosted.history.lock().unwrap();
+                    let missed: VecDeque<Frame> = history
+                        .iter()
+                        .filter(|frame| frame.seq.is_some_and(|seq| seq > self.last_seq))
+                        .cloned()
+                        .collect();
+                    warn!("Subscriber lagged by {} frames, replaying {}", n, missed.len());
+                    let stats = BTreeMap::from([("missed".to_string(), n), ("replayed".to_string(), missed.len() as u64)]);
+                    let notice = Frame::new(FrameType::Reconnect).with_reason("lagged".to_string()).with_stats(stats);
+                    self.backlog = missed;
+                    return Some(notice);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use ulid::Ulid;
+
+/// Placeholder in output paths replaced by the session ID.
+pub const SESSION_ID_PLACEHOLDER: &str = "{session_id}";
+
+/// Source of session identifiers, for embedders that want IDs from their
+/// own scheme without passing one to every `SessionBuilder`.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+/// ULIDs: unique without coordination and sortable by creation time.
+pub struct UlidGenerator;
+
+impl IdGenerator for UlidGenerator {
+    fn generate(&self) -> String {
+        Ulid::new().to_string()
+    }
+}
+
+pub fn new_session_id() -> String {
+    UlidGenerator.generate()
+}
+
+/// Check an externally assigned ID; IDs end up in file names and metric
+/// labels, so only a conservative character set is accepted.
+pub fn validate_session_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id.len() <= 128
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(anyhow!(
+            "Invalid session ID '{}': use up to 128 letters, digits, '-', '_' or '.'",
+            id
+        ));
+    }
+    Ok(())
+}
+
+/// Replace `{session_id}` in a path given on the command line.
+pub fn expand_path(path: &Path, session_id: &str) -> PathBuf {
+    match path.to_str() {
+        Some(text) if text.contains(SESSION_ID_PLACEHOLDER) => {
+            PathBuf::from(text.replace(SESSION_ID_PLACEHOLDER, session_id))
+        }
+        _ => path.to_path_buf(),
+    }
+}
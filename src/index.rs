@@ -0,0 +1,311 @@
+use crate::expect::strip_escapes;
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::DirBuilder;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tantivy::collector::DocSetCollector;
+use tantivy::query::{AllQuery, BooleanQuery, Query, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, OwnedValue, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED, STORED,
+};
+use tantivy::tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer};
+use tantivy::{doc, Index, IndexWriter, TantivyDocument, Term};
+
+/// Directory of a session's index, next to its journal segments.
+pub const INDEX_DIR: &str = "index";
+
+/// Tokenizer of the `text` field: every three characters, lowercased, so
+/// any substring of three or more characters can be looked up.
+const TRIGRAMS: &str = "trigrams";
+
+/// Memory the writer buffers documents in before it flushes them.
+const WRITER_MEMORY: usize = 15_000_000;
+
+/// How often new lines are committed, and so become searchable.
+const COMMIT_EVERY: Duration = Duration::from_secs(1);
+
+/// A line longer than this is ended where it is.
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Where a line of output starts: the journal frame holding its first
+/// character.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub ts: f64,
+    pub seq: Option<u64>,
+    /// Journal segment holding the frame
+    pub segment: u64,
+    /// Byte offset of the frame in the segment
+    pub offset: u64,
+}
+
+/// A whole line of output, escape sequences removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub at: Position,
+    pub text: String,
+}
+
+impl Line {
+    /// Whether the line contains `needle` (lowercase) and started at or
+    /// after `since`.
+    pub fn matches(&self, needle: &str, since: Option<f64>) -> bool {
+        self.text.to_lowercase().contains(needle) && since.is_none_or(|since| self.at.ts >= since)
+    }
+}
+
+/// Output frames joined back into lines, so that text split across two
+/// frames is found whole.
+#[derive(Default)]
+pub struct Lines {
+    partial: String,
+    start: Option<Position>,
+    /// Line feeds in the start frame before the line begins
+    start_skip: usize,
+    /// Line feeds to pass over in the next frame, which earlier lines end
+    skip: usize,
+}
+
+impl Lines {
+    /// Lines that pick up from a `Resume` point.
+    pub fn resuming(resume: &Resume) -> Self {
+        Self {
+            skip: resume.skip,
+            ..Self::default()
+        }
+    }
+
+    /// The lines an output frame at `offset` of `segment` completes.
+    pub fn push(&mut self, frame: &Frame, segment: u64, offset: u64) -> Vec<Line> {
+        let skip = std::mem::take(&mut self.skip);
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Vec::new();
+        }
+        let Some(text) = frame.text() else {
+            return Vec::new();
+        };
+        let at = Position {
+            ts: frame.ts,
+            seq: frame.seq,
+            segment,
+            offset,
+        };
+        let mut lines = Vec::new();
+        for (i, piece) in text.split('\n').enumerate().skip(skip) {
+            if i > skip {
+                lines.extend(self.take());
+            }
+            if piece.is_empty() {
+                continue;
+            }
+            if self.start.is_none() {
+                self.start = Some(at);
+                self.start_skip = i;
+            }
+            self.partial.push_str(piece);
+            if self.partial.len() > MAX_LINE_BYTES {
+                lines.extend(self.take());
+            }
+        }
+        lines
+    }
+
+    /// The line so far, ended early, as at the end of a journal.
+    pub fn take(&mut self) -> Option<Line> {
+        let at = self.start.take()?;
+        let text = strip_escapes(&std::mem::take(&mut self.partial));
+        Some(Line { at, text })
+    }
+
+    /// Where to pick up again with everything before the line so far
+    /// done with, or at `end` of `segment` if no line is under way.
+    fn resume(&self, segment: u64, end: u64) -> Resume {
+        match self.start {
+            Some(start) => Resume {
+                segment: start.segment,
+                offset: start.offset,
+                skip: self.start_skip,
+            },
+            None => Resume { segment, offset: end, skip: 0 },
+        }
+    }
+}
+
+/// How far into a journal the index goes: lines from here on are not in
+/// it yet. Saved with each commit.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Resume {
+    pub segment: u64,
+    /// Byte offset of the first frame not indexed in full
+    pub offset: u64,
+    /// Line feeds in that frame ending lines that are indexed
+    pub skip: usize,
+}
+
+struct Fields {
+    text: Field,
+    ts: Field,
+    seq: Field,
+    segment: Field,
+    offset: Field,
+}
+
+fn open(dir: &Path) -> Result<(Index, Fields)> {
+    let mut schema = Schema::builder();
+    let indexing = TextFieldIndexing::default()
+        .set_tokenizer(TRIGRAMS)
+        .set_index_option(IndexRecordOption::Basic);
+    let fields = Fields {
+        text: schema.add_text_field("text", TextOptions::default().set_indexing_options(indexing).set_stored()),
+        ts: schema.add_f64_field("ts", STORED),
+        seq: schema.add_u64_field("seq", STORED),
+        segment: schema.add_u64_field("segment", INDEXED | STORED | FAST),
+        offset: schema.add_u64_field("offset", STORED),
+    };
+    let index = Index::open_or_create(tantivy::directory::MmapDirectory::open(dir)?, schema.build())
+        .with_context(|| format!("Failed to open index {:?}", dir))?;
+    let trigrams = TextAnalyzer::builder(NgramTokenizer::all_ngrams(3, 3)?).filter(LowerCaser).build();
+    index.tokenizers().register(TRIGRAMS, trigrams);
+    Ok((index, fields))
+}
+
+/// The full-text index of an unsealed journal, kept up as it is written.
+/// Each line of output is a document, and a commit at most every
+/// `COMMIT_EVERY` makes new ones searchable.
+pub struct JournalIndex {
+    writer: IndexWriter,
+    fields: Fields,
+    lines: Lines,
+    /// Where the journal has been written up to
+    end: (u64, u64),
+    committed: Instant,
+}
+
+impl JournalIndex {
+    /// Start the index in `dir`, created private like the journal.
+    pub fn create(dir: &Path) -> Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+        let (index, fields) = open(dir)?;
+        let writer = index
+            .writer_with_num_threads(1, WRITER_MEMORY)
+            .with_context(|| format!("Failed to lock index {:?}", dir))?;
+        Ok(Self {
+            writer,
+            fields,
+            lines: Lines::default(),
+            end: (1, 0),
+            committed: Instant::now(),
+        })
+    }
+
+    /// Index the lines `frame`, journaled from `offset` to `end` of
+    /// `segment`, completes.
+    pub fn record(&mut self, frame: &Frame, segment: u64, offset: u64, end: u64) -> Result<()> {
+        for line in self.lines.push(frame, segment, offset) {
+            self.add(line)?;
+        }
+        self.end = (segment, end);
+        if self.committed.elapsed() >= COMMIT_EVERY {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the lines of segments before `segment`, which a checkpoint
+    /// has replaced.
+    pub fn forget_before(&mut self, segment: u64) -> Result<()> {
+        let field = "segment".to_string();
+        self.writer.delete_query(Box::new(RangeQuery::new_u64(field, 0..segment)))?;
+        self.commit()
+    }
+
+    /// Index the line left unfinished and commit.
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(line) = self.lines.take() {
+            self.add(line)?;
+        }
+        self.commit()
+    }
+
+    fn add(&mut self, line: Line) -> Result<()> {
+        let fields = &self.fields;
+        let mut document = doc!(
+            fields.text => line.text,
+            fields.ts => line.at.ts,
+            fields.segment => line.at.segment,
+            fields.offset => line.at.offset,
+        );
+        if let Some(seq) = line.at.seq {
+            document.add_u64(fields.seq, seq);
+        }
+        self.writer.add_document(document)?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let resume = self.lines.resume(self.end.0, self.end.1);
+        let mut commit = self.writer.prepare_commit()?;
+        commit.set_payload(&serde_json::to_string(&resume)?);
+        commit.commit()?;
+        self.committed = Instant::now();
+        Ok(())
+    }
+}
+
+/// Lines in the index at `dir` containing `needle` (lowercase), stamped at
+/// or after `since`, in journal order, and where the journal goes on past
+/// the index. The trigrams of `needle` narrow the lines down, and each
+/// is then checked for `needle` itself.
+pub fn search(dir: &Path, needle: &str, since: Option<f64>) -> Result<(Vec<Line>, Resume)> {
+    let (index, fields) = open(dir)?;
+    let resume = match index.load_metas()?.payload {
+        Some(payload) => serde_json::from_str(&payload).with_context(|| format!("Corrupt index {:?}", dir))?,
+        None => Resume::default(),
+    };
+    let mut trigrams = BTreeSet::new();
+    index
+        .tokenizer_for_field(fields.text)?
+        .token_stream(needle)
+        .process(&mut |token| {
+            trigrams.insert(token.text.clone());
+        });
+    let query: Box<dyn Query> = if trigrams.is_empty() {
+        Box::new(AllQuery)
+    } else {
+        let terms = trigrams.into_iter().map(|trigram| {
+            let term = Term::from_field_text(fields.text, &trigram);
+            Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>
+        });
+        Box::new(BooleanQuery::intersection(terms.collect()))
+    };
+    let searcher = index.reader()?.searcher();
+    let mut lines = Vec::new();
+    let mut addresses: Vec<_> = searcher.search(&query, &DocSetCollector)?.into_iter().collect();
+    addresses.sort();
+    for address in addresses {
+        let document: TantivyDocument = searcher.doc(address)?;
+        let get = |field| document.get_first(field);
+        let line = Line {
+            at: Position {
+                ts: get(fields.ts).and_then(|value| value.as_f64()).unwrap_or_default(),
+                seq: get(fields.seq).and_then(|value| value.as_u64()),
+                segment: get(fields.segment).and_then(|value| value.as_u64()).unwrap_or_default(),
+                offset: get(fields.offset).and_then(|value| value.as_u64()).unwrap_or_default(),
+            },
+            text: get(fields.text).and_then(|value: &OwnedValue| value.as_str()).unwrap_or_default().to_string(),
+        };
+        if line.matches(needle, since) {
+            lines.push(line);
+        }
+    }
+    lines.sort_by_key(|line| (line.at.segment, line.at.offset));
+    Ok((lines, resume))
+}
@@ -0,0 +1,227 @@
+use crate::control::ClientId;
+use crate::error::SessionError;
+use crate::frame::{Frame, FrameType};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Input patterns held for approval when no --destructive-regex is given.
+const DEFAULT_DESTRUCTIVE_PATTERNS: &[&str] = &[
+    r"\brm\s+(-[a-zA-Z]*[rf][a-zA-Z]*\s+)+",
+    r"\bmkfs(\.\w+)?\b",
+    r"\bdd\s+.*\bof=",
+    r"(?i)\bdrop\s+(table|database|schema)\b",
+    r"(?i)\btruncate\s+table\b",
+    r"\bgit\s+push\s+.*(--force|-f)\b",
+    r"\b(shutdown|reboot|halt|poweroff)\b",
+    r">\s*/dev/[sh]d[a-z]",
+];
+
+/// Number of distinct approvers needed to release held input.
+const REQUIRED_APPROVALS: usize = 2;
+
+struct PendingInput {
+    data: String,
+    requester: String,
+    approvals: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct VoteOutcome {
+    pub frames: Vec<Frame>,
+    pub release: Option<String>,
+}
+
+/// Two-person integrity gate: input matching a destructive pattern is held
+/// until two distinct authenticated clients, other than the requester,
+/// approve it. Any single authenticated denial discards it.
+///
+/// Nothing runs before Enter, so input is matched together with what the
+/// same client has typed on the line so far: `rm -r` and then `f /\n` are
+/// held just as `rm -rf /\n` is.
+pub struct ApprovalGate {
+    patterns: Vec<Regex>,
+    pending: HashMap<String, PendingInput>,
+    next_id: u64,
+    /// What each client has typed since its last Enter
+    lines: HashMap<String, String>,
+}
+
+impl ApprovalGate {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let sources: Vec<&str> = if patterns.is_empty() {
+            DEFAULT_DESTRUCTIVE_PATTERNS.to_vec()
+        } else {
+            patterns.iter().map(String::as_str).collect()
+        };
+
+        let patterns = sources
+            .into_iter()
+            .map(Regex::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Invalid destructive pattern: {}", e))?;
+
+        Ok(Self {
+            patterns,
+            pending: HashMap::new(),
+            next_id: 1,
+            lines: HashMap::new(),
+        })
+    }
+
+    /// Whether `data` would be held back for approval.
+    pub fn requires_approval(&self, data: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(data))
+    }
+
+    /// Returns the ApprovalRequired frame when `data` is held back. Its
+    /// `data` is the line as the command would see it, including what the
+    /// client typed on it before.
+    pub fn check(&mut self, client: &ClientId, data: &str) -> Option<Frame> {
+        let typed = edit_line(self.lines.get(&client.label()).map_or("", String::as_str), data);
+        let Some(pattern) = self.patterns.iter().find(|p| p.is_match(&typed)) else {
+            self.typed(&client.label(), data);
+            return None;
+        };
+
+        let id = format!("approval-{}", self.next_id);
+        self.next_id += 1;
+
+        let frame = Frame::new(FrameType::ApprovalRequired)
+            .with_id(id.clone())
+            .with_client(client.label())
+            .with_regex(pattern.as_str().to_string())
+            .with_data(typed);
+
+        self.pending.insert(
+            id,
+            PendingInput {
+                data: data.to_string(),
+                requester: client.label(),
+                approvals: Vec::new(),
+            },
+        );
+
+        Some(frame)
+    }
+
+    pub fn vote(&mut self, client: &ClientId, id: &str, approve: bool) -> VoteOutcome {
+        let mut outcome = VoteOutcome::default();
+
+        let Some(voter) = client.name.clone() else {
+            return rejected(client, id, "unauthenticated clients cannot vote");
+        };
+        let Some(pending) = self.pending.get_mut(id) else {
+            return rejected(client, id, "unknown approval");
+        };
+        if pending.requester == voter {
+            return rejected(client, id, "requesters cannot approve their own input");
+        }
+        if pending.approvals.contains(&voter) {
+            return rejected(client, id, "already voted");
+        }
+
+        let decision = if approve { "approve" } else { "deny" };
+        outcome.frames.push(
+            Frame::new(FrameType::ApprovalVote)
+                .with_id(id.to_string())
+                .with_client(voter.clone())
+                .with_reason(decision.to_string()),
+        );
+
+        if !approve {
+            self.pending.remove(id);
+            outcome.frames.push(
+                Frame::new(FrameType::ApprovalDenied)
+                    .with_id(id.to_string())
+                    .with_client(voter),
+            );
+            return outcome;
+        }
+
+        pending.approvals.push(voter);
+        if pending.approvals.len() >= REQUIRED_APPROVALS {
+            let pending = self.pending.remove(id).expect("pending approval present");
+            outcome.frames.push(
+                Frame::new(FrameType::ApprovalGranted)
+                    .with_id(id.to_string())
+                    .with_client(pending.approvals.join(","))
+                    .with_data(pending.data.clone()),
+            );
+            self.typed(&pending.requester, &pending.data);
+            outcome.release = Some(pending.data);
+        }
+
+        outcome
+    }
+
+    /// Drop the line `client` was typing, once its connection has closed.
+    pub fn forget(&mut self, client: &ClientId) {
+        self.lines.remove(&client.label());
+    }
+
+    /// Note input from `requester` reaching the command.
+    fn typed(&mut self, requester: &str, data: &str) {
+        let line = self.lines.entry(requester.to_string()).or_default();
+        let typed = edit_line(line, data);
+        // Only the line after the last Enter is still being typed
+        *line = match typed.rfind(['\r', '\n']) {
+            Some(end) => typed[end + 1..].to_string(),
+            None => typed,
+        };
+    }
+
+    /// Withdraw held input at its requester's request, returning the
+    /// `cancelled` frame, or None when nothing is held under `id`.
+    pub fn cancel(&mut self, client: &ClientId, id: &str) -> Result<Option<Frame>, SessionError> {
+        let Some(pending) = self.pending.get(id) else {
+            return Ok(None);
+        };
+        if pending.requester != client.label() {
+            return Err(SessionError::Policy(format!(
+                "cancel of {} rejected: only its requester can cancel it",
+                id
+            )));
+        }
+        let pending = self.pending.remove(id).expect("pending approval present");
+        Ok(Some(
+            Frame::new(FrameType::Cancelled)
+                .with_id(id.to_string())
+                .with_reason("approval".to_string())
+                .with_data(pending.data),
+        ))
+    }
+}
+
+/// `line` with `input` typed onto it, applying the line editing keys a
+/// shell honours: backspace removes a character, Ctrl-U and Ctrl-C drop
+/// the line.
+fn edit_line(line: &str, input: &str) -> String {
+    let mut typed = line.to_string();
+    for c in input.chars() {
+        match c {
+            '\x7f' | '\x08' => {
+                if !typed.ends_with(['\r', '\n']) {
+                    typed.pop();
+                }
+            }
+            '\x15' | '\x03' => {
+                let start = typed.rfind(['\r', '\n']).map_or(0, |end| end + 1);
+                typed.truncate(start);
+            }
+            c => typed.push(c),
+        }
+    }
+    typed
+}
+
+/// Ignore a vote, telling controllers why via a `policy` error frame.
+fn rejected(client: &ClientId, id: &str, why: &str) -> VoteOutcome {
+    let error = SessionError::Policy(format!("vote on {} rejected: {}", id, why));
+    warn!("Ignoring vote from {}: {}", client.label(), error);
+    VoteOutcome {
+        frames: vec![error.to_frame().with_id(id.to_string()).with_client(client.label())],
+        release: None,
+    }
+}
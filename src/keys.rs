@@ -0,0 +1,131 @@
+/// Keys that send a CSI sequence, with the final byte and the number that
+/// carries modifiers: `ESC [ <n> ; <mods> <final>`.
+const CSI_KEYS: &[(&str, &str, char)] = &[
+    ("Up", "1", 'A'),
+    ("Down", "1", 'B'),
+    ("Right", "1", 'C'),
+    ("Left", "1", 'D'),
+    ("Home", "1", 'H'),
+    ("End", "1", 'F'),
+    ("F1", "1", 'P'),
+    ("F2", "1", 'Q'),
+    ("F3", "1", 'R'),
+    ("F4", "1", 'S'),
+    ("Insert", "2", '~'),
+    ("Delete", "3", '~'),
+    ("PageUp", "5", '~'),
+    ("PageDown", "6", '~'),
+    ("F5", "15", '~'),
+    ("F6", "17", '~'),
+    ("F7", "18", '~'),
+    ("F8", "19", '~'),
+    ("F9", "20", '~'),
+    ("F10", "21", '~'),
+    ("F11", "23", '~'),
+    ("F12", "24", '~'),
+];
+
+/// Modifiers held down with a key.
+#[derive(Debug, Default, Clone, Copy)]
+struct Modifiers {
+    shift: bool,
+    alt: bool,
+    ctrl: bool,
+}
+
+impl Modifiers {
+    /// The xterm modifier parameter, 1 for none.
+    fn param(&self) -> u8 {
+        1 + self.shift as u8 + 2 * self.alt as u8 + 4 * self.ctrl as u8
+    }
+}
+
+/// What the terminal sends for `key`: a key name such as `Enter`, `Tab`,
+/// `Up`, `PageDown` or `F5`, a single character, or either of those after
+/// modifiers joined with `+` (`Ctrl+C`, `Alt+b`, `Ctrl+Shift+Up`). Text
+/// that names no key is typed as it is.
+pub fn key_bytes(key: &str) -> String {
+    let (mods, base) = split_modifiers(key);
+
+    if let Some((_, number, last)) = CSI_KEYS.iter().find(|(name, ..)| *name == base) {
+        let param = mods.param();
+        return match (param, *number, *last) {
+            (1, _, 'P'..='S') => format!("\x1bO{}", last),
+            (1, "1", _) => format!("\x1b[{}", last),
+            (1, _, _) => format!("\x1b[{}{}", number, last),
+            _ => format!("\x1b[{};{}{}", number, param, last),
+        };
+    }
+
+    let plain = match base {
+        "Enter" => "\r",
+        "Tab" if mods.shift => "\x1b[Z",
+        "Tab" => "\t",
+        "Backspace" if mods.ctrl => "\x08",
+        "Backspace" => "\x7f",
+        "Escape" | "Esc" => "\x1b",
+        "Space" if mods.ctrl => "\0",
+        "Space" => " ",
+        _ => "",
+    };
+    let mut text = if !plain.is_empty() {
+        plain.to_string()
+    } else {
+        match single_char(base) {
+            Some(c) if mods.ctrl && (c.is_ascii_alphabetic() || "@[\\]^_".contains(c)) => {
+                char::from(c.to_ascii_uppercase() as u8 & 0x1f).to_string()
+            }
+            Some(c) if mods.shift && !mods.ctrl => c.to_uppercase().to_string(),
+            Some(_) if !mods.ctrl => base.to_string(),
+            // A chord with no meaning to a terminal is typed as written
+            _ if mods.param() > 1 => return key.to_string(),
+            _ => base.to_string(),
+        }
+    };
+    if mods.alt {
+        text.insert(0, '\x1b');
+    }
+    text
+}
+
+/// The keystrokes `keys` stand for, one per named key and one per
+/// character of text, so that input can be typed at a human pace.
+pub fn keystrokes(keys: &[impl AsRef<str>]) -> Vec<String> {
+    let mut strokes = Vec::new();
+    for key in keys {
+        let key = key.as_ref();
+        let bytes = key_bytes(key);
+        if bytes == key {
+            strokes.extend(key.chars().map(String::from));
+        } else {
+            strokes.push(bytes);
+        }
+    }
+    strokes
+}
+
+/// Modifiers before the last `+` of `key` and the key they apply to; no
+/// modifiers and all of `key` when it is not a chord.
+fn split_modifiers(key: &str) -> (Modifiers, &str) {
+    let mut mods = Modifiers::default();
+    let mut rest = key;
+    while let Some((name, after)) = rest.split_once('+') {
+        if after.is_empty() {
+            break;
+        }
+        match name {
+            "Shift" => mods.shift = true,
+            "Alt" | "Meta" => mods.alt = true,
+            "Ctrl" | "Control" => mods.ctrl = true,
+            _ => return (Modifiers::default(), key),
+        }
+        rest = after;
+    }
+    (mods, rest)
+}
+
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
@@ -0,0 +1,103 @@
+//! SpecterTTY runs commands under a pseudo-terminal and reports everything
+//! they do as structured frames. The `spectertty` binary is a thin CLI over
+//! this library; Rust programs can embed a session directly:
+//!
+//! ```no_run
+//! use spectertty::Session;
+//! use std::time::Duration;
+//!
+//! # async fn demo() -> anyhow::Result<()> {
+//! let mut session = Session::builder("bash").arg("--norc").size(100, 30).spawn().await?;
+//! session.send("echo ready\n")?;
+//! let found = session.wait_for(r"ready\r?\n", Duration::from_secs(5)).await?;
+//! println!("{}", found.to_json()?);
+//! session.send("exit\n")?;
+//! let code = session.wait().await;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod anomaly;
+pub mod asciicast;
+pub mod attach;
+pub mod audit;
+pub mod bell;
+pub mod capsule;
+pub mod cgroup;
+pub mod chaos;
+pub mod clipboard;
+pub mod cli;
+pub mod control;
+pub mod controller;
+pub mod convert;
+pub mod emulator;
+pub mod env;
+pub mod envelope;
+pub mod error;
+pub mod expect;
+pub mod expiry;
+pub mod explore;
+pub mod flake;
+pub mod frame;
+pub mod framelog;
+pub mod handover;
+pub mod grpc;
+pub mod health;
+pub mod hooks;
+pub mod host;
+pub mod http;
+pub mod hub;
+pub mod index;
+pub mod ids;
+pub mod integrity;
+pub mod keys;
+pub mod logging;
+pub mod markup;
+pub mod metrics;
+pub mod player;
+pub mod pretty;
+pub mod notify;
+pub mod osc;
+pub mod overload;
+pub mod processor;
+pub mod prompt;
+pub mod pty;
+pub mod reaper;
+pub mod recorder;
+pub mod redact;
+pub mod registers;
+pub mod registry;
+pub mod remote;
+pub mod respond;
+pub mod rest;
+pub mod restart;
+pub mod routing;
+pub mod sampling;
+pub mod sched;
+pub mod script;
+pub mod seal;
+pub mod schema;
+pub mod scrub;
+pub mod segment;
+pub mod session;
+pub mod shadow;
+pub mod sidecar;
+pub mod sink;
+pub mod state;
+pub mod strict;
+pub mod structure;
+pub mod summarize;
+pub mod syslog;
+pub mod termios;
+pub mod throttle;
+pub mod tokens;
+pub mod transfer;
+pub mod transcript;
+pub mod transport;
+pub mod ttyd;
+pub mod ttyrec;
+pub mod typescript;
+
+pub use error::SessionError;
+pub use frame::{Frame, FrameType};
+pub use session::{Session, SessionBuilder};
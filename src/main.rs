@@ -1,136 +1,909 @@
-mod cli;
-mod frame;
-mod pty;
-mod processor;
-mod recorder;
-
-use cli::Cli;
-use processor::OutputProcessor;
-use pty::PtySession;
-use recorder::RecordingManager;
-
-use anyhow::Result;
+use spectertty::anomaly::AnomalyDetector;
+use spectertty::audit::AuditLog;
+use spectertty::framelog::FrameLog;
+use spectertty::capsule::Capsule;
+use spectertty::cgroup::IoCgroup;
+use spectertty::cli::{Cli, Commands, KillChildren};
+use spectertty::controller::Controller;
+use spectertty::envelope::{self, SessionInfo, SessionTally};
+use spectertty::expiry::{Expiry, IdleExit, REAP_GRACE};
+use spectertty::explore::{self, Explorer};
+use spectertty::grpc::GrpcServer;
+use spectertty::health::{HealthSample, Watchdog};
+use spectertty::hooks::ShellHooks;
+use spectertty::host::HostTerminal;
+use spectertty::hub::SessionHub;
+use spectertty::ids;
+use spectertty::logging;
+use spectertty::integrity::ApprovalGate;
+use spectertty::metrics::{Metrics, MetricsServer};
+use spectertty::notify::Notifier;
+use spectertty::overload::Ladder;
+use spectertty::pretty::PrettyPrinter;
+use spectertty::processor::OutputProcessor;
+use spectertty::reaper;
+use spectertty::recorder::{self, RecordingManager};
+use spectertty::redact::SecretRedactor;
+use spectertty::registry::{self, Entry, Registry};
+use spectertty::remote::RecordUrl;
+use spectertty::rest::RestServer;
+use spectertty::restart::Supervisor;
+use spectertty::scrub::PiiScrubber;
+use spectertty::segment::Segmenter;
+use spectertty::shadow::Shadow;
+use spectertty::sidecar::Sidecars;
+use spectertty::seal::StateKey;
+use spectertty::sink::FrameSinks;
+use spectertty::state::{self, StateDir};
+use spectertty::strict::{self, StrictStdout, STRICT_STATUS};
+use spectertty::syslog::{JournaldSink, SyslogSink, JOURNALD_SOCKET, SYSLOG_SOCKET};
+use spectertty::summarize::Summarizer;
+use spectertty::throttle::OutputLimiter;
+use spectertty::transport::{self, SocketOptions, SocketServer};
+use spectertty::ttyd::TtydServer;
+use spectertty::{
+    attach, convert, flake, handover, player, pretty, pty, schema, script, transcript, transfer, Frame, FrameType,
+    Session,
+};
+
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::signal;
-use tracing::{error, info, Level};
-use tracing_subscriber;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::{error, info, info_span, warn, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    
-    // Initialize logging
-    let level = if cli.verbose { Level::DEBUG } else { Level::INFO };
-    tracing_subscriber::fmt()
-        .with_max_level(level)
-        .with_target(false)
-        .init();
+    let mut cli = Cli::parse();
+    // `shadow` runs a session like any other, with this terminal as its
+    // own; stdout belongs to the command
+    let shadowing = matches!(cli.subcommand, Some(Commands::Shadow(_)));
+
+    // Initialize logging; subcommands report on stdout, so keep them quiet
+    let level = if cli.verbose {
+        Level::DEBUG
+    } else if cli.subcommand.is_some() {
+        Level::WARN
+    } else {
+        Level::INFO
+    };
+    let session_id = cli.session_id.clone().unwrap_or_else(ids::new_session_id);
+    // Frames on stdout cannot share it with log lines
+    let (writer, ansi) = match cli.log_file {
+        Some(ref path) => {
+            ids::validate_session_id(&session_id)?;
+            let path = ids::expand_path(path, &session_id);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open log file {:?}", path))?;
+            if cli.ndjson_strict && strict::is_stdout(&file) {
+                return Err(anyhow!("--ndjson-strict: --log-file {:?} is stdout", path));
+            }
+            (BoxMakeWriter::new(Arc::new(file)), false)
+        }
+        None if cli.json || shadowing => (BoxMakeWriter::new(std::io::stderr), true),
+        None => (BoxMakeWriter::new(std::io::stdout), true),
+    };
+    logging::init(cli.log_format, level, writer, ansi);
+
+    match cli.subcommand {
+        Some(Commands::Script(ref args)) => {
+            if !script::run(args).await? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Play(ref args)) => return player::run(args).await,
+        Some(Commands::Convert(ref args)) => return convert::run(args).await,
+        Some(Commands::Transcript(ref args)) => return transcript::run(args).await,
+        Some(Commands::Cp(ref args)) => return transfer::run(args).await,
+        Some(Commands::Attach(ref args)) => return attach::run(args).await,
+        Some(Commands::List(ref args)) => return registry::print_list(args),
+        Some(Commands::Kill(ref args)) => return registry::kill_session(args),
+        Some(Commands::Pretty(ref args)) => return pretty::run(args).await,
+        Some(Commands::Schema(ref args)) => return schema::run(args),
+        Some(Commands::Flake(ref args)) => {
+            if !flake::run(args).await? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::ExportState(ref args)) => return handover::export_state(args),
+        Some(Commands::ImportState(ref args)) => return handover::import_state(args),
+        Some(Commands::Search(ref args)) => {
+            if !state::run(args)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Shadow(ref args)) => {
+            if cli.json || cli.pretty {
+                return Err(anyhow!("shadow shows the command on stdout, so frames cannot go there too"));
+            }
+            cli.command = Some(args.command.clone().unwrap_or_else(explore::shell));
+            cli.args = args.args.clone();
+            if let Some((cols, rows)) = HostTerminal::open().as_ref().and_then(HostTerminal::size) {
+                (cli.cols, cli.rows) = (cols, rows);
+            }
+        }
+        None => {}
+    }
 
     // Validate CLI arguments
     cli.validate()?;
 
+    // --grpc and --http serve sessions made on request instead of running one
+    if cli.grpc.is_some() || cli.http.is_some() {
+        let tokens = transport::parse_auth_tokens(&cli.auth_token)?;
+        let hub = SessionHub::with_pool(cli.max_sessions, cli.pool());
+        let filling = hub.clone();
+        tokio::spawn(async move { filling.fill_pool().await });
+        let _rest = match cli.http {
+            Some(addr) => Some(RestServer::bind(addr, hub.clone(), tokens.clone())?),
+            None => None,
+        };
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        let shutdown = async move {
+            tokio::select! {
+                _ = signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        };
+        return match cli.grpc {
+            Some(addr) => GrpcServer::bind(addr, hub, tokens)?.serve(shutdown).await,
+            None => {
+                shutdown.await;
+                Ok(())
+            }
+        };
+    }
+
+    // Before anything else can write to stdout
+    let (strict, mut stray_rx) = if cli.ndjson_strict {
+        let (strict, stray_rx) = StrictStdout::claim()?;
+        (Some(strict), Some(stray_rx))
+    } else {
+        (None, None)
+    };
+
+    let target = cli
+        .command
+        .clone()
+        .or_else(|| cli.explore.map(|_| explore::shell()))
+        .ok_or_else(|| anyhow!("No command given"))?;
+
+    let command_str = format!("{} {}", target, cli.args.join(" "));
+    // Every log line from here on names the session it belongs to
+    let _session_span = info_span!(
+        "session",
+        session_id = %session_id,
+        pid = std::process::id(),
+        command = %command_str.trim()
+    )
+    .entered();
+
     info!("Starting SpecterTTY v{}", env!("CARGO_PKG_VERSION"));
-    info!("Command: {} {:?}", cli.command, cli.args);
-
-    // Create PTY session
-    let session = PtySession::new(
-        &cli.command,
-        &cli.args,
-        cli.cols,
-        cli.rows,
-        cli.prompt_regex.clone(),
-        cli.idle_duration(),
-    ).await?;
+    info!("Command: {} {:?}", target, cli.args);
+    info!("Session: {}", session_id);
+    // Every random choice derives from one seed, so a run can be replayed
+    let seed = cli.seed.unwrap_or_else(|| fastrand::u64(..));
+    info!("Seed: {}", seed);
+
+    // Run the target inside capsule-run when sandboxing is requested
+    let capsule = cli
+        .capsule
+        .then(|| Capsule::new(cli.capsule_bin.clone(), cli.sandbox_profile.clone()));
+    // Hook the shell to mark its commands before anything wraps it
+    let hooks = match cli.shell_integration {
+        true => ShellHooks::install(&target, &cli.args, &session_id)?,
+        false => None,
+    };
+    let target_args = hooks.as_ref().map_or(&cli.args, |hooks| &hooks.args);
+    let (command, args) = match capsule {
+        Some(ref capsule) => capsule.wrap(&target, target_args),
+        None => (target.clone(), target_args.clone()),
+    };
 
     // Create output processor
-    let mut processor = OutputProcessor::new(cli.token_mode);
+    let mut processor = OutputProcessor::new(cli.token_mode, cli.cols, cli.rows)
+        .preserve_color(cli.preserve_color)
+        .dedupe_lines(cli.dedupe_lines)
+        .snapshot_interval(cli.snapshot_interval);
+    if let Some(ms) = cli.coalesce_ms {
+        processor = processor.coalesce(Duration::from_millis(ms), cli.coalesce_max as usize);
+    }
+
+    let mut detector = cli
+        .detect_anomalies
+        .then(|| AnomalyDetector::new(cli.anomaly_spike_factor, cli.anomaly_entropy));
 
     // Create recording manager
     let mut recording_manager = RecordingManager::new();
-    if let Some(ref record_path) = cli.record {
-        let command_str = format!("{} {}", cli.command, cli.args.join(" "));
-        recording_manager.start_recording(record_path, cli.cols, cli.rows, Some(command_str))?;
-        info!("Recording to: {:?}", record_path);
+    recording_manager.set_idle_limit(cli.record_idle_limit);
+    let mut meta = BTreeMap::from([
+        ("session_id".to_string(), session_id.clone()),
+        ("spectertty_version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+    ]);
+    if let Some(host) = envelope::hostname() {
+        meta.insert("host".to_string(), host);
+    }
+    meta.extend(cli.meta.iter().cloned());
+    recording_manager.set_metadata(cli.label.clone(), meta);
+
+    // Claim the name before anything is started under it
+    let registration = Registry::open(Registry::default_dir()).register(&Entry {
+        id: session_id.clone(),
+        name: cli.name.clone(),
+        pid: std::process::id(),
+        command: command_str.trim().to_string(),
+        started: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        socket: cli.socket.as_deref().map(std::path::absolute).transpose()?,
+        ttyd: cli.ttyd.map(|addr| addr.to_string()),
+    })?;
+    // --explore records the session whether or not --record is given
+    let record_path = cli
+        .record
+        .clone()
+        .or_else(|| cli.explore.map(|_| PathBuf::from(explore::DEFAULT_RECORDING)))
+        .map(|path| ids::expand_path(&path, &session_id));
+    if let Some(ref record_path) = record_path {
+        match cli.record_rotation() {
+            Some(rotation) => {
+                recording_manager.start_segmented_recording(
+                    record_path,
+                    cli.cols,
+                    cli.rows,
+                    Some(command_str.clone()),
+                    cli.record_format,
+                    rotation,
+                )?;
+                info!("Recording to: {:?}", recorder::segment_path(record_path, 1));
+            }
+            None => {
+                recording_manager.start_recording(record_path, cli.cols, cli.rows, Some(command_str.clone()), cli.record_format)?;
+                info!("Recording to: {:?}", record_path);
+            }
+        }
+    }
+    if let Some(ref url) = cli.record_url {
+        let mut headers = vec![("X-Spectertty-Session".to_string(), session_id.clone())];
+        headers.extend(cli.record_header.iter().cloned());
+        recording_manager.start_streaming(
+            RecordUrl::parse(url)?,
+            headers,
+            cli.cols,
+            cli.rows,
+            Some(command_str.clone()),
+            cli.record_format,
+        )?;
+        info!("Streaming recording to: {}", url);
+    }
+
+    // Set up control channels
+    let tokens = transport::parse_auth_tokens(&cli.auth_token)?;
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    if cli.json {
+        transport::spawn_stdin_control(tokens.clone(), control_tx.clone());
+    }
+    let metrics = cli.metrics_addr.map(|_| Metrics::new(&session_id));
+    let _metrics_server = match (cli.metrics_addr, &metrics) {
+        (Some(addr), Some(metrics)) => Some(MetricsServer::bind(addr, metrics.clone())?),
+        _ => None,
+    };
+    let ttyd = match cli.ttyd {
+        Some(addr) => Some(TtydServer::bind(
+            addr,
+            command_str.trim().to_string(),
+            tokens.clone(),
+            control_tx.clone(),
+            cli.ttyd_writable,
+        )?),
+        None => None,
+    };
+    let keepalive = cli.keepalive();
+    let chaos_socket = match (&cli.chaos_socket, cli.chaos) {
+        (Some(path), Some(chaos)) => {
+            let options = SocketOptions {
+                chaos: Some((chaos, seed)),
+                keepalive,
+            };
+            Some(SocketServer::bind_with(path, tokens.clone(), control_tx.clone(), cli.format, options)?)
+        }
+        _ => None,
+    };
+    let options = SocketOptions { chaos: None, keepalive };
+    let tcp = match cli.bind {
+        Some(addr) => Some(SocketServer::bind_tcp(addr, tokens.clone(), control_tx.clone(), cli.format, options)?),
+        None => None,
+    };
+    let socket = match cli.socket {
+        Some(ref path) => Some(SocketServer::bind_with(path, tokens, control_tx, cli.format, options)?),
+        None => None,
+    };
+
+    let gate = if cli.two_person {
+        Some(ApprovalGate::new(&cli.destructive_regex)?)
+    } else {
+        None
+    };
+
+    let audit = match cli.audit_log {
+        Some(ref path) => Some(AuditLog::open(ids::expand_path(path, &session_id))?),
+        None => None,
+    };
+
+    let frame_log = match cli.frame_log {
+        Some(ref path) => Some(FrameLog::create(ids::expand_path(path, &session_id))?),
+        None => None,
+    };
+    let journal = match cli.state_dir {
+        Some(ref dir) => {
+            let key = cli.state_key.as_deref().map(StateKey::load).transpose()?;
+            let state = StateDir::open(dir).with_key(key).with_checkpoints(cli.state_checkpoint);
+            Some(state.create_journal(&session_id)?)
+        }
+        None => None,
+    };
+
+    let syslog = if cli.syslog {
+        Some(SyslogSink::connect(SYSLOG_SOCKET)?)
+    } else {
+        None
+    };
+    let journald = if cli.journald {
+        Some(JournaldSink::connect(JOURNALD_SOCKET)?)
+    } else {
+        None
+    };
+
+    let mut sinks = FrameSinks {
+        json: cli.json || cli.pretty,
+        format: cli.format,
+        pretty: cli.pretty.then(PrettyPrinter::for_stdout),
+        strict,
+        socket,
+        chaos_socket,
+        tcp,
+        ttyd,
+        recording: recording_manager,
+        frame_log,
+        journal,
+        audit,
+        syslog,
+        journald,
+        metrics: metrics.clone(),
+        notifier: match cli.notify_cmd {
+            Some(ref command) => Some(Notifier::new(command.clone(), &cli.notify_on, &cli.notify_regex)?),
+            None => None,
+        },
+        routes: cli.routes()?,
+        session_id: Some(session_id.clone()),
+        redactor: SecretRedactor::from_args(&cli.redact)?,
+        scrubber: PiiScrubber::from_args(&cli.pii),
+        next_seq: 1,
+        ts_format: cli.ts_format,
+        ts_origin: None,
+        tokenizer: (cli.count_tokens || cli.token_budget.is_some()).then_some(cli.tokenizer),
+        summarizer: cli
+            .summarize_cmd
+            .clone()
+            .map(|command| Summarizer::new(command, cli.summarize_after as usize)),
+    };
+
+    let command_env = cli.command_env()?;
+
+    // The envelope opens the stream, ahead of anything the command or a
+    // sidecar produces
+    let start = SessionInfo {
+        name: cli.name.as_deref(),
+        command: &target,
+        args: &cli.args,
+        cwd: command_env.working_dir(),
+        env: command_env.resolved_vars(),
+        cols: cli.cols,
+        rows: cli.rows,
+        seed,
+    };
+    sinks.emit(&start.frame())?;
+    let mut tally = SessionTally::new();
+
+    // --limit-io-* put the command in a cgroup of its own
+    let io_limits = cli.io_limits();
+    let mut io_cgroup = if io_limits.is_empty() {
+        None
+    } else {
+        Some(IoCgroup::create(&format!("spectertty-{}", session_id), io_limits)?)
+    };
+    let mut scheduling = cli.scheduling()?;
+    scheduling.cgroup = io_cgroup.as_ref().map(|cgroup| cgroup.path().to_path_buf());
+
+    // The "before" picture of the directory --explore reports changes to
+    let mut explorer = match cli.explore {
+        Some(_) => Some(
+            Explorer::start(
+                session_id.clone(),
+                command_str.trim().to_string(),
+                match cli.cwd {
+                    Some(ref cwd) => cwd.clone(),
+                    None => std::env::current_dir()?,
+                },
+                record_path.clone(),
+                cli.explore_report.clone(),
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    // Start sidecars first so they see what the command does from the start
+    let (sidecar_tx, mut sidecar_rx) = mpsc::unbounded_channel();
+    let mut sidecars = Sidecars::spawn(&cli.sidecar, sidecar_tx)?;
+
+    // Create PTY session; a command that cannot start is still reported on
+    // every sink so automation sees a well-formed failure
+    let mut builder = Session::builder(command)
+        .args(args)
+        .envs(command_env.vars)
+        .envs(hooks.iter().flat_map(|hooks| hooks.env.clone()))
+        .clear_env(command_env.clear)
+        .size(cli.cols, cli.rows)
+        .idle_timeout(cli.idle_duration())
+        .split_stderr(cli.split_stderr)
+        .termios(cli.termios())
+        .scheduling(scheduling)
+        .session_id(session_id);
+    if let Some(pace) = cli.throttle_input {
+        builder = builder.throttle_input(pace);
+    }
+    if let Some(rate) = cli.write_rate {
+        builder = builder.write_rate(rate);
+    }
+    if let Some(dir) = command_env.cwd {
+        builder = builder.cwd(dir);
+    }
+    for pattern in &cli.prompt_regex {
+        builder = builder.prompt_regex(pattern);
+    }
+    for (name, pattern) in &cli.prompt {
+        builder = builder.prompt(name, pattern);
+    }
+    for (pattern, response) in &cli.auto_respond {
+        builder = builder.auto_respond(pattern, response, cli.auto_respond_limit);
+    }
+    let mut session = match builder.clone().spawn().await {
+        Ok(session) => session,
+        Err(e) => {
+            error!("{}", e);
+            let status = e.exit_status();
+            sinks.emit(&e.to_frame())?;
+            let exit = Frame::new(FrameType::Exit)
+                .with_exit_code(status)
+                .with_reason(e.category().to_string());
+            tally.observe(&exit);
+            sinks.emit(&exit)?;
+            sinks.emit(&tally.end_frame())?;
+            sinks.finish()?;
+            drop(sinks);
+            drop(io_cgroup);
+            drop(registration);
+            sidecars.shutdown();
+            std::process::exit(status);
+        }
+    };
+
+    // Only once the command runs, so errors before it reach a sane terminal
+    let mut shadow = match shadowing {
+        true => Some(Shadow::start()?),
+        false => None,
+    };
+
+    let mut watchdog = Watchdog::new(cli.health_interval(), cli.self_mem_limit);
+    let mut ladder = Ladder::new(cli.buffer, cli.overflow_timeout());
+    let mut limiter = OutputLimiter::new(cli.max_frames_per_sec, cli.sample_after);
+    if let Some(budget) = cli.token_budget {
+        limiter = limiter.token_budget(budget, cli.tokenizer);
     }
 
     // Set up signal handling
     let mut sigint = signal::unix::signal(signal::unix::SignalKind::interrupt())?;
     let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    let mut sighup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    let mut sigwinch = signal::unix::signal(signal::unix::SignalKind::window_change())?;
+    let host = HostTerminal::open();
+
+    let mut controller = Controller::new(gate, seed);
+
+    let mut expiry = Expiry::new(cli.session_ttl.or(cli.explore), cli.reap_idle)
+        .with_timeout(cli.timeout)
+        .with_max_output(cli.max_output_bytes);
+    let prompts = cli.prompt_patterns();
+    let mut idle_exit = IdleExit::new(cli.exit_on_idle.map(Duration::from_millis), &prompts)?;
+    let mut segmenter = match cli.segment_commands {
+        true => Some(Segmenter::new(&prompts)?),
+        false => None,
+    };
+    let mut reap_deadline: Option<Instant> = None;
+    let mut kill_deadline: Option<Instant> = None;
+    let mut limit_status: Option<i32> = None;
+    let mut supervisor = Supervisor::new(cli.restart);
+    let mut restart_at: Option<Instant> = None;
 
-    // Start background tasks
-    let mut stdout = io::stdout();
-    
-    // Split session into runner and receiver
-    let (runner, mut frame_rx) = session.split();
-    
-    // Start PTY session background task
-    let mut session_task = tokio::spawn(async move {
-        runner.run().await
-    });
-    
     // Main event loop
     loop {
         tokio::select! {
             // Handle frames from PTY
-            frame = frame_rx.recv() => {
+            frame = session.next_frame(), if restart_at.is_none() => {
                 match frame {
                     Some(frame) => {
-                        // Process frame through token processor
-                        let processed_frames = processor.process_frame(frame).await?;
-                        
-                        // Output frames
-                        for frame in processed_frames {
-                            // Record frame if recording is enabled
-                            recording_manager.record_frame(&frame)?;
-                            
-                            if cli.json {
-                                let json = frame.to_json()?;
-                                println!("{}", json);
-                                stdout.flush()?;
+                        if let Some(ref mut shadow) = shadow {
+                            shadow.show(&frame).await?;
+                        }
+                        tally.observe(&frame);
+                        if let Some(ref mut explorer) = explorer {
+                            explorer.observe(&frame);
+                        }
+                        if let Some(ref metrics) = metrics {
+                            metrics.observe_pty(&frame);
+                        }
+                        expiry.observe(&frame);
+                        idle_exit.observe(&frame);
+
+                        // Inspect raw output before the processor rewrites it
+                        let mut observed = match detector {
+                            Some(ref mut detector) => detector.observe(&frame),
+                            None => Vec::new(),
+                        };
+                        if let Some(ref mut segmenter) = segmenter {
+                            observed.extend(segmenter.observe(&frame));
+                        }
+
+                        for frame in observe_capsule(&capsule, frame) {
+                            // Process frame through token processor
+                            let processed_frames = processor.process_frame(frame).await?;
+
+                            // Output frames, summarized past the rate limits and
+                            // thinned when the sinks fall behind
+                            let limited = processed_frames.into_iter().flat_map(|frame| limiter.filter(frame));
+                            for frame in limited.flat_map(|frame| ladder.filter(frame)) {
+                                sinks.emit(&frame)?;
                             }
                         }
+
+                        for frame in observed {
+                            sinks.emit(&frame)?;
+                        }
                     }
                     None => {
                         info!("Frame stream ended");
+                        // --restart, unless the command was told to stop
+                        if reap_deadline.is_none() && kill_deadline.is_none() {
+                            if let Some((frame, wait)) = supervisor.exited(session.exit_code()) {
+                                info!("Restarting the command in {}ms", wait.as_millis());
+                                if let Some(frame) = clean_up_orphans(&session, cli.kill_children).await? {
+                                    sinks.emit(&frame)?;
+                                }
+                                sinks.emit(&frame)?;
+                                restart_at = Some(Instant::now() + wait);
+                                continue;
+                            }
+                        }
                         break;
                     }
                 }
             }
-            
+
+            // Start the command again once the --restart backoff is over
+            _ = sleep_until(restart_at.unwrap_or_else(Instant::now)), if restart_at.is_some() => {
+                restart_at = None;
+                match builder.clone().spawn().await {
+                    Ok(restarted) => {
+                        session = restarted;
+                        sinks.emit(&supervisor.restarted())?;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        sinks.emit(&e.to_frame())?;
+                        let exit = Frame::new(FrameType::Exit)
+                            .with_exit_code(e.exit_status())
+                            .with_reason(e.category().to_string());
+                        tally.observe(&exit);
+                        sinks.emit(&exit)?;
+                        let Some((frame, wait)) = supervisor.exited(exit.code) else {
+                            break;
+                        };
+                        sinks.emit(&frame)?;
+                        restart_at = Some(Instant::now() + wait);
+                    }
+                }
+            }
+
+            // Output --coalesce-ms held back, once its window closes
+            _ = sleep_until(processor.coalesce_deadline().unwrap_or_else(Instant::now)), if processor.coalesce_deadline().is_some() => {
+                let limited = processor.flush_coalesced().into_iter().flat_map(|frame| limiter.filter(frame));
+                for frame in limited.flat_map(|frame| ladder.filter(frame)) {
+                    sinks.emit(&frame)?;
+                }
+            }
+
+            // What --max-frames-per-sec and --sample-after have dropped lately
+            frame = limiter.summary() => {
+                sinks.emit(&frame)?;
+            }
+
+            // Keys typed by the person a shadow session is run for
+            keys = async { shadow.as_mut().unwrap().keys().await }, if shadow.is_some() => {
+                if let Err(e) = session.send(keys) {
+                    warn!("Failed to pass keys to the command: {}", e);
+                }
+            }
+
+            // What --summarize-cmd made of the output it took over
+            Some(frame) = async { sinks.summarizer.as_mut().unwrap().next().await }, if sinks.summarizer.is_some() => {
+                sinks.emit(&frame)?;
+            }
+
+            // Output of --sidecar commands, already tagged with its source
+            Some(frame) = sidecar_rx.recv() => {
+                sinks.emit(&frame)?;
+            }
+
+            // Handle commands from controllers
+            Some(event) = control_rx.recv() => {
+                if let Err(e) = controller.handle(event, &mut session, &mut processor, &mut sinks) {
+                    warn!("Control command failed: {}", e);
+                }
+            }
+
+            // --ndjson-strict: something other than a frame reached stdout
+            stray = async { stray_rx.as_mut().unwrap().recv().await }, if stray_rx.is_some() => {
+                let Some(stray) = stray else {
+                    stray_rx = None;
+                    continue;
+                };
+                let text = String::from_utf8_lossy(&stray);
+                error!("--ndjson-strict: non-frame output on stdout: {:?}", text);
+                let frame = Frame::new(FrameType::Error)
+                    .with_reason("ndjson_strict".to_string())
+                    .with_data(format!("Non-frame output on stdout: {}", text));
+                sinks.emit(&frame)?;
+                limit_status = Some(STRICT_STATUS);
+                break;
+            }
+
+            // Report on spectertty's own health
+            lag = watchdog.tick() => {
+                let sample = HealthSample {
+                    frame_queue: session.pending_frames(),
+                    control_queue: control_rx.len(),
+                    dropped_frames: sinks.dropped_frames(),
+                };
+                let report = watchdog.check(lag, sample);
+                if report.degrade {
+                    for frame in processor.degrade() {
+                        sinks.emit(&frame)?;
+                    }
+                }
+                if let Some(frame) = report.frame {
+                    sinks.emit(&frame)?;
+                }
+            }
+
+            // Step the degradation ladder when output piles up
+            _ = ladder.tick() => {
+                let check = ladder.check(session.pending_bytes());
+                if let Some(signal) = check.signal {
+                    if let Err(e) = session.signal(signal) {
+                        warn!("Failed to send {} to the command: {}", signal, e);
+                    }
+                }
+                for frame in check.frames {
+                    sinks.emit(&frame)?;
+                }
+            }
+
+            // Report when --limit-io-* start or stop holding the command back
+            _ = async { io_cgroup.as_mut().unwrap().tick().await }, if io_cgroup.is_some() => {
+                if let Some(frame) = io_cgroup.as_mut().and_then(IoCgroup::check) {
+                    sinks.emit(&frame)?;
+                }
+            }
+
+            // Terminate sessions that outlive --session-ttl or --reap-idle;
+            // the exit frame and recording still finish normally.
+            // --timeout and --max-output-bytes escalate from SIGTERM to
+            // SIGKILL and fail the run
+            frame = expiry.expired() => {
+                info!("Session expired ({}), terminating", frame.reason.as_deref().unwrap_or_default());
+                if let Some(ref mut explorer) = explorer {
+                    explorer.observe(&frame);
+                }
+                sinks.emit(&frame)?;
+                limit_status = Expiry::exit_status(&frame);
+                if limit_status.is_some() {
+                    if let Err(e) = session.signal("SIGTERM") {
+                        warn!("Failed to terminate session: {}", e);
+                    }
+                    kill_deadline = Some(Instant::now() + REAP_GRACE);
+                } else {
+                    if let Err(e) = session.kill() {
+                        warn!("Failed to terminate expired session: {}", e);
+                    }
+                    reap_deadline = Some(Instant::now() + REAP_GRACE);
+                }
+            }
+            _ = sleep_until(kill_deadline.unwrap_or_else(Instant::now)), if kill_deadline.is_some() => {
+                warn!("Session ignored SIGTERM for {}s, killing it", REAP_GRACE.as_secs());
+                if let Err(e) = session.signal("SIGKILL").and_then(|()| session.kill()) {
+                    warn!("Failed to kill session: {}", e);
+                }
+                kill_deadline = None;
+                reap_deadline = Some(Instant::now() + REAP_GRACE);
+            }
+            // --exit-on-idle: the command looks done, so end its input
+            frame = idle_exit.ready() => {
+                info!("Output idle, sending EOF to finish the session");
+                sinks.emit(&frame)?;
+                if let Err(e) = session.send([0x04]) {
+                    warn!("Failed to send EOF: {}", e);
+                }
+                reap_deadline = Some(Instant::now() + REAP_GRACE);
+            }
+            _ = sleep_until(reap_deadline.unwrap_or_else(Instant::now)), if reap_deadline.is_some() => {
+                warn!("Session still running {}s after being told to stop, closing it", REAP_GRACE.as_secs());
+                break;
+            }
+
+            // Follow the size of the terminal spectertty runs in
+            _ = sigwinch.recv(), if host.is_some() => {
+                if let Some((cols, rows)) = host.as_ref().and_then(HostTerminal::size) {
+                    info!("Host terminal resized to {}x{}", cols, rows);
+                    if let Err(e) = session.resize(cols, rows) {
+                        warn!("Failed to follow host terminal size: {}", e);
+                        sinks.emit(&e.to_frame())?;
+                    }
+                }
+            }
+
             // Handle signals
             _ = sigint.recv() => {
-                info!("Received SIGINT, shutting down");
-                break;
+                if !forward_signal(&cli, &session, "SIGINT") {
+                    break;
+                }
             }
             _ = sigterm.recv() => {
-                info!("Received SIGTERM, shutting down");
-                break;
+                if !forward_signal(&cli, &session, "SIGTERM") {
+                    break;
+                }
             }
-            
-            // Check session task
-            result = &mut session_task => {
-                match result {
-                    Ok(Ok(())) => info!("PTY session completed"),
-                    Ok(Err(e)) => error!("PTY session error: {}", e),
-                    Err(e) => error!("PTY task error: {}", e),
+            _ = sighup.recv() => {
+                if !forward_signal(&cli, &session, "SIGHUP") {
+                    break;
                 }
-                break;
             }
         }
     }
 
-    let session_handle = session_task;
+    // Stop the session, then drain frames it produced before stopping and
+    // anything the processor is still holding back
+    for frame in ladder.flush() {
+        sinks.emit(&frame)?;
+    }
+    for frame in session.close() {
+        if let Some(ref mut shadow) = shadow {
+            shadow.show(&frame).await?;
+        }
+        tally.observe(&frame);
+        if let Some(ref mut explorer) = explorer {
+            explorer.observe(&frame);
+        }
+        if let Some(ref metrics) = metrics {
+            metrics.observe_pty(&frame);
+        }
+        let turns = match segmenter {
+            Some(ref mut segmenter) => segmenter.observe(&frame),
+            None => Vec::new(),
+        };
+        for frame in observe_capsule(&capsule, frame) {
+            for frame in processor.process_frame(frame).await? {
+                for frame in limiter.filter(frame) {
+                    sinks.emit(&frame)?;
+                }
+            }
+        }
+        for frame in turns {
+            sinks.emit(&frame)?;
+        }
+    }
+    // Give the person their terminal back as it was
+    drop(shadow);
+    for frame in processor.flush_buffer() {
+        for frame in limiter.filter(frame) {
+            sinks.emit(&frame)?;
+        }
+    }
+    if let Some(frame) = limiter.flush() {
+        sinks.emit(&frame)?;
+    }
+    if let Some(frame) = segmenter.as_mut().and_then(Segmenter::finish) {
+        sinks.emit(&frame)?;
+    }
+    // Processes the command left behind, such as a shell's background jobs
+    if let Some(frame) = clean_up_orphans(&session, cli.kill_children).await? {
+        sinks.emit(&frame)?;
+    }
+    sidecars.shutdown();
+    while let Ok(frame) = sidecar_rx.try_recv() {
+        sinks.emit(&frame)?;
+    }
+    if let Some(ref mut summarizer) = sinks.summarizer {
+        for frame in summarizer.finish().await {
+            sinks.emit(&frame)?;
+        }
+    }
 
-    // Clean shutdown
-    session_handle.abort();
-    
-    // Stop recording if active
-    if recording_manager.is_recording() {
-        recording_manager.stop_recording()?;
-        info!("Recording stopped");
+    let end = tally.end_frame();
+    if let Some(explorer) = explorer {
+        if let Some(report) = explorer.finish(&end).await? {
+            sinks.emit(&report)?;
+        }
     }
-    
+    sinks.emit(&end)?;
+
+    // Stop recording if active
+    sinks.finish()?;
+
     info!("SpecterTTY shutdown complete");
+    // A shadow session exits as its command did, as the shell it stands
+    // in for would
+    if shadowing && limit_status.is_none() {
+        let signalled = end.signal.as_deref().and_then(|name| pty::parse_signal(name).ok());
+        limit_status = end.code.or(signalled.map(|signal| 128 + signal as i32));
+    }
+    if let Some(status) = limit_status {
+        drop(sinks);
+        drop(controller);
+        drop(io_cgroup);
+        drop(registration);
+        std::process::exit(status);
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Deal with the processes the session's command left running, as
+/// --kill-children says, off the async workers.
+async fn clean_up_orphans(session: &Session, policy: KillChildren) -> Result<Option<Frame>> {
+    let Some(pid) = session.pid() else {
+        return Ok(None);
+    };
+    Ok(tokio::task::spawn_blocking(move || reaper::clean_up(pid as i32, policy)).await?)
+}
+
+/// Pass a signal spectertty received on to the command when
+/// --forward-signals is set; false means shut down instead.
+fn forward_signal(cli: &Cli, session: &Session, name: &str) -> bool {
+    if !cli.forward_signals {
+        info!("Received {}, shutting down", name);
+        return false;
+    }
+    info!("Forwarding {} to the command", name);
+    if let Err(e) = session.signal(name) {
+        warn!("Failed to forward {}: {}", name, e);
+    }
+    true
+}
+
+fn observe_capsule(capsule: &Option<Capsule>, frame: Frame) -> Vec<Frame> {
+    match capsule {
+        Some(capsule) => capsule.observe(frame),
+        None => vec![frame],
+    }
+}
@@ -0,0 +1,190 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+const COLORS: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// An SGR sequence and its parameters.
+fn sgr_regex() -> &'static Regex {
+    static SGR: OnceLock<Regex> = OnceLock::new();
+    SGR.get_or_init(|| Regex::new(r"\x1b\[([0-9;:]*)m").unwrap())
+}
+
+/// Text attributes as SGR sets them.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    blink: bool,
+    inverse: bool,
+    hidden: bool,
+    strike: bool,
+    fg: Option<String>,
+    bg: Option<String>,
+}
+
+impl Style {
+    /// The opening tag for text in this style, or None for plain text.
+    fn tag(&self) -> Option<String> {
+        let flags = [
+            (self.bold, "bold"),
+            (self.dim, "dim"),
+            (self.italic, "italic"),
+            (self.underline, "underline"),
+            (self.blink, "blink"),
+            (self.inverse, "inverse"),
+            (self.hidden, "hidden"),
+            (self.strike, "strike"),
+        ];
+        let mut names: Vec<String> = flags.iter().filter(|(on, _)| *on).map(|(_, name)| name.to_string()).collect();
+        names.extend(self.fg.clone());
+        names.extend(self.bg.as_ref().map(|bg| format!("on-{}", bg)));
+        (!names.is_empty()).then(|| format!("<{}>", names.join(" ")))
+    }
+
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';').map(|code| code.split(':').collect::<Vec<_>>());
+        while let Some(code) = codes.next() {
+            let number = |part: Option<&&str>| part.and_then(|part| part.parse::<u32>().ok());
+            match number(code.first()).unwrap_or(0) {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = number(code.get(1)) != Some(0),
+                5 | 6 => self.blink = true,
+                7 => self.inverse = true,
+                8 => self.hidden = true,
+                9 => self.strike = true,
+                21 => self.underline = true,
+                22 => (self.bold, self.dim) = (false, false),
+                23 => self.italic = false,
+                24 => self.underline = false,
+                25 => self.blink = false,
+                27 => self.inverse = false,
+                28 => self.hidden = false,
+                29 => self.strike = false,
+                n @ 30..=37 => self.fg = Some(COLORS[n as usize - 30].to_string()),
+                n @ 40..=47 => self.bg = Some(COLORS[n as usize - 40].to_string()),
+                n @ 90..=97 => self.fg = Some(format!("bright-{}", COLORS[n as usize - 90])),
+                n @ 100..=107 => self.bg = Some(format!("bright-{}", COLORS[n as usize - 100])),
+                39 => self.fg = None,
+                49 => self.bg = None,
+                n @ (38 | 48) => {
+                    // 38;5;N and 38;2;R;G;B, or the same joined with colons
+                    let rest: Vec<u32> = if code.len() > 1 {
+                        code[1..].iter().filter(|part| !part.is_empty()).filter_map(|part| part.parse().ok()).collect()
+                    } else {
+                        let kind = codes.next().and_then(|kind| kind[0].parse().ok());
+                        let count = if kind == Some(5) { 1 } else { 3 };
+                        kind.into_iter()
+                            .chain(codes.by_ref().take(count).filter_map(|part| part[0].parse().ok()))
+                            .collect()
+                    };
+                    let color = match rest[..] {
+                        [5, index] => Some(indexed(index)),
+                        [2, r, g, b] | [2, _, r, g, b] => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+                        _ => None,
+                    };
+                    if n == 38 {
+                        self.fg = color;
+                    } else {
+                        self.bg = color;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn indexed(index: u32) -> String {
+    match index {
+        0..=7 => COLORS[index as usize].to_string(),
+        8..=15 => format!("bright-{}", COLORS[index as usize - 8]),
+        _ => format!("color{}", index),
+    }
+}
+
+/// Rewrites SGR sequences as lightweight markup for `--preserve-color`:
+/// styled text is wrapped in a tag naming its attributes and colors,
+/// `<bold red>error</>`, with backgrounds as `on-<color>`, bright colors
+/// as `bright-<color>`, 256-color indexes as `color<N>` and true color as
+/// `#rrggbb`. Tags close at each line end and reopen on the next line,
+/// so every line of output stands on its own. Other escape sequences are
+/// left for the caller to strip.
+#[derive(Debug, Default)]
+pub struct ColorMarkup {
+    style: Style,
+    /// A tag is open in the output
+    open: bool,
+    escape: Escape,
+}
+
+/// Where the text is in an escape sequence, which may span reads.
+#[derive(Debug, Default, Clone, Copy)]
+enum Escape {
+    #[default]
+    None,
+    Start,
+    Csi,
+    /// OSC, DCS and the like, ended by BEL or ST
+    String,
+}
+
+impl ColorMarkup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn render(&mut self, data: &str) -> String {
+        let mut out = String::with_capacity(data.len());
+        let mut rest = data;
+        while let Some(captures) = sgr_regex().captures(rest) {
+            let whole = captures.get(0).expect("group 0 always participates");
+            self.text(&rest[..whole.start()], &mut out);
+            let before = self.style.clone();
+            self.style.apply(&captures[1]);
+            if self.open && self.style != before {
+                out.push_str("</>");
+                self.open = false;
+            }
+            rest = &rest[whole.end()..];
+        }
+        self.text(rest, &mut out);
+        out
+    }
+
+    fn text(&mut self, text: &str, out: &mut String) {
+        for c in text.chars() {
+            // Escape sequences other than SGR pass through whole
+            self.escape = match (self.escape, c) {
+                (_, '\x1b') => Escape::Start,
+                (Escape::Start, '[') => Escape::Csi,
+                (Escape::Start, ']' | 'P' | 'X' | '^' | '_') => Escape::String,
+                (Escape::Start, _) => Escape::None,
+                (Escape::Csi, '@'..='~') => Escape::None,
+                (Escape::String, '\x07') => Escape::None,
+                (escape @ (Escape::Csi | Escape::String), _) => escape,
+                (Escape::None, '\r' | '\n') => {
+                    if self.open {
+                        out.push_str("</>");
+                        self.open = false;
+                    }
+                    Escape::None
+                }
+                (Escape::None, c) => {
+                    if !self.open && !c.is_control() {
+                        if let Some(tag) = self.style.tag() {
+                            out.push_str(&tag);
+                            self.open = true;
+                        }
+                    }
+                    Escape::None
+                }
+            };
+            out.push(c);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::frame::{Frame, FrameType};
+use crate::http;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Longest a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    active: AtomicU64,
+    dropped: AtomicU64,
+    overflows: AtomicU64,
+    restarts: AtomicU64,
+    /// Emitted frames by type name
+    frames: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Counters for `--metrics-addr`, shared between the session loop that
+/// feeds them and the server that renders them.
+#[derive(Clone)]
+pub struct Metrics {
+    session_id: String,
+    counters: Arc<Counters>,
+}
+
+impl Metrics {
+    pub fn new(session_id: impl Into<String>) -> Self {
+        Self {
+            session_id: session_id.into(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Count the terminal traffic in a frame straight from the PTY, before
+    /// token processing changes its size.
+    pub fn observe_pty(&self, frame: &Frame) {
+        let size = || frame.bytes().map_or(0, |data| data.len() as u64);
+        let counter = match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => &self.counters.bytes_read,
+            FrameType::Stdin => &self.counters.bytes_written,
+            _ => return,
+        };
+        counter.fetch_add(size(), Ordering::Relaxed);
+    }
+
+    /// Count a frame as it is emitted.
+    pub fn observe_emitted(&self, frame: &Frame) {
+        match frame.frame_type {
+            FrameType::SessionStart | FrameType::Continued => self.counters.active.store(1, Ordering::Relaxed),
+            FrameType::Exit | FrameType::SessionEnd => self.counters.active.store(0, Ordering::Relaxed),
+            FrameType::Overflow => {
+                self.counters.overflows.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        let name = serde_json::to_value(&frame.frame_type)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        *self.counters.frames.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    /// Frames socket clients have lost so far.
+    pub fn set_dropped(&self, dropped: u64) {
+        self.counters.dropped.store(dropped, Ordering::Relaxed);
+    }
+
+    pub fn child_restarted(&self) {
+        self.counters.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let labels = format!("session_id=\"{}\"", escape(&self.session_id));
+        let counters = &self.counters;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "spectertty_bytes_read_total",
+            "counter",
+            "Bytes of output read from the command's terminal.",
+            load(&counters.bytes_read),
+        );
+        metric(
+            "spectertty_bytes_written_total",
+            "counter",
+            "Bytes of input written to the command's terminal.",
+            load(&counters.bytes_written),
+        );
+        metric(
+            "spectertty_active_sessions",
+            "gauge",
+            "Sessions whose command is running.",
+            load(&counters.active),
+        );
+        metric(
+            "spectertty_dropped_frames_total",
+            "counter",
+            "Frames lost to socket clients that fell too far behind.",
+            load(&counters.dropped),
+        );
+        metric(
+            "spectertty_overflow_events_total",
+            "counter",
+            "Times the command's output overflowed.",
+            load(&counters.overflows),
+        );
+        metric(
+            "spectertty_child_restarts_total",
+            "counter",
+            "Times the command was restarted.",
+            load(&counters.restarts),
+        );
+
+        out.push_str("# HELP spectertty_frames_total Frames emitted, by type.\n# TYPE spectertty_frames_total counter\n");
+        for (kind, count) in counters.frames.lock().unwrap().iter() {
+            let _ = writeln!(out, "spectertty_frames_total{{{},type=\"{}\"}} {}", labels, kind, count);
+        }
+        out
+    }
+}
+
+/// Label values escaped as the exposition format requires.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` over plain HTTP for Prometheus to scrape.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: SocketAddr, metrics: Metrics) -> Result<Self> {
+        let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept metrics scrape: {}", e);
+                        continue;
+                    }
+                };
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &metrics).await {
+                        debug!("Metrics scrape failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        info!("Serving metrics on http://{}/metrics", addr);
+        Ok(Self { addr, accept_task })
+    }
+
+    /// The address scraped, with the port picked if it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, http::read_request(&mut reader)).await??;
+
+    let (status, content_type, body) = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not found; try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+    };
+    http::respond(&mut writer, status, &[("Content-Type", content_type)], body.as_bytes()).await?;
+    Ok(())
+}
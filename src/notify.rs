@@ -0,0 +1,118 @@
+use crate::cli::NotifyEvent;
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Least time between two notifications for the same event, so a bell
+/// storm or a flood of errors does not start a command per chunk. Exit
+/// always notifies.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most frame data passed to the command; environment strings over
+/// 128KiB are refused by the kernel.
+const MAX_DATA_BYTES: usize = 32 * 1024;
+
+/// Runs `--notify-cmd` when the session needs a human: it rang the bell,
+/// printed something matching `--notify-regex`, is waiting on an approval
+/// or has exited.
+///
+/// The command runs under `sh -c` without waiting for it, with the event
+/// in `SPECTERTTY_EVENT`, the triggering frame as JSON in
+/// `SPECTERTTY_FRAME` and the session in `SPECTERTTY_SESSION_ID`.
+pub struct Notifier {
+    command: String,
+    events: Vec<NotifyEvent>,
+    failures: Vec<Regex>,
+    last_sent: HashMap<NotifyEvent, Instant>,
+}
+
+impl Notifier {
+    pub fn new(command: String, events: &[NotifyEvent], failure_patterns: &[String]) -> Result<Self> {
+        let failures = failure_patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid --notify-regex '{}'", pattern)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            command,
+            events: events.to_vec(),
+            failures,
+            last_sent: HashMap::new(),
+        })
+    }
+
+    pub fn observe(&mut self, frame: &Frame) {
+        let Some(event) = self.event(frame) else {
+            return;
+        };
+        if !self.events.contains(&event) {
+            return;
+        }
+        if event != NotifyEvent::Exit {
+            let now = Instant::now();
+            if self.last_sent.get(&event).is_some_and(|sent| now - *sent < REPEAT_INTERVAL) {
+                debug!("Skipping {} notification sent moments ago", event.name());
+                return;
+            }
+            self.last_sent.insert(event, now);
+        }
+        self.run(event, frame);
+    }
+
+    fn event(&self, frame: &Frame) -> Option<NotifyEvent> {
+        match frame.frame_type {
+            FrameType::Bell => Some(NotifyEvent::Bell),
+            FrameType::ApprovalRequired => Some(NotifyEvent::Approval),
+            FrameType::Exit => Some(NotifyEvent::Exit),
+            FrameType::Stdout | FrameType::Stderr if !self.failures.is_empty() => {
+                let data = frame.text()?;
+                self.failures
+                    .iter()
+                    .any(|failure| failure.is_match(&data))
+                    .then_some(NotifyEvent::Failure)
+            }
+            _ => None,
+        }
+    }
+
+    fn run(&self, event: NotifyEvent, frame: &Frame) {
+        let mut context = frame.clone();
+        if let Some(ref mut data) = context.data {
+            if data.len() > MAX_DATA_BYTES {
+                let end = (0..=MAX_DATA_BYTES).rev().find(|&i| data.is_char_boundary(i)).unwrap_or(0);
+                data.truncate(end);
+            }
+        }
+
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", &self.command])
+            .env("SPECTERTTY_EVENT", event.name())
+            .env("SPECTERTTY_FRAME", context.to_json().unwrap_or_default())
+            // stdout carries the frame stream
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        if let Some(ref session_id) = frame.session_id {
+            command.env("SPECTERTTY_SESSION_ID", session_id);
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run --notify-cmd for {}: {}", event.name(), e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if !status.success() => warn!("--notify-cmd for {} failed: {}", event.name(), status),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to wait for --notify-cmd: {}", e),
+            }
+        });
+    }
+}
@@ -0,0 +1,111 @@
+use crate::expect::{split_unfinished, strip_escapes};
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Link text kept per hyperlink; a link wrapped around more than this is
+/// not a link an agent would follow by its text.
+pub const MAX_LINK_TEXT: usize = 4096;
+
+/// An OSC sequence, its number and its parameters, ended by BEL or ST.
+fn osc_regex() -> &'static Regex {
+    static OSC: OnceLock<Regex> = OnceLock::new();
+    OSC.get_or_init(|| Regex::new(r"\x1b\](\d+);([^\x07\x1b]*)(?:\x07|\x1b\\)").unwrap())
+}
+
+/// A hyperlink whose text is still being printed.
+struct OpenLink {
+    url: String,
+    id: Option<String>,
+    text: String,
+}
+
+/// Finds hyperlinks (OSC 8) and window title changes (OSC 0 and 2) in a
+/// command's output. A link is reported as a `link` frame once its text
+/// has been printed, with the target in `url`; a title as a `title` frame
+/// when it differs from the last one.
+#[derive(Default)]
+pub struct OscScanner {
+    /// Start of a sequence the next read finishes
+    carry: String,
+    link: Option<OpenLink>,
+    title: Option<String>,
+}
+
+impl OscScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follow an output frame, returning the `link` and `title` frames it
+    /// completes.
+    pub fn observe(&mut self, frame: &Frame) -> Vec<Frame> {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Vec::new();
+        }
+        let data = frame.text().unwrap_or_default();
+        if self.carry.is_empty() && self.link.is_none() && !data.contains('\x1b') {
+            return Vec::new();
+        }
+        let mut text = std::mem::take(&mut self.carry);
+        text.push_str(&data);
+        self.carry = split_unfinished(&mut text);
+
+        let mut frames = Vec::new();
+        let mut rest = text.as_str();
+        while let Some(captures) = osc_regex().captures(rest) {
+            let whole = captures.get(0).expect("group 0 always participates");
+            self.link_text(&rest[..whole.start()]);
+            match &captures[1] {
+                "8" => {
+                    let (params, url) = captures[2].split_once(';').unwrap_or(("", ""));
+                    // A new link also ends the one before it
+                    frames.extend(self.close_link());
+                    if !url.is_empty() {
+                        let id = params
+                            .split(':')
+                            .find_map(|param| param.strip_prefix("id="))
+                            .map(str::to_string);
+                        self.link = Some(OpenLink {
+                            url: url.to_string(),
+                            id,
+                            text: String::new(),
+                        });
+                    }
+                }
+                "0" | "2" => {
+                    let title = captures[2].to_string();
+                    if self.title.as_ref() != Some(&title) {
+                        frames.push(Frame::new(FrameType::Title).with_data(title.clone()));
+                        self.title = Some(title);
+                    }
+                }
+                _ => {}
+            }
+            rest = &rest[whole.end()..];
+        }
+        self.link_text(rest);
+        frames
+    }
+
+    fn link_text(&mut self, text: &str) {
+        let Some(ref mut link) = self.link else {
+            return;
+        };
+        for c in strip_escapes(text).chars() {
+            if link.text.len() + c.len_utf8() > MAX_LINK_TEXT {
+                break;
+            }
+            link.text.push(c);
+        }
+    }
+
+    fn close_link(&mut self) -> Option<Frame> {
+        let link = self.link.take()?;
+        let frame = Frame::new(FrameType::Link).with_url(link.url).with_data(link.text);
+        Some(match link.id {
+            Some(id) => frame.with_id(id),
+            None => frame,
+        })
+    }
+}
@@ -0,0 +1,238 @@
+use crate::frame::{Frame, FrameType};
+use crate::sampling::Sampler;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// How often the queue is measured, and coalesced output released.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Checks in a row over `--buffer` before stepping up a rung.
+pub const ESCALATE_AFTER: u32 = 3;
+
+/// Checks in a row at or under a quarter of `--buffer` before stepping down.
+pub const RELAX_AFTER: u32 = 10;
+
+/// On the `sample` rung, one output frame in this many is kept.
+pub const SAMPLE_EVERY: u64 = 4;
+
+/// Rungs of the degradation ladder, each shedding more than the last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rung {
+    Normal,
+    /// Output merged into one frame per check, and only the latest line
+    /// update, snapshot, cursor and health frame kept
+    Coalesce,
+    /// `line_update` and `idle` frames dropped as well
+    Shed,
+    /// Only one output frame in `SAMPLE_EVERY` delivered
+    Sample,
+    /// The command stopped with SIGSTOP until the queue drains
+    Pause,
+}
+
+impl Rung {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rung::Normal => "normal",
+            Rung::Coalesce => "coalesce",
+            Rung::Shed => "shed",
+            Rung::Sample => "sample",
+            Rung::Pause => "pause",
+        }
+    }
+
+    fn up(self) -> Self {
+        match self {
+            Rung::Normal => Rung::Coalesce,
+            Rung::Coalesce => Rung::Shed,
+            Rung::Shed => Rung::Sample,
+            Rung::Sample | Rung::Pause => Rung::Pause,
+        }
+    }
+
+    fn down(self) -> Self {
+        match self {
+            Rung::Normal | Rung::Coalesce => Rung::Normal,
+            Rung::Shed => Rung::Coalesce,
+            Rung::Sample => Rung::Shed,
+            Rung::Pause => Rung::Sample,
+        }
+    }
+}
+
+/// What a check asks of the main loop: frames to deliver, the transition
+/// announcement among them, and a signal for the command.
+#[derive(Debug, Default)]
+pub struct Check {
+    pub frames: Vec<Frame>,
+    pub signal: Option<&'static str>,
+}
+
+/// Graceful degradation when frames are produced faster than the sinks
+/// take them. While more than `--buffer` bytes of output wait, the ladder
+/// climbs a rung every `ESCALATE_AFTER` checks, and climbs back down as
+/// the queue drains, announcing every step with an `overflow` frame. Only
+/// when the command has been paused for `--overflow-timeout` without the
+/// queue draining is it killed.
+pub struct Ladder {
+    buffer: usize,
+    overflow_timeout: Duration,
+    interval: Interval,
+    rung: Rung,
+    strained: u32,
+    relieved: u32,
+    paused_at: Option<Instant>,
+    killed: bool,
+    coalescer: Sampler,
+    outputs: u64,
+    shed_frames: u64,
+    sampled_out_bytes: u64,
+}
+
+impl Ladder {
+    pub fn new(buffer: usize, overflow_timeout: Duration) -> Self {
+        let mut interval = tokio::time::interval_at(Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Released on every check rather than by its own clock
+        let rate = 1.0 / CHECK_INTERVAL.as_secs_f64();
+        let coalescer = Sampler::new(Some(rate), false)
+            .ok()
+            .flatten()
+            .expect("the check rate is positive");
+        Self {
+            buffer,
+            overflow_timeout,
+            interval,
+            rung: Rung::Normal,
+            strained: 0,
+            relieved: 0,
+            paused_at: None,
+            killed: false,
+            coalescer,
+            outputs: 0,
+            shed_frames: 0,
+            sampled_out_bytes: 0,
+        }
+    }
+
+    pub fn rung(&self) -> Rung {
+        self.rung
+    }
+
+    /// Wait for the next check.
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// Measure `queued` bytes of unread output, step the ladder if the
+    /// pressure has been sustained, and release coalesced output.
+    pub fn check(&mut self, queued: usize) -> Check {
+        let held = self.coalescer.flush();
+        let mut check = Check {
+            frames: self.release(held),
+            signal: None,
+        };
+
+        if queued > self.buffer {
+            self.strained += 1;
+            self.relieved = 0;
+        } else if queued <= self.buffer / 4 {
+            self.relieved += 1;
+            self.strained = 0;
+        } else {
+            self.strained = 0;
+            self.relieved = 0;
+        }
+
+        let next = if self.strained >= ESCALATE_AFTER {
+            self.rung.up()
+        } else if self.relieved >= RELAX_AFTER {
+            self.rung.down()
+        } else {
+            self.rung
+        };
+
+        if next != self.rung {
+            self.strained = 0;
+            self.relieved = 0;
+            if next == Rung::Pause {
+                check.signal = Some("SIGSTOP");
+                self.paused_at = Some(Instant::now());
+            } else if self.rung == Rung::Pause {
+                check.signal = Some("SIGCONT");
+                self.paused_at = None;
+            }
+            if next > self.rung {
+                warn!("{} bytes of output queued, degrading to {}", queued, next.name());
+            } else {
+                info!("Output queue down to {} bytes, recovering to {}", queued, next.name());
+            }
+            self.rung = next;
+            check.frames.push(self.announce(next.name(), queued));
+        } else if let Some(paused_at) = self.paused_at {
+            if !self.killed && queued > self.buffer && paused_at.elapsed() >= self.overflow_timeout {
+                warn!("Output queue still over --buffer after --overflow-timeout, killing the command");
+                self.killed = true;
+                check.signal = Some("SIGKILL");
+                check.frames.push(self.announce("kill", queued));
+            }
+        }
+        check
+    }
+
+    /// Pass a frame on its way to the sinks through the current rung.
+    pub fn filter(&mut self, frame: Frame) -> Vec<Frame> {
+        if self.rung == Rung::Normal {
+            // Whatever was coalesced before the ladder stepped down
+            let mut frames = self.coalescer.flush();
+            frames.push(frame);
+            return frames;
+        }
+        if self.rung >= Rung::Shed && matches!(frame.frame_type, FrameType::LineUpdate | FrameType::Idle) {
+            self.shed_frames += 1;
+            return Vec::new();
+        }
+        let frames = self.coalescer.offer(frame);
+        self.release(frames)
+    }
+
+    /// Output still held back, at the end of the session.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        self.coalescer.flush()
+    }
+
+    /// Coalesced frames as they leave, thinned further on the `sample` rung
+    /// and above.
+    fn release(&mut self, frames: Vec<Frame>) -> Vec<Frame> {
+        if self.rung < Rung::Sample {
+            return frames;
+        }
+        frames.into_iter().filter(|frame| self.sample(frame)).collect()
+    }
+
+    fn sample(&mut self, frame: &Frame) -> bool {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return true;
+        }
+        self.outputs += 1;
+        if self.outputs % SAMPLE_EVERY == 1 {
+            return true;
+        }
+        self.sampled_out_bytes += frame.data.as_ref().map_or(0, String::len) as u64;
+        false
+    }
+
+    fn announce(&self, reason: &str, queued: usize) -> Frame {
+        let mut stats = BTreeMap::new();
+        stats.insert("level".to_string(), self.rung as u64);
+        stats.insert("queued_bytes".to_string(), queued as u64);
+        stats.insert("buffer_bytes".to_string(), self.buffer as u64);
+        stats.insert("shed_frames".to_string(), self.shed_frames);
+        stats.insert("sampled_out_bytes".to_string(), self.sampled_out_bytes);
+        Frame::new(FrameType::Overflow)
+            .with_reason(reason.to_string())
+            .with_stats(stats)
+    }
+}
@@ -0,0 +1,41 @@
+use crate::asciicast::Reader;
+use crate::cli::PlayArgs;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Replay an asciicast v2 recording to the terminal with its original
+/// timing, scaled by --speed and with pauses capped by --idle-limit (or the
+/// recording's own idle_time_limit).
+pub async fn run(args: &PlayArgs) -> Result<()> {
+    let file = File::open(&args.file).with_context(|| format!("Failed to open {:?}", args.file))?;
+    let (header, events) = Reader::new(BufReader::new(file))?;
+    let idle_limit = args.idle_limit.or(header.idle_time_limit);
+
+    let mut stdout = io::stdout();
+    let started = Instant::now();
+    let mut last_time = 0.0;
+    let mut elapsed = 0.0;
+
+    for event in events {
+        let event = event?;
+
+        let mut gap = (event.time() - last_time).max(0.0);
+        if let Some(limit) = idle_limit {
+            gap = gap.min(limit);
+        }
+        last_time = event.time();
+        elapsed += gap / args.speed;
+
+        if event.code() != "o" {
+            continue;
+        }
+        tokio::time::sleep_until(started + Duration::from_secs_f64(elapsed)).await;
+        stdout.write_all(event.data().as_bytes())?;
+        stdout.flush()?;
+    }
+
+    Ok(())
+}
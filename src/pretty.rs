@@ -0,0 +1,223 @@
+use crate::cli::{FrameFormat, PrettyArgs};
+use crate::frame::{Frame, FrameType};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Write};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Characters of `data` shown before it is cut off.
+const DATA_WIDTH: usize = 100;
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+
+/// Renders frames as a timeline for people reading along: local time, seq,
+/// a type badge, the payload with control characters escaped, and the
+/// frame's other fields.
+pub struct PrettyPrinter {
+    color: bool,
+}
+
+impl PrettyPrinter {
+    pub fn new(color: bool) -> Self {
+        Self { color }
+    }
+
+    /// Colored when stdout is a terminal and `NO_COLOR` is not set.
+    pub fn for_stdout() -> Self {
+        Self::new(io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none())
+    }
+
+    pub fn render(&self, frame: &Frame) -> String {
+        let time = DateTime::from_timestamp_micros((frame.ts * 1e6) as i64)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+            .format("%H:%M:%S%.3f");
+        let seq = frame.seq.map(|seq| format!("#{}", seq)).unwrap_or_default();
+        let kind = serde_json::to_value(&frame.frame_type)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let mut line = format!(
+            "{} {} {}",
+            self.paint(DIM, &time.to_string()),
+            self.paint(DIM, &format!("{:>6}", seq)),
+            self.paint(badge_color(&frame.frame_type), &format!("{:<17}", kind)),
+        );
+        if let Some(ref source) = frame.source {
+            let _ = write!(line, " {}", self.paint("\x1b[35m", &format!("[{}]", source)));
+        }
+        if let Some(data) = payload(frame) {
+            let _ = write!(line, " {}", data);
+        }
+        let fields = fields(frame);
+        if !fields.is_empty() {
+            let _ = write!(line, " {}", self.paint(DIM, &fields.join(" ")));
+        }
+        line
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+fn badge_color(kind: &FrameType) -> &'static str {
+    match kind {
+        FrameType::Stdout => "\x1b[32m",
+        FrameType::Stderr
+        | FrameType::Error
+        | FrameType::WriteError
+        | FrameType::CapsuleKill
+        | FrameType::ApprovalDenied => "\x1b[1;31m",
+        FrameType::Stdin => "\x1b[36m",
+        FrameType::Exit
+        | FrameType::Expired
+        | FrameType::SidecarExit
+        | FrameType::SessionStart
+        | FrameType::SessionEnd => "\x1b[1;35m",
+        FrameType::Prompt
+        | FrameType::CommandStart
+        | FrameType::CommandEnd
+        | FrameType::Match
+        | FrameType::ApprovalRequired
+        | FrameType::Anomaly
+        | FrameType::BudgetExceeded
+        | FrameType::Bell => "\x1b[1;33m",
+        FrameType::ExpectTimeout | FrameType::Overflow | FrameType::Summary => "\x1b[33m",
+        _ => "\x1b[34m",
+    }
+}
+
+/// `data` quoted with control characters escaped and cut to
+/// `DATA_WIDTH`, or a byte count for binary payloads.
+fn payload(frame: &Frame) -> Option<String> {
+    if frame.binary == Some(true) {
+        return frame.bytes().map(|bytes| format!("<{} bytes binary>", bytes.len()));
+    }
+    let data = frame.data.as_deref()?;
+    let escaped: String = data.escape_debug().collect();
+    let mut chars = escaped.char_indices();
+    Some(match chars.nth(DATA_WIDTH) {
+        Some((end, _)) => format!("\"{}\"… ({} bytes)", &escaped[..end], data.len()),
+        None => format!("\"{}\"", escaped),
+    })
+}
+
+/// The frame's other fields as `key=value`.
+fn fields(frame: &Frame) -> Vec<String> {
+    let mut fields = Vec::new();
+    if let (Some(cols), Some(rows)) = (frame.cols, frame.rows) {
+        fields.push(format!("size={}x{}", cols, rows));
+    }
+    if let Some(code) = frame.code {
+        fields.push(format!("code={}", code));
+    }
+    if let Some(ref signal) = frame.signal {
+        fields.push(format!("signal={}", signal));
+    }
+    if let Some(ref reason) = frame.reason {
+        fields.push(format!("reason={}", reason));
+    }
+    if let Some(ref mode) = frame.mode {
+        fields.push(format!("mode={}", mode));
+    }
+    if let Some(ref url) = frame.url {
+        fields.push(format!("url={}", url));
+    }
+    if let Some(ref regex) = frame.regex {
+        fields.push(format!("regex={:?}", regex));
+    }
+    if let Some(ref id) = frame.id {
+        fields.push(format!("id={}", id));
+    }
+    if let Some(ref client) = frame.client {
+        fields.push(format!("client={}", client));
+    }
+    if let Some(value) = frame.value {
+        fields.push(format!("value={}", value));
+    }
+    if let Some(dur_ms) = frame.dur_ms {
+        fields.push(format!("dur={}ms", dur_ms));
+    }
+    if let Some(ref stats) = frame.stats {
+        fields.extend(stats.iter().map(|(key, value)| format!("{}={}", key, value)));
+    }
+    if let Some(ref captures) = frame.captures {
+        fields.extend(captures.iter().map(|(key, value)| format!("${}={:?}", key, value)));
+    }
+    if let Some(ref structured) = frame.structured {
+        fields.push(format!("structured={}", structured));
+    }
+    fields
+}
+
+/// Print a frame log from a file or stdin as a timeline, frame by frame as
+/// it arrives. JSON, MessagePack and CBOR logs are told apart by their
+/// first byte.
+pub async fn run(args: &PrettyArgs) -> Result<()> {
+    let printer = PrettyPrinter::for_stdout();
+    match args.file {
+        Some(ref path) => {
+            let file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("Failed to open {:?}", path))?;
+            print_frames(file, &printer).await
+        }
+        None => print_frames(tokio::io::stdin(), &printer).await,
+    }
+}
+
+async fn print_frames(mut input: impl AsyncRead + Unpin, printer: &PrettyPrinter) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut detected = None;
+    let mut count = 0;
+    loop {
+        let read = input.read_buf(&mut buf).await?;
+
+        let mut rest = buf.as_slice();
+        let mut stdout = io::stdout().lock();
+        loop {
+            // Blank lines between JSON frames are skipped
+            if detected.is_none_or(|format| format == FrameFormat::Json) {
+                rest = rest.trim_ascii_start();
+            }
+            let format = match (detected, rest.first()) {
+                (Some(format), _) => format,
+                (None, None) => break,
+                (None, Some(&first)) => *detected.insert(FrameFormat::detect(first)),
+            };
+            let Some((frame, used)) =
+                Frame::decode(format, rest).with_context(|| format!("Invalid frame {}", count + 1))?
+            else {
+                break;
+            };
+            count += 1;
+            writeln!(stdout, "{}", printer.render(&frame))?;
+            rest = &rest[used..];
+        }
+        stdout.flush()?;
+        let used = buf.len() - rest.len();
+        buf.drain(..used);
+
+        if read == 0 {
+            if buf.is_empty() {
+                return Ok(());
+            }
+            // A last JSON frame may lack its newline
+            if detected != Some(FrameFormat::Json) {
+                bail!("Frame log ends partway through frame {}", count + 1);
+            }
+            let frame = Frame::from_json(std::str::from_utf8(&buf)?)
+                    .with_context(|| format!("Invalid frame {}", count + 1))?;
+            println!("{}", printer.render(&frame));
+            return Ok(());
+        }
+    }
+}
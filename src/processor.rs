@@ -1,33 +1,490 @@
 use crate::cli::TokenMode;
+use crate::emulator::Emulator;
+use crate::expect;
 use crate::frame::{Frame, FrameType};
+use crate::markup::ColorMarkup;
+use crate::osc::OscScanner;
+use crate::segment::Segmenter;
+use crate::structure::{self, MAX_BLOCK_LINES};
 use anyhow::Result;
 use regex::Regex;
 use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Largest frame `--coalesce-ms` builds out of output, unless told otherwise.
+pub const DEFAULT_COALESCE_MAX: usize = 64 * 1024;
+
+/// Least time between the snapshots compact mode sends of a full-screen
+/// application, unless told otherwise.
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The first switch to the alternate screen (`h`, smcup) or back from it
+/// (`l`, rmcup) in `text`.
+fn alt_screen_switch(text: &str, direction: char) -> Option<regex::Match<'_>> {
+    static ALT_SCREEN: OnceLock<Regex> = OnceLock::new();
+    let regex = ALT_SCREEN.get_or_init(|| Regex::new(r"\x1b\[\?(?:1049|1047|47)[hl]").unwrap());
+    regex.find_iter(text).find(|found| found.as_str().ends_with(direction))
+}
+
+/// A full-screen application compact mode is following on a screen grid
+/// of its own rather than passing on its redraws.
+struct FullScreen {
+    emulator: Emulator,
+    cols: u16,
+    rows: u16,
+    last_snapshot: String,
+    /// When the last snapshot was sent, as a frame timestamp
+    last_sent: f64,
+    /// The screen has changed since the last snapshot
+    pending: bool,
+}
+
+impl FullScreen {
+    fn new(cols: u16, rows: u16) -> Self {
+        Self {
+            emulator: Emulator::new(cols, rows),
+            cols,
+            rows,
+            last_snapshot: String::new(),
+            last_sent: f64::NEG_INFINITY,
+            pending: false,
+        }
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) {
+        self.emulator.resize(cols, rows);
+        (self.cols, self.rows) = (cols, rows);
+    }
+
+    /// A snapshot of the screen if it has changed and `interval` has passed
+    /// since the last one, or if `settled` and it changed at all.
+    fn snapshot(&mut self, ts: f64, interval: Duration, settled: bool) -> Option<Frame> {
+        let contents = self.emulator.screen_contents();
+        if contents != self.last_snapshot {
+            self.pending = true;
+        }
+        if !self.pending || (!settled && ts - self.last_sent < interval.as_secs_f64()) {
+            return None;
+        }
+        self.pending = false;
+        self.last_sent = ts;
+        self.last_snapshot = contents.clone();
+        Some(Frame::new(FrameType::Snapshot).with_data(contents).with_size(self.cols, self.rows))
+    }
+}
+
+/// Stdout frames `--coalesce-ms` is merging into one: output that arrives
+/// within `window` of the first joins it, up to `max_bytes` of payload.
+struct Coalescing {
+    window: Duration,
+    max_bytes: usize,
+    pending: Option<Frame>,
+    /// When the pending frame's window closes
+    deadline: Option<Instant>,
+}
+
+impl Coalescing {
+    fn offer(&mut self, frame: Frame) -> Vec<Frame> {
+        if frame.frame_type != FrameType::Stdout {
+            // Anything held back came first
+            let mut frames: Vec<Frame> = self.take().into_iter().collect();
+            frames.push(frame);
+            return frames;
+        }
+        let mut frames = Vec::new();
+        match self.pending {
+            Some(ref mut pending) if payload_len(pending) + payload_len(&frame) <= self.max_bytes => {
+                append(pending, &frame);
+            }
+            _ => {
+                // Too big to join what is held, which goes first
+                frames.extend(self.take());
+                self.pending = Some(frame);
+                self.deadline = Some(Instant::now() + self.window);
+            }
+        }
+        if self.pending.as_ref().is_some_and(|pending| payload_len(pending) >= self.max_bytes) {
+            frames.extend(self.take());
+        }
+        frames
+    }
+
+    fn take(&mut self) -> Option<Frame> {
+        self.deadline = None;
+        self.pending.take()
+    }
+}
+
+fn payload_len(frame: &Frame) -> usize {
+    frame.data.as_ref().map_or(0, String::len)
+}
+
+/// Add `frame`'s output to `pending`, which keeps its own timestamps.
+fn append(pending: &mut Frame, frame: &Frame) {
+    if pending.binary.is_none() && frame.binary.is_none() {
+        pending.data.get_or_insert_with(String::new).push_str(frame.data.as_deref().unwrap_or_default());
+        return;
+    }
+    let mut bytes = pending.bytes().unwrap_or_default().into_owned();
+    bytes.extend_from_slice(&frame.bytes().unwrap_or_default());
+    let held = std::mem::replace(pending, Frame::new(FrameType::Stdout));
+    *pending = held.with_binary_data(bytes);
+}
+
+/// The line the cursor is on in compact mode. A `\r` or backspace moves
+/// the cursor back over it and later text overwrites what was there, as
+/// on a terminal, so only what the line settles on is sent.
+#[derive(Clone, Default)]
+struct CurrentLine {
+    cells: Vec<char>,
+    cursor: usize,
+}
+
+impl CurrentLine {
+    /// Apply output on this line, with the escape sequences `escapes`
+    /// matches. Of those only erase in line has an effect.
+    fn write(&mut self, text: &str, escapes: &Regex) {
+        let mut last = 0;
+        for escape in escapes.find_iter(text) {
+            self.put(&text[last..escape.start()]);
+            match escape.as_str().strip_suffix('K').map(|sequence| &sequence[2..]) {
+                Some("" | "0") => self.cells.truncate(self.cursor),
+                Some("1") => {
+                    let end = (self.cursor + 1).min(self.cells.len());
+                    self.cells[..end].fill(' ');
+                }
+                Some("2") => self.cells.clear(),
+                _ => {}
+            }
+            last = escape.end();
+        }
+        self.put(&text[last..]);
+    }
+
+    fn put(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\r' => self.cursor = 0,
+                '\x08' => self.cursor = self.cursor.saturating_sub(1),
+                '\t' => self.print(c),
+                c if c.is_control() => {}
+                c => self.print(c),
+            }
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        if self.cursor < self.cells.len() {
+            self.cells[self.cursor] = c;
+        } else {
+            self.cells.resize(self.cursor, ' ');
+            self.cells.push(c);
+        }
+        self.cursor += 1;
+    }
+
+    /// What the line shows, without trailing whitespace.
+    fn contents(&self) -> String {
+        self.cells.iter().collect::<String>().trim_end().to_string()
+    }
+
+    fn take(&mut self) -> String {
+        let contents = self.contents();
+        *self = Self::default();
+        contents
+    }
+}
+
+/// A progress bar or counter compact mode sees being redrawn in place.
+/// Its sequence of states ends at a newline or at a line of other output.
+#[derive(Default)]
+struct ProgressLine {
+    /// What the line shows now
+    state: String,
+    /// The state last sent as a `line_update`
+    sent: Option<String>,
+}
+
+/// A spinner compact mode is holding back: a line redrawn with only its
+/// spinner glyph changing.
+struct Spinner {
+    /// The line's text, without the glyph
+    label: String,
+    started: f64,
+}
+
+/// Runs of a repeated line compact mode collapses with `--dedupe-lines`,
+/// the way syslog reports "message repeated N times". Lines that differ
+/// only in their numbers, such as retry counters and timestamps, count as
+/// the same.
+#[derive(Default)]
+struct Repeats {
+    /// The last line passed, with its numbers masked
+    key: Option<String>,
+    /// The run so far: its latest line, and how many times it repeated
+    run: Option<(Frame, String)>,
+    count: u64,
+}
+
+impl Repeats {
+    fn offer(&mut self, frame: Frame) -> Vec<Frame> {
+        let text = match (&frame.frame_type, &frame.data) {
+            (FrameType::Stdout | FrameType::Stderr, Some(text)) => text.clone(),
+            _ => {
+                // Anything else ends the run, which happened first. Output
+                // going quiet only reports it: the line repeating after
+                // the pause, as retry loops do, is still collapsed
+                let mut frames: Vec<Frame> = self.end_run().into_iter().collect();
+                if frame.frame_type != FrameType::Idle {
+                    self.key = None;
+                }
+                frames.push(frame);
+                return frames;
+            }
+        };
+
+        let mut frames = Vec::new();
+        let mut kept = String::new();
+        for piece in text.split_inclusive('\n') {
+            let line = piece.strip_suffix('\n');
+            let key = line.filter(|line| !line.trim().is_empty()).map(mask_numbers);
+            if key.is_some() && key == self.key {
+                self.count += 1;
+                self.run = Some((frame.clone(), piece.to_string()));
+                continue;
+            }
+            if let Some(repeated) = self.end_run() {
+                if !kept.is_empty() {
+                    frames.push(frame.clone().with_data(std::mem::take(&mut kept)));
+                }
+                frames.push(repeated);
+            }
+            // A line still being written is not compared
+            self.key = key;
+            kept.push_str(piece);
+        }
+        if !kept.is_empty() {
+            frames.push(frame.with_data(kept));
+        }
+        frames
+    }
+
+    /// The frame standing for the run of repeats, once it is over.
+    fn end_run(&mut self) -> Option<Frame> {
+        let (frame, line) = self.run.take()?;
+        let mut repeated = frame.with_data(line);
+        repeated.repeat_count = Some(std::mem::take(&mut self.count));
+        Some(repeated)
+    }
+}
+
+/// `line` with every run of digits replaced by a single `0`.
+fn mask_numbers(line: &str) -> String {
+    let mut masked = String::with_capacity(line.len());
+    let mut in_number = false;
+    for c in line.trim_end().chars() {
+        if c.is_ascii_digit() {
+            if !in_number {
+                masked.push('0');
+            }
+            in_number = true;
+        } else {
+            masked.push(c);
+            in_number = false;
+        }
+    }
+    masked
+}
+
+/// Compact mode's place in one output stream. Stdout and stderr each have
+/// their own, so text on one never finishes a line begun on the other.
+struct StreamLines {
+    stream: FrameType,
+    /// Settled lines batched for the next frame
+    line_buffer: String,
+    current_line: CurrentLine,
+    /// Start of an escape sequence the next chunk finishes, held back from
+    /// compact output so it can be stripped whole
+    escape_carry: String,
+    /// The progress line being redrawn
+    progress: Option<ProgressLine>,
+    spinner: Option<Spinner>,
+}
+
+impl StreamLines {
+    fn new(stream: FrameType) -> Self {
+        Self {
+            stream,
+            line_buffer: String::new(),
+            current_line: CurrentLine::default(),
+            escape_carry: String::new(),
+            progress: None,
+            spinner: None,
+        }
+    }
+}
 
 pub struct OutputProcessor {
     mode: TokenMode,
-    line_buffer: String,
+    /// The stream compact mode is working on
+    lines: StreamLines,
+    /// The other stream, set aside until its next output
+    parked: StreamLines,
     ansi_strip_regex: Regex,
+    osc_strip_regex: Regex,
     progress_regex: Regex,
-    last_line_update: Option<String>,
+    percent_regex: Regex,
+    spinner_regex: Regex,
+    shell_prompt_regex: Regex,
     frame_buffer: VecDeque<Frame>,
+    emulator: Option<Emulator>,
+    cols: u16,
+    rows: u16,
+    last_partial_line: String,
+    last_snapshot: String,
+    /// Lines of command output since the last blank line or prompt
+    block: Vec<String>,
+    /// Prompt showing when the last block closed, whose echoed command
+    /// line opens the next
+    block_prompt: String,
+    /// Prompts and commands the shell marks with OSC 133/633
+    commands: Segmenter,
+    /// Hyperlinks and window titles
+    osc: OscScanner,
+    /// Colors kept as markup in compact mode
+    markup: Option<ColorMarkup>,
+    /// The full-screen application compact mode is snapshotting
+    full_screen: Option<FullScreen>,
+    snapshot_interval: Duration,
+    /// Stdout being merged for `--coalesce-ms`
+    coalescing: Option<Coalescing>,
+    /// Repeated lines being collapsed in compact mode
+    repeats: Option<Repeats>,
 }
 
 impl OutputProcessor {
-    pub fn new(mode: TokenMode) -> Self {
+    pub fn new(mode: TokenMode, cols: u16, rows: u16) -> Self {
         Self {
             mode,
-            line_buffer: String::new(),
+            lines: StreamLines::new(FrameType::Stdout),
+            parked: StreamLines::new(FrameType::Stderr),
             // Regex to strip ANSI escape sequences
             ansi_strip_regex: Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap(),
+            // OSC sequences (titles, hyperlinks, shell marks), which leave a
+            // link's text in place; they are reported as frames of their own
+            osc_strip_regex: Regex::new(r"\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)").unwrap(),
             // Regex to detect progress indicators (e.g., downloading, installing)
             progress_regex: Regex::new(r"[\r\n]*[\s]*[▌▍▎▏█░▒▓■□▪▫●○◐◑◒◓◔◕◖◗◘◙◚◛◜◝◞◟◠◡◢◣◤◥◦◧◨◩◪◫◬◭◮◯]+|[0-9]+%|\[[=>\-\s]*\]").unwrap(),
-            last_line_update: None,
+            // How far along a progress line is
+            percent_regex: Regex::new(r"(\d+(?:\.\d+)?)\s?%").unwrap(),
+            // Spinner glyphs: braille dots, turning circles and quadrants,
+            // and the classic `|/-\` standing on its own
+            spinner_regex: Regex::new(r"[\x{2800}-\x{28FF}◐◑◒◓◴◵◶◷◰◱◲◳◜◝◞◟▖▘▝▗]|(?:^|\s)[|/\\-](?:\s|$)").unwrap(),
+            // Regex to recognise a shell prompt waiting on the current line
+            shell_prompt_regex: Regex::new(r"[$#>%❯]\s?$").unwrap(),
             frame_buffer: VecDeque::new(),
+            emulator: matches!(mode, TokenMode::Parsed).then(|| Emulator::new(cols, rows)),
+            cols,
+            rows,
+            last_partial_line: String::new(),
+            last_snapshot: String::new(),
+            block: Vec::new(),
+            block_prompt: String::new(),
+            commands: Segmenter::marks_only(),
+            osc: OscScanner::new(),
+            markup: None,
+            full_screen: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            coalescing: None,
+            repeats: None,
         }
     }
 
+    /// Keep colors and text attributes in compact output as markup
+    /// rather than strip them.
+    pub fn preserve_color(mut self, preserve: bool) -> Self {
+        self.markup = preserve.then(ColorMarkup::new);
+        self
+    }
+
+    /// Collapse runs of the same line in compact output into one frame
+    /// with a `repeat_count`.
+    pub fn dedupe_lines(mut self, dedupe: bool) -> Self {
+        self.repeats = dedupe.then(Repeats::default);
+        self
+    }
+
+    /// Send a full-screen application's screen in compact mode at most
+    /// this often while it keeps redrawing.
+    pub fn snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// Merge stdout frames arriving within `window` of the first into one
+    /// of at most `max_bytes` of payload, so a command writing in small
+    /// pieces costs far fewer frames. Held output leaves ahead of any other
+    /// kind of frame, or at `coalesce_deadline`.
+    pub fn coalesce(mut self, window: Duration, max_bytes: usize) -> Self {
+        self.coalescing = Some(Coalescing {
+            window,
+            max_bytes,
+            pending: None,
+            deadline: None,
+        });
+        self
+    }
+
+    /// When the output being coalesced is due to be sent.
+    pub fn coalesce_deadline(&self) -> Option<Instant> {
+        self.coalescing.as_ref().and_then(|coalescing| coalescing.deadline)
+    }
+
+    /// The output being coalesced, once its window has closed.
+    pub fn flush_coalesced(&mut self) -> Vec<Frame> {
+        self.coalescing.as_mut().and_then(Coalescing::take).into_iter().collect()
+    }
+
     pub async fn process_frame(&mut self, frame: Frame) -> Result<Vec<Frame>> {
+        let frames = self.process_marked(frame).await?;
+        Ok(match self.coalescing {
+            Some(ref mut coalescing) => frames.into_iter().flat_map(|frame| coalescing.offer(frame)).collect(),
+            None => frames,
+        })
+    }
+
+    async fn process_marked(&mut self, frame: Frame) -> Result<Vec<Frame>> {
+        // Track the window size in every mode so a later switch to parsed
+        // mode starts with the right grid
+        if let (FrameType::Resize, Some(cols), Some(rows)) = (&frame.frame_type, frame.cols, frame.rows) {
+            self.cols = cols;
+            self.rows = rows;
+            if let Some(ref mut emulator) = self.emulator {
+                emulator.resize(cols, rows);
+            }
+            if let Some(ref mut screen) = self.full_screen {
+                screen.resize(cols, rows);
+            }
+        }
+
+        // Commands the shell marks, links and titles, in every mode; a
+        // command still running when the command exits ends before the
+        // exit frame
+        if frame.frame_type == FrameType::Exit {
+            let mut frames: Vec<Frame> = self.commands.finish().into_iter().collect();
+            frames.extend(self.process_mode(frame).await?);
+            return Ok(frames);
+        }
+        let mut marked = self.commands.observe(&frame);
+        marked.extend(self.osc.observe(&frame));
+        let mut frames = self.process_mode(frame).await?;
+        frames.extend(marked);
+        Ok(frames)
+    }
+
+    async fn process_mode(&mut self, frame: Frame) -> Result<Vec<Frame>> {
         match self.mode {
             TokenMode::Raw => Ok(vec![frame]),
             TokenMode::Compact => self.process_compact(frame).await,
@@ -35,61 +492,320 @@ impl OutputProcessor {
         }
     }
 
-    async fn process_compact(&mut self, mut frame: Frame) -> Result<Vec<Frame>> {
+    async fn process_compact(&mut self, frame: Frame) -> Result<Vec<Frame>> {
+        let frames = self.compact_frame(frame).await?;
+        Ok(match self.repeats {
+            Some(ref mut repeats) => frames.into_iter().flat_map(|frame| repeats.offer(frame)).collect(),
+            None => frames,
+        })
+    }
+
+    async fn compact_frame(&mut self, mut frame: Frame) -> Result<Vec<Frame>> {
         match frame.frame_type {
             FrameType::Stdout | FrameType::Stderr => {
-                if let Some(ref data) = frame.data {
-                    let cleaned = self.clean_output(data);
-                    
-                    // Check if this looks like a progress update
-                    if self.is_progress_update(&cleaned) {
-                        return self.handle_progress_update(frame, cleaned).await;
-                    }
+                let Some(data) = frame.text() else {
+                    return Ok(vec![frame]);
+                };
+                if self.lines.stream != frame.frame_type {
+                    std::mem::swap(&mut self.lines, &mut self.parked);
+                }
+                let mut data = std::mem::take(&mut self.lines.escape_carry) + &data;
+                self.lines.escape_carry = expect::split_unfinished(&mut data);
+                // What is emitted is cleaned text, whatever came in
+                frame.binary = None;
 
-                    // Batch small outputs together
-                    self.line_buffer.push_str(&cleaned);
-                    
-                    // If we have a complete line or buffer is getting large, emit it
-                    if cleaned.contains('\n') || self.line_buffer.len() > 512 {
-                        frame.data = Some(self.line_buffer.clone());
-                        self.line_buffer.clear();
-                        Ok(vec![frame])
-                    } else {
-                        // Buffer for later
-                        Ok(vec![])
+                // A full-screen application's redraws are held back and
+                // its screen sent as snapshots instead
+                let mut frames = Vec::new();
+                let mut rest = data.as_str();
+                loop {
+                    match self.full_screen {
+                        None => {
+                            let Some(enter) = alt_screen_switch(rest, 'h') else {
+                                frames.extend(self.compact_text(frame, rest).await?);
+                                break;
+                            };
+                            frames.extend(self.compact_text(frame.clone(), &rest[..enter.start()]).await?);
+                            frames.extend(self.flush_lines());
+                            self.full_screen = Some(FullScreen::new(self.cols, self.rows));
+                            rest = &rest[enter.start()..];
+                        }
+                        Some(ref mut screen) => {
+                            let Some(exit) = alt_screen_switch(rest, 'l') else {
+                                screen.emulator.process(rest.as_bytes());
+                                frames.extend(screen.snapshot(frame.ts, self.snapshot_interval, false));
+                                break;
+                            };
+                            // The last screen is sent before the one underneath comes back
+                            screen.emulator.process(&rest.as_bytes()[..exit.start()]);
+                            frames.extend(screen.snapshot(frame.ts, self.snapshot_interval, true));
+                            self.full_screen = None;
+                            rest = &rest[exit.end()..];
+                        }
                     }
-                } else {
-                    Ok(vec![frame])
                 }
+                Ok(frames)
+            }
+            // Output has paused, so the screen as it stands is sent
+            FrameType::Idle => {
+                let mut frames: Vec<Frame> = match self.full_screen {
+                    Some(ref mut screen) => screen.snapshot(frame.ts, Duration::ZERO, true).into_iter().collect(),
+                    None => Vec::new(),
+                };
+                frames.push(frame);
+                Ok(frames)
+            }
+            // Lines either stream left unfinished go out before the exit
+            FrameType::Exit => {
+                let mut frames = self.flush_lines();
+                frames.push(frame);
+                Ok(frames)
             }
             _ => Ok(vec![frame]),
         }
     }
 
+    async fn compact_text(&mut self, frame: Frame, data: &str) -> Result<Vec<Frame>> {
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        let rendered;
+        let data = match self.markup {
+            Some(ref mut markup) => {
+                rendered = markup.render(data);
+                &rendered
+            }
+            None => data,
+        };
+        let data = self.osc_strip_regex.replace_all(data, "").replace("\r\n", "\n");
+
+        let mut frames = Vec::new();
+        let mut complete = false;
+        for segment in data.split_inclusive('\n') {
+            let ends_line = segment.ends_with('\n');
+            let line = segment.trim_end_matches('\n');
+            if line.contains('\r') {
+                let mut drawn = self.lines.current_line.clone();
+                drawn.write(line, &self.ansi_strip_regex);
+                if let Some(label) = self.spinner_turned(&self.lines.current_line.contents(), &drawn.contents()) {
+                    if self.lines.spinner.is_none() {
+                        frames.extend(self.take_line_buffer(&frame));
+                        frames.push(self.spinner_frame(&frame, FrameType::SpinnerStart, label.clone()));
+                        self.lines.spinner = Some(Spinner {
+                            label,
+                            started: frame.ts,
+                        });
+                    }
+                    self.lines.current_line = drawn;
+                    if ends_line {
+                        // The line the spinner was on is left behind
+                        frames.extend(self.end_spinner(&frame));
+                        self.lines.current_line = CurrentLine::default();
+                        self.lines.progress = None;
+                    }
+                    continue;
+                }
+            }
+            frames.extend(self.end_spinner(&frame));
+            // A line redrawn after a progress line carries on its sequence,
+            // so the state it ends in is not lost
+            let redraw = self.lines.progress.is_some() && line.starts_with('\r');
+            let progress = redraw || self.is_progress_update(&self.ansi_strip_regex.replace_all(line, ""));
+            if !progress {
+                frames.extend(self.end_progress(&frame));
+            }
+            self.lines.current_line.write(line, &self.ansi_strip_regex);
+            if !progress {
+                // Batch small outputs together
+                if ends_line {
+                    self.lines.line_buffer.push_str(&self.lines.current_line.take());
+                    self.lines.line_buffer.push('\n');
+                    complete = true;
+                }
+                continue;
+            }
+
+            // Lines before the progress line go out ahead of it
+            frames.extend(self.take_line_buffer(&frame));
+            let state = self.lines.current_line.contents();
+            let progress = self.lines.progress.get_or_insert_with(ProgressLine::default);
+            if !state.is_empty() {
+                progress.state = state;
+            }
+            if ends_line {
+                frames.extend(self.end_progress(&frame));
+            } else if progress.sent.as_ref() != Some(&progress.state) {
+                progress.sent = Some(progress.state.clone());
+                let state = progress.state.clone();
+                frames.push(self.line_update(&frame, state));
+            }
+        }
+
+        // A line too long to wait for its end goes out as it stands
+        if self.lines.current_line.cells.len() > 512 && self.lines.progress.is_none() {
+            let partial = self.lines.current_line.take();
+            self.lines.line_buffer.push_str(&partial);
+            complete = true;
+        }
+        // If we have a complete line or buffer is getting large, emit it
+        if complete || self.lines.line_buffer.len() > 512 {
+            frames.extend(self.take_line_buffer(&frame));
+        }
+        Ok(frames)
+    }
+
+    /// The text of a line redrawn from `before` to `after` if all that
+    /// changed is its spinner glyph.
+    fn spinner_turned(&self, before: &str, after: &str) -> Option<String> {
+        let label = |line: &str| self.spinner_regex.replace_all(line, " ").trim().to_string();
+        let turned = before != after && self.spinner_regex.is_match(after) && label(before) == label(after);
+        turned.then(|| label(after))
+    }
+
+    fn spinner_frame(&self, frame: &Frame, frame_type: FrameType, label: String) -> Frame {
+        let mut spinner = frame.clone();
+        spinner.frame_type = frame_type;
+        spinner.data = Some(label);
+        spinner
+    }
+
+    /// A `spinner_end` for the spinner being held back, once its line
+    /// shows something else.
+    fn end_spinner(&mut self, frame: &Frame) -> Option<Frame> {
+        let spinner = self.lines.spinner.take()?;
+        let elapsed = ((frame.ts - spinner.started) * 1000.0).max(0.0).round() as u64;
+        Some(self.spinner_frame(frame, FrameType::SpinnerEnd, spinner.label).with_duration(elapsed))
+    }
+
+    /// The text batched so far, sent as `frame`.
+    fn take_line_buffer(&mut self, frame: &Frame) -> Option<Frame> {
+        if self.lines.line_buffer.is_empty() {
+            return None;
+        }
+        let mut frame = frame.clone();
+        frame.data = Some(std::mem::take(&mut self.lines.line_buffer));
+        Some(frame)
+    }
+
+    fn line_update(&self, frame: &Frame, state: String) -> Frame {
+        let mut update = frame.clone();
+        update.frame_type = FrameType::LineUpdate;
+        update.percent = self
+            .percent_regex
+            .captures_iter(&state)
+            .last()
+            .and_then(|captures| captures[1].parse().ok())
+            .filter(|percent| *percent <= 100.0);
+        update.data = Some(state);
+        update
+    }
+
+    /// The last state of a progress sequence that has ended, which is
+    /// always sent even if it was sent before, so the consumer sees how
+    /// the progress finished.
+    fn end_progress(&mut self, frame: &Frame) -> Option<Frame> {
+        let progress = self.lines.progress.take()?;
+        // The line it was drawn on has been reported
+        self.lines.current_line = CurrentLine::default();
+        if progress.state.is_empty() {
+            return None;
+        }
+        Some(self.line_update(frame, progress.state).with_reason("final".to_string()))
+    }
+
     async fn process_parsed(&mut self, frame: Frame) -> Result<Vec<Frame>> {
-        // For parsed mode, we would implement more sophisticated parsing
-        // For now, use compact mode as a base
-        self.process_compact(frame).await
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                let (Some(data), Some(ref mut emulator)) = (frame.bytes(), &mut self.emulator) else {
+                    return Ok(vec![frame]);
+                };
+                emulator.process(&data);
+                Ok(self.emulator_frames(frame))
+            }
+            // Output has settled, so whatever block is open is complete.
+            // Exit is left to flush_buffer, as output can still follow it
+            FrameType::Idle => {
+                let mut frames: Vec<Frame> = Self::end_block(&mut self.block).into_iter().collect();
+                frames.push(frame);
+                Ok(frames)
+            }
+            _ => Ok(vec![frame]),
+        }
     }
 
-    fn clean_output(&self, data: &str) -> String {
-        // Strip ANSI escape sequences
-        let mut cleaned = self.ansi_strip_regex.replace_all(data, "").to_string();
-        
-        // Normalize line endings
-        cleaned = cleaned.replace("\r\n", "\n").replace('\r', "\n");
-        
-        // Remove excessive whitespace but preserve structure
-        let lines: Vec<&str> = cleaned.lines().collect();
-        let processed_lines: Vec<String> = lines
-            .iter()
-            .map(|line| {
-                // Trim trailing whitespace but preserve leading indentation
-                line.trim_end().to_string()
-            })
-            .collect();
-        
-        processed_lines.join("\n")
+    /// Turn the emulator's state after an output chunk into frames: settled
+    /// lines as text, the line being edited as a LineUpdate, and the whole
+    /// grid as a Snapshot while a full-screen application is active.
+    fn emulator_frames(&mut self, mut frame: Frame) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let Some(ref mut emulator) = self.emulator else {
+            return vec![frame];
+        };
+
+        let lines = emulator.take_committed_lines();
+        let mut structured = Vec::new();
+        if !lines.is_empty() {
+            for line in &lines {
+                if line.trim().is_empty() {
+                    structured.extend(Self::end_block(&mut self.block));
+                } else if self.block.is_empty()
+                    && !self.block_prompt.is_empty()
+                    && line.starts_with(&self.block_prompt)
+                {
+                    // The command line echoed after the prompt is not output
+                    self.block_prompt.clear();
+                } else if self.block.len() < MAX_BLOCK_LINES {
+                    self.block.push(line.clone());
+                }
+            }
+
+            let mut text = lines.join("\n");
+            text.push('\n');
+            frame.data = Some(text);
+            frame.binary = None;
+            frames.push(frame);
+            self.last_partial_line.clear();
+        }
+
+        if emulator.is_alternate_screen() {
+            let contents = emulator.screen_contents();
+            if contents != self.last_snapshot {
+                frames.push(
+                    Frame::new(FrameType::Snapshot)
+                        .with_data(contents.clone())
+                        .with_size(self.cols, self.rows),
+                );
+                self.last_snapshot = contents;
+            }
+        } else {
+            self.last_snapshot.clear();
+            let partial = emulator.current_line();
+            // A prompt on the current line ends the command's output
+            if self.shell_prompt_regex.is_match(&partial) {
+                structured.extend(Self::end_block(&mut self.block));
+                self.block_prompt = partial.clone();
+            }
+            if partial != self.last_partial_line {
+                if !partial.is_empty() {
+                    frames.push(Frame::new(FrameType::LineUpdate).with_data(partial.clone()));
+                }
+                self.last_partial_line = partial;
+            }
+        }
+
+        frames.extend(structured);
+        frames
+    }
+
+    /// Close the open block of output, with a Structured frame if it parses.
+    fn end_block(block: &mut Vec<String>) -> Option<Frame> {
+        let lines = std::mem::take(block);
+        let found = structure::detect(&lines)?;
+        Some(
+            Frame::new(FrameType::Structured)
+                .with_reason(found.kind.to_string())
+                .with_structured(found.value),
+        )
     }
 
     fn is_progress_update(&self, data: &str) -> bool {
@@ -103,36 +819,72 @@ impl OutputProcessor {
         data.chars().filter(|&c| c == '\r').count() > 2
     }
 
-    async fn handle_progress_update(&mut self, mut frame: Frame, cleaned: String) -> Result<Vec<Frame>> {
-        // Convert progress output to line_update frames
-        frame.frame_type = FrameType::LineUpdate;
-        
-        // Only emit if this is different from the last update
-        if self.last_line_update.as_ref() != Some(&cleaned) {
-            frame.data = Some(cleaned.clone());
-            self.last_line_update = Some(cleaned);
-            Ok(vec![frame])
-        } else {
-            // Skip duplicate progress updates
-            Ok(vec![])
+    pub fn mode(&self) -> TokenMode {
+        self.mode
+    }
+
+    /// Whatever compact mode holds of both streams' output: spinners and
+    /// progress lines ended, and unfinished lines as they stand.
+    fn flush_lines(&mut self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            let end = Frame::new(self.lines.stream.clone());
+            let ended = self.end_spinner(&end);
+            if ended.is_some() {
+                // Nothing but the spinner was left on the line
+                self.lines.progress = None;
+                self.lines.current_line = CurrentLine::default();
+            }
+            frames.extend(ended.or_else(|| self.end_progress(&end)));
+            let partial = self.lines.current_line.take();
+            self.lines.line_buffer.push_str(&partial);
+            frames.extend(self.take_line_buffer(&end));
+            std::mem::swap(&mut self.lines, &mut self.parked);
         }
+        frames
+    }
+
+    /// Switch token mode mid-session, returning output buffered by the old
+    /// mode. Parsed mode starts from a blank screen grid.
+    pub fn set_mode(&mut self, mode: TokenMode) -> Vec<Frame> {
+        let frames = self.flush_buffer();
+        self.block_prompt.clear();
+        self.lines.escape_carry.clear();
+        self.parked.escape_carry.clear();
+        self.full_screen = None;
+        self.mode = mode;
+        self.emulator = matches!(mode, TokenMode::Parsed).then(|| Emulator::new(self.cols, self.rows));
+        self.last_partial_line = String::new();
+        self.last_snapshot = String::new();
+        frames
+    }
+
+    /// Shed all processing state and fall back to raw passthrough, returning
+    /// whatever output was still buffered.
+    pub fn degrade(&mut self) -> Vec<Frame> {
+        self.set_mode(TokenMode::Raw)
     }
 
     pub fn flush_buffer(&mut self) -> Vec<Frame> {
-        let mut frames = Vec::new();
-        
-        // Flush any remaining line buffer
-        if !self.line_buffer.is_empty() {
-            let frame = Frame::new(FrameType::Stdout)
-                .with_data(self.line_buffer.clone());
-            frames.push(frame);
-            self.line_buffer.clear();
+        let mut frames = self.flush_coalesced();
+        // Flush any remaining line buffer, and the run of repeats it may end
+        let mut buffered = self.flush_lines();
+        if let Some(ref mut repeats) = self.repeats {
+            buffered = buffered.into_iter().flat_map(|frame| repeats.offer(frame)).collect();
+            buffered.extend(repeats.end_run());
+            repeats.key = None;
+        }
+        frames.extend(buffered);
+        if let Some(ref mut screen) = self.full_screen {
+            frames.extend(screen.snapshot(f64::INFINITY, Duration::ZERO, true));
         }
         
         // Flush frame buffer
         while let Some(frame) = self.frame_buffer.pop_front() {
             frames.push(frame);
         }
+
+        frames.extend(Self::end_block(&mut self.block));
         
         frames
     }
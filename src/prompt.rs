@@ -0,0 +1,133 @@
+use crate::error::SessionError;
+use crate::expect::EscapeStripper;
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// Longest cursor line kept; a prompt is at its end.
+const MAX_LINE_BYTES: usize = 4096;
+
+/// A prompt pattern, named for `--prompt NAME:REGEX`.
+struct Matcher {
+    name: Option<String>,
+    /// The pattern as given
+    pattern: String,
+    /// The pattern anchored to the end of the line
+    regex: Regex,
+}
+
+/// Reports a `prompt` frame whenever the line the cursor is on ends in
+/// one of the prompt patterns, naming the pattern in `id` and with its
+/// capture groups, so an agent driving nested REPLs (bash, then python,
+/// then pdb) knows which one is waiting. Patterns are tried in the order
+/// given, and a prompt redrawn unchanged is reported once. Python and
+/// others draw their prompts on stderr, so it is followed too.
+#[derive(Default)]
+pub struct PromptDetector {
+    matchers: Vec<Matcher>,
+    /// The line the cursor is on, escapes stripped
+    line: String,
+    /// A carriage return not yet known to start a CRLF
+    cr: bool,
+    /// The prompt line last reported, until the next line starts
+    reported: Option<String>,
+    escapes: EscapeStripper,
+}
+
+impl PromptDetector {
+    /// Detect `prompts`, each a pattern and an optional name.
+    pub fn new(prompts: &[(Option<String>, String)]) -> Result<Self, SessionError> {
+        let matchers = prompts
+            .iter()
+            .map(|(name, pattern)| {
+                let regex = Regex::new(&format!("(?:{})\\s*$", pattern))
+                    .map_err(|e| SessionError::InvalidRequest(format!("invalid prompt regex: {}", e)))?;
+                Ok(Matcher {
+                    name: name.clone(),
+                    pattern: pattern.clone(),
+                    regex,
+                })
+            })
+            .collect::<Result<_, SessionError>>()?;
+        Ok(Self {
+            matchers,
+            ..Default::default()
+        })
+    }
+
+    /// Follow an output frame, returning the `prompt` frame when it leaves
+    /// the cursor at a prompt.
+    pub fn observe(&mut self, frame: &Frame) -> Option<Frame> {
+        if self.matchers.is_empty() || !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return None;
+        }
+        let data = frame.text()?;
+        for c in self.escapes.strip(&data).chars() {
+            if std::mem::take(&mut self.cr) && c != '\n' {
+                self.line.clear();
+            }
+            match c {
+                '\n' => {
+                    self.line.clear();
+                    self.reported = None;
+                }
+                '\r' => self.cr = true,
+                '\x08' => {
+                    self.line.pop();
+                }
+                c if c.is_control() && c != '\t' => {}
+                c => self.line.push(c),
+            }
+        }
+        if self.line.len() > MAX_LINE_BYTES {
+            let mut cut = self.line.len() - MAX_LINE_BYTES;
+            while !self.line.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.line.drain(..cut);
+        }
+
+        if self.reported.as_ref() == Some(&self.line) {
+            return None;
+        }
+        let (matcher, captures) = self
+            .matchers
+            .iter()
+            .find_map(|matcher| Some((matcher, matcher.regex.captures(&self.line)?)))?;
+        let mut groups = BTreeMap::new();
+        for (i, group) in captures.iter().enumerate().skip(1) {
+            if let Some(group) = group {
+                groups.insert(i.to_string(), group.as_str().to_string());
+            }
+        }
+        for name in matcher.regex.capture_names().flatten() {
+            if let Some(group) = captures.name(name) {
+                groups.insert(name.to_string(), group.as_str().to_string());
+            }
+        }
+        let mut prompt = Frame::new(FrameType::Prompt)
+            .with_data(self.line.clone())
+            .with_regex(matcher.pattern.clone());
+        if !groups.is_empty() {
+            prompt = prompt.with_captures(groups);
+        }
+        if let Some(ref name) = matcher.name {
+            prompt = prompt.with_id(name.clone());
+        }
+        self.reported = Some(self.line.clone());
+        Some(prompt)
+    }
+}
+
+/// Parse `--prompt NAME:REGEX`, a prompt pattern with a name made of
+/// letters, digits, `-` and `_`.
+pub fn parse_named_prompt(spec: &str) -> Result<(String, String), String> {
+    let (name, pattern) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not NAME:REGEX, e.g. 'python:>>> $'", spec))?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("prompt name '{}' may only hold letters, digits, '-' and '_'", name));
+    }
+    Regex::new(pattern).map_err(|e| format!("invalid regex '{}': {}", pattern, e))?;
+    Ok((name.to_string(), pattern.to_string()))
+}
@@ -1,112 +1,369 @@
+use crate::env::CommandEnv;
+use crate::error::SessionError;
 use crate::frame::{Frame, FrameType};
-use anyhow::{anyhow, Result};
-use futures::stream::Stream;
+use crate::redact::MASK;
+use crate::sched::Scheduling;
+use crate::termios::{termios_frame, TermiosChange};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::termios::{self, LocalFlags, SpecialCharacterIndices};
+use nix::unistd::Pid;
 use portable_pty::{Child, CommandBuilder, PtyPair, PtySize};
-use regex::Regex;
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::{Read, Write};
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tokio::time::sleep;
+use tokio::time::{sleep, Interval, MissedTickBehavior};
 use tracing::{debug, error, info, warn};
 
+/// End of file at a terminal unless its settings say otherwise.
+const CTRL_D: u8 = 0x04;
+
+/// Requests handled by a running PTY session.
+pub enum PtyCommand {
+    Write(Vec<u8>),
+    /// Write once `delay` has passed since the input before it went out
+    WriteAfter { delay: Duration, data: Vec<u8> },
+    /// End the command's input as Ctrl-D at a terminal does
+    Eof,
+    Resize { cols: u16, rows: u16 },
+    /// Write with echo off, reporting the input only as redacted
+    WriteSecret(Vec<u8>),
+    /// Change the terminal's line discipline once earlier input is written
+    SetTermios(TermiosChange),
+    Kill,
+    Signal(Signal),
+}
+
+/// Parse a signal name such as `SIGINT` or `INT`.
+pub fn parse_signal(name: &str) -> Result<Signal, SessionError> {
+    let name = name.trim().to_ascii_uppercase();
+    let full = if name.starts_with("SIG") { name } else { format!("SIG{}", name) };
+    full.parse()
+        .map_err(|_| SessionError::InvalidRequest(format!("unknown signal {}", full)))
+}
+
+/// Cloneable handle for driving a `PtyRunner` after `split()`.
+#[derive(Clone)]
+pub struct PtyHandle {
+    cmd_tx: mpsc::UnboundedSender<PtyCommand>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl PtyHandle {
+    /// Bytes of output read from the PTY that are still waiting in the
+    /// frame channel, i.e. how far the reader of the frames is behind.
+    pub fn queued_output(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Account for `frame` having been taken from the frame channel.
+    pub fn output_taken(&self, frame: &Frame) {
+        let len = queued_len(frame);
+        if len > 0 {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+        }
+    }
+
+    pub fn write(&self, data: Vec<u8>) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::Write(data))
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Write `data` once `delay` has passed since the input queued before
+    /// it was written. Input written later waits its turn behind it.
+    pub fn write_after(&self, delay: Duration, data: Vec<u8>) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::WriteAfter { delay, data })
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Send the terminal's end-of-file character after the input queued
+    /// so far, so a command reading its input sees it end.
+    pub fn eof(&self) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::Eof)
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::Resize { cols, rows })
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Deliver `signal` to the terminal's foreground process group.
+    pub fn signal(&self, signal: Signal) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::Signal(signal))
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Write a password or other secret with the terminal's echo off; its
+    /// `stdin` frame says only that a secret was sent.
+    pub fn write_secret(&self, data: Vec<u8>) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::WriteSecret(data))
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Change echo, raw mode and other termios flags of the terminal; a
+    /// `termios` frame reports the settings it leaves.
+    pub fn set_termios(&self, change: TermiosChange) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::SetTermios(change))
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+
+    /// Ask the command to terminate (SIGHUP); its `exit` frame follows.
+    pub fn kill(&self) -> Result<(), SessionError> {
+        self.cmd_tx
+            .send(PtyCommand::Kill)
+            .map_err(|_| SessionError::PtyIo("PTY session is closed".to_string()))
+    }
+}
+
+/// A second PTY receiving the command's stderr, so it can be told apart
+/// from stdout while still looking like a terminal to the command.
+struct StderrPty {
+    master: File,
+    // Held open so reads block rather than fail until the command exits
+    _slave: OwnedFd,
+}
+
+impl StderrPty {
+    fn open(size: PtySize) -> Result<(Self, String), SessionError> {
+        let winsize = nix::pty::Winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = nix::pty::openpty(&winsize, None)
+            .map_err(|e| SessionError::PtyIo(format!("failed to open stderr PTY: {}", e)))?;
+        let path = nix::unistd::ttyname(pty.slave.as_raw_fd())
+            .map_err(|e| SessionError::PtyIo(format!("failed to name stderr PTY: {}", e)))?;
+
+        let stderr = Self {
+            master: File::from(pty.master),
+            _slave: pty.slave,
+        };
+        Ok((stderr, path.to_string_lossy().into_owned()))
+    }
+}
+
 pub struct PtySession {
     pty_pair: PtyPair,
+    stderr: Option<StderrPty>,
     child: Box<dyn Child + Send + Sync>,
     frame_tx: mpsc::UnboundedSender<Frame>,
-    pub frame_rx: mpsc::UnboundedReceiver<Frame>,
-    prompt_regexes: Vec<Regex>,
+    frame_rx: mpsc::UnboundedReceiver<Frame>,
     idle_timeout: Duration,
+    input_pace: Option<Duration>,
+    write_rate: Option<u64>,
     last_activity: Instant,
-    buffer: Vec<u8>,
-    current_line: String,
 }
 
 impl PtySession {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         command: &str,
         args: &[String],
-        cols: u16,
-        rows: u16,
-        prompt_regexes: Vec<String>,
+        env: &CommandEnv,
+        size: PtySize,
         idle_timeout: Duration,
-    ) -> Result<Self> {
+        split_stderr: bool,
+        scheduling: Scheduling,
+        input_pace: Option<Duration>,
+        write_rate: Option<u64>,
+        termios: TermiosChange,
+    ) -> Result<Self, SessionError> {
         let pty_system = portable_pty::native_pty_system();
-        let size = PtySize {
-            rows: rows,
-            cols: cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        };
 
-        let pty_pair = pty_system.openpty(size)?;
+        let pty_pair = pty_system
+            .openpty(size)
+            .map_err(|e| SessionError::PtyIo(format!("failed to open PTY: {}", e)))?;
+        // Before the command starts, so it never sees the defaults
+        if !termios.is_empty() {
+            // SAFETY: the descriptor belongs to the PTY master, which outlives this call
+            let fd = pty_pair.master.as_raw_fd().map(|fd| unsafe { BorrowedFd::borrow_raw(fd) });
+            fd.ok_or(nix::errno::Errno::EBADF)
+                .and_then(|fd| termios.apply(fd))
+                .map_err(|e| SessionError::PtyIo(format!("failed to set terminal modes: {}", e)))?;
+        }
         
-        let mut cmd = CommandBuilder::new(command);
+        // portable-pty points all three streams at the PTY and closes every
+        // other descriptor, so stderr is redirected by a shell wrapper that
+        // opens the second PTY by name
+        let (stderr, mut cmd) = if split_stderr {
+            let (stderr, path) = StderrPty::open(size)?;
+            let mut cmd = CommandBuilder::new("/bin/sh");
+            cmd.args(["-c", r#"exec 2>"$1" && shift && exec "$@""#, "spectertty", &path, command]);
+            (Some(stderr), cmd)
+        } else {
+            (None, CommandBuilder::new(command))
+        };
         for arg in args {
             cmd.arg(arg);
         }
+        env.apply(&mut cmd)
+            .map_err(|e| SessionError::SpawnFailed(format!("{}: {}", command, e)))?;
 
-        let child = pty_pair.slave.spawn_command(cmd)?;
+        // portable-pty has no pre-exec hook, so CPU affinity, the
+        // scheduling class and IO priority are set on a throwaway thread
+        // that the child inherits them from
+        let PtyPair { master, slave } = pty_pair;
+        let (slave, child) = if !scheduling.applies_to_thread() {
+            let child = slave.spawn_command(cmd);
+            (slave, child)
+        } else {
+            let scheduling = scheduling.clone();
+            let spawner = std::thread::spawn(move || {
+                let child = match scheduling.apply_to_current_thread() {
+                    Ok(()) => slave.spawn_command(cmd),
+                    Err(e) => Err(anyhow::anyhow!("cannot set CPU affinity, scheduling class or IO priority: {}", e)),
+                };
+                (slave, child)
+            });
+            spawner
+                .join()
+                .map_err(|_| SessionError::SpawnFailed(format!("{}: spawning thread panicked", command)))?
+        };
+        let mut child = child.map_err(|e| SessionError::SpawnFailed(format!("{}: {}", command, e)))?;
+        // A cgroup can only be joined once the process exists, so this
+        // follows the spawn as closely as possible
+        if let Err(e) = child.process_id().map_or(Ok(()), |pid| scheduling.place(pid)) {
+            let _ = child.kill();
+            return Err(SessionError::SpawnFailed(format!("{}: cannot move into cgroup: {}", command, e)));
+        }
+        let pty_pair = PtyPair { master, slave };
         
         let (frame_tx, frame_rx) = mpsc::unbounded_channel();
 
-        let compiled_regexes = prompt_regexes
-            .into_iter()
-            .map(|pattern| Regex::new(&pattern))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|e| anyhow!("Invalid regex pattern: {}", e))?;
-
         let session = Self {
             pty_pair,
+            stderr,
             child,
             frame_tx,
             frame_rx,
-            prompt_regexes: compiled_regexes,
             idle_timeout,
+            input_pace,
+            write_rate,
             last_activity: Instant::now(),
-            buffer: Vec::new(),
-            current_line: String::new(),
         };
 
         info!("PTY session started with PID: {:?}", session.child.process_id());
         Ok(session)
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        let mut reader = self.pty_pair.master.try_clone_reader()?;
-        let frame_tx = self.frame_tx.clone();
-        
-        // Spawn output reader task
-        let output_task = tokio::spawn(async move {
-            let mut buffer = [0u8; 8192];
-            loop {
-                match reader.read(&mut buffer) {
-                    Ok(0) => {
-                        debug!("PTY output stream closed");
-                        break;
-                    }
-                    Ok(n) => {
-                        let data = String::from_utf8_lossy(&buffer[..n]).to_string();
-                        let frame = Frame::new(FrameType::Stdout).with_data(data);
-                        
-                        if let Err(e) = frame_tx.send(frame) {
-                            error!("Failed to send stdout frame: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error reading from PTY: {}", e);
-                        break;
-                    }
-                }
-            }
-        });
+    /// Process ID of the command, which also names its process group and
+    /// terminal session.
+    pub fn pid(&self) -> Option<u32> {
+        self.child.process_id()
+    }
+
+    pub fn split(self) -> (PtyRunner, PtyHandle, mpsc::UnboundedReceiver<Frame>) {
+        let PtySession {
+            pty_pair,
+            stderr,
+            child,
+            frame_tx,
+            frame_rx,
+            idle_timeout,
+            input_pace,
+            write_rate,
+            last_activity,
+        } = self;
+
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let runner = PtyRunner {
+            pty_pair,
+            stderr,
+            child,
+            frame_tx,
+            cmd_rx,
+            queued: queued.clone(),
+            idle_timeout,
+            pace: input_pace.map(|pace| {
+                let mut interval = tokio::time::interval(pace);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval
+            }),
+            pending_input: VecDeque::new(),
+            write_rate,
+            delayed: VecDeque::new(),
+            delayed_since: Instant::now(),
+            line_start: true,
+            last_activity,
+        };
+
+        (runner, PtyHandle { cmd_tx, queued }, frame_rx)
+    }
+}
+
+pub struct PtyRunner {
+    pty_pair: PtyPair,
+    stderr: Option<StderrPty>,
+    child: Box<dyn Child + Send + Sync>,
+    frame_tx: mpsc::UnboundedSender<Frame>,
+    cmd_rx: mpsc::UnboundedReceiver<PtyCommand>,
+    /// Output bytes sent but not yet taken, shared with the handle
+    queued: Arc<AtomicUsize>,
+    idle_timeout: Duration,
+    /// Set by `--throttle-input`: ticks once per character to write
+    pace: Option<Interval>,
+    /// Input waiting for its turn under `pace`
+    pending_input: VecDeque<u8>,
+    /// Most bytes of input written a second
+    write_rate: Option<u64>,
+    /// Input sent with a delay, and what was queued behind it, in order
+    delayed: VecDeque<(Duration, WriterJob)>,
+    /// When the delay of the first delayed input started
+    delayed_since: Instant,
+    /// The input queued so far ends a line
+    line_start: bool,
+    last_activity: Instant,
+}
+
+impl PtyRunner {
+    pub async fn run(mut self) -> Result<(), SessionError> {
+        let reader = self
+            .pty_pair
+            .master
+            .try_clone_reader()
+            .map_err(|e| SessionError::PtyIo(format!("failed to read PTY: {}", e)))?;
+        let writer = self
+            .pty_pair
+            .master
+            .take_writer()
+            .map_err(|e| SessionError::PtyIo(format!("failed to write PTY: {}", e)))?;
+        // SAFETY: the descriptor belongs to the PTY master, which outlives this call
+        let terminal = self
+            .pty_pair
+            .master
+            .as_raw_fd()
+            .and_then(|fd| unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned().ok());
+        let writer = PtyWriter::spawn(writer, terminal, self.frame_tx.clone(), self.write_rate);
+        spawn_reader(reader, FrameType::Stdout, self.frame_tx.clone(), self.queued.clone());
+        if let Some(ref stderr) = self.stderr {
+            let reader = stderr
+                .master
+                .try_clone()
+                .map_err(|e| SessionError::PtyIo(format!("failed to read stderr PTY: {}", e)))?;
+            spawn_reader(Box::new(reader), FrameType::Stderr, self.frame_tx.clone(), self.queued.clone());
+        }
 
         // Check child process status periodically
         let mut interval = tokio::time::interval(Duration::from_millis(100));
         
         loop {
+            let delayed_due = self.delayed.front().map(|(delay, _)| self.delayed_since + *delay);
             tokio::select! {
                 // Check for idle timeout
                 _ = sleep(self.idle_timeout) => {
@@ -121,11 +378,40 @@ impl PtySession {
                     }
                 }
                 
+                // Handle input and resize requests
+                Some(command) = self.cmd_rx.recv() => {
+                    // Report failed writes and resizes but keep the session up
+                    if let Err(e) = self.handle_command(command, &writer) {
+                        warn!("{}", e);
+                        let _ = self.frame_tx.send(e.to_frame());
+                    }
+                }
+
+                // Write delayed input whose time has come
+                _ = async { tokio::time::sleep_until(delayed_due.unwrap().into()).await }, if delayed_due.is_some() => {
+                    let (_, job) = self.delayed.pop_front().expect("a delay was due");
+                    self.delayed_since = Instant::now();
+                    if let Err(e) = self.queue_input(job, &writer) {
+                        warn!("{}", e);
+                        let _ = self.frame_tx.send(e.to_frame());
+                    }
+                }
+
+                // Write the next character of throttled input
+                _ = async { self.pace.as_mut().unwrap().tick().await }, if !self.pending_input.is_empty() => {
+                    let len = next_char_len(self.pending_input.make_contiguous());
+                    let data: Vec<u8> = self.pending_input.drain(..len).collect();
+                    if let Err(e) = self.write_input(WriterJob::Input(data), &writer) {
+                        warn!("{}", e);
+                        let _ = self.frame_tx.send(e.to_frame());
+                    }
+                }
+
                 // Check child process status
                 _ = interval.tick() => {
                     match self.child.try_wait() {
                         Ok(Some(exit_status)) => {
-                            let code = if exit_status.success() { 0 } else { 1 };
+                            let code = exit_status.exit_code() as i32;
                             let frame = Frame::new(FrameType::Exit).with_exit_code(code);
                             let _ = self.frame_tx.send(frame);
                             info!("Child process exited with code: {}", code);
@@ -143,181 +429,413 @@ impl PtySession {
             }
         }
 
-        output_task.abort();
         Ok(())
     }
 
+    fn handle_command(
+        &mut self,
+        command: PtyCommand,
+        writer: &PtyWriter,
+    ) -> Result<(), SessionError> {
+        match command {
+            PtyCommand::Write(data) => self.accept_input(Duration::ZERO, WriterJob::Input(data), writer)?,
+            PtyCommand::WriteAfter { delay, data } => self.accept_input(delay, WriterJob::Input(data), writer)?,
+            PtyCommand::WriteSecret(data) => self.accept_input(Duration::ZERO, WriterJob::Secret(data), writer)?,
+            PtyCommand::Eof => {
+                let data = self.eof_input();
+                self.accept_input(Duration::ZERO, WriterJob::Input(data), writer)?;
+            }
+            PtyCommand::Resize { cols, rows } => {
+                // Announce the size first: the command redraws as soon as
+                // it gets SIGWINCH, and that output must not overtake the
+                // resize frame
+                let frame = Frame::new(FrameType::Resize).with_size(cols, rows);
+                if let Err(e) = self.frame_tx.send(frame) {
+                    warn!("Failed to send resize frame: {}", e);
+                }
 
-    pub async fn write_input(&mut self, data: &[u8]) -> Result<()> {
-        let mut writer = self.pty_pair.master.take_writer()?;
-        writer.write_all(data)?;
-        writer.flush()?;
-        
-        let frame = Frame::new(FrameType::Stdin)
-            .with_data(String::from_utf8_lossy(data).to_string());
-        
-        if let Err(e) = self.frame_tx.send(frame) {
-            warn!("Failed to send stdin frame: {}", e);
-        }
+                self.pty_pair.master.resize(PtySize {
+                    rows,
+                    cols,
+                    pixel_width: 0,
+                    pixel_height: 0,
+                })
+                .map_err(|e| SessionError::PtyIo(format!("failed to resize PTY: {}", e)))?;
 
-        self.last_activity = Instant::now();
+                // Confirm the PTY really has the new size
+                let frame = Frame::new(FrameType::ResizeAck).with_size(cols, rows);
+                if let Err(e) = self.frame_tx.send(frame) {
+                    warn!("Failed to send resize_ack frame: {}", e);
+                }
+            }
+            PtyCommand::SetTermios(change) => self.accept_input(Duration::ZERO, WriterJob::Termios(change), writer)?,
+            PtyCommand::Kill => {
+                self.child.kill()?;
+            }
+            PtyCommand::Signal(signal) => {
+                // Deliver it like the terminal would: to the foreground
+                // process group, which is the command's own unless it
+                // started a job of its own
+                let group = self
+                    .pty_pair
+                    .master
+                    .process_group_leader()
+                    .or_else(|| self.child.process_id().map(|pid| pid as i32))
+                    .ok_or_else(|| SessionError::PtyIo("command has no process group".to_string()))?;
+                killpg(Pid::from_raw(group), signal)
+                    .map_err(|e| SessionError::PtyIo(format!("failed to send {}: {}", signal, e)))?;
+
+                let frame = Frame::new(FrameType::Signal).with_signal(signal.as_str().to_string());
+                if let Err(e) = self.frame_tx.send(frame) {
+                    warn!("Failed to send signal frame: {}", e);
+                }
+            }
+        }
         Ok(())
     }
+}
 
-    pub async fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
-        let size = PtySize {
-            rows,
-            cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        };
-
-        self.pty_pair.master.resize(size)?;
-        
-        let frame = Frame::new(FrameType::Resize).with_size(cols, rows);
-        if let Err(e) = self.frame_tx.send(frame) {
-            warn!("Failed to send resize frame: {}", e);
+impl PtyRunner {
+    /// Take input to write after `delay`; nothing overtakes delayed input.
+    fn accept_input(
+        &mut self,
+        delay: Duration,
+        job: WriterJob,
+        writer: &PtyWriter,
+    ) -> Result<(), SessionError> {
+        if let WriterJob::Input(data) | WriterJob::Secret(data) = &job {
+            if let Some(last) = data.last() {
+                self.line_start = matches!(last, b'\n' | b'\r');
+            }
         }
-
+        if delay.is_zero() && self.delayed.is_empty() {
+            return self.queue_input(job, writer);
+        }
+        if self.delayed.is_empty() {
+            self.delayed_since = Instant::now();
+        }
+        self.delayed.push_back((delay, job));
         Ok(())
     }
 
-    pub fn next_frame(&mut self) -> Option<Frame> {
-        self.frame_rx.try_recv().ok()
+    /// What ends the command's input: the terminal's EOF character, twice
+    /// in canonical mode when a line has been typed but not entered, as the
+    /// first only hands over that line.
+    fn eof_input(&self) -> Vec<u8> {
+        // SAFETY: the descriptor belongs to the PTY master, which outlives this call
+        let termios = self
+            .pty_pair
+            .master
+            .as_raw_fd()
+            .and_then(|fd| termios::tcgetattr(unsafe { BorrowedFd::borrow_raw(fd) }).ok());
+        match termios {
+            Some(termios) if termios.local_flags.contains(LocalFlags::ICANON) => {
+                let veof = match termios.control_chars[SpecialCharacterIndices::VEOF as usize] {
+                    0 => CTRL_D,
+                    veof => veof,
+                };
+                if self.line_start {
+                    vec![veof]
+                } else {
+                    vec![veof, veof]
+                }
+            }
+            _ => vec![CTRL_D],
+        }
     }
 
-    pub async fn wait_for_frame(&mut self) -> Option<Frame> {
-        self.frame_rx.recv().await
+    /// Write input now, or a character at a time under `pace`; secrets
+    /// and terminal mode changes are never paced.
+    fn queue_input(&mut self, job: WriterJob, writer: &PtyWriter) -> Result<(), SessionError> {
+        match job {
+            WriterJob::Input(data) if self.pace.is_some() => {
+                self.pending_input.extend(data);
+                Ok(())
+            }
+            job => self.write_input(job, writer),
+        }
     }
 
-
-    pub fn is_alive(&mut self) -> bool {
-        self.child.try_wait().unwrap_or(None).is_none()
+    fn write_input(&mut self, job: WriterJob, writer: &PtyWriter) -> Result<(), SessionError> {
+        writer.send(job)?;
+        self.last_activity = Instant::now();
+        Ok(())
     }
+}
 
-    pub fn split(self) -> (PtyRunner, mpsc::UnboundedReceiver<Frame>) {
-        let PtySession {
-            pty_pair,
-            child,
-            frame_tx,
-            frame_rx,
-            prompt_regexes,
-            idle_timeout,
-            last_activity,
-            buffer,
-            current_line,
-        } = self;
+/// Largest single write to the PTY. Bigger input goes in pieces, so that
+/// a command reading slowly never faces one huge burst.
+const WRITE_CHUNK: usize = 4096;
 
-        let runner = PtyRunner {
-            pty_pair,
-            child,
-            frame_tx,
-            prompt_regexes,
-            idle_timeout,
-            last_activity,
-            buffer,
-            current_line,
-        };
+/// How long echo stays off after a secret is written. The terminal reads
+/// what was written a moment later, and turning echo back on sooner would
+/// still echo it.
+const SECRET_SETTLE: Duration = Duration::from_millis(100);
 
-        (runner, frame_rx)
-    }
+/// Work for the writer thread, done in the order it was sent.
+enum WriterJob {
+    Input(Vec<u8>),
+    /// Input written with echo off and reported only as redacted
+    Secret(Vec<u8>),
+    /// Terminal modes change between writes, so input written with echo
+    /// off is never echoed
+    Termios(TermiosChange),
 }
 
-pub struct PtyRunner {
-    pty_pair: PtyPair,
-    child: Box<dyn Child + Send + Sync>,
-    frame_tx: mpsc::UnboundedSender<Frame>,
-    prompt_regexes: Vec<Regex>,
-    idle_timeout: Duration,
-    last_activity: Instant,
-    buffer: Vec<u8>,
-    current_line: String,
+/// Writes input to the PTY on a thread of its own, in order: a command
+/// that stops reading fills the terminal's input queue and blocks the
+/// write, which must not hold up the session. Each input is confirmed by
+/// a `stdin` frame once written, or reported by a `write_error` frame.
+struct PtyWriter {
+    tx: std::sync::mpsc::Sender<WriterJob>,
 }
 
-impl PtyRunner {
-    pub async fn run(mut self) -> Result<()> {
-        let mut reader = self.pty_pair.master.try_clone_reader()?;
-        let frame_tx = self.frame_tx.clone();
-        
-        // Spawn output reader task
-        let output_task = tokio::spawn(async move {
-            let mut buffer = [0u8; 8192];
-            loop {
-                match reader.read(&mut buffer) {
-                    Ok(0) => {
-                        debug!("PTY output stream closed");
-                        break;
-                    }
-                    Ok(n) => {
-                        let data = String::from_utf8_lossy(&buffer[..n]).to_string();
-                        let frame = Frame::new(FrameType::Stdout).with_data(data);
-                        
-                        if let Err(e) = frame_tx.send(frame) {
-                            error!("Failed to send stdout frame: {}", e);
+impl PtyWriter {
+    /// Start writing to `writer`, at most `rate` bytes a second if given;
+    /// `terminal` is the PTY whose modes `WriterJob::Termios` changes.
+    fn spawn(
+        mut writer: Box<dyn Write + Send>,
+        terminal: Option<OwnedFd>,
+        frame_tx: mpsc::UnboundedSender<Frame>,
+        rate: Option<u64>,
+    ) -> Self {
+        let (tx, rx) = std::sync::mpsc::channel::<WriterJob>();
+        std::thread::spawn(move || {
+            for job in rx {
+                let (data, secret) = match job {
+                    WriterJob::Input(data) => (data, false),
+                    WriterJob::Secret(data) => (data, true),
+                    WriterJob::Termios(change) => {
+                        let frame = match terminal.as_ref().map(|fd| change.apply(fd.as_fd())) {
+                            Some(Ok(settings)) => termios_frame(&settings),
+                            Some(Err(e)) => {
+                                SessionError::PtyIo(format!("failed to set terminal modes: {}", e)).to_frame()
+                            }
+                            None => SessionError::PtyIo("terminal modes cannot be changed".to_string()).to_frame(),
+                        };
+                        if frame_tx.send(frame).is_err() {
                             break;
                         }
+                        continue;
+                    }
+                };
+                let silenced = secret && silence_echo(terminal.as_ref());
+                let mut written = 0;
+                let result = data.chunks(WRITE_CHUNK).try_for_each(|chunk| {
+                    writer.write_all(chunk)?;
+                    writer.flush()?;
+                    written += chunk.len();
+                    if let Some(rate) = rate {
+                        std::thread::sleep(Duration::from_secs_f64(chunk.len() as f64 / rate as f64));
                     }
-                    Err(e) => {
-                        error!("Error reading from PTY: {}", e);
-                        break;
+                    Ok::<_, std::io::Error>(())
+                });
+
+                if silenced {
+                    std::thread::sleep(SECRET_SETTLE);
+                    let echo = TermiosChange { echo: Some(true), ..Default::default() };
+                    if let Err(e) = terminal.as_ref().map_or(Ok(()), |fd| echo.apply(fd.as_fd()).map(drop)) {
+                        warn!("Failed to turn echo back on: {}", e);
                     }
                 }
+
+                let mut frames = Vec::new();
+                if written > 0 && secret {
+                    let frame = Frame::new(FrameType::Stdin).with_data(MASK.to_string());
+                    frames.push(frame.with_reason("secret".to_string()));
+                } else if written > 0 {
+                    let text = String::from_utf8_lossy(&data[..written]).into_owned();
+                    frames.push(Frame::new(FrameType::Stdin).with_data(text));
+                }
+                if let Err(e) = result {
+                    warn!("Failed to write to PTY: {}", e);
+                    frames.push(
+                        Frame::new(FrameType::WriteError)
+                            .with_reason(e.to_string())
+                            .with_value((data.len() - written) as f64),
+                    );
+                }
+                // Only an abandoned session stops listening
+                if frames.into_iter().any(|frame| frame_tx.send(frame).is_err()) {
+                    break;
+                }
             }
+            disable_eof(terminal.as_ref());
+            drop(writer);
         });
+        Self { tx }
+    }
+
+    fn send(&self, job: WriterJob) -> Result<(), SessionError> {
+        self.tx
+            .send(job)
+            .map_err(|_| SessionError::PtyIo("PTY writer has stopped".to_string()))
+    }
+}
+
+/// Turn echo off on `terminal` if it is on, returning whether it was.
+/// Stop portable-pty's writer from sending a newline and EOF as it is
+/// dropped: the command has finished by then, and the terminal would echo
+/// the newline as a stray line of output after the `exit` frame.
+fn disable_eof(terminal: Option<&OwnedFd>) {
+    let Some(fd) = terminal else { return };
+    if let Ok(mut settings) = termios::tcgetattr(fd.as_fd()) {
+        settings.control_chars[SpecialCharacterIndices::VEOF as usize] = 0;
+        let _ = termios::tcsetattr(fd.as_fd(), termios::SetArg::TCSANOW, &settings);
+    }
+}
+
+fn silence_echo(terminal: Option<&OwnedFd>) -> bool {
+    let Some(fd) = terminal else {
+        return false;
+    };
+    let echoing = termios::tcgetattr(fd.as_fd()).is_ok_and(|settings| settings.local_flags.contains(LocalFlags::ECHO));
+    let quiet = TermiosChange { echo: Some(false), ..Default::default() };
+    echoing && quiet.apply(fd.as_fd()).is_ok()
+}
+
+/// Length of the character `input` starts with when input is throttled: a
+/// whole CSI or SS3 escape sequence, an Alt-modified key or one UTF-8
+/// encoded character.
+fn next_char_len(input: &[u8]) -> usize {
+    let len = match input {
+        [] => 0,
+        [0x1b, b'[', rest @ ..] => rest
+            .iter()
+            .position(|byte| (0x40..=0x7e).contains(byte))
+            .map_or(input.len(), |end| end + 3),
+        [0x1b, b'O', _, ..] => 3,
+        [0x1b, _, ..] => 2,
+        [lead, ..] => match lead.leading_ones() {
+            2..=4 => lead.leading_ones() as usize,
+            _ => 1,
+        },
+    };
+    len.min(input.len())
+}
+
+impl Drop for PtyRunner {
+    fn drop(&mut self) {
+        // The reader threads keep the PTY open, so the command would not
+        // get a hangup when the session is abandoned
+        if let Ok(None) = self.child.try_wait() {
+            debug!("Terminating command of abandoned session");
+            let _ = self.child.kill();
+        }
+    }
+}
+
+/// Forward output from a PTY master as frames of `frame_type`, on a thread
+/// of its own since PTY reads block and would otherwise starve the runtime
+/// workers.
+fn spawn_reader(
+    mut reader: Box<dyn Read + Send>,
+    frame_type: FrameType,
+    frame_tx: mpsc::UnboundedSender<Frame>,
+    queued: Arc<AtomicUsize>,
+) {
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 8192];
+        let mut decoder = OutputDecoder::default();
+        let send = |output: Output| {
+            let frame = output.into_frame(frame_type.clone());
+            queued.fetch_add(queued_len(&frame), Ordering::Relaxed);
+            let sent = frame_tx.send(frame);
+            // Only an abandoned session stops listening, and then the
+            // rest of the output has nowhere to go
+            if let Err(ref e) = sent {
+                debug!("Failed to send {:?} frame: {}", frame_type, e);
+            }
+            sent.is_ok()
+        };
 
-        // Check child process status periodically
-        let mut interval = tokio::time::interval(Duration::from_millis(100));
-        
         loop {
-            tokio::select! {
-                // Check for idle timeout
-                _ = sleep(self.idle_timeout) => {
-                    if self.last_activity.elapsed() >= self.idle_timeout {
-                        let frame = Frame::new(FrameType::Idle)
-                            .with_duration(self.last_activity.elapsed().as_millis() as u64);
-                        if let Err(e) = self.frame_tx.send(frame) {
-                            error!("Failed to send idle frame: {}", e);
-                            break;
-                        }
-                        self.last_activity = Instant::now();
-                    }
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => {
+                    debug!("PTY output stream closed");
+                    break;
                 }
-                
-                // Check child process status
-                _ = interval.tick() => {
-                    match self.child.try_wait() {
-                        Ok(Some(exit_status)) => {
-                            let code = if exit_status.success() { 0 } else { 1 };
-                            let frame = Frame::new(FrameType::Exit).with_exit_code(code);
-                            let _ = self.frame_tx.send(frame);
-                            info!("Child process exited with code: {}", code);
-                            break;
-                        }
-                        Ok(None) => {
-                            // Still running
-                        }
-                        Err(e) => {
-                            error!("Error checking child status: {}", e);
-                            break;
-                        }
-                    }
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Error reading from PTY: {}", e);
+                    break;
+                }
+            };
+            if let Some(output) = decoder.decode(&buffer[..n]) {
+                if !send(output) {
+                    return;
                 }
             }
         }
+        if let Some(output) = decoder.finish() {
+            send(output);
+        }
+    });
+}
 
-        output_task.abort();
-        Ok(())
+/// What an output frame adds to the queue: its payload as sent.
+fn queued_len(frame: &Frame) -> usize {
+    match frame.frame_type {
+        FrameType::Stdout | FrameType::Stderr => frame.data.as_ref().map_or(0, String::len),
+        _ => 0,
     }
 }
 
-impl Stream for PtySession {
-    type Item = Frame;
+/// A chunk of command output: text when it is valid UTF-8, else the raw
+/// bytes.
+enum Output {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Output {
+    fn into_frame(self, frame_type: FrameType) -> Frame {
+        match self {
+            Output::Text(text) => Frame::new(frame_type).with_data(text),
+            Output::Binary(bytes) => Frame::new(frame_type).with_binary_data(bytes),
+        }
+    }
+}
+
+/// Splits PTY reads into text and binary chunks. A UTF-8 sequence cut off
+/// by the end of a read is held back until the rest arrives, so text is
+/// never mangled into replacement characters.
+#[derive(Default)]
+struct OutputDecoder {
+    pending: Vec<u8>,
+}
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.frame_rx.poll_recv(cx) {
-            Poll::Ready(Some(frame)) => Poll::Ready(Some(frame)),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+impl OutputDecoder {
+    fn decode(&mut self, data: &[u8]) -> Option<Output> {
+        self.pending.extend_from_slice(data);
+        let held = incomplete_tail(&self.pending);
+        let tail = self.pending.split_off(self.pending.len() - held);
+        let chunk = std::mem::replace(&mut self.pending, tail);
+        if chunk.is_empty() {
+            return None;
         }
+        Some(match String::from_utf8(chunk) {
+            Ok(text) => Output::Text(text),
+            Err(e) => Output::Binary(e.into_bytes()),
+        })
     }
-}
\ No newline at end of file
+
+    /// Bytes still held back when the output ends; a sequence that never
+    /// completed is not valid UTF-8.
+    fn finish(&mut self) -> Option<Output> {
+        (!self.pending.is_empty()).then(|| Output::Binary(std::mem::take(&mut self.pending)))
+    }
+}
+
+/// Length of the start of a multi-byte UTF-8 sequence at the end of
+/// `bytes`, or 0 when they end on a character boundary or in invalid data.
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        match std::str::from_utf8(&bytes[bytes.len() - len..]) {
+            Ok(_) => return 0,
+            Err(e) if e.valid_up_to() == 0 && e.error_len().is_none() => return len,
+            Err(_) => {}
+        }
+    }
+    0
+}
@@ -0,0 +1,104 @@
+use crate::cli::KillChildren;
+use crate::frame::{Frame, FrameType};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::Pid;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long processes left behind get to exit after SIGTERM before they
+/// are sent SIGKILL.
+pub const ORPHAN_GRACE: Duration = Duration::from_secs(2);
+
+/// A process still running in the command's terminal session after the
+/// command itself is gone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Orphan {
+    pub pid: i32,
+    pub group: i32,
+    pub command: String,
+}
+
+/// Processes in terminal session `sid`, other than zombies, found through
+/// /proc. The command leads a session of its own on its PTY, so this is
+/// the command and everything it started that did not leave with setsid.
+pub fn session_members(sid: i32) -> Vec<Orphan> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut members: Vec<Orphan> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter_map(|pid| {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // The command name is in parentheses and may itself hold spaces
+            // and parentheses, so the fields are counted from the last ')'
+            let (head, tail) = stat.rsplit_once(')')?;
+            let command = head.split_once('(')?.1.to_string();
+            let fields: Vec<&str> = tail.split_whitespace().collect();
+            let (state, group, session) = (fields.first()?, fields.get(2)?.parse().ok()?, fields.get(3)?);
+            (*state != "Z" && session.parse() == Ok(sid)).then_some(Orphan { pid, group, command })
+        })
+        .collect();
+    members.sort_by_key(|orphan| orphan.pid);
+    members
+}
+
+/// Deal with whatever the command with process ID `sid` left running in
+/// its session, as `policy` says, and report it as an `orphans` frame, or
+/// None when nothing was left. This blocks for up to `ORPHAN_GRACE`.
+pub fn clean_up(sid: i32, policy: KillChildren) -> Option<Frame> {
+    let found = session_members(sid);
+    if found.is_empty() {
+        return None;
+    }
+    let targets: Vec<&Orphan> = found
+        .iter()
+        .filter(|orphan| match policy {
+            KillChildren::None => false,
+            KillChildren::Group => orphan.group == sid,
+            KillChildren::Session => true,
+        })
+        .collect();
+    if !targets.is_empty() {
+        info!("Terminating {} process(es) left behind by the command", targets.len());
+        signal_all(&targets, sid, policy, Signal::SIGTERM);
+        // Stopped jobs only act on SIGTERM once they run again
+        signal_all(&targets, sid, policy, Signal::SIGCONT);
+
+        let deadline = Instant::now() + ORPHAN_GRACE;
+        let alive = |orphan: &&Orphan| kill(Pid::from_raw(orphan.pid), None).is_ok();
+        while targets.iter().any(alive) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let stubborn: Vec<&Orphan> = targets.iter().copied().filter(alive).collect();
+        if !stubborn.is_empty() {
+            warn!("{} process(es) ignored SIGTERM, killing them", stubborn.len());
+            signal_all(&stubborn, sid, policy, Signal::SIGKILL);
+        }
+    }
+
+    let processes: Vec<_> = found
+        .iter()
+        .map(|orphan| {
+            let killed = targets.iter().any(|target| target.pid == orphan.pid);
+            json!({"pid": orphan.pid, "pgid": orphan.group, "command": orphan.command, "killed": killed})
+        })
+        .collect();
+    Some(
+        Frame::new(FrameType::Orphans)
+            .with_reason(policy.name().to_string())
+            .with_value(found.len() as f64)
+            .with_structured(json!(processes)),
+    )
+}
+
+fn signal_all(targets: &[&Orphan], sid: i32, policy: KillChildren, signal: Signal) {
+    if policy == KillChildren::Group {
+        let _ = killpg(Pid::from_raw(sid), signal);
+        return;
+    }
+    for orphan in targets {
+        let _ = kill(Pid::from_raw(orphan.pid), signal);
+    }
+}
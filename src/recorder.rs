@@ -1,129 +1,419 @@
+use crate::asciicast::{self, EventClock, Header};
+use crate::cli::RecordingFormat;
 use crate::frame::{Frame, FrameType};
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use crate::remote::{RecordUrl, RemoteRecorder};
+use crate::{ttyrec, typescript};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
-#[derive(Serialize, Deserialize)]
-struct AsciinemaHeader {
-    version: u8,
-    width: u16,
-    height: u16,
-    timestamp: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    title: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    command: Option<String>,
-    env: AsciinemaEnv,
+/// Writes a session to a recording in one file format.
+pub trait RecorderBackend: Send {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()>;
+
+    /// Add a marker labelled `label`; formats without markers skip it.
+    fn mark(&mut self, _label: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Write anything the format closes with and flush.
+    fn finish(self: Box<Self>) -> Result<()>;
 }
 
-#[derive(Serialize, Deserialize)]
-struct AsciinemaEnv {
-    #[serde(rename = "SHELL")]
-    shell: String,
-    #[serde(rename = "TERM")]
-    term: String,
+/// Recording time with every silence longer than the idle limit cut down
+/// to it, so an overnight session does not play back as hours of nothing.
+struct Timeline {
+    limit: Option<f64>,
+    last_real: f64,
+    last: f64,
 }
 
-#[derive(Serialize)]
-struct AsciinemaEvent {
-    time: f64,
-    event_type: String,
-    data: String,
+impl Timeline {
+    /// A timeline beginning at `start`, in seconds on any clock.
+    fn new(start: f64, limit: Option<f64>) -> Self {
+        Self {
+            limit,
+            last_real: start,
+            last: start,
+        }
+    }
+
+    /// Where `real`, on the same clock, falls in the recording.
+    fn at(&mut self, real: f64) -> f64 {
+        let gap = (real - self.last_real).max(0.0);
+        self.last_real = self.last_real.max(real);
+        self.last += self.limit.map_or(gap, |limit| gap.min(limit));
+        self.last
+    }
 }
 
 pub struct AsciinemaRecorder {
-    writer: BufWriter<File>,
+    writer: Box<dyn Write + Send>,
     start_time: Instant,
-    last_timestamp: f64,
+    timeline: Timeline,
+    clock: EventClock,
+    version: u8,
 }
 
 impl AsciinemaRecorder {
+    /// Record to a new file at `path`, opening with `header`. Silences
+    /// are capped at the header's `idle_time_limit`.
+    pub fn new<P: AsRef<Path>>(path: P, header: Header) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::with_writer(Box::new(BufWriter::new(file)), header)
+    }
+
+    /// Record to `writer`, which is flushed after every event.
+    pub fn with_writer(mut writer: Box<dyn Write + Send>, header: Header) -> Result<Self> {
+        writeln!(writer, "{}", header.to_line()?)?;
+
+        Ok(Self {
+            writer,
+            start_time: Instant::now(),
+            timeline: Timeline::new(0.0, header.idle_time_limit),
+            clock: EventClock::new(header.version),
+            version: header.version,
+        })
+    }
+
+    fn write_event(&mut self, code: &str, data: String) -> Result<()> {
+        let timestamp = self.timeline.at(self.start_time.elapsed().as_secs_f64());
+        let event = self.clock.event(timestamp, code, data);
+        writeln!(self.writer, "{}", serde_json::to_string(&event)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl RecorderBackend for AsciinemaRecorder {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()> {
+        // Only record frame types asciicast can represent
+        match asciicast::frame_event(frame, self.version) {
+            Some((code, data)) => self.write_event(code, data),
+            None => Ok(()),
+        }
+    }
+
+    fn mark(&mut self, label: &str) -> Result<()> {
+        self.write_event("m", label.to_string())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Output as ttyrec records, for ttyplay. ttyrec has no input, resize or
+/// exit records, so only output is kept.
+pub struct TtyrecRecorder {
+    writer: BufWriter<File>,
+    /// Unix time the recording started; records are timed from it on the
+    /// monotonic clock, so a wall clock stepped mid-session cannot skew them
+    started: f64,
+    start_time: Instant,
+    timeline: Timeline,
+}
+
+impl TtyrecRecorder {
+    pub fn new<P: AsRef<Path>>(path: P, idle_limit: Option<f64>) -> Result<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            started: now.as_secs_f64(),
+            start_time: Instant::now(),
+            timeline: Timeline::new(0.0, idle_limit),
+        })
+    }
+}
+
+impl RecorderBackend for TtyrecRecorder {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()> {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Ok(());
+        }
+        if let Some(data) = frame.bytes() {
+            let ts = self.started + self.timeline.at(self.start_time.elapsed().as_secs_f64());
+            ttyrec::write_record(&mut self.writer, ts, &data)?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Output as a script(1) typescript, with a classic timing file beside it
+/// so `scriptreplay FILE.timing FILE` plays it back.
+pub struct TypescriptRecorder {
+    log: BufWriter<File>,
+    timing: BufWriter<File>,
+    last_time: Instant,
+    idle_limit: Option<f64>,
+    exit_code: Option<i32>,
+}
+
+impl TypescriptRecorder {
     pub fn new<P: AsRef<Path>>(
         path: P,
         width: u16,
         height: u16,
         command: Option<String>,
+        idle_limit: Option<f64>,
     ) -> Result<Self> {
-        let file = File::create(path)?;
-        let mut writer = BufWriter::new(file);
+        let path = path.as_ref();
+        let mut log = BufWriter::new(File::create(path)?);
+        let timing = BufWriter::new(File::create(typescript::timing_path(path))?);
+        log.write_all(typescript::header(command.as_deref(), width, height).as_bytes())?;
+        Ok(Self {
+            log,
+            timing,
+            last_time: Instant::now(),
+            idle_limit,
+            exit_code: None,
+        })
+    }
+}
 
-        // Write asciinema v2 header
-        let header = AsciinemaHeader {
-            version: 2,
-            width,
-            height,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            title: Some("SpecterTTY Recording".to_string()),
-            command,
-            env: AsciinemaEnv {
-                shell: std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()),
-                term: std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string()),
-            },
-        };
+impl RecorderBackend for TypescriptRecorder {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()> {
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                let Some(data) = frame.bytes() else {
+                    return Ok(());
+                };
+                let now = Instant::now();
+                let delay = (now - self.last_time).as_secs_f64();
+                let delay = self.idle_limit.map_or(delay, |limit| delay.min(limit));
+                self.last_time = now;
+                typescript::write_chunk(&mut self.log, &mut self.timing, delay, &data)?;
+                self.log.flush()?;
+                self.timing.flush()?;
+            }
+            FrameType::Exit => self.exit_code = frame.code,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.log.write_all(typescript::trailer(self.exit_code).as_bytes())?;
+        self.log.flush()?;
+        self.timing.flush()?;
+        Ok(())
+    }
+}
+
+/// When a recording moves on to a new segment and how many are kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    /// Size in bytes past which the segment is closed
+    pub max_size: u64,
+    /// Segments kept on disk, oldest deleted first (default: all)
+    pub keep: Option<usize>,
+}
+
+/// Where segment `index` of a recording at `path` goes: the number goes
+/// before the extension, so `session.cast` becomes `session.2.cast`.
+pub fn segment_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.file_stem().unwrap_or_default());
+    name.push(format!(".{}", index));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
 
-        let header_json = serde_json::to_string(&header)?;
-        writeln!(writer, "{}", header_json)?;
+/// A recording split into numbered segments of about `max_size` bytes, so
+/// a long-lived session does not grow one file without bound. Every
+/// segment is a complete recording with a header of its own, opening with
+/// a marker naming the segment it continues, in formats that have
+/// markers. The next segment is only started once there is something to
+/// put in it.
+struct SegmentedRecorder {
+    path: PathBuf,
+    template: Template,
+    rotation: Rotation,
+    index: usize,
+    current: Option<Box<dyn RecorderBackend>>,
+}
 
+impl SegmentedRecorder {
+    fn new(path: PathBuf, template: Template, rotation: Rotation) -> Result<Self> {
+        let current = template.open(&segment_path(&path, 1))?;
         Ok(Self {
-            writer,
-            start_time: Instant::now(),
-            last_timestamp: 0.0,
+            path,
+            template,
+            rotation,
+            index: 1,
+            current: Some(current),
         })
     }
 
-    pub fn record_frame(&mut self, frame: &Frame) -> Result<()> {
-        let timestamp = self.start_time.elapsed().as_secs_f64();
-        
-        // Only record certain frame types for asciinema compatibility
-        let (event_type, data) = match &frame.frame_type {
-            FrameType::Stdout => ("o", frame.data.as_deref().unwrap_or("")),
-            FrameType::Stdin => ("i", frame.data.as_deref().unwrap_or("")),
-            FrameType::Stderr => ("o", frame.data.as_deref().unwrap_or("")), // stderr goes to stdout in asciinema
-            FrameType::Resize => {
-                if let (Some(cols), Some(rows)) = (frame.cols, frame.rows) {
-                    // Asciinema doesn't have a standard resize event, so we'll output a comment
-                    ("o", "# Terminal resized\r\n")
-                } else {
-                    return Ok(()); // Skip if no size info
-                }
+    fn next_segment(&mut self) -> Result<Box<dyn RecorderBackend>> {
+        let previous = segment_path(&self.path, self.index);
+        self.index += 1;
+        let path = segment_path(&self.path, self.index);
+        let mut recorder = self.template.open(&path)?;
+        let previous_name = previous.file_name().unwrap_or_default().to_string_lossy();
+        recorder.mark(&format!("continued from {}", previous_name))?;
+        info!("Recording continues in {:?}", path);
+
+        if let Some(expired) = self.rotation.keep.and_then(|keep| self.index.checked_sub(keep)) {
+            if expired > 0 {
+                remove_segment(&segment_path(&self.path, expired), self.template.format);
             }
-            _ => return Ok(()), // Skip other frame types
-        };
+        }
+        Ok(recorder)
+    }
+}
 
-        let event = AsciinemaEvent {
-            time: timestamp,
-            event_type: event_type.to_string(),
-            data: data.to_string(),
+impl RecorderBackend for SegmentedRecorder {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()> {
+        // New segments open at the current size, not the initial one
+        if let (FrameType::Resize, Some(cols), Some(rows)) = (&frame.frame_type, frame.cols, frame.rows) {
+            self.template.width = cols;
+            self.template.height = rows;
+        }
+        let mut recorder = match self.current.take() {
+            Some(recorder) => recorder,
+            None => self.next_segment()?,
         };
+        recorder.record_frame(frame)?;
 
-        let event_json = serde_json::to_string(&event)?;
-        writeln!(self.writer, "{}", event_json)?;
-        
-        self.last_timestamp = timestamp;
-        self.writer.flush()?;
-        
+        // Recorders flush every frame, so the file is as long as written
+        let size = fs::metadata(segment_path(&self.path, self.index))?.len();
+        if size >= self.rotation.max_size {
+            recorder.finish()?;
+        } else {
+            self.current = Some(recorder);
+        }
         Ok(())
     }
 
-    pub fn finish(mut self) -> Result<()> {
-        self.writer.flush()?;
-        Ok(())
+    fn mark(&mut self, label: &str) -> Result<()> {
+        match self.current {
+            Some(ref mut recorder) => recorder.mark(label),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        match self.current {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn remove_segment(path: &Path, format: RecordingFormat) {
+    let mut paths = vec![path.to_path_buf()];
+    if matches!(format, RecordingFormat::Raw) {
+        paths.push(typescript::timing_path(path));
+    }
+    for path in paths {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove old recording segment {:?}: {}", path, e),
+        }
+    }
+}
+
+/// What every recording of a session is opened with.
+#[derive(Clone)]
+struct Template {
+    width: u16,
+    height: u16,
+    command: Option<String>,
+    format: RecordingFormat,
+    idle_limit: Option<f64>,
+    tags: Vec<String>,
+    meta: BTreeMap<String, String>,
+}
+
+impl Template {
+    fn cast_header(&self, version: u8) -> Header {
+        Header {
+            idle_time_limit: self.idle_limit,
+            tags: self.tags.clone(),
+            meta: self.meta.clone(),
+            ..Header::for_session(version, self.width, self.height, self.command.clone())
+        }
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn RecorderBackend>> {
+        Ok(match self.format {
+            RecordingFormat::Cast => Box::new(AsciinemaRecorder::new(path, self.cast_header(2))?),
+            RecordingFormat::CastV3 => Box::new(AsciinemaRecorder::new(path, self.cast_header(3))?),
+            RecordingFormat::Ttyrec => Box::new(TtyrecRecorder::new(path, self.idle_limit)?),
+            RecordingFormat::Raw => Box::new(TypescriptRecorder::new(
+                path,
+                self.width,
+                self.height,
+                self.command.clone(),
+                self.idle_limit,
+            )?),
+            RecordingFormat::Frames => return Err(anyhow!("Frame logs are written with --frame-log, not --record")),
+        })
     }
 }
 
+/// The active recordings, each in whichever format was asked for.
 pub struct RecordingManager {
-    recorder: Option<AsciinemaRecorder>,
+    recorders: Vec<Box<dyn RecorderBackend>>,
+    idle_limit: Option<f64>,
+    tags: Vec<String>,
+    meta: BTreeMap<String, String>,
+}
+
+impl Default for RecordingManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RecordingManager {
     pub fn new() -> Self {
-        Self { recorder: None }
+        Self {
+            recorders: Vec::new(),
+            idle_limit: None,
+            tags: Vec::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+
+    /// Cap silences in recordings started from now on at `secs` seconds.
+    pub fn set_idle_limit(&mut self, secs: Option<f64>) {
+        self.idle_limit = secs;
+    }
+
+    /// Label asciicast recordings started from now on with `tags` and
+    /// the provenance in `meta`. The other formats have no header for them.
+    pub fn set_metadata(&mut self, tags: Vec<String>, meta: BTreeMap<String, String>) {
+        self.tags = tags;
+        self.meta = meta;
+    }
+
+    fn template(&self, width: u16, height: u16, command: Option<String>, format: RecordingFormat) -> Template {
+        Template {
+            width,
+            height,
+            command,
+            format,
+            idle_limit: self.idle_limit,
+            tags: self.tags.clone(),
+            meta: self.meta.clone(),
+        }
     }
 
     pub fn start_recording<P: AsRef<Path>>(
@@ -132,26 +422,66 @@ impl RecordingManager {
         width: u16,
         height: u16,
         command: Option<String>,
+        format: RecordingFormat,
     ) -> Result<()> {
-        self.recorder = Some(AsciinemaRecorder::new(path, width, height, command)?);
+        let recorder = self.template(width, height, command, format).open(path.as_ref())?;
+        self.recorders.push(recorder);
+        Ok(())
+    }
+
+    /// Record to numbered segments beside `path` (see `segment_path`),
+    /// moving on to the next whenever one reaches `rotation.max_size`.
+    pub fn start_segmented_recording<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        width: u16,
+        height: u16,
+        command: Option<String>,
+        format: RecordingFormat,
+        rotation: Rotation,
+    ) -> Result<()> {
+        let template = self.template(width, height, command, format);
+        let recorder = SegmentedRecorder::new(path.as_ref().to_path_buf(), template, rotation)?;
+        self.recorders.push(Box::new(recorder));
+        Ok(())
+    }
+
+    /// Stream an asciicast recording to `url` as well as, or instead of,
+    /// recording to a file.
+    pub fn start_streaming(
+        &mut self,
+        url: RecordUrl,
+        headers: Vec<(String, String)>,
+        width: u16,
+        height: u16,
+        command: Option<String>,
+        format: RecordingFormat,
+    ) -> Result<()> {
+        let version = match format {
+            RecordingFormat::Cast => 2,
+            RecordingFormat::CastV3 => 3,
+            _ => return Err(anyhow!("--record-url streams asciicast, so --record-format must be cast or cast-v3")),
+        };
+        let header = self.template(width, height, command, format).cast_header(version);
+        self.recorders.push(Box::new(RemoteRecorder::new(url, headers, header)?));
         Ok(())
     }
 
     pub fn record_frame(&mut self, frame: &Frame) -> Result<()> {
-        if let Some(ref mut recorder) = self.recorder {
+        for recorder in &mut self.recorders {
             recorder.record_frame(frame)?;
         }
         Ok(())
     }
 
     pub fn stop_recording(&mut self) -> Result<()> {
-        if let Some(recorder) = self.recorder.take() {
+        for recorder in self.recorders.drain(..) {
             recorder.finish()?;
         }
         Ok(())
     }
 
     pub fn is_recording(&self) -> bool {
-        self.recorder.is_some()
+        !self.recorders.is_empty()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,140 @@
+use crate::cli::RedactArgs;
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use regex::bytes::{Captures, Regex};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// What masked text is replaced with.
+pub const MASK: &str = "[REDACTED]";
+
+/// Shortest secret `add_secret` masks; shorter ones would mask ordinary
+/// words.
+pub const MIN_SECRET_LEN: usize = 4;
+
+/// Built-in credential patterns. A `keep` group survives masking so the
+/// text still says what was there.
+const BUILT_IN: &[&str] = &[
+    // AWS access key ids
+    r"\b(?:AKIA|ASIA|ABIA|ACCA)[0-9A-Z]{16}\b",
+    // AWS secret access keys, named as in credentials files and env vars
+    r"(?i)(?P<keep>aws_?secret_?access_?key\W{0,3}\s*[=:]\s*\W?)[A-Za-z0-9/+=]{40,}",
+    // Bearer tokens in headers and curl commands
+    r"(?i)(?P<keep>\bbearer\s+)[A-Za-z0-9\-._~+/]+=*",
+    // PEM private key blocks
+    r"-----BEGIN [A-Z0-9 ]*PRIVATE KEY-----(?s:.*?)-----END [A-Z0-9 ]*PRIVATE KEY-----",
+];
+
+/// Masks credentials in output and input frames before they reach any
+/// sink, recordings included.
+///
+/// Patterns are matched within one frame, except private key blocks:
+/// once a block has begun, everything in that stream is masked until its
+/// END line arrives.
+pub struct SecretRedactor {
+    rules: Vec<Regex>,
+    begin_key: Regex,
+    end_key: Regex,
+    /// Streams, by frame type and source, inside a private key block
+    open_keys: HashSet<(FrameType, Option<String>)>,
+}
+
+impl SecretRedactor {
+    pub fn new(patterns: &[String], built_in: bool) -> Result<Self> {
+        let mut rules = Vec::new();
+        if built_in {
+            rules.extend(BUILT_IN.iter().map(|pattern| Regex::new(pattern).expect("built-in secret pattern")));
+        }
+        for pattern in patterns {
+            rules.push(Regex::new(pattern).with_context(|| format!("Invalid --redact pattern '{}'", pattern))?);
+        }
+        Ok(Self {
+            rules,
+            begin_key: Regex::new(r"-----BEGIN [A-Z0-9 ]*PRIVATE KEY-----").unwrap(),
+            end_key: Regex::new(r"-----END [A-Z0-9 ]*PRIVATE KEY-----").unwrap(),
+            open_keys: HashSet::new(),
+        })
+    }
+
+    pub fn from_args(args: &RedactArgs) -> Result<Option<Self>> {
+        if args.redact.is_empty() && !args.redact_secrets {
+            return Ok(None);
+        }
+        Self::new(&args.redact, args.redact_secrets).map(Some)
+    }
+
+    /// Mask `secret` itself from now on, wherever it appears, if it is at
+    /// least `MIN_SECRET_LEN` bytes long.
+    pub fn add_secret(&mut self, secret: &str) {
+        if secret.len() >= MIN_SECRET_LEN {
+            self.rules.push(Regex::new(&regex::escape(secret)).expect("escaped text is a valid pattern"));
+        }
+    }
+
+    pub fn redact<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut data = Cow::Borrowed(data);
+        for rule in &self.rules {
+            let replaced = rule.replace_all(&data, |caps: &Captures| {
+                let mut masked = caps.name("keep").map_or(Vec::new(), |keep| keep.as_bytes().to_vec());
+                masked.extend_from_slice(MASK.as_bytes());
+                masked
+            });
+            if let Cow::Owned(replaced) = replaced {
+                data = Cow::Owned(replaced);
+            }
+        }
+        data
+    }
+
+    /// Redacted copy of an output or input frame, or None when it contains
+    /// nothing to mask.
+    pub fn redact_frame(&mut self, frame: &Frame) -> Option<Frame> {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr | FrameType::Stdin) {
+            return None;
+        }
+        let bytes = frame.bytes()?;
+        let stream = (frame.frame_type.clone(), frame.source.clone());
+        let mut data = Cow::Borrowed(bytes.as_ref());
+
+        // The rest of a private key that began in an earlier frame
+        if self.open_keys.contains(&stream) {
+            let Some(end) = self.end_key.find(&data).map(|found| found.end()) else {
+                return Some(with_bytes(frame, MASK.as_bytes().to_vec()));
+            };
+            let mut rest = MASK.as_bytes().to_vec();
+            rest.extend_from_slice(&data[end..]);
+            data = Cow::Owned(rest);
+            self.open_keys.remove(&stream);
+        }
+
+        if let Cow::Owned(redacted) = self.redact(&data) {
+            data = Cow::Owned(redacted);
+        }
+
+        // A private key whose END is still to come; whole blocks are
+        // already masked by now
+        if let Some(begin) = self.begin_key.find(&data).map(|found| found.start()) {
+            let mut head = data[..begin].to_vec();
+            head.extend_from_slice(MASK.as_bytes());
+            data = Cow::Owned(head);
+            self.open_keys.insert(stream);
+        }
+
+        match data {
+            Cow::Owned(data) => Some(with_bytes(frame, data)),
+            Cow::Borrowed(_) => None,
+        }
+    }
+}
+
+/// `frame` carrying `data`, as text where it is valid UTF-8.
+fn with_bytes(frame: &Frame, data: Vec<u8>) -> Frame {
+    match String::from_utf8(data) {
+        Ok(text) => {
+            let mut frame = frame.clone().with_data(text);
+            frame.binary = None;
+            frame
+        }
+        Err(err) => frame.clone().with_binary_data(err.into_bytes()),
+    }
+}
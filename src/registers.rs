@@ -0,0 +1,180 @@
+use crate::error::SessionError;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Largest register typed in as a heredoc when the controller leaves the
+/// choice to us; bigger ones go through a temp file.
+const MAX_TYPED_BYTES: usize = 16 * 1024;
+
+/// Longest line typed in; terminals in canonical mode cut lines at 4096.
+const MAX_TYPED_LINE: usize = 4000;
+
+/// Heredoc input is sent in pieces of about this size so that no single
+/// write floods the command's input queue.
+const CHUNK_BYTES: usize = 1024;
+
+/// How a register reaches the command.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Via {
+    /// Typed into the shell as `<command> <<'DELIM'`, in chunks
+    Heredoc,
+    /// Bracketed paste, for editors and REPLs
+    Paste,
+    /// Written to a private temp file and typed as `<command> < <file>`
+    File,
+}
+
+impl Via {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Via::Heredoc => "heredoc",
+            Via::Paste => "paste",
+            Via::File => "file",
+        }
+    }
+}
+
+/// Input that delivers a register, split into the writes to make.
+#[derive(Debug)]
+pub struct Delivery {
+    pub via: Via,
+    pub chunks: Vec<String>,
+}
+
+/// Named text buffers controllers fill once and send to the command in
+/// one request, instead of streaming large inputs as keystrokes.
+#[derive(Debug, Default)]
+pub struct Registers {
+    values: HashMap<String, String>,
+    files: Vec<PathBuf>,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, name: &str, data: String) -> Result<(), SessionError> {
+        if name.is_empty() {
+            return Err(SessionError::InvalidRequest("register name is empty".to_string()));
+        }
+        self.values.insert(name.to_string(), data);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Build the input that hands register `name` to the command. Without
+    /// `via`, small registers are typed as a heredoc and the rest go
+    /// through a temp file. `command` receives heredoc and file input and
+    /// defaults to `cat`.
+    pub fn delivery(
+        &mut self,
+        name: &str,
+        via: Option<Via>,
+        command: Option<&str>,
+    ) -> Result<Delivery, SessionError> {
+        let data = self
+            .values
+            .get(name)
+            .ok_or_else(|| SessionError::InvalidRequest(format!("no register named '{}'", name)))?;
+        let command = command.unwrap_or("cat");
+
+        let via = via.unwrap_or_else(|| {
+            let long_line = data.lines().any(|line| line.len() > MAX_TYPED_LINE);
+            if data.len() > MAX_TYPED_BYTES || long_line {
+                Via::File
+            } else {
+                Via::Heredoc
+            }
+        });
+
+        let chunks = match via {
+            Via::Heredoc => heredoc(command, data),
+            Via::Paste => match bracketed(data) {
+                Some(paste) => vec![paste],
+                None => {
+                    return Err(SessionError::InvalidRequest(format!(
+                        "register '{}' contains the bracketed paste end marker",
+                        name
+                    )))
+                }
+            },
+            Via::File => {
+                let data = data.clone();
+                let path = self.write_file(&data)?;
+                vec![format!("{} < {}\n", command, shell_quote(&path.to_string_lossy()))]
+            }
+        };
+        Ok(Delivery { via, chunks })
+    }
+
+    fn write_file(&mut self, data: &str) -> Result<PathBuf, SessionError> {
+        let path = std::env::temp_dir().join(format!(
+            "spectertty-{}-{}.reg",
+            std::process::id(),
+            self.files.len() + 1
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|e| SessionError::PtyIo(format!("failed to create {}: {}", path.display(), e)))?;
+        file.write_all(data.as_bytes())
+            .map_err(|e| SessionError::PtyIo(format!("failed to write {}: {}", path.display(), e)))?;
+        self.files.push(path.clone());
+        Ok(path)
+    }
+}
+
+impl Drop for Registers {
+    fn drop(&mut self) {
+        for path in &self.files {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `command <<'DELIM'` with a delimiter that does not occur in `data`,
+/// split at line ends into chunks of about `CHUNK_BYTES`.
+fn heredoc(command: &str, data: &str) -> Vec<String> {
+    let mut delimiter = "SPECTERTTY_EOF".to_string();
+    while data.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+
+    let mut chunks = vec![format!("{} <<'{}'\n", command, delimiter)];
+    let mut chunk = String::new();
+    for line in data.split_inclusive('\n') {
+        if !chunk.is_empty() && chunk.len() + line.len() > CHUNK_BYTES {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push_str(line);
+    }
+    if !chunk.ends_with('\n') && !data.is_empty() {
+        chunk.push('\n');
+    }
+    chunk.push_str(&delimiter);
+    chunk.push('\n');
+    chunks.push(chunk);
+    chunks
+}
+
+/// `data` wrapped as a bracketed paste, or None when it holds the end
+/// marker, which would end the paste early.
+pub fn bracketed(data: &str) -> Option<String> {
+    (!data.contains("\x1b[201~")).then(|| format!("\x1b[200~{}\x1b[201~", data))
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
@@ -0,0 +1,213 @@
+use crate::cli::{KillArgs, ListArgs};
+use crate::pty::parse_signal;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, TimeZone};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::os::unix::fs::DirBuilderExt;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// A running spectertty session as other spectertty commands find it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The spectertty process serving the session
+    pub pid: u32,
+    pub command: String,
+    /// Unix time the session started
+    pub started: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttyd: Option<String>,
+}
+
+impl Entry {
+    /// Whether `key` is this session's name or ID.
+    pub fn matches(&self, key: &str) -> bool {
+        self.id == key || self.name.as_deref() == Some(key)
+    }
+
+    fn is_alive(&self) -> bool {
+        // Signal 0 only checks the process exists; EPERM means it does
+        // but belongs to someone else
+        matches!(kill(Pid::from_raw(self.pid as i32), None), Ok(()) | Err(Errno::EPERM))
+    }
+}
+
+/// Directory of `ID.json` files, one per live session, shared by every
+/// spectertty of a user so sessions can be listed and found by name.
+pub struct Registry {
+    dir: PathBuf,
+}
+
+impl Registry {
+    /// `$SPECTERTTY_RUNTIME_DIR`, else `spectertty` under
+    /// `$XDG_RUNTIME_DIR`, else `/tmp/spectertty-UID`.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("SPECTERTTY_RUNTIME_DIR") {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+            return Path::new(&dir).join("spectertty");
+        }
+        // SAFETY: getuid has no preconditions and cannot fail
+        let uid = unsafe { libc::getuid() };
+        PathBuf::from(format!("/tmp/spectertty-{}", uid))
+    }
+
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Add a session, refusing an ID or name a live session already has.
+    /// The entry is removed when the returned guard is dropped.
+    pub fn register(&self, entry: &Entry) -> Result<Registration> {
+        // Private: entries name sockets that take commands
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .with_context(|| format!("Failed to create {:?}", self.dir))?;
+        if let Some(ref name) = entry.name {
+            if self.list()?.iter().any(|other| other.matches(name)) {
+                return Err(anyhow!("A session named '{}' is already running", name));
+            }
+        }
+
+        let path = self.dir.join(format!("{}.json", entry.id));
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            // Left behind by a session that did not exit cleanly
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && self.is_stale(&path) => {
+                fs::remove_file(&path)?;
+                OpenOptions::new().write(true).create_new(true).open(&path)?
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(anyhow!("A session with ID '{}' is already running", entry.id));
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {:?}", path)),
+        };
+        file.write_all(serde_json::to_string(entry)?.as_bytes())?;
+        debug!("Registered session {} in {:?}", entry.id, path);
+        Ok(Registration { path })
+    }
+
+    /// Live sessions, oldest first. Entries of sessions that are gone are
+    /// removed on the way.
+    pub fn list(&self) -> Result<Vec<Entry>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.dir)),
+        };
+        let mut entries = Vec::new();
+        for file in dir.flatten() {
+            let path = file.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_entry(&path) {
+                Some(entry) if entry.is_alive() => entries.push(entry),
+                // Unreadable entries may be half-written by a starting
+                // session, so only dead ones are cleaned up
+                Some(_) => {
+                    debug!("Removing stale session entry {:?}", path);
+                    let _ = fs::remove_file(&path);
+                }
+                None => {}
+            }
+        }
+        entries.sort_by(|a, b| a.started.total_cmp(&b.started));
+        Ok(entries)
+    }
+
+    /// The live session with this name or ID.
+    pub fn find(&self, key: &str) -> Result<Entry> {
+        self.list()?
+            .into_iter()
+            .find(|entry| entry.matches(key))
+            .ok_or_else(|| anyhow!("No running session is named '{}' or has that ID", key))
+    }
+
+    fn is_stale(&self, path: &Path) -> bool {
+        read_entry(path).is_some_and(|entry| !entry.is_alive())
+    }
+}
+
+fn read_entry(path: &Path) -> Option<Entry> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// A session's place in the registry, given up on drop.
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Registration {
+    /// Leave the entry in place for a session whose process outlives this
+    /// one, as one restored by `import-state --criu` does.
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove session entry {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Send `signal` to the spectertty process serving `entry`.
+pub fn signal(entry: &Entry, signal: Signal) -> Result<()> {
+    kill(Pid::from_raw(entry.pid as i32), signal)
+        .with_context(|| format!("Failed to send {} to session {} (pid {})", signal, entry.id, entry.pid))
+}
+
+/// Print the running sessions as a table, or as NDJSON with `--json`.
+pub fn print_list(args: &ListArgs) -> Result<()> {
+    let entries = Registry::open(Registry::default_dir()).list()?;
+    if args.json {
+        for entry in &entries {
+            println!("{}", serde_json::to_string(entry)?);
+        }
+        return Ok(());
+    }
+    println!("{:<28} {:<16} {:>8}  {:<19}  {:<24} COMMAND", "ID", "NAME", "PID", "STARTED", "SOCKET");
+    for entry in &entries {
+        let started = Local
+            .timestamp_opt(entry.started as i64, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let socket = entry.socket.as_ref().map(|path| path.display().to_string());
+        println!(
+            "{:<28} {:<16} {:>8}  {:<19}  {:<24} {}",
+            entry.id,
+            entry.name.as_deref().unwrap_or("-"),
+            entry.pid,
+            started,
+            socket.as_deref().unwrap_or("-"),
+            entry.command
+        );
+    }
+    Ok(())
+}
+
+/// Stop the session named on the command line. spectertty shuts down on
+/// SIGTERM as it does for Ctrl-C, finishing recordings and logs.
+pub fn kill_session(args: &KillArgs) -> Result<()> {
+    let signal = parse_signal(&args.signal)?;
+    let entry = Registry::open(Registry::default_dir()).find(&args.session)?;
+    self::signal(&entry, signal)
+}
@@ -0,0 +1,600 @@
+use crate::frame::Frame;
+use crate::http;
+use crate::asciicast::Header;
+use crate::recorder::{AsciinemaRecorder, RecorderBackend};
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Most events held while the collector is unreachable; the oldest go
+/// first beyond this.
+const MAX_BUFFERED: usize = 10_000;
+
+/// Wait before the first reconnection attempt, doubling up to
+/// `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Events written this recently before a connection fails are sent again,
+/// as the kernel accepts writes for a while after the peer has gone.
+const RESEND_WINDOW: Duration = Duration::from_secs(2);
+
+/// Limit on connecting and on each write or read.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the end of a session waits for buffered events to go out.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where `--record-url` streams to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordUrl {
+    tls: bool,
+    websocket: bool,
+    host: String,
+    port: u16,
+    /// Path and query, from the leading `/`
+    target: String,
+}
+
+impl RecordUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| anyhow!("Invalid --record-url '{}': no scheme", url))?;
+        let (tls, websocket, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "http" => (false, false, 80),
+            "https" => (true, false, 443),
+            "ws" => (false, true, 80),
+            "wss" => (true, true, 443),
+            _ => return Err(anyhow!("Invalid --record-url '{}': expected http, https, ws or wss", url)),
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("Invalid --record-url '{}': bad port '{}'", url, port))?,
+            ),
+            _ => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Invalid --record-url '{}': no host", url));
+        }
+        Ok(Self {
+            tls,
+            websocket,
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            target,
+        })
+    }
+
+    /// Host name, with brackets around an IPv6 address.
+    fn bracketed_host(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+
+    /// `Host` header value.
+    fn authority(&self) -> String {
+        let host = self.bracketed_host();
+        let default_port = if self.tls { 443 } else { 80 };
+        if self.port == default_port {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+/// Streams an asciicast recording to a collector over HTTP(S) with a
+/// chunked request body or over a WebSocket, one event per chunk or text
+/// message, instead of writing a file.
+///
+/// Uploading happens on a thread of its own. Events are buffered while
+/// the collector cannot be reached and the connection is retried with
+/// exponential backoff. Every new connection starts again with the
+/// asciicast header and resends the events written in the moments before
+/// the last one failed, so `X-Spectertty-Offset` gives the index of its
+/// first event to let the collector drop repeats. TLS is provided by
+/// `openssl s_client`.
+pub struct RemoteRecorder {
+    cast: AsciinemaRecorder,
+    uploads: Sender<Upload>,
+    done: Receiver<()>,
+}
+
+enum Upload {
+    Line(String),
+    Finish,
+}
+
+impl RemoteRecorder {
+    pub fn new(url: RecordUrl, headers: Vec<(String, String)>, header: Header) -> Result<Self> {
+        let (uploads, queue) = mpsc::channel();
+        let (finished, done) = mpsc::channel();
+        let uploader = Uploader {
+            url,
+            headers,
+            header: None,
+            pending: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            connection: None,
+            delivered_header: false,
+            sent: 0,
+            dropped: 0,
+            backoff: INITIAL_BACKOFF,
+            next_attempt: Instant::now(),
+            failing: false,
+        };
+        thread::Builder::new()
+            .name("record-url".to_string())
+            .spawn(move || {
+                uploader.run(queue);
+                let _ = finished.send(());
+            })?;
+
+        let lines = LineSender {
+            buffer: Vec::new(),
+            uploads: uploads.clone(),
+        };
+        let cast = AsciinemaRecorder::with_writer(Box::new(lines), header)?;
+        Ok(Self { cast, uploads, done })
+    }
+}
+
+impl RecorderBackend for RemoteRecorder {
+    fn record_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.cast.record_frame(frame)
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let Self { cast, uploads, done } = *self;
+        Box::new(cast).finish()?;
+        let _ = uploads.send(Upload::Finish);
+        if done.recv_timeout(FINISH_TIMEOUT + IO_TIMEOUT).is_err() {
+            warn!("Gave up waiting for --record-url to finish uploading");
+        }
+        Ok(())
+    }
+}
+
+/// Hands each complete line written by the asciicast recorder to the
+/// uploader.
+struct LineSender {
+    buffer: Vec<u8>,
+    uploads: Sender<Upload>,
+}
+
+impl Write for LineSender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line[..end]).into_owned();
+            // A stopped uploader has already said why
+            let _ = self.uploads.send(Upload::Line(line));
+        }
+        Ok(())
+    }
+}
+
+struct Uploader {
+    url: RecordUrl,
+    headers: Vec<(String, String)>,
+    /// The asciicast header, resent on every connection
+    header: Option<String>,
+    /// Events not yet written to a connection
+    pending: VecDeque<String>,
+    /// Events written to the current connection within `RESEND_WINDOW`
+    in_flight: VecDeque<(Instant, String)>,
+    connection: Option<Box<dyn Connection>>,
+    delivered_header: bool,
+    sent: u64,
+    dropped: u64,
+    backoff: Duration,
+    next_attempt: Instant,
+    /// Whether the last attempt failed, to log outages once
+    failing: bool,
+}
+
+impl Uploader {
+    fn run(mut self, queue: Receiver<Upload>) {
+        let mut deadline: Option<Instant> = None;
+        loop {
+            let outstanding = !self.pending.is_empty() || (self.header.is_some() && !self.delivered_header);
+            if deadline.is_some() && !outstanding {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                warn!(
+                    "--record-url collector unreachable at the end of the session, {} events not delivered",
+                    self.pending.len()
+                );
+                break;
+            }
+
+            // Wait for events, or until the next attempt to send is due
+            let received = if !outstanding && deadline.is_none() {
+                queue.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                let mut wait = self.next_attempt.saturating_duration_since(Instant::now());
+                if self.connection.is_some() {
+                    wait = Duration::ZERO;
+                }
+                if let Some(deadline) = deadline {
+                    wait = wait.min(deadline.saturating_duration_since(Instant::now()));
+                }
+                queue.recv_timeout(wait)
+            };
+            let mut finish = matches!(received, Err(RecvTimeoutError::Disconnected));
+            for upload in received.into_iter().chain(std::iter::from_fn(|| queue.try_recv().ok())) {
+                match upload {
+                    Upload::Line(line) => self.push(line),
+                    Upload::Finish => finish = true,
+                }
+            }
+            if finish {
+                deadline.get_or_insert_with(|| Instant::now() + FINISH_TIMEOUT);
+            }
+
+            self.send_pending();
+        }
+
+        if let Some(connection) = self.connection.take() {
+            match connection.close() {
+                Ok(()) => info!("Streamed recording to {}", self.url.authority()),
+                Err(e) => warn!("--record-url collector did not accept the recording: {}", e),
+            }
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.header.is_none() {
+            self.header = Some(line);
+            return;
+        }
+        if self.pending.len() >= MAX_BUFFERED {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+        self.pending.push_back(line);
+    }
+
+    fn send_pending(&mut self) {
+        let Some(ref header) = self.header else {
+            return;
+        };
+        if self.connection.is_none() {
+            if Instant::now() < self.next_attempt {
+                return;
+            }
+            match connect(&self.url, &self.headers, self.sent, self.dropped, header) {
+                Ok(connection) => {
+                    self.in_flight.clear();
+                    if self.failing {
+                        info!("Reconnected to --record-url collector {}", self.url.authority());
+                    }
+                    self.connection = Some(connection);
+                    self.delivered_header = true;
+                    self.failing = false;
+                    self.backoff = INITIAL_BACKOFF;
+                }
+                Err(e) => {
+                    self.retry_later(e);
+                    return;
+                }
+            }
+        }
+
+        while let Some(line) = self.pending.front() {
+            let connection = self.connection.as_mut().expect("connected above");
+            if let Err(e) = connection.send_line(line) {
+                self.connection = None;
+                self.requeue_in_flight();
+                self.retry_later(e);
+                return;
+            }
+            let now = Instant::now();
+            while self.in_flight.front().is_some_and(|(written, _)| now - *written > RESEND_WINDOW) {
+                self.in_flight.pop_front();
+            }
+            let line = self.pending.pop_front().expect("front exists");
+            self.in_flight.push_back((now, line));
+            self.sent += 1;
+        }
+    }
+
+    fn requeue_in_flight(&mut self) {
+        while let Some((_, line)) = self.in_flight.pop_back() {
+            self.pending.push_front(line);
+            self.sent -= 1;
+        }
+    }
+
+    fn retry_later(&mut self, error: io::Error) {
+        if self.failing {
+            debug!("--record-url still unreachable: {}", error);
+        } else {
+            warn!("--record-url collector unreachable, buffering events: {}", error);
+        }
+        self.failing = true;
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// An open upload to the collector.
+trait Connection: Send {
+    fn send_line(&mut self, line: &str) -> io::Result<()>;
+
+    /// End the upload and check the collector accepted it.
+    fn close(self: Box<Self>) -> io::Result<()>;
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Open a connection and send the request head and the asciicast header.
+fn connect(
+    url: &RecordUrl,
+    headers: &[(String, String)],
+    offset: u64,
+    dropped: u64,
+    header: &str,
+) -> io::Result<Box<dyn Connection>> {
+    let stream = open_stream(url)?;
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: spectertty/{}\r\nX-Spectertty-Offset: {}\r\n",
+        if url.websocket { "GET" } else { "POST" },
+        url.target,
+        url.authority(),
+        env!("CARGO_PKG_VERSION"),
+        offset,
+    );
+    if dropped > 0 {
+        head.push_str(&format!("X-Spectertty-Dropped: {}\r\n", dropped));
+    }
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    let mut connection: Box<dyn Connection> = if url.websocket {
+        Box::new(WebSocket::open(stream, head)?)
+    } else {
+        Box::new(ChunkedPost::open(stream, head)?)
+    };
+    connection.send_line(header)?;
+    Ok(connection)
+}
+
+fn open_stream(url: &RecordUrl) -> io::Result<Box<dyn Stream>> {
+    if url.tls {
+        return Ok(Box::new(TlsTunnel::open(url)?));
+    }
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", url.host));
+    for address in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, IO_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(Box::new(stream));
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// TLS through `openssl s_client`, verifying the collector's certificate
+/// and host name against the system trust store.
+struct TlsTunnel {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl TlsTunnel {
+    /// Start `openssl s_client` and wait for its handshake, which `-brief`
+    /// reports on stderr.
+    fn open(url: &RecordUrl) -> io::Result<Self> {
+        let mut child = Command::new("openssl")
+            .args(["s_client", "-brief", "-ign_eof", "-nocommands", "-verify_return_error"])
+            .args(["-verify_hostname", &url.host, "-servername", &url.host])
+            .args(["-connect", &format!("{}:{}", url.bracketed_host(), url.port)])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot run openssl for TLS: {}", e)))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = child.stdout.take().expect("piped stdout");
+        let mut tunnel = Self { child, stdin, stdout };
+
+        let mut stderr = BufReader::new(tunnel.child.stderr.take().expect("piped stderr"));
+        let mut last = String::new();
+        loop {
+            let mut line = String::new();
+            if stderr.read_line(&mut line)? == 0 {
+                let _ = tunnel.child.wait();
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("TLS connection failed: {}", last.trim()),
+                ));
+            }
+            if line.trim() == "CONNECTION ESTABLISHED" {
+                break;
+            }
+            last = line;
+        }
+        // Keep reading so openssl never blocks on a full pipe
+        thread::spawn(move || {
+            for line in stderr.lines().map_while(Result::ok) {
+                debug!("openssl: {}", line);
+            }
+        });
+        Ok(tunnel)
+    }
+}
+
+impl Read for TlsTunnel {
+    /// Reads give up after `IO_TIMEOUT`, as on a TCP connection.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut poll = libc::pollfd {
+            fd: self.stdout.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `poll` is a single valid pollfd
+        match unsafe { libc::poll(&mut poll, 1, IO_TIMEOUT.as_millis() as libc::c_int) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Err(io::Error::new(io::ErrorKind::TimedOut, "no response from openssl")),
+            _ => self.stdout.read(buf),
+        }
+    }
+}
+
+impl Write for TlsTunnel {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.stdin.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl Drop for TlsTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A POST whose body is the recording, one chunk per line.
+struct ChunkedPost {
+    stream: Box<dyn Stream>,
+}
+
+impl ChunkedPost {
+    fn open(mut stream: Box<dyn Stream>, mut head: String) -> io::Result<Self> {
+        head.push_str("Content-Type: application/x-asciicast\r\nTransfer-Encoding: chunked\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        Ok(Self { stream })
+    }
+}
+
+impl Connection for ChunkedPost {
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let chunk = format!("{:x}\r\n{}\n\r\n", line.len() + 1, line);
+        self.stream.write_all(chunk.as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn close(mut self: Box<Self>) -> io::Result<()> {
+        self.stream.write_all(b"0\r\n\r\n")?;
+        self.stream.flush()?;
+        let response = http::read_response(&mut self.stream)?;
+        match response.status {
+            200..=299 => Ok(()),
+            _ => Err(io::Error::other(response.to_string())),
+        }
+    }
+}
+
+/// A WebSocket carrying each line as a text message.
+struct WebSocket {
+    stream: Box<dyn Stream>,
+    random: File,
+}
+
+impl WebSocket {
+    fn open(mut stream: Box<dyn Stream>, mut head: String) -> io::Result<Self> {
+        let mut random = File::open("/dev/urandom")?;
+        let mut key = [0u8; 16];
+        random.read_exact(&mut key)?;
+        head.push_str(&format!(
+            "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            base64::prelude::BASE64_STANDARD.encode(key)
+        ));
+        stream.write_all(head.as_bytes())?;
+        stream.flush()?;
+
+        let response = http::read_response(&mut stream)?;
+        if response.status != 101 {
+            return Err(io::Error::other(format!("WebSocket refused: {}", response)));
+        }
+        Ok(Self { stream, random })
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        // Client frames are masked with a fresh random key
+        let mut mask = [0u8; 4];
+        self.random.read_exact(&mut mask)?;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+impl Connection for WebSocket {
+    fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.send_frame(0x1, line.as_bytes())
+    }
+
+    fn close(mut self: Box<Self>) -> io::Result<()> {
+        // Normal closure, then wait for the collector to close its side so
+        // nothing is cut off in transit
+        self.send_frame(0x8, &1000u16.to_be_bytes())?;
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(()),
+                // Everything was sent; the collector just never hung up
+                Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => {
+                    debug!("--record-url collector did not close the WebSocket");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
@@ -0,0 +1,185 @@
+use crate::error::SessionError;
+use crate::expect::EscapeStripper;
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+/// Output kept for matching auto-responders; older text is discarded.
+const MAX_BUFFER_BYTES: usize = 16 * 1024;
+
+/// A rule answering output that matches `regex` with `response`.
+struct Responder {
+    id: String,
+    regex: Regex,
+    response: String,
+    /// Responses left before the rule retires, if limited
+    remaining: Option<u32>,
+    sent: u32,
+}
+
+/// Answers prompts on the command's behalf, such as `Are you sure? [y/N]`
+/// with `y`. Output accumulates with ANSI escapes removed, and a match
+/// consumes it up to the end of the matched text, so one prompt is
+/// answered once however many reads it arrives in.
+#[derive(Default)]
+pub struct AutoResponders {
+    rules: Vec<Responder>,
+    buffer: String,
+    escapes: EscapeStripper,
+    next_id: u64,
+}
+
+impl AutoResponders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer output matching `pattern` with `response`, at most `limit`
+    /// times if given. Returns the rule's ID, `id` or a generated
+    /// `respond-N`; a rule with the same ID is replaced.
+    pub fn add(
+        &mut self,
+        id: Option<String>,
+        pattern: &str,
+        response: String,
+        limit: Option<u32>,
+    ) -> Result<String, SessionError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| SessionError::InvalidRequest(format!("invalid auto-respond regex: {}", e)))?;
+        if regex.is_match("") {
+            let error = format!("auto-respond regex '{}' would match empty output", pattern);
+            return Err(SessionError::InvalidRequest(error));
+        }
+        if limit == Some(0) {
+            return Err(SessionError::InvalidRequest("auto-respond limit must be greater than 0".to_string()));
+        }
+        let id = id.unwrap_or_else(|| {
+            self.next_id += 1;
+            format!("respond-{}", self.next_id)
+        });
+        self.rules.retain(|rule| rule.id != id);
+        self.rules.push(Responder {
+            id: id.clone(),
+            regex,
+            response,
+            remaining: limit,
+            sent: 0,
+        });
+        Ok(id)
+    }
+
+    /// Withdraw the rule registered under `id`, returning the `cancelled`
+    /// frame for it, or None when there is no such rule.
+    pub fn remove(&mut self, id: &str) -> Option<Frame> {
+        let index = self.rules.iter().position(|rule| rule.id == id)?;
+        let rule = self.rules.remove(index);
+        Some(
+            Frame::new(FrameType::Cancelled)
+                .with_id(id.to_string())
+                .with_reason("auto_respond".to_string())
+                .with_regex(rule.regex.as_str().to_string())
+                .with_value(rule.sent as f64),
+        )
+    }
+
+    /// Follow an output frame, returning the responses to send, each
+    /// with the `auto_response` frame reporting it.
+    pub fn feed(&mut self, frame: &Frame) -> Vec<(String, Frame)> {
+        if self.rules.is_empty() || !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Vec::new();
+        }
+        let Some(data) = frame.text() else {
+            return Vec::new();
+        };
+        self.buffer.push_str(&self.escapes.strip(&data));
+        if self.buffer.len() > MAX_BUFFER_BYTES {
+            let mut cut = self.buffer.len() - MAX_BUFFER_BYTES;
+            while !self.buffer.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.buffer.drain(..cut);
+        }
+
+        let mut responses = Vec::new();
+        // The earliest match in the buffer goes first, so prompts are
+        // answered in the order they appeared
+        while let Some((index, start, end)) = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter_map(|(index, rule)| rule.regex.find(&self.buffer).map(|found| (index, found.start(), found.end())))
+            .min_by_key(|&(index, start, _)| (start, index))
+        {
+            let rule = &mut self.rules[index];
+            rule.sent += 1;
+            let last = rule.remaining.as_mut().is_some_and(|remaining| {
+                *remaining -= 1;
+                *remaining == 0
+            });
+            let mut matched = BTreeMap::new();
+            matched.insert("0".to_string(), self.buffer[start..end].to_string());
+            let frame = Frame::new(FrameType::AutoResponse)
+                .with_id(rule.id.clone())
+                .with_regex(rule.regex.as_str().to_string())
+                .with_data(rule.response.clone())
+                .with_captures(matched)
+                .with_value(rule.sent as f64);
+            if last {
+                responses.push((rule.response.clone(), frame.with_reason("limit".to_string())));
+                self.rules.remove(index);
+            } else {
+                responses.push((rule.response.clone(), frame));
+            }
+            self.buffer.drain(..end);
+        }
+        responses
+    }
+}
+
+/// Parse `--auto-respond REGEX=>RESPONSE`. The response may use `\n`,
+/// `\r`, `\t`, `\e`, `\\` and `\xHH` escapes, so `'\[y/N\]=>y\n'` answers
+/// yes and presses Enter.
+pub fn parse_auto_respond(spec: &str) -> Result<(String, String), String> {
+    let (pattern, response) = spec
+        .rsplit_once("=>")
+        .ok_or_else(|| format!("'{}' is not REGEX=>RESPONSE", spec))?;
+    match Regex::new(pattern) {
+        Ok(regex) if regex.is_match("") => return Err(format!("'{}' would match empty output", pattern)),
+        Ok(_) => {}
+        Err(e) => return Err(format!("invalid regex '{}': {}", pattern, e)),
+    }
+    Ok((pattern.to_string(), unescape(response)))
+}
+
+fn unescape(escaped: &str) -> String {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let simple = match tail.first() {
+            Some(b'n') => Some(b'\n'),
+            Some(b'r') => Some(b'\r'),
+            Some(b't') => Some(b'\t'),
+            Some(b'e') => Some(0x1b),
+            Some(b'\\') => Some(b'\\'),
+            _ => None,
+        };
+        if let Some(value) = simple {
+            bytes.push(value);
+            rest = &tail[1..];
+            continue;
+        }
+        let hex = tail.strip_prefix(b"x").and_then(|hex| hex.get(..2));
+        if let Some(value) = hex.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+            bytes.push(value);
+            rest = &tail[3..];
+            continue;
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
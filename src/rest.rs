@@ -0,0 +1,301 @@
+use crate::error::SessionError;
+use crate::http::{self, Request};
+use crate::hub::{SessionHub, SessionSpec};
+use crate::transport::{next_conn_id, require_auth_off_loopback, token_matches, AuthTokens};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Largest request body accepted.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Comment line sent on an idle event stream, so proxies keep it open.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Body of `POST /sessions`.
+#[derive(Debug, Deserialize)]
+struct CreateSession {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
+    #[serde(default = "default_cols")]
+    cols: u16,
+    #[serde(default = "default_rows")]
+    rows: u16,
+}
+
+fn default_cols() -> u16 {
+    120
+}
+
+fn default_rows() -> u16 {
+    40
+}
+
+#[derive(Debug, Deserialize)]
+struct Resize {
+    cols: u16,
+    rows: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct Signal {
+    signal: String,
+}
+
+/// Serves sessions over plain HTTP for tools that speak nothing else:
+///
+/// - `POST /sessions` starts one from a JSON body and returns its ID
+/// - `POST /sessions/ID/input` writes the request body to it
+/// - `POST /sessions/ID/resize` and `/signal` resize and signal it
+/// - `GET /sessions/ID/frames` streams its frames as Server-Sent Events
+/// - `GET /sessions/ID/screen` returns what its screen shows
+/// - `DELETE /sessions/ID` ends it and forgets it
+///
+/// With auth tokens configured, every request must carry an
+/// `Authorization: Bearer NAME:TOKEN` header; without them, only the
+/// loopback interface is served.
+pub struct RestServer {
+    addr: SocketAddr,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl RestServer {
+    pub fn bind(addr: SocketAddr, hub: Arc<SessionHub>, tokens: AuthTokens) -> Result<Self> {
+        require_auth_off_loopback(addr, &tokens, "HTTP")?;
+        let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept HTTP client: {}", e);
+                        continue;
+                    }
+                };
+                let (hub, tokens) = (hub.clone(), tokens.clone());
+                tokio::spawn(async move {
+                    let conn = next_conn_id();
+                    debug!("HTTP client conn-{} connected from {}", conn, peer);
+                    if let Err(e) = serve(stream, &hub, &tokens).await {
+                        debug!("HTTP client conn-{}: {}", conn, e);
+                    }
+                });
+            }
+        });
+
+        info!("Serving HTTP on http://{}/sessions", addr);
+        Ok(Self { addr, accept_task })
+    }
+
+    /// The address clients connect to, with the port picked if it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for RestServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// The request body as JSON, or the 400 to answer with.
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, Reply> {
+    serde_json::from_slice(body).map_err(|e| Reply::error("400 Bad Request", format!("invalid JSON body: {}", e)))
+}
+
+/// A response with a JSON body.
+struct Reply {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Reply {
+    fn ok(status: &'static str, body: serde_json::Value) -> Self {
+        Self { status, body }
+    }
+
+    fn error(status: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.into() }),
+        }
+    }
+
+    fn session(error: SessionError) -> Self {
+        let status = match error {
+            SessionError::InvalidRequest(_) => "400 Bad Request",
+            SessionError::SpawnFailed(_) => "422 Unprocessable Entity",
+            SessionError::Policy(_) => "403 Forbidden",
+            _ => "500 Internal Server Error",
+        };
+        Self {
+            status,
+            body: json!({ "error": error.to_string(), "category": error.category() }),
+        }
+    }
+
+    async fn send(self, writer: &mut OwnedWriteHalf) -> io::Result<()> {
+        let body = self.body.to_string();
+        http::respond(writer, self.status, &[("Content-Type", "application/json")], body.as_bytes()).await
+    }
+}
+
+async fn serve(stream: TcpStream, hub: &Arc<SessionHub>, tokens: &AuthTokens) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (request, body) = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok(read)) => read,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+            return Reply::error("400 Bad Request", e.to_string()).send(&mut writer).await;
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no request")),
+    };
+
+    if !tokens.is_empty() && !authorized(tokens, request.header("authorization")) {
+        warn!("HTTP request for {} without a valid credential", request.path);
+        return Reply::error("401 Unauthorized", "expected Authorization: Bearer NAME:TOKEN")
+            .send(&mut writer)
+            .await;
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let reply = match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["sessions"]) => create(hub, &body).await,
+        ("DELETE", ["sessions", id]) => hub
+            .remove(id)
+            .map(|()| Reply::ok("200 OK", json!({})))
+            .unwrap_or_else(|_| Reply::error("404 Not Found", format!("no session '{}'", id))),
+        (method, ["sessions", id, action]) if hub.contains(id) => match (method, *action) {
+            ("GET", "frames") => {
+                let after = request.header("last-event-id").and_then(|seq| seq.parse().ok()).unwrap_or(0);
+                return stream_frames(hub, id, after, &mut writer).await;
+            }
+            ("GET", "screen") => hub
+                .screen(id)
+                .map(|screen| Reply::ok("200 OK", json!(screen)))
+                .unwrap_or_else(Reply::session),
+            ("POST", "input") => hub
+                .send(id, body)
+                .map(|()| Reply::ok("202 Accepted", json!({})))
+                .unwrap_or_else(Reply::session),
+            ("POST", "resize") => match parse_body::<Resize>(&body) {
+                Ok(size) => hub
+                    .resize(id, size.cols, size.rows)
+                    .map(|()| Reply::ok("202 Accepted", json!({})))
+                    .unwrap_or_else(Reply::session),
+                Err(reply) => reply,
+            },
+            ("POST", "signal") => match parse_body::<Signal>(&body) {
+                Ok(signal) => hub
+                    .signal(id, &signal.signal)
+                    .map(|()| Reply::ok("202 Accepted", json!({})))
+                    .unwrap_or_else(Reply::session),
+                Err(reply) => reply,
+            },
+            (_, "frames" | "screen" | "input" | "resize" | "signal") => {
+                Reply::error("405 Method Not Allowed", format!("{} is not supported here", method))
+            }
+            _ => Reply::error("404 Not Found", format!("no such endpoint {}", request.path)),
+        },
+        (_, ["sessions", id, _]) => Reply::error("404 Not Found", format!("no session '{}'", id)),
+        _ => Reply::error("404 Not Found", format!("no such endpoint {}", request.path)),
+    };
+    reply.send(&mut writer).await
+}
+
+async fn create(hub: &Arc<SessionHub>, body: &[u8]) -> Reply {
+    let body = match parse_body::<CreateSession>(body) {
+        Ok(body) => body,
+        Err(reply) => return reply,
+    };
+    if body.command.is_empty() || body.cols == 0 || body.rows == 0 {
+        return Reply::error("400 Bad Request", "a command and a non-zero window size are needed");
+    }
+    let spec = SessionSpec {
+        command: body.command,
+        args: body.args,
+        env: body.env,
+        cwd: body.cwd,
+        cols: body.cols,
+        rows: body.rows,
+    };
+    match hub.create(spec).await {
+        Ok(session_id) => Reply::ok("201 Created", json!({ "session_id": session_id })),
+        Err(e) => Reply::session(e),
+    }
+}
+
+/// Send the session's frames as Server-Sent Events, one per frame with its
+/// `seq` as the event ID and its type as the event name, until the
+/// session ends. A client reconnecting with `Last-Event-ID` picks up after
+/// that frame.
+async fn stream_frames(hub: &SessionHub, id: &str, after: u64, writer: &mut OwnedWriteHalf) -> io::Result<()> {
+    let mut frames = match hub.subscribe(id) {
+        Ok(subscription) => subscription.after(after),
+        Err(e) => return Reply::session(e).send(writer).await,
+    };
+    let headers = [("Content-Type", "text/event-stream"), ("Cache-Control", "no-cache"), ("Connection", "close")];
+    http::write_head(writer, "200 OK", &headers).await?;
+    loop {
+        let frame = match tokio::time::timeout(SSE_KEEPALIVE, frames.next()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(_) => {
+                writer.write_all(b": keepalive\n\n").await?;
+                continue;
+            }
+        };
+        let name = serde_json::to_value(&frame.frame_type)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut event = String::new();
+        if let Some(seq) = frame.seq {
+            event.push_str(&format!("id: {}\n", seq));
+        }
+        event.push_str(&format!("event: {}\ndata: {}\n\n", name, frame.to_json().map_err(io::Error::other)?));
+        writer.write_all(event.as_bytes()).await?;
+    }
+    writer.shutdown().await
+}
+
+/// Whether an `Authorization` header carries a known `Bearer NAME:TOKEN`.
+fn authorized(tokens: &AuthTokens, header: Option<&str>) -> bool {
+    let credential = header.and_then(|value| value.strip_prefix("Bearer ")).and_then(|value| value.trim().split_once(':'));
+    matches!(credential, Some((name, token)) if token_matches(tokens, name, token))
+}
+
+/// A request and its body.
+async fn read_request(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<(Request, Vec<u8>)> {
+    let request = http::read_request(reader).await?;
+    let length = request.content_length()?;
+    if length > MAX_BODY_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok((request, body))
+}
@@ -0,0 +1,104 @@
+use crate::frame::{Frame, FrameType};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Wait before the first restart; it doubles with each restart in a row,
+/// up to `MAX_BACKOFF`.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest wait between restarts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A run that lasts this long was healthy, so the next restart waits
+/// `INITIAL_BACKOFF` again.
+pub const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+/// When a command that exits is started again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    /// The session ends with the command
+    No,
+    /// After a non-zero exit, at most this many times if given
+    OnFailure(Option<u32>),
+    /// After every exit
+    Always,
+}
+
+/// Parse `--restart`: `no`, `on-failure`, `on-failure:N` or `always`.
+pub fn parse_restart(spec: &str) -> Result<RestartPolicy, String> {
+    match spec.trim().split_once(':') {
+        None => match spec.trim() {
+            "no" => Ok(RestartPolicy::No),
+            "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            "always" => Ok(RestartPolicy::Always),
+            other => Err(format!("Unknown restart policy '{}', expected no, on-failure[:N] or always", other)),
+        },
+        Some(("on-failure", limit)) => match limit.trim().parse::<u32>() {
+            Ok(limit) if limit > 0 => Ok(RestartPolicy::OnFailure(Some(limit))),
+            _ => Err(format!("on-failure needs a number of restarts greater than 0, got '{}'", limit)),
+        },
+        Some(_) => Err(format!("'{}' is not on-failure:N", spec)),
+    }
+}
+
+/// Starts the command again as `--restart` says when it exits. Each
+/// restart is announced by a `stopped` frame when the command has exited,
+/// with its exit `code` and the wait in `duration_ms`, and a `continued`
+/// frame once it runs again; both carry the restart count in `value`.
+pub struct Supervisor {
+    policy: RestartPolicy,
+    restarts: u32,
+    backoff: Duration,
+    started: Instant,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            restarts: 0,
+            backoff: INITIAL_BACKOFF,
+            started: Instant::now(),
+        }
+    }
+
+    /// The command exited with `code`: the `stopped` frame and how long
+    /// to wait before starting it again, or None when the session ends.
+    pub fn exited(&mut self, code: Option<i32>) -> Option<(Frame, Duration)> {
+        let failed = code != Some(0);
+        let again = match self.policy {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure(limit) => failed && limit.is_none_or(|limit| self.restarts < limit),
+            RestartPolicy::Always => true,
+        };
+        if !again {
+            return None;
+        }
+        if self.started.elapsed() >= HEALTHY_RUN {
+            self.backoff = INITIAL_BACKOFF;
+        }
+        let wait = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        self.restarts += 1;
+
+        let frame = Frame::new(FrameType::Stopped)
+            .with_reason("restart".to_string())
+            .with_duration(wait.as_millis() as u64)
+            .with_value(self.restarts as f64);
+        Some((
+            match code {
+                Some(code) => frame.with_exit_code(code),
+                None => frame,
+            },
+            wait,
+        ))
+    }
+
+    /// The command is running again: the `continued` frame.
+    pub fn restarted(&mut self) -> Frame {
+        self.started = Instant::now();
+        Frame::new(FrameType::Continued)
+            .with_reason("restart".to_string())
+            .with_value(self.restarts as f64)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::frame::FrameType;
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+/// Destinations a frame can be routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sink {
+    Json,
+    Socket,
+    Recording,
+    Audit,
+    Syslog,
+    Journald,
+}
+
+impl Sink {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "json" | "stdout" => Ok(Sink::Json),
+            "socket" => Ok(Sink::Socket),
+            "recording" => Ok(Sink::Recording),
+            "audit" => Ok(Sink::Audit),
+            "syslog" => Ok(Sink::Syslog),
+            "journald" => Ok(Sink::Journald),
+            _ => Err(anyhow!(
+                "Unknown sink '{}', expected one of json, socket, recording, audit, syslog, journald",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Route {
+    All,
+    Only(HashSet<FrameType>),
+}
+
+impl Route {
+    fn allows(&self, frame_type: &FrameType) -> bool {
+        match self {
+            Route::All => true,
+            Route::Only(types) => types.contains(frame_type),
+        }
+    }
+}
+
+/// Which frame types each sink receives. Sinks without a `--route` entry
+/// keep their default: everything, except the audit log which only takes
+/// approval and cancellation frames.
+pub struct RoutingTable {
+    routes: HashMap<Sink, Route>,
+}
+
+impl RoutingTable {
+    /// Parse `SINK=TYPE[,TYPE...]` specs, where the type list may also be
+    /// `all` or `none`.
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut routes = HashMap::new();
+
+        for spec in specs {
+            let (sink, types) = spec
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid route '{}', expected SINK=TYPE[,TYPE...]", spec))?;
+            let sink = Sink::parse(sink.trim())?;
+
+            if routes.insert(sink, parse_route(types)?).is_some() {
+                return Err(anyhow!("Sink '{}' is routed more than once", sink_name(sink)));
+            }
+        }
+
+        Ok(Self { routes })
+    }
+
+    /// `--emit`: the frame types consumers of stdout and the socket
+    /// receive, leaving recordings and logs complete. A `--route` for
+    /// either sink takes precedence.
+    pub fn with_emit(mut self, types: &str) -> Result<Self> {
+        let route = parse_route(types)?;
+        for sink in [Sink::Json, Sink::Socket] {
+            self.routes.entry(sink).or_insert_with(|| route.clone());
+        }
+        Ok(self)
+    }
+
+    pub fn allows(&self, sink: Sink, frame_type: &FrameType) -> bool {
+        match self.routes.get(&sink) {
+            Some(route) => route.allows(frame_type),
+            None if sink == Sink::Audit => is_audited(frame_type),
+            None => true,
+        }
+    }
+}
+
+fn sink_name(sink: Sink) -> &'static str {
+    match sink {
+        Sink::Json => "json",
+        Sink::Socket => "socket",
+        Sink::Recording => "recording",
+        Sink::Audit => "audit",
+        Sink::Syslog => "syslog",
+        Sink::Journald => "journald",
+    }
+}
+
+/// `TYPE[,TYPE...]`, `all` or `none`.
+fn parse_route(types: &str) -> Result<Route> {
+    Ok(match types.trim() {
+        "all" => Route::All,
+        "none" | "" => Route::Only(HashSet::new()),
+        types => Route::Only(
+            types
+                .split(',')
+                .map(|name| parse_frame_type(name.trim()))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+fn parse_frame_type(name: &str) -> Result<FrameType> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|_| anyhow!("Unknown frame type '{}' in route", name))
+}
+
+fn is_audited(frame_type: &FrameType) -> bool {
+    matches!(
+        frame_type,
+        FrameType::ApprovalRequired
+            | FrameType::ApprovalVote
+            | FrameType::ApprovalGranted
+            | FrameType::ApprovalDenied
+            | FrameType::Cancelled
+    )
+}
@@ -0,0 +1,106 @@
+use crate::error::SessionError;
+use crate::frame::{Frame, FrameType};
+use std::time::Duration;
+
+/// Thins the frame stream for an observer: output and other high-volume
+/// frames are coalesced into at most `sample_rate` updates a second, or
+/// dropped for `summary_only`, while key events such as prompts, errors
+/// and exit are delivered as they happen.
+#[derive(Debug)]
+pub struct Sampler {
+    period: Duration,
+    summary_only: bool,
+    pending: Vec<Frame>,
+}
+
+impl Sampler {
+    /// Sampler for a `subscribe` request; None asks for the full stream.
+    pub fn new(sample_rate: Option<f64>, summary_only: bool) -> Result<Option<Self>, SessionError> {
+        let period = match sample_rate {
+            Some(rate) if !(rate.is_finite() && rate > 0.0) => {
+                return Err(SessionError::InvalidRequest(format!(
+                    "sample_rate must be a positive number of updates per second, got {}",
+                    rate
+                )));
+            }
+            Some(rate) => Duration::from_secs_f64(1.0 / rate),
+            None if summary_only => Duration::ZERO,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            period,
+            summary_only,
+            pending: Vec::new(),
+        }))
+    }
+
+    /// Time between coalesced updates.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Whether frames are waiting for the next update.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Take a frame from the stream and return what to send now.
+    pub fn offer(&mut self, frame: Frame) -> Vec<Frame> {
+        if !is_sampled(&frame.frame_type) {
+            // Anything held back happened first
+            let mut frames = self.flush();
+            frames.push(frame);
+            return frames;
+        }
+        if self.summary_only {
+            return Vec::new();
+        }
+
+        if is_output(&frame.frame_type) {
+            match self.pending.last_mut() {
+                Some(last) if last.frame_type == frame.frame_type && last.source == frame.source => {
+                    let earlier = std::mem::replace(last, Frame::new(FrameType::Stdout));
+                    *last = merge(earlier, frame);
+                }
+                _ => self.pending.push(frame),
+            }
+        } else {
+            // Only the latest line, screen or health sample matters
+            self.pending
+                .retain(|held| held.frame_type != frame.frame_type || held.source != frame.source);
+            self.pending.push(frame);
+        }
+        Vec::new()
+    }
+
+    /// Frames held back since the last update.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Frame types thinned for observers; the rest are key events.
+fn is_sampled(kind: &FrameType) -> bool {
+    is_output(kind)
+        || matches!(
+            kind,
+            FrameType::LineUpdate | FrameType::Snapshot | FrameType::Cursor | FrameType::Health | FrameType::Idle
+        )
+}
+
+fn is_output(kind: &FrameType) -> bool {
+    matches!(kind, FrameType::Stdout | FrameType::Stderr | FrameType::Stdin)
+}
+
+/// One frame carrying both payloads, stamped like the later one so its
+/// `seq` still works with `resume_from`.
+fn merge(earlier: Frame, later: Frame) -> Frame {
+    if earlier.binary.is_none() && later.binary.is_none() {
+        let mut data = earlier.data.unwrap_or_default();
+        data.push_str(later.data.as_deref().unwrap_or_default());
+        return later.with_data(data);
+    }
+    let mut bytes = earlier.bytes().unwrap_or_default().into_owned();
+    bytes.extend_from_slice(&later.bytes().unwrap_or_default());
+    later.with_binary_data(bytes)
+}
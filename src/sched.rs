@@ -0,0 +1,96 @@
+use crate::cli::{IoClass, SchedPolicy};
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use std::io;
+use std::path::PathBuf;
+
+/// `ioprio_set` target meaning a process, or with id 0 the calling thread.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// CPUs, scheduling class and IO priority the command is spawned with, so
+/// heavy work in the session leaves room for whatever shares the host.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduling {
+    /// CPUs the command may run on (default: any)
+    pub cpus: Vec<usize>,
+    /// Scheduling class (default: inherited)
+    pub policy: Option<SchedPolicy>,
+    /// IO priority class (default: inherited)
+    pub io_class: Option<IoClass>,
+    /// cgroup v2 group the command is moved into once started
+    pub cgroup: Option<PathBuf>,
+}
+
+impl Scheduling {
+    /// Whether anything must be set on the spawning thread.
+    pub fn applies_to_thread(&self) -> bool {
+        !self.cpus.is_empty() || self.policy.is_some() || self.io_class.is_some()
+    }
+
+    /// Apply to the calling thread, whose settings a process it forks
+    /// inherits through exec.
+    pub(crate) fn apply_to_current_thread(&self) -> io::Result<()> {
+        if !self.cpus.is_empty() {
+            let mut set = CpuSet::new();
+            for &cpu in &self.cpus {
+                set.set(cpu)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("CPU {} is out of range", cpu)))?;
+            }
+            sched_setaffinity(Pid::from_raw(0), &set).map_err(io::Error::from)?;
+        }
+        if let Some(policy) = self.policy {
+            let policy = match policy {
+                SchedPolicy::Other => libc::SCHED_OTHER,
+                SchedPolicy::Batch => libc::SCHED_BATCH,
+                SchedPolicy::Idle => libc::SCHED_IDLE,
+            };
+            let param = libc::sched_param { sched_priority: 0 };
+            // SAFETY: `param` is a valid sched_param for these policies
+            if unsafe { libc::sched_setscheduler(0, policy, &param) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(class) = self.io_class {
+            let priority = match class {
+                IoClass::BestEffort => (2 << IOPRIO_CLASS_SHIFT) | 7,
+                IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+            };
+            // SAFETY: ioprio_set takes three integers and no pointers
+            if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the started command into `cgroup`, if set.
+    pub(crate) fn place(&self, pid: u32) -> io::Result<()> {
+        match self.cgroup {
+            Some(ref dir) => std::fs::write(dir.join("cgroup.procs"), pid.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse a CPU list such as `2-3` or `0,4-7`.
+pub fn parse_cpuset(spec: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("'{}' is not a CPU list such as 3, 2-3 or 0,4-7", spec);
+    let mut cpus = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last || last >= CpuSet::count() {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
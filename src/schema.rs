@@ -0,0 +1,39 @@
+use crate::cli::SchemaArgs;
+use crate::control::ControlCommand;
+use crate::frame::{Frame, PROTOCOL_VERSION};
+use anyhow::{Context, Result};
+use schemars::generate::SchemaSettings;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+
+/// The JSON Schema (draft 2020-12) of the wire protocol, derived from the
+/// serde types themselves so it cannot drift from them: `Frame` for what
+/// spectertty sends, `ControlCommand` for what controllers send, under
+/// `$defs`, with the `PROTOCOL_VERSION` they belong to. An instance is
+/// valid if it is either.
+pub fn schema() -> Value {
+    let mut generator = SchemaSettings::draft2020_12().into_generator();
+    let frame = generator.subschema_for::<Frame>();
+    let command = generator.subschema_for::<ControlCommand>();
+    let defs: serde_json::Map<String, Value> = generator.take_definitions(true).into_iter().collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("https://github.com/spectertty/spectertty/schema/v{}.json", PROTOCOL_VERSION),
+        "title": "spectertty frame and control protocol",
+        "protocol_version": PROTOCOL_VERSION,
+        "anyOf": [frame, command],
+        "$defs": defs,
+    })
+}
+
+/// Print the schema for SDK generators and validators.
+pub fn run(args: &SchemaArgs) -> Result<()> {
+    let mut text = serde_json::to_string_pretty(&schema())?;
+    text.push('\n');
+    match args.output {
+        Some(ref path) => fs::write(path, text).with_context(|| format!("Failed to write {:?}", path))?,
+        None => io::stdout().lock().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use crate::cli::ScriptArgs;
+use crate::frame::Frame;
+use crate::session::Session;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Wait applied to expect and exit_code steps that do not give one.
+const DEFAULT_STEP_TIMEOUT_MS: u64 = 10_000;
+
+/// A send/expect script. YAML is a superset of JSON, so both load here.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Script {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default = "default_cols")]
+    cols: u16,
+    #[serde(default = "default_rows")]
+    rows: u16,
+    /// Default timeout for expect and exit_code steps
+    timeout_ms: Option<u64>,
+    steps: Vec<Step>,
+}
+
+fn default_cols() -> u16 {
+    120
+}
+
+fn default_rows() -> u16 {
+    40
+}
+
+/// One script step; exactly one action field must be set.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Step {
+    name: Option<String>,
+    send: Option<String>,
+    expect: Option<String>,
+    sleep_ms: Option<u64>,
+    resize: Option<Size>,
+    exit_code: Option<i32>,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Size {
+    cols: u16,
+    rows: u16,
+}
+
+enum Action {
+    Send(String),
+    Expect(String),
+    Sleep(Duration),
+    Resize { cols: u16, rows: u16 },
+    ExitCode(i32),
+}
+
+impl Step {
+    fn action(&self) -> Result<Action> {
+        let mut actions = Vec::new();
+        if let Some(ref text) = self.send {
+            actions.push(Action::Send(text.clone()));
+        }
+        if let Some(ref pattern) = self.expect {
+            actions.push(Action::Expect(pattern.clone()));
+        }
+        if let Some(ms) = self.sleep_ms {
+            actions.push(Action::Sleep(Duration::from_millis(ms)));
+        }
+        if let Some(ref size) = self.resize {
+            actions.push(Action::Resize {
+                cols: size.cols,
+                rows: size.rows,
+            });
+        }
+        if let Some(code) = self.exit_code {
+            actions.push(Action::ExitCode(code));
+        }
+
+        match actions.len() {
+            1 => Ok(actions.remove(0)),
+            0 => Err(anyhow!("step has no action (send, expect, sleep_ms, resize or exit_code)")),
+            _ => Err(anyhow!("step has more than one action")),
+        }
+    }
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Action::Send(text) => format!("send {:?}", text),
+            Action::Expect(pattern) => format!("expect /{}/", pattern),
+            Action::Sleep(duration) => format!("sleep {}ms", duration.as_millis()),
+            Action::Resize { cols, rows } => format!("resize {}x{}", cols, rows),
+            Action::ExitCode(code) => format!("exit_code {}", code),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Pass,
+    Fail,
+    Skip,
+}
+
+/// Per-step result, printed as a TAP line or an NDJSON object.
+#[derive(Serialize)]
+struct StepReport {
+    step: usize,
+    name: String,
+    status: Status,
+    dur_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl StepReport {
+    fn print(&self, json: bool) -> Result<()> {
+        if json {
+            println!("{}", serde_json::to_string(self)?);
+            return Ok(());
+        }
+        match self.status {
+            Status::Pass => println!("ok {} - {}", self.step, self.name),
+            Status::Fail => println!(
+                "not ok {} - {} # {}",
+                self.step,
+                self.name,
+                self.reason.as_deref().unwrap_or("failed")
+            ),
+            Status::Skip => println!("ok {} - {} # SKIP", self.step, self.name),
+        }
+        Ok(())
+    }
+}
+
+/// A script whose steps all parsed, ready to be run any number of times.
+pub(crate) struct Playbook {
+    script: Script,
+    actions: Vec<Action>,
+}
+
+impl Playbook {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path).with_context(|| format!("Failed to read script {:?}", path))?;
+        let script: Script = serde_yaml::from_str(&source).with_context(|| format!("Invalid script {:?}", path))?;
+
+        let actions = script
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| step.action().with_context(|| format!("Invalid step {}", i + 1)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { script, actions })
+    }
+
+    /// Start the script's command.
+    pub(crate) async fn spawn(&self, throttle_input: Option<Duration>) -> Result<Session> {
+        let mut builder = Session::builder(self.script.command.clone())
+            .args(self.script.args.clone())
+            .size(self.script.cols, self.script.rows);
+        if let Some(pace) = throttle_input {
+            builder = builder.throttle_input(pace);
+        }
+        Ok(builder.spawn().await?)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// The step's name, or what it does when it has none.
+    pub(crate) fn step_name(&self, index: usize) -> String {
+        self.script.steps[index]
+            .name
+            .clone()
+            .unwrap_or_else(|| self.actions[index].describe())
+    }
+
+    /// Perform step `index`, handing each frame read meanwhile to `seen`.
+    pub(crate) async fn perform(&self, index: usize, session: &mut Session, seen: impl FnMut(&Frame)) -> Result<()> {
+        let timeout = Duration::from_millis(
+            self.script.steps[index]
+                .timeout_ms
+                .or(self.script.timeout_ms)
+                .unwrap_or(DEFAULT_STEP_TIMEOUT_MS),
+        );
+        perform(session, &self.actions[index], timeout, seen).await
+    }
+}
+
+/// Run a script file, printing one report per step. Returns whether every
+/// step passed; the first failure skips the remaining steps.
+pub async fn run(args: &ScriptArgs) -> Result<bool> {
+    let playbook = Playbook::load(&args.file)?;
+    let mut session = playbook.spawn(args.throttle_input).await?;
+
+    if !args.json {
+        println!("1..{}", playbook.len());
+    }
+
+    let mut failed = false;
+    for i in 0..playbook.len() {
+        let name = playbook.step_name(i);
+        let started = Instant::now();
+
+        let (status, reason) = if failed {
+            (Status::Skip, None)
+        } else {
+            match playbook.perform(i, &mut session, |_| {}).await {
+                Ok(()) => (Status::Pass, None),
+                Err(e) => {
+                    failed = true;
+                    (Status::Fail, Some(e.to_string()))
+                }
+            }
+        };
+
+        StepReport {
+            step: i + 1,
+            name,
+            status,
+            dur_ms: started.elapsed().as_millis() as u64,
+            reason,
+        }
+        .print(args.json)?;
+    }
+
+    session.close();
+    Ok(!failed)
+}
+
+async fn perform(session: &mut Session, action: &Action, timeout: Duration, seen: impl FnMut(&Frame)) -> Result<()> {
+    match action {
+        Action::Send(text) => Ok(session.send(text)?),
+        Action::Resize { cols, rows } => Ok(session.resize(*cols, *rows)?),
+        Action::Sleep(duration) => {
+            tokio::time::sleep(*duration).await;
+            Ok(())
+        }
+        Action::Expect(pattern) => {
+            session.wait_for_with(pattern, timeout, seen).await?;
+            Ok(())
+        }
+        Action::ExitCode(expected) => match tokio::time::timeout(timeout, session.wait_with(seen)).await {
+            Ok(Some(code)) if code == *expected => Ok(()),
+            Ok(Some(code)) => Err(anyhow!("exited with {}", code)),
+            Ok(None) => Err(anyhow!("session ended without an exit code")),
+            Err(_) => Err(anyhow!("still running after {}ms", timeout.as_millis())),
+        },
+    }
+}
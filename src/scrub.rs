@@ -0,0 +1,131 @@
+use crate::cli::{PhoneLocale, PiiArgs};
+use crate::frame::Frame;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::net::Ipv6Addr;
+
+struct Detector {
+    mask: &'static str,
+    regex: Regex,
+    /// Second opinion on a regex match, e.g. a checksum
+    accept: fn(&str) -> bool,
+}
+
+impl Detector {
+    fn new(mask: &'static str, pattern: &str, accept: fn(&str) -> bool) -> Self {
+        Self {
+            mask,
+            regex: Regex::new(pattern).expect("built-in PII pattern"),
+            accept,
+        }
+    }
+}
+
+/// Masks personal data (emails, phone numbers, IBANs, IP addresses) in
+/// frame text before it reaches any sink.
+///
+/// Frames are scrubbed one at a time, so a value split across two output
+/// chunks can slip through.
+pub struct PiiScrubber {
+    detectors: Vec<Detector>,
+}
+
+impl PiiScrubber {
+    pub fn new(locales: &[PhoneLocale]) -> Self {
+        // Emails first, since their local part can look like other data
+        let mut detectors = vec![
+            Detector::new("[EMAIL]", r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b", |_| true),
+            Detector::new("[IBAN]", r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,3})?\b", valid_iban),
+            Detector::new("[IP]", r"\b(?:\d{1,3}\.){3}\d{1,3}\b", valid_ipv4),
+            Detector::new("[IP]", r"(?i)\b[0-9a-f]{1,4}(?::[0-9a-f]{0,4}){2,7}\b", valid_ipv6),
+        ];
+        for locale in locales {
+            let pattern = match locale {
+                PhoneLocale::Intl => r"\+[1-9][\d .()-]{6,18}\d",
+                PhoneLocale::Us => r"(?:\(\d{3}\) ?|\b\d{3}[ .-])\d{3}[ .-]\d{4}\b",
+                PhoneLocale::Uk => r"\b0\d{2,4}[ -]?\d{3,4}[ -]?\d{3,4}\b",
+                PhoneLocale::De => r"\b0\d{2,5}[ /-]\d{4,8}\b",
+            };
+            detectors.push(Detector::new("[PHONE]", pattern, plausible_phone));
+        }
+        Self { detectors }
+    }
+
+    pub fn from_args(args: &PiiArgs) -> Option<Self> {
+        args.pii_scrub.then(|| Self::new(&args.pii_locale))
+    }
+
+    pub fn scrub<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for detector in &self.detectors {
+            let replaced = detector.regex.replace_all(&text, |caps: &Captures| {
+                let found = &caps[0];
+                if (detector.accept)(found) {
+                    detector.mask.to_string()
+                } else {
+                    found.to_string()
+                }
+            });
+            if let Cow::Owned(replaced) = replaced {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// Scrubbed copy of `frame`, or None when it contains nothing to mask.
+    pub fn scrub_frame(&self, frame: &Frame) -> Option<Frame> {
+        let mut scrubbed: Option<Frame> = None;
+
+        if let (Some(ref data), None) = (&frame.data, frame.binary) {
+            if let Cow::Owned(data) = self.scrub(data) {
+                scrubbed.get_or_insert_with(|| frame.clone()).data = Some(data);
+            }
+        }
+        if let Some(ref captures) = frame.captures {
+            for (name, value) in captures {
+                if let Cow::Owned(value) = self.scrub(value) {
+                    let copy = scrubbed.get_or_insert_with(|| frame.clone());
+                    copy.captures.get_or_insert_with(Default::default).insert(name.clone(), value);
+                }
+            }
+        }
+
+        scrubbed
+    }
+}
+
+/// ISO 13616 check digits: the rearranged number mod 97 must be 1.
+fn valid_iban(candidate: &str) -> bool {
+    let compact: String = candidate.chars().filter(|c| !c.is_whitespace()).collect();
+    if !(15..=34).contains(&compact.len()) {
+        return false;
+    }
+    let (head, tail) = compact.split_at(4);
+    let mut remainder = 0u32;
+    for c in tail.chars().chain(head.chars()) {
+        let Some(value) = c.to_digit(36) else {
+            return false;
+        };
+        remainder = if value < 10 {
+            (remainder * 10 + value) % 97
+        } else {
+            (remainder * 100 + value) % 97
+        };
+    }
+    remainder == 1
+}
+
+fn valid_ipv4(candidate: &str) -> bool {
+    candidate.split('.').all(|octet| octet.parse::<u8>().is_ok())
+}
+
+fn valid_ipv6(candidate: &str) -> bool {
+    candidate.parse::<Ipv6Addr>().is_ok()
+}
+
+/// E.164 allows at most 15 digits; fewer than 7 is a short code at best.
+fn plausible_phone(candidate: &str) -> bool {
+    let digits = candidate.chars().filter(char::is_ascii_digit).count();
+    (7..=15).contains(&digits)
+}
@@ -0,0 +1,282 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::Command;
+
+/// First bytes of every sealed file.
+pub const MAGIC: &[u8; 8] = b"SPTSEAL1";
+
+/// Bytes of a Poly1305 tag.
+pub const TAG_LEN: usize = 16;
+
+/// Bytes of the random salt after the magic, from which the file's own key
+/// is derived.
+const SALT_LEN: usize = 12;
+
+/// Largest record accepted when reading, so a corrupt length cannot ask
+/// for gigabytes.
+const MAX_RECORD: usize = 64 << 20;
+
+/// Associated data of the record that ends a file, which no other record
+/// has, so a record of data cannot pass for it.
+const END_AAD: &[u8] = b"end";
+
+/// A sealed file that stops without its end record: cut short, or still
+/// being written. Every record before that point was authentic.
+#[derive(Debug, thiserror::Error)]
+#[error("Sealed file stops at offset {offset} without its end record; it was cut short or is still being written")]
+pub struct Unfinished {
+    pub offset: u64,
+}
+
+/// The `--state-key`: 32 bytes for ChaCha20-Poly1305 (RFC 8439).
+#[derive(Clone)]
+pub struct StateKey([u8; 32]);
+
+impl std::fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StateKey(..)")
+    }
+}
+
+impl StateKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Load a key from `env:VAR`, `file:PATH`, `keyring:SERVICE/USER` (the
+    /// OS keyring; the kernel's keyutils on Linux) or `cmd:COMMAND`, the
+    /// last being how KMS and other keyrings are reached, e.g.
+    /// `cmd:secret-tool lookup service spectertty`. The key itself is 64
+    /// hex digits or 32 bytes in base64.
+    pub fn load(source: &str) -> Result<Self> {
+        let text = match source.split_once(':') {
+            Some(("env", var)) => std::env::var(var).with_context(|| format!("--state-key: ${} is not set", var))?,
+            Some(("file", path)) => {
+                std::fs::read_to_string(path).with_context(|| format!("--state-key: failed to read {}", path))?
+            }
+            Some(("keyring", entry)) => {
+                let (service, user) = entry
+                    .split_once('/')
+                    .ok_or_else(|| anyhow!("--state-key: expected keyring:SERVICE/USER, got {:?}", source))?;
+                keyring::Entry::new(service, user)
+                    .and_then(|entry| entry.get_password())
+                    .with_context(|| format!("--state-key: no key for {} in the keyring", entry))?
+            }
+            Some(("cmd", command)) => {
+                let output = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .with_context(|| format!("--state-key: failed to run {:?}", command))?;
+                if !output.status.success() {
+                    bail!("--state-key: {:?} failed with {}", command, output.status);
+                }
+                String::from_utf8(output.stdout).context("--state-key: the command printed something other than text")?
+            }
+            _ => bail!("--state-key must be env:VAR, file:PATH, keyring:SERVICE/USER or cmd:COMMAND"),
+        };
+        Self::parse(text.trim())
+    }
+
+    /// 64 hex digits or 32 bytes in base64.
+    pub fn parse(text: &str) -> Result<Self> {
+        let bytes = if text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            (0..32)
+                .map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()?
+        } else {
+            BASE64_STANDARD
+                .decode(text)
+                .map_err(|_| anyhow!("--state-key: the key must be 64 hex digits or base64"))?
+        };
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| anyhow!("--state-key: the key is {} bytes, expected 32", bytes.len()))?;
+        Ok(Self(key))
+    }
+
+    /// A key of its own for one file, so no two files share a nonce
+    /// sequence: the ChaCha20 keystream for the salt, which is a PRF of it.
+    fn derive(&self, salt: &[u8; SALT_LEN]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        ChaCha20::new(&self.0.into(), salt.into()).apply_keystream(&mut key);
+        key
+    }
+}
+
+/// Appends records to a sealed file. Each record is a big-endian `u32`
+/// length followed by the record encrypted under the file key with the
+/// record number as its nonce, then its tag. `finish` adds an end record
+/// holding the number of records before it, without which readers take the
+/// file as cut short.
+pub struct SealedWriter<W: Write> {
+    inner: W,
+    key: [u8; 32],
+    next: u64,
+    finished: bool,
+}
+
+impl<W: Write> SealedWriter<W> {
+    pub fn new(mut inner: W, key: &StateKey) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        File::open("/dev/urandom")?.read_exact(&mut salt)?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&salt)?;
+        Ok(Self {
+            inner,
+            key: key.derive(&salt),
+            next: 0,
+            finished: false,
+        })
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> Result<()> {
+        if self.finished {
+            bail!("The sealed file has already been finished");
+        }
+        self.write_record(&[], record)
+    }
+
+    /// Write the end record; nothing can be appended after it. Finishing
+    /// again does nothing.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_record(END_AAD, &self.next.to_be_bytes())?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn write_record(&mut self, aad: &[u8], record: &[u8]) -> Result<()> {
+        let sealed = seal(&self.key, &nonce(self.next), aad, record);
+        self.next += 1;
+        self.inner.write_all(&(sealed.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&sealed)?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+/// Reads the records of a sealed file back, in order.
+pub struct SealedReader<R: Read> {
+    inner: R,
+    key: [u8; 32],
+    next: u64,
+    /// Bytes of the file consumed so far
+    offset: u64,
+    /// Whether the end record has been read
+    ended: bool,
+}
+
+impl<R: Read> SealedReader<R> {
+    /// Fails if `inner` is not a sealed file.
+    pub fn new(mut inner: R, key: &StateKey) -> Result<Self> {
+        let mut header = [0u8; MAGIC.len() + SALT_LEN];
+        inner.read_exact(&mut header).context("Not a sealed file")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("Not a sealed file");
+        }
+        let salt: [u8; SALT_LEN] = header[MAGIC.len()..].try_into().unwrap();
+        Ok(Self {
+            inner,
+            key: key.derive(&salt),
+            next: 0,
+            offset: header.len() as u64,
+            ended: false,
+        })
+    }
+
+    /// The next record and the offset it starts at, None after the end
+    /// record. A record that fails to authenticate is an error: the key
+    /// is wrong or the file was altered. A file that stops before its end
+    /// record, at a record boundary or partway through one, fails with
+    /// `Unfinished`.
+    pub fn next_record(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        let at = self.offset;
+        let mut len = [0u8; 4];
+        if !read_full(&mut self.inner, &mut len)? {
+            if self.ended {
+                return Ok(None);
+            }
+            return Err(Unfinished { offset: at }.into());
+        }
+        if self.ended {
+            bail!("Sealed file continues past its end record at offset {}", at);
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if !(TAG_LEN..=MAX_RECORD).contains(&len) {
+            bail!("Corrupt sealed record at offset {}", at);
+        }
+        let mut sealed = vec![0u8; len];
+        if !read_full(&mut self.inner, &mut sealed)? {
+            return Err(Unfinished { offset: at }.into());
+        }
+        let nonce = nonce(self.next);
+        self.next += 1;
+        self.offset += 4 + len as u64;
+        if let Some(record) = open(&self.key, &nonce, &[], &sealed) {
+            return Ok(Some((at, record)));
+        }
+        let count = open(&self.key, &nonce, END_AAD, &sealed)
+            .ok_or_else(|| anyhow!("Sealed record at offset {} does not authenticate; wrong --state-key?", at))?;
+        // Authentic, so only a bug could make the count disagree
+        if count != (self.next - 1).to_be_bytes() {
+            bail!("Sealed file's end record at offset {} does not match its records", at);
+        }
+        self.ended = true;
+        self.next_record()
+    }
+}
+
+/// Fill `buf`, or return false if the input ends first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Whether a file starts like a sealed file.
+pub fn is_sealed(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+fn nonce(record: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&record.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20-Poly1305 encryption: the ciphertext with its tag appended.
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(nonce.into(), Payload { msg: plaintext, aad })
+        .expect("ChaCha20-Poly1305 seals any record that fits in memory")
+}
+
+/// ChaCha20-Poly1305 decryption; None if the tag does not match.
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(nonce.into(), Payload { msg: sealed, aad })
+        .ok()
+}
@@ -0,0 +1,340 @@
+use crate::expect::{split_unfinished, strip_escapes};
+use crate::frame::{Frame, FrameType};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a prompt looks like when no `--prompt-regex` is given: the line
+/// ends in a usual shell prompt character.
+pub const DEFAULT_PROMPT: &str = r"[$#>%❯]\s?$";
+
+/// Output kept per command; past it the start is dropped, as the end of
+/// a command's output is where its result and errors are.
+pub const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+fn mark_regex() -> &'static Regex {
+    static MARK: OnceLock<Regex> = OnceLock::new();
+    MARK.get_or_init(|| Regex::new(r"\x1b\](?:133|633);([^\x07\x1b]*)(?:\x07|\x1b\\)").unwrap())
+}
+
+enum Turn {
+    /// No prompt seen yet
+    Starting,
+    /// At a prompt ending with this text, waiting for a line to be entered
+    Prompt(String),
+    /// A line was entered at the prompt; its echo is the command
+    Entered { prompt: String, ts: f64 },
+    /// The command is running and its output is being collected
+    Running {
+        id: String,
+        ts: f64,
+        output: String,
+        truncated: bool,
+    },
+}
+
+/// Groups a shell session into command turns, for `--segment-commands`.
+/// A line entered at a prompt starts a `command_start` frame once its
+/// echo comes back; the next prompt ends it with a `command_end` frame
+/// holding the command's output, escapes stripped, and how long it took.
+///
+/// Shells that mark their prompts and commands with OSC 133 or 633, as
+/// `--shell-integration` hooks them to, are followed by their marks
+/// instead (see `marks_only`), and guessing stops at the first mark.
+pub struct Segmenter {
+    prompts: Vec<Regex>,
+    /// Commands are found by prompts rather than by marks
+    guessing: bool,
+    /// The shell marks its commands, so prompts are not guessed at
+    marked: bool,
+    /// Command line sent with a 633 `E` mark, for the next `C`
+    entered: Option<String>,
+    /// Start of an escape sequence the next read finishes
+    carry: String,
+    turn: Turn,
+    /// The line the cursor is on, escapes stripped
+    line: String,
+    /// A carriage return not yet known to start a CRLF
+    cr: bool,
+    commands: u64,
+}
+
+impl Segmenter {
+    /// Recognise prompts by `prompts`, the `--prompt-regex` patterns, or
+    /// `DEFAULT_PROMPT` when there are none. A prompt must match at the
+    /// end of the line the cursor is on.
+    pub fn new(prompts: &[String]) -> anyhow::Result<Self> {
+        let prompts = if prompts.is_empty() {
+            vec![Regex::new(DEFAULT_PROMPT)?]
+        } else {
+            prompts
+                .iter()
+                .map(|pattern| Regex::new(&format!("(?:{})\\s*$", pattern)))
+                .collect::<Result<_, _>>()?
+        };
+        Ok(Self {
+            prompts,
+            guessing: true,
+            marked: false,
+            entered: None,
+            carry: String::new(),
+            turn: Turn::Starting,
+            line: String::new(),
+            cr: false,
+            commands: 0,
+        })
+    }
+
+    /// Follow only the shell's marks: `A` starts a prompt, reported as a
+    /// `prompt` frame, `C` starts a command, named by a preceding 633 `E`,
+    /// and `D` ends it with its exit status in `code`.
+    pub fn marks_only() -> Self {
+        Self {
+            prompts: Vec::new(),
+            guessing: false,
+            marked: true,
+            entered: None,
+            carry: String::new(),
+            turn: Turn::Starting,
+            line: String::new(),
+            cr: false,
+            commands: 0,
+        }
+    }
+
+    /// Follow a raw session frame, returning any command frames it
+    /// completes.
+    pub fn observe(&mut self, frame: &Frame) -> Vec<Frame> {
+        if self.guessing && self.marked {
+            return Vec::new();
+        }
+        match frame.frame_type {
+            FrameType::Stdin => {
+                if let (false, Turn::Prompt(ref prompt)) = (self.marked, &self.turn) {
+                    let typed = frame.text().unwrap_or_default();
+                    if typed.contains(['\r', '\n']) {
+                        self.turn = Turn::Entered {
+                            prompt: prompt.clone(),
+                            ts: frame.ts,
+                        };
+                    }
+                }
+                Vec::new()
+            }
+            FrameType::Stdout | FrameType::Stderr => {
+                let mut text = std::mem::take(&mut self.carry);
+                text.push_str(&frame.text().unwrap_or_default());
+                // Marks and other escapes a read cut wait for the next
+                self.carry = split_unfinished(&mut text);
+
+                let mut frames = Vec::new();
+                let mut rest = text.as_str();
+                while let Some(captures) = mark_regex().captures(rest) {
+                    let whole = captures.get(0).expect("group 0 always participates");
+                    self.text(&rest[..whole.start()], &mut frames);
+                    frames.extend(self.mark(&captures[1], frame.ts));
+                    if self.guessing && self.marked {
+                        return frames;
+                    }
+                    rest = &rest[whole.end()..];
+                }
+                self.text(rest, &mut frames);
+
+                if !self.marked && self.prompts.iter().any(|prompt| prompt.is_match(&self.line)) {
+                    frames.extend(self.prompt(frame.ts));
+                }
+                frames
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// A command still running when the session ends, ended without a
+    /// prompt (`reason: session_end`).
+    pub fn finish(&mut self) -> Option<Frame> {
+        let Turn::Running { ref mut output, .. } = self.turn else {
+            return None;
+        };
+        let mut output = std::mem::take(output);
+        output.push_str(&self.line);
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |now| now.as_secs_f64());
+        let frame = self.end(output, ts).with_reason("session_end".to_string());
+        self.turn = Turn::Starting;
+        Some(frame)
+    }
+
+    fn text(&mut self, text: &str, frames: &mut Vec<Frame>) {
+        for c in strip_escapes(text).chars() {
+            if std::mem::take(&mut self.cr) && c != '\n' {
+                self.line.clear();
+            }
+            match c {
+                '\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    frames.extend(self.end_line(line));
+                }
+                '\r' => self.cr = true,
+                '\x08' => {
+                    self.line.pop();
+                }
+                c if c.is_control() && c != '\t' => {}
+                c => self.line.push(c),
+            }
+        }
+    }
+
+    /// Act on an OSC 133/633 mark, given its parameters.
+    fn mark(&mut self, params: &str, ts: f64) -> Vec<Frame> {
+        let mut fields = params.split(';');
+        let Some(kind @ ("A" | "C" | "D" | "E")) = fields.next() else {
+            return Vec::new();
+        };
+        self.marked = true;
+        if self.guessing {
+            return Vec::new();
+        }
+        match kind {
+            "E" => {
+                self.entered = fields.next().map(unescape);
+                Vec::new()
+            }
+            "C" => {
+                self.commands += 1;
+                let id = format!("cmd-{}", self.commands);
+                self.line.clear();
+                self.turn = Turn::Running {
+                    id: id.clone(),
+                    ts,
+                    output: String::new(),
+                    truncated: false,
+                };
+                let frame = Frame::new(FrameType::CommandStart).with_id(id);
+                vec![match self.entered.take() {
+                    Some(command) => frame.with_data(command),
+                    None => frame,
+                }]
+            }
+            // `A` starts the next prompt, ending a command no `D` ended
+            _ => {
+                let code = fields.next().and_then(|code| code.parse().ok());
+                let mut frames = Vec::new();
+                if let Turn::Running { ref mut output, .. } = self.turn {
+                    let mut output = std::mem::take(output);
+                    output.push_str(&std::mem::take(&mut self.line));
+                    let frame = self.end(output, ts);
+                    frames.push(match code {
+                        Some(code) => frame.with_exit_code(code),
+                        None => frame,
+                    });
+                }
+                if kind == "A" {
+                    frames.push(Frame::new(FrameType::Prompt));
+                }
+                self.turn = Turn::Prompt(String::new());
+                frames
+            }
+        }
+    }
+
+    fn end_line(&mut self, line: String) -> Option<Frame> {
+        match self.turn {
+            Turn::Entered { ref prompt, ts: entered } => {
+                let command = line.strip_prefix(prompt.as_str()).unwrap_or(&line).trim().to_string();
+                if command.is_empty() {
+                    self.turn = Turn::Starting;
+                    return None;
+                }
+                self.commands += 1;
+                let id = format!("cmd-{}", self.commands);
+                self.turn = Turn::Running {
+                    id: id.clone(),
+                    ts: entered,
+                    output: String::new(),
+                    truncated: false,
+                };
+                Some(Frame::new(FrameType::CommandStart).with_id(id).with_data(command))
+            }
+            Turn::Running {
+                ref mut output,
+                ref mut truncated,
+                ..
+            } => {
+                output.push_str(&line);
+                output.push('\n');
+                if output.len() > MAX_OUTPUT_BYTES {
+                    let mut cut = output.len() - MAX_OUTPUT_BYTES;
+                    while !output.is_char_boundary(cut) {
+                        cut += 1;
+                    }
+                    output.drain(..cut);
+                    *truncated = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn prompt(&mut self, ts: f64) -> Option<Frame> {
+        let ended = match self.turn {
+            Turn::Running { ref mut output, .. } => {
+                let output = std::mem::take(output);
+                Some(self.end(output, ts))
+            }
+            // Typing after the prompt that happens to end like one
+            Turn::Prompt(ref prompt) if self.line.starts_with(prompt.as_str()) => return None,
+            _ => None,
+        };
+        self.turn = Turn::Prompt(self.line.clone());
+        ended
+    }
+
+    fn end(&self, output: String, ts: f64) -> Frame {
+        let Turn::Running {
+            ref id,
+            ts: started,
+            truncated,
+            ..
+        } = self.turn
+        else {
+            unreachable!("only a running command ends");
+        };
+        let mut stats = BTreeMap::new();
+        stats.insert("output_bytes".to_string(), output.len() as u64);
+        let frame = Frame::new(FrameType::CommandEnd)
+            .with_id(id.clone())
+            .with_data(output)
+            .with_duration(((ts - started).max(0.0) * 1000.0) as u64)
+            .with_stats(stats);
+        if truncated {
+            frame.with_reason("truncated".to_string())
+        } else {
+            frame
+        }
+    }
+}
+
+/// Undo the escaping of a 633 `E` command line: `\\` and `\xHH`.
+fn unescape(escaped: &str) -> String {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte == b'\\' {
+            if tail.first() == Some(&b'\\') {
+                bytes.push(b'\\');
+                rest = &tail[1..];
+                continue;
+            }
+            let hex = tail.strip_prefix(b"x").and_then(|hex| hex.get(..2));
+            if let Some(value) = hex.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                bytes.push(value);
+                rest = &tail[3..];
+                continue;
+            }
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,628 @@
+use crate::bell::BellDetector;
+use crate::clipboard::{Clipboard, ClipboardRequest};
+use crate::env::CommandEnv;
+use crate::error::SessionError;
+use crate::expect::ExpectManager;
+use crate::frame::{Frame, FrameType};
+use crate::ids::{IdGenerator, UlidGenerator};
+use crate::prompt::PromptDetector;
+use crate::pty::{self, PtyHandle, PtySession};
+use crate::respond::AutoResponders;
+use crate::sched::Scheduling;
+use crate::termios::TermiosChange;
+use futures::stream::{self, Stream};
+use portable_pty::PtySize;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Configures a `Session` before spawning its command.
+#[derive(Clone)]
+pub struct SessionBuilder {
+    command: String,
+    args: Vec<String>,
+    env: CommandEnv,
+    cols: u16,
+    rows: u16,
+    idle_timeout: Duration,
+    /// Prompt patterns, each with its name if it has one
+    prompts: Vec<(Option<String>, String)>,
+    split_stderr: bool,
+    scheduling: Scheduling,
+    input_pace: Option<Duration>,
+    write_rate: Option<u64>,
+    termios: TermiosChange,
+    /// Auto-responder rules: pattern, response and limit
+    responders: Vec<(String, String, Option<u32>)>,
+    session_id: Option<String>,
+    id_generator: Arc<dyn IdGenerator>,
+}
+
+impl SessionBuilder {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+            env: CommandEnv::default(),
+            cols: 120,
+            rows: 40,
+            idle_timeout: Duration::from_millis(200),
+            prompts: Vec::new(),
+            split_stderr: false,
+            scheduling: Scheduling::default(),
+            input_pace: None,
+            write_rate: None,
+            termios: TermiosChange::default(),
+            responders: Vec::new(),
+            session_id: None,
+            id_generator: Arc::new(UlidGenerator),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set an environment variable for the command.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.vars.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn envs<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env
+            .vars
+            .extend(vars.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Start the command with only the variables set through `env`
+    /// instead of inheriting this process's environment. The command is
+    /// then looked up in that `PATH`, if any.
+    pub fn clear_env(mut self, clear: bool) -> Self {
+        self.env.clear = clear;
+        self
+    }
+
+    /// Run the command in `dir` instead of this process's working directory.
+    pub fn cwd(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.env.cwd = Some(dir.into());
+        self
+    }
+
+    pub fn size(mut self, cols: u16, rows: u16) -> Self {
+        self.cols = cols;
+        self.rows = rows;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Report a `prompt` frame whenever the line the cursor is on ends
+    /// in `pattern`.
+    pub fn prompt_regex(mut self, pattern: impl Into<String>) -> Self {
+        self.prompts.push((None, pattern.into()));
+        self
+    }
+
+    /// Like `prompt_regex`, with `name` in the `id` of the `prompt` frames
+    /// this pattern matches, so nested REPLs can be told apart.
+    pub fn prompt(mut self, name: impl Into<String>, pattern: impl Into<String>) -> Self {
+        self.prompts.push((Some(name.into()), pattern.into()));
+        self
+    }
+
+    /// Deliver the command's stderr as `stderr` frames instead of mixing
+    /// it into stdout. The two streams are read independently, so their
+    /// relative order is not preserved.
+    pub fn split_stderr(mut self, split: bool) -> Self {
+        self.split_stderr = split;
+        self
+    }
+
+    /// Pin the command to CPUs and a scheduling class. They are set on
+    /// the thread that spawns it, so this process keeps its own.
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = scheduling;
+        self
+    }
+
+    /// Write input at most one character every `pace`, whoever sends it,
+    /// for consoles that drop input arriving faster. Escape sequences
+    /// count as one character, so keys are never torn apart.
+    pub fn throttle_input(mut self, pace: Duration) -> Self {
+        self.input_pace = Some(pace);
+        self
+    }
+
+    /// Write input at most `bytes_per_sec` bytes a second, in pieces, for
+    /// commands that lose input when it arrives in bulk.
+    pub fn write_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.write_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Start the command's terminal with these modes instead of the
+    /// defaults, e.g. echo off or raw.
+    pub fn termios(mut self, change: TermiosChange) -> Self {
+        self.termios = change;
+        self
+    }
+
+    /// Send `response` whenever output matches `pattern`, at most `limit`
+    /// times if given; see `Session::auto_respond`.
+    pub fn auto_respond(mut self, pattern: impl Into<String>, response: impl Into<String>, limit: Option<u32>) -> Self {
+        self.responders.push((pattern.into(), response.into(), limit));
+        self
+    }
+
+    /// Use this ID instead of generating one. Every session spawned from
+    /// the builder gets it, so prefer `id_generator` on one spawned twice.
+    pub fn session_id(mut self, id: impl Into<String>) -> Self {
+        self.session_id = Some(id.into());
+        self
+    }
+
+    /// Generate IDs with `generator` instead of as ULIDs.
+    pub fn id_generator(mut self, generator: Arc<dyn IdGenerator>) -> Self {
+        self.id_generator = generator;
+        self
+    }
+
+    pub async fn spawn(self) -> Result<Session, SessionError> {
+        let id = self
+            .session_id
+            .unwrap_or_else(|| self.id_generator.generate());
+        let prompts = PromptDetector::new(&self.prompts)?;
+        let pty = PtySession::new(
+            &self.command,
+            &self.args,
+            &self.env,
+            PtySize {
+                rows: self.rows,
+                cols: self.cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            },
+            self.idle_timeout,
+            self.split_stderr,
+            self.scheduling,
+            self.input_pace,
+            self.write_rate,
+            self.termios,
+        )
+        .await?;
+
+        let mut responders = AutoResponders::new();
+        for (pattern, response, limit) in self.responders {
+            responders.add(None, &pattern, response, limit)?;
+        }
+
+        let pid = pty.pid();
+        let (runner, handle, frame_rx) = pty.split();
+        let task = tokio::spawn(runner.run());
+
+        Ok(Session {
+            id,
+            pid,
+            handle,
+            source: FrameSource {
+                frame_rx,
+                task: Some(task),
+            },
+            expects: ExpectManager::new(),
+            responders,
+            prompts,
+            clipboard: Clipboard::new(),
+            bells: Default::default(),
+            bracketed_paste: false,
+            backlog: VecDeque::new(),
+            exit_code: None,
+            waits: 0,
+        })
+    }
+}
+
+/// A command running under a PTY, observed as a stream of frames.
+///
+/// Expect waiters registered with `expect` resolve into `match` and
+/// `expect_timeout` frames delivered in order with the output they matched.
+/// Every frame carries the session's ID.
+pub struct Session {
+    id: String,
+    pid: Option<u32>,
+    handle: PtyHandle,
+    source: FrameSource,
+    expects: ExpectManager,
+    responders: AutoResponders,
+    prompts: PromptDetector,
+    clipboard: Clipboard,
+    /// Bell scanners for stdout and stderr
+    bells: [BellDetector; 2],
+    /// The command has turned on bracketed paste (DECSET 2004)
+    bracketed_paste: bool,
+    backlog: VecDeque<Frame>,
+    exit_code: Option<i32>,
+    waits: u64,
+}
+
+impl Session {
+    /// Spawn `command` with default settings; see `Session::builder`.
+    pub async fn spawn<I, S>(command: &str, args: I) -> Result<Self, SessionError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::builder(command).args(args).spawn().await
+    }
+
+    pub fn builder(command: impl Into<String>) -> SessionBuilder {
+        SessionBuilder::new(command)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Process ID of the command; it leads a process group and terminal
+    /// session of its own, which the processes it starts join.
+    pub fn pid(&self) -> Option<u32> {
+        self.pid
+    }
+
+    /// Cloneable handle for writing to the session from other tasks.
+    pub fn handle(&self) -> PtyHandle {
+        self.handle.clone()
+    }
+
+    pub fn send(&self, data: impl AsRef<[u8]>) -> Result<(), SessionError> {
+        self.handle.write(data.as_ref().to_vec())
+    }
+
+    /// Send `data` once `delay` has passed since the input before it was
+    /// written; input sent after it waits its turn.
+    pub fn send_after(&self, delay: Duration, data: impl AsRef<[u8]>) -> Result<(), SessionError> {
+        self.handle.write_after(delay, data.as_ref().to_vec())
+    }
+
+    /// Send a password or other secret with the terminal's echo off, so it
+    /// is not reflected in the output; its `stdin` frame carries
+    /// `[REDACTED]` and `reason: secret` instead of the text.
+    pub fn send_secret(&self, data: impl AsRef<[u8]>) -> Result<(), SessionError> {
+        self.handle.write_secret(data.as_ref().to_vec())
+    }
+
+    /// End the command's input after what has been sent, as Ctrl-D does
+    /// at a terminal: `cat`, `python -` and the like see end of file.
+    pub fn eof(&self) -> Result<(), SessionError> {
+        self.handle.eof()
+    }
+
+    /// Whether the command has asked for pastes to be bracketed, as shells
+    /// with readline, editors and most REPLs do while reading input.
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<(), SessionError> {
+        self.handle.resize(cols, rows)
+    }
+
+    /// Change echo, raw mode and other flags of the command's terminal
+    /// once the input sent before has been written, so that a password
+    /// sent with echo off is not reflected in the output. A `termios`
+    /// frame reports the settings it leaves; the command may change them
+    /// again itself.
+    pub fn set_termios(&self, change: TermiosChange) -> Result<(), SessionError> {
+        self.handle.set_termios(change)
+    }
+
+    /// Send a signal by name (`SIGINT`, `TERM`, ...) to the foreground
+    /// process group; a `signal` frame confirms delivery.
+    pub fn signal(&self, name: &str) -> Result<(), SessionError> {
+        self.handle.signal(pty::parse_signal(name)?)
+    }
+
+    /// Ask the command to terminate; read on until its `exit` frame.
+    pub fn kill(&self) -> Result<(), SessionError> {
+        self.handle.kill()
+    }
+
+    /// Exit code, once the `exit` frame has been read.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Frames produced but not yet read.
+    pub fn pending_frames(&self) -> usize {
+        self.backlog.len() + self.source.frame_rx.len()
+    }
+
+    /// Bytes of command output produced but not yet read.
+    pub fn pending_bytes(&self) -> usize {
+        self.handle.queued_output()
+    }
+
+    /// Register an expect waiter; its outcome arrives as a frame.
+    pub fn expect(&mut self, id: Option<String>, pattern: &str, timeout: Duration) {
+        let frames = self.expects.expect(id, pattern, timeout);
+        self.backlog.extend(frames);
+    }
+
+    /// Answer output matching `pattern` with `response` from now on, at
+    /// most `limit` times if given, and report each answer with an
+    /// `auto_response` frame. Returns the rule's ID, `id` or a generated
+    /// one, under which `cancel` withdraws it.
+    pub fn auto_respond(
+        &mut self,
+        id: Option<String>,
+        pattern: &str,
+        response: impl Into<String>,
+        limit: Option<u32>,
+    ) -> Result<String, SessionError> {
+        self.responders.add(id, pattern, response.into(), limit)
+    }
+
+    /// Withdraw the expect waiter or auto-responder registered under `id`,
+    /// returning the `cancelled` frame for it, or None when there is
+    /// nothing under `id`.
+    pub fn cancel(&mut self, id: &str) -> Option<Frame> {
+        self.expects.cancel(id).or_else(|| self.responders.remove(id))
+    }
+
+    /// Text the command last copied with OSC 52, or the last `set_clipboard`.
+    pub fn clipboard(&self) -> Option<&str> {
+        self.clipboard.contents()
+    }
+
+    /// Replace the clipboard the command reads when it pastes with OSC 52.
+    pub fn set_clipboard(&mut self, text: impl Into<String>) {
+        self.clipboard.set(text);
+    }
+
+    /// Next frame, or None once the command has finished and every frame
+    /// it produced has been read.
+    pub async fn next_frame(&mut self) -> Option<Frame> {
+        let frame = self.read_frame().await?;
+        Some(frame.with_session_id(self.id.clone()))
+    }
+
+    async fn read_frame(&mut self) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.backlog.pop_front() {
+                return Some(frame);
+            }
+
+            tokio::select! {
+                frame = self.source.recv() => match frame {
+                    Some(frame) => {
+                        self.handle.output_taken(&frame);
+                        if let (FrameType::Exit, Some(code)) = (&frame.frame_type, frame.code) {
+                            self.exit_code = Some(code);
+                        }
+                        let resolved = self.expects.feed(&frame);
+                        self.backlog.extend(resolved);
+                        for (response, answered) in self.responders.feed(&frame) {
+                            if let Err(e) = self.send(&response) {
+                                warn!("Failed to send auto-response: {}", e);
+                            }
+                            self.backlog.push_back(answered);
+                        }
+                        self.backlog.extend(self.prompts.observe(&frame));
+                        self.observe_clipboard(&frame);
+                        self.observe_bells(&frame);
+                        self.observe_paste_mode(&frame);
+                        return Some(frame);
+                    }
+                    None => {
+                        let cancelled = self.expects.cancel_all("session_ended");
+                        if cancelled.is_empty() {
+                            return None;
+                        }
+                        self.backlog.extend(cancelled);
+                    }
+                },
+                _ = self.expects.next_timeout() => {
+                    let expired = self.expects.take_expired();
+                    self.backlog.extend(expired);
+                }
+            }
+        }
+    }
+
+    /// Capture OSC 52 copies as `clipboard` frames and answer pastes.
+    fn observe_clipboard(&mut self, frame: &Frame) {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return;
+        }
+        let Some(data) = frame.text() else {
+            return;
+        };
+        for request in self.clipboard.observe(&data) {
+            let clipboard = Frame::new(FrameType::Clipboard);
+            let clipboard = match request {
+                ClipboardRequest::Set(text) => clipboard.with_data(text).with_reason("copy".to_string()),
+                ClipboardRequest::Query => {
+                    if let Err(e) = self.send(self.clipboard.reply()) {
+                        warn!("Failed to answer clipboard query: {}", e);
+                    }
+                    clipboard.with_reason("paste".to_string())
+                }
+            };
+            self.backlog.push_back(clipboard);
+        }
+    }
+
+    /// Follow the command turning bracketed paste on and off.
+    fn observe_paste_mode(&mut self, frame: &Frame) {
+        if frame.frame_type != FrameType::Stdout {
+            return;
+        }
+        let Some(data) = frame.text() else {
+            return;
+        };
+        match (data.rfind("\x1b[?2004h"), data.rfind("\x1b[?2004l")) {
+            (Some(on), off) => self.bracketed_paste = off.is_none_or(|off| on > off),
+            (None, Some(_)) => self.bracketed_paste = false,
+            (None, None) => {}
+        }
+    }
+
+    /// Report terminal bells as `bell` frames, one per chunk that rings.
+    fn observe_bells(&mut self, frame: &Frame) {
+        let detector = match frame.frame_type {
+            FrameType::Stdout => &mut self.bells[0],
+            FrameType::Stderr => &mut self.bells[1],
+            _ => return,
+        };
+        let Some(data) = frame.bytes() else {
+            return;
+        };
+        let rung = detector.observe(&data);
+        if rung > 0 {
+            self.backlog.push_back(Frame::new(FrameType::Bell).with_value(rung as f64));
+        }
+    }
+
+    pub fn frames(&mut self) -> impl Stream<Item = Frame> + '_ {
+        stream::unfold(self, |session| async move {
+            session.next_frame().await.map(|frame| (frame, session))
+        })
+    }
+
+    /// Wait for output matching `pattern` and return the `match` frame.
+    /// Frames read while waiting are consumed.
+    pub async fn wait_for(&mut self, pattern: &str, timeout: Duration) -> Result<Frame, SessionError> {
+        self.wait_for_with(pattern, timeout, |_| {}).await
+    }
+
+    /// `wait_for`, handing each frame read while waiting to `seen`.
+    pub async fn wait_for_with(
+        &mut self,
+        pattern: &str,
+        timeout: Duration,
+        mut seen: impl FnMut(&Frame),
+    ) -> Result<Frame, SessionError> {
+        self.waits += 1;
+        let id = format!("wait-for-{}", self.waits);
+        self.expect(Some(id.clone()), pattern, timeout);
+
+        while let Some(frame) = self.next_frame().await {
+            seen(&frame);
+            if frame.id.as_deref() != Some(id.as_str()) {
+                continue;
+            }
+            if matches!(frame.frame_type, FrameType::Match) {
+                return Ok(frame);
+            }
+            if matches!(frame.frame_type, FrameType::Cancelled) {
+                return Err(SessionError::InvalidRequest(format!("wait for /{}/ was cancelled", pattern)));
+            }
+            return Err(match frame.reason {
+                None => SessionError::Timeout(format!(
+                    "no output matched /{}/ within {}ms",
+                    pattern,
+                    timeout.as_millis()
+                )),
+                Some(reason) if reason == "session_ended" => break,
+                Some(reason) => SessionError::InvalidRequest(reason),
+            });
+        }
+        Err(SessionError::PtyIo(format!("session ended before /{}/ matched", pattern)))
+    }
+
+    /// Read the remaining frames and return the exit code.
+    pub async fn wait(&mut self) -> Option<i32> {
+        self.wait_with(|_| {}).await
+    }
+
+    /// `wait`, handing each remaining frame to `seen`.
+    pub async fn wait_with(&mut self, mut seen: impl FnMut(&Frame)) -> Option<i32> {
+        while let Some(frame) = self.next_frame().await {
+            seen(&frame);
+        }
+        self.exit_code
+    }
+
+    /// Stop the session and return frames it produced that were not read.
+    pub fn close(&mut self) -> Vec<Frame> {
+        if let Some(task) = self.source.task.take() {
+            task.abort();
+        }
+        let mut frames: Vec<Frame> = self.backlog.drain(..).collect();
+        while let Ok(frame) = self.source.frame_rx.try_recv() {
+            frames.push(frame);
+        }
+        frames
+            .into_iter()
+            .map(|frame| frame.with_session_id(self.id.clone()))
+            .collect()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(task) = self.source.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// The runner's frame channel, which ends when the runner task does.
+struct FrameSource {
+    frame_rx: mpsc::UnboundedReceiver<Frame>,
+    task: Option<JoinHandle<Result<(), SessionError>>>,
+}
+
+impl FrameSource {
+    async fn recv(&mut self) -> Option<Frame> {
+        loop {
+            let Some(ref mut task) = self.task else {
+                // The reader thread may outlive the runner, so only take
+                // what was already sent
+                return self.frame_rx.try_recv().ok();
+            };
+
+            tokio::select! {
+                biased;
+                frame = self.frame_rx.recv() => return frame,
+                result = task => {
+                    self.task = None;
+                    match result {
+                        Ok(Ok(())) => info!("PTY session completed"),
+                        Ok(Err(e)) => {
+                            error!("PTY session error: {}", e);
+                            return Some(e.to_frame());
+                        }
+                        Err(e) => {
+                            error!("PTY task error: {}", e);
+                            return Some(SessionError::PtyIo(e.to_string()).to_frame());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
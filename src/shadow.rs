@@ -0,0 +1,56 @@
+use crate::frame::{Frame, FrameType};
+use crate::host::{self, RawMode};
+use anyhow::{bail, Result};
+use std::io::{self, IsTerminal};
+use tokio::io::{AsyncWriteExt, Stdout};
+use tokio::sync::mpsc;
+
+/// The terminal of the person a `spectertty shadow` session is run for.
+/// Their keys go to the command untouched, and its output comes back
+/// untouched, before token processing or redaction: in raw mode Ctrl-C,
+/// Ctrl-Z and the like reach the command's own terminal, which turns them
+/// into signals. The frames go only to the sinks the session was given.
+pub struct Shadow {
+    keys: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+    stdout: Stdout,
+    _raw: RawMode,
+}
+
+impl Shadow {
+    pub fn start() -> Result<Self> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            bail!("shadow needs a terminal on stdin and stdout");
+        }
+        Ok(Self {
+            _raw: RawMode::enable()?,
+            keys: Some(host::spawn_key_reader()),
+            stdout: tokio::io::stdout(),
+        })
+    }
+
+    /// Keys typed since the last call; pending forever once the terminal
+    /// has closed.
+    pub async fn keys(&mut self) -> Vec<u8> {
+        loop {
+            let Some(ref mut keys) = self.keys else {
+                return std::future::pending().await;
+            };
+            match keys.recv().await {
+                Some(data) => return data,
+                None => self.keys = None,
+            }
+        }
+    }
+
+    /// Show the command's output as it printed it.
+    pub async fn show(&mut self, frame: &Frame) -> Result<()> {
+        if !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return Ok(());
+        }
+        if let Some(data) = frame.bytes() {
+            self.stdout.write_all(&data).await?;
+            self.stdout.flush().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Auxiliary read-only commands (`--sidecar`), such as `tail -f` on an
+/// application log, whose output joins the frame stream tagged with a
+/// `source` so it can be correlated with the main session.
+pub struct Sidecars {
+    running: Vec<Running>,
+}
+
+struct Running {
+    name: String,
+    group: Option<Pid>,
+    task: JoinHandle<()>,
+}
+
+impl Sidecars {
+    /// Start each command under `sh -c` in a process group of its own,
+    /// named `sidecar-1`, `sidecar-2`, ... in order.
+    pub fn spawn(commands: &[String], frame_tx: mpsc::UnboundedSender<Frame>) -> Result<Self> {
+        let mut running = Vec::with_capacity(commands.len());
+        for (i, command) in commands.iter().enumerate() {
+            let name = format!("sidecar-{}", i + 1);
+            let mut child = Command::new("sh")
+                .args(["-c", command])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0)
+                .kill_on_drop(true)
+                .spawn()
+                .with_context(|| format!("Failed to start sidecar '{}'", command))?;
+            info!("Started {}: {}", name, command);
+
+            let group = child.id().map(|pid| Pid::from_raw(pid as i32));
+            let stdout = child.stdout.take().expect("stdout is piped");
+            let stderr = child.stderr.take().expect("stderr is piped");
+            let (source, tx) = (name.clone(), frame_tx.clone());
+            let task = tokio::spawn(async move {
+                tokio::join!(
+                    forward_lines(stdout, FrameType::Stdout, &source, &tx),
+                    forward_lines(stderr, FrameType::Stderr, &source, &tx),
+                );
+                let frame = match child.wait().await {
+                    Ok(status) => Frame::new(FrameType::SidecarExit).with_exit_code(status.code().unwrap_or(-1)),
+                    Err(e) => Frame::new(FrameType::SidecarExit).with_reason(e.to_string()),
+                };
+                warn!("{} exited", source);
+                let _ = tx.send(frame.with_source(source));
+            });
+            running.push(Running { name, group, task });
+        }
+        Ok(Self { running })
+    }
+
+    /// Stop every sidecar along with anything it started.
+    pub fn shutdown(&mut self) {
+        for sidecar in self.running.drain(..) {
+            sidecar.task.abort();
+            if let Some(group) = sidecar.group {
+                if let Err(e) = killpg(group, Signal::SIGTERM) {
+                    // Already gone is the common case
+                    if e != nix::errno::Errno::ESRCH {
+                        warn!("Failed to stop {}: {}", sidecar.name, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Sidecars {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Emit each line read from a sidecar as a frame; lines need not be UTF-8.
+async fn forward_lines(
+    stream: impl AsyncRead + Unpin,
+    frame_type: FrameType,
+    source: &str,
+    frame_tx: &mpsc::UnboundedSender<Frame>,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => break,
+            Ok(_) => {
+                let frame = Frame::new(frame_type.clone())
+                    .with_data(String::from_utf8_lossy(&line).into_owned())
+                    .with_source(source.to_string());
+                if frame_tx.send(frame).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read {} output: {}", source, e);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::audit::AuditLog;
+use crate::cli::{FrameFormat, TsFormat};
+use crate::frame::Frame;
+use crate::framelog::FrameLog;
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+use crate::pretty::PrettyPrinter;
+use crate::recorder::RecordingManager;
+use crate::redact::SecretRedactor;
+use crate::routing::{RoutingTable, Sink};
+use crate::scrub::PiiScrubber;
+use crate::state::Journal;
+use crate::strict::StrictStdout;
+use crate::summarize::Summarizer;
+use crate::syslog::{JournaldSink, SyslogSink};
+use crate::transport::SocketServer;
+use crate::tokens::Tokenizer;
+use crate::ttyd::TtydServer;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// Fan-out of emitted frames to every configured destination.
+pub struct FrameSinks {
+    pub json: bool,
+    /// Encoding of the stdout stream
+    pub format: FrameFormat,
+    /// Render the stdout stream for people instead
+    pub pretty: Option<PrettyPrinter>,
+    /// Stdout reserved for frames by --ndjson-strict
+    pub strict: Option<StrictStdout>,
+    pub socket: Option<SocketServer>,
+    /// Second socket whose clients get a deliberately faulty stream
+    pub chaos_socket: Option<SocketServer>,
+    /// The socket protocol over TCP, on --bind
+    pub tcp: Option<SocketServer>,
+    /// Browser terminals on the ttyd/gotty protocol; they only take output
+    pub ttyd: Option<TtydServer>,
+    pub recording: RecordingManager,
+    /// Every frame as NDJSON, regardless of routes
+    pub frame_log: Option<FrameLog>,
+    /// The session's journal in --state-dir
+    pub journal: Option<Journal>,
+    pub audit: Option<AuditLog>,
+    pub syslog: Option<SyslogSink>,
+    pub journald: Option<JournaldSink>,
+    /// Counters served on --metrics-addr
+    pub metrics: Option<Metrics>,
+    /// Runs --notify-cmd on frames that need a human
+    pub notifier: Option<Notifier>,
+    pub routes: RoutingTable,
+    pub session_id: Option<String>,
+    pub redactor: Option<SecretRedactor>,
+    pub scrubber: Option<PiiScrubber>,
+    /// Sequence number of the next frame, counting from 1
+    pub next_seq: u64,
+    pub ts_format: TsFormat,
+    /// `mono_ns` of the first frame, which `elapsed_ms` counts from
+    pub ts_origin: Option<u64>,
+    /// Annotates frames with the tokens their `data` costs
+    pub tokenizer: Option<Tokenizer>,
+    /// Stands in for long runs of output with `--summarize-cmd` summaries
+    pub summarizer: Option<Summarizer>,
+}
+
+impl FrameSinks {
+    /// Mask `secret` in every frame from now on, as `--redact` would.
+    pub fn add_secret(&mut self, secret: &str) {
+        let redactor = self
+            .redactor
+            .get_or_insert_with(|| SecretRedactor::new(&[], false).expect("no patterns to fail on"));
+        redactor.add_secret(secret);
+    }
+
+    pub fn emit(&mut self, frame: &Frame) -> Result<()> {
+        let mut frame = Cow::Borrowed(frame);
+        if let Some(redacted) = self.redactor.as_mut().and_then(|r| r.redact_frame(&frame)) {
+            frame = Cow::Owned(redacted);
+        }
+        if let Some(scrubbed) = self.scrubber.as_ref().and_then(|s| s.scrub_frame(&frame)) {
+            frame = Cow::Owned(scrubbed);
+        }
+
+        // Output a summary stands in for is numbered and kept like any
+        // other frame, but not streamed
+        let summarized = self.summarizer.as_mut().is_some_and(|summarizer| summarizer.observe(&frame));
+
+        // Frames made outside the session (processor, health, errors) are
+        // stamped here so every delivered frame names its session
+        if let (None, Some(id)) = (&frame.session_id, &self.session_id) {
+            frame = Cow::Owned(frame.into_owned().with_session_id(id.clone()));
+        }
+
+        // Numbered before routing, so every sink sees the same seq
+        let mut frame = frame.into_owned().with_seq(self.next_seq);
+        self.next_seq += 1;
+        if let Some(tokenizer) = self.tokenizer {
+            frame.tokens = tokenizer.frame_tokens(&frame);
+        }
+        if self.ts_format != TsFormat::Wall {
+            if let Some(mono_ns) = frame.mono_ns {
+                let origin = *self.ts_origin.get_or_insert(mono_ns);
+                frame.elapsed_ms = Some(mono_ns.saturating_sub(origin) / 1_000_000);
+            }
+        }
+        self.deliver(&frame, summarized)
+    }
+
+    /// The frame as stdout and the socket carry it: under `--ts-format
+    /// elapsed` its `ts` is seconds since the session started, on the
+    /// monotonic clock, so it never goes backwards when the wall clock is
+    /// stepped or slewed.
+    fn streamed<'a>(&self, frame: &'a Frame) -> Cow<'a, Frame> {
+        match (self.ts_format, frame.mono_ns, self.ts_origin) {
+            (TsFormat::Elapsed, Some(mono_ns), Some(origin)) => {
+                let mut frame = frame.clone();
+                frame.ts = mono_ns.saturating_sub(origin) as f64 / 1e9;
+                Cow::Owned(frame)
+            }
+            _ => Cow::Borrowed(frame),
+        }
+    }
+
+    /// Hand the frame to every sink that wants it; `summarized` output
+    /// stays off stdout, the sockets and ttyd.
+    fn deliver(&mut self, frame: &Frame, summarized: bool) -> Result<()> {
+        let routes = &self.routes;
+        let kind = &frame.frame_type;
+
+        if let Some(ref mut frame_log) = self.frame_log {
+            frame_log.record(frame)?;
+        }
+        if let Some(ref mut journal) = self.journal {
+            journal.record(frame)?;
+        }
+
+        if routes.allows(Sink::Recording, kind) {
+            self.recording.record_frame(frame)?;
+        }
+
+        if self.json && !summarized && routes.allows(Sink::Json, kind) {
+            let streamed = self.streamed(frame);
+            if let Some(ref mut strict) = self.strict {
+                strict.write_frame(&streamed.encode(self.format)?)?;
+            } else {
+                let mut stdout = io::stdout();
+                match self.pretty {
+                    Some(ref pretty) => writeln!(stdout, "{}", pretty.render(frame))?,
+                    None => stdout.write_all(&streamed.encode(self.format)?)?,
+                }
+                stdout.flush()?;
+            }
+        }
+
+        if !summarized && routes.allows(Sink::Socket, kind) {
+            let streamed = self.streamed(frame);
+            for socket in self.socket.iter().chain(&self.tcp).chain(&self.chaos_socket) {
+                socket.broadcast(&streamed);
+            }
+        }
+
+        if let Some(ttyd) = self.ttyd.as_ref().filter(|_| !summarized) {
+            ttyd.broadcast(frame);
+        }
+
+        if let Some(ref mut audit) = self.audit {
+            if routes.allows(Sink::Audit, kind) {
+                audit.record(frame)?;
+            }
+        }
+
+        if let Some(ref mut syslog) = self.syslog {
+            if routes.allows(Sink::Syslog, kind) {
+                syslog.record(frame)?;
+            }
+        }
+
+        if let Some(ref mut journald) = self.journald {
+            if routes.allows(Sink::Journald, kind) {
+                journald.record(frame)?;
+            }
+        }
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe_emitted(frame);
+            metrics.set_dropped(self.dropped_frames());
+        }
+
+        if let Some(ref mut notifier) = self.notifier {
+            notifier.observe(frame);
+        }
+
+        Ok(())
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.socket.iter().chain(&self.tcp).map(SocketServer::dropped_frames).sum()
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        if self.recording.is_recording() {
+            self.recording.stop_recording()?;
+        }
+        if let Some(ref mut frame_log) = self.frame_log {
+            frame_log.finish()?;
+        }
+        if let Some(ref mut journal) = self.journal {
+            journal.finish()?;
+        }
+        Ok(())
+    }
+}
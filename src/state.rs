@@ -0,0 +1,650 @@
+use crate::cli::SearchArgs;
+use crate::emulator::{Emulator, ScreenState};
+use crate::frame::{Frame, FrameType};
+use crate::framelog::FrameLog;
+use crate::index::{self, JournalIndex, Line, Lines, Resume, INDEX_DIR};
+use crate::seal::{SealedReader, SealedWriter, StateKey, Unfinished};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// Checkpoint of a session's screen, plain or encrypted with `--state-key`.
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+pub const SEALED_CHECKPOINT_FILE: &str = "checkpoint.sealed";
+
+/// Longest stretch of a matching line shown in search results.
+const SNIPPET_CHARS: usize = 200;
+
+/// Settled lines a checkpoint keeps, newest last.
+const SCROLLBACK_LINES: usize = 10_000;
+
+/// Size assumed until a `session_start` or `resize` frame gives one.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// File name of journal segment `n`.
+pub fn segment_file(n: u64, sealed: bool) -> String {
+    format!("journal.{:06}.{}", n, if sealed { "sealed" } else { "ndjson" })
+}
+
+/// Segment number of a journal segment's file name.
+fn segment_number(name: &str) -> Option<u64> {
+    let (n, ext) = name.strip_prefix("journal.")?.split_once('.')?;
+    matches!(ext, "ndjson" | "sealed").then_some(())?;
+    n.parse().ok()
+}
+
+/// `--state-dir`: a directory per session, named by its ID, holding the
+/// session's journal in numbered segments and, once one has been taken,
+/// a checkpoint of its screen. Shared by every session pointed at it.
+pub struct StateDir {
+    root: PathBuf,
+    /// Encrypts new state and decrypts sealed state
+    key: Option<StateKey>,
+    /// How often new journals checkpoint and drop older segments
+    checkpoint_every: Option<Duration>,
+}
+
+impl StateDir {
+    pub fn open(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            key: None,
+            checkpoint_every: None,
+        }
+    }
+
+    pub fn with_key(mut self, key: Option<StateKey>) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn with_checkpoints(mut self, every: Option<Duration>) -> Self {
+        self.checkpoint_every = every;
+        self
+    }
+
+    fn session_dir(&self, session_id: &str) -> PathBuf {
+        self.root.join(session_id)
+    }
+
+    /// Start the journal of a new session, sealed if there is a key.
+    pub fn create_journal(&self, session_id: &str) -> Result<Journal> {
+        let dir = self.session_dir(session_id);
+        // Private: journals hold everything the session ever printed
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+        // Its frames would be mixed up with the new session's
+        if !self.segments(session_id)?.is_empty() {
+            bail!(
+                "Session '{}' already has a journal in {:?}; give the new session another --session-id",
+                session_id,
+                self.root
+            );
+        }
+        let writer = SegmentWriter::create(&dir, 1, self.key.as_ref())?;
+        // Sealed journals are not indexed, as the index would hold their
+        // output in the clear
+        let index = match self.key {
+            Some(_) => None,
+            None => Some(JournalIndex::create(&dir.join(INDEX_DIR))?),
+        };
+        Ok(Journal {
+            dir,
+            key: self.key.clone(),
+            segment: 1,
+            writer,
+            index,
+            checkpoints: self.checkpoint_every.map(|every| (every, Instant::now(), Replica::new())),
+        })
+    }
+
+    /// Sessions with a journal, in ID (and so, for ULIDs, start) order.
+    pub fn sessions(&self) -> Result<Vec<String>> {
+        let dir = match fs::read_dir(&self.root) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.root)),
+        };
+        let mut sessions: Vec<String> = dir
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .filter(|id| self.segments(id).is_ok_and(|segments| !segments.is_empty()))
+            .collect();
+        sessions.sort();
+        Ok(sessions)
+    }
+
+    /// A session's journal segments that have not been compacted away, in
+    /// order.
+    pub fn segments(&self, session_id: &str) -> Result<Vec<(u64, PathBuf)>> {
+        let dir = self.session_dir(session_id);
+        let entries = fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))?;
+        let mut segments: Vec<(u64, PathBuf)> = entries
+            .flatten()
+            .filter_map(|entry| Some((segment_number(entry.file_name().to_str()?)?, entry.path())))
+            .collect();
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// Read back a segment found by `segments`.
+    pub fn read_segment(&self, path: &Path) -> Result<JournalReader> {
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let reader = BufReader::new(file);
+        if path.extension().is_some_and(|ext| ext == "sealed") {
+            let sealed = SealedReader::new(reader, self.key_for(path)?)
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            return Ok(JournalReader::Sealed(sealed));
+        }
+        Ok(JournalReader::Plain { reader, offset: 0 })
+    }
+
+    fn key_for(&self, path: &Path) -> Result<&StateKey> {
+        self.key
+            .as_ref()
+            .ok_or_else(|| anyhow!("{:?} is encrypted; pass its --state-key", path))
+    }
+
+    /// Pass each record of a segment from offset `from` on, and its
+    /// offset, to `each`. A sealed segment stopping short of its end
+    /// record is an error, except for a session's `last` segment, which is
+    /// still being written while the session runs and is left that way by
+    /// a crash; there it is warned about and what was read is kept.
+    fn each_record(&self, path: &Path, last: bool, from: u64, mut each: impl FnMut(u64, Vec<u8>)) -> Result<()> {
+        let mut reader = self.read_segment(path)?;
+        if let JournalReader::Plain { reader, offset } = &mut reader {
+            *offset = reader.seek(SeekFrom::Start(from))?;
+        }
+        loop {
+            match reader.next_record() {
+                Ok(Some((at, _))) if at < from => {}
+                Ok(Some((at, record))) => each(at, record),
+                Ok(None) => return Ok(()),
+                Err(e) if last && e.is::<Unfinished>() => {
+                    warn!("{:?}: {}", path, e);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.context(format!("Failed to read {:?}", path))),
+            }
+        }
+    }
+
+    /// The session's latest checkpoint, if it has taken one.
+    pub fn checkpoint(&self, session_id: &str) -> Result<Option<Checkpoint>> {
+        let dir = self.session_dir(session_id);
+        let sealed = dir.join(SEALED_CHECKPOINT_FILE);
+        let json = if sealed.is_file() {
+            let file = BufReader::new(File::open(&sealed)?);
+            let mut reader = SealedReader::new(file, self.key_for(&sealed)?)?;
+            let (_, record) = reader.next_record()?.ok_or_else(|| anyhow!("{:?} is empty", sealed))?;
+            if reader.next_record()?.is_some() {
+                bail!("{:?} holds more than a checkpoint", sealed);
+            }
+            record
+        } else {
+            match fs::read(dir.join(CHECKPOINT_FILE)) {
+                Ok(json) => json,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let checkpoint = serde_json::from_slice(&json).with_context(|| format!("Corrupt checkpoint in {:?}", dir))?;
+        Ok(Some(checkpoint))
+    }
+
+    /// Rebuild the session's screen and scrollback: its checkpoint, then
+    /// the frames journaled after it. Only the segments after the last
+    /// checkpoint are read, however long the session has run.
+    pub fn restore(&self, session_id: &str) -> Result<Replica> {
+        let checkpoint = self.checkpoint(session_id)?;
+        let first = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.segment);
+        let mut replica = checkpoint.as_ref().map_or_else(Replica::new, Replica::from_checkpoint);
+        let segments = self.segments(session_id)?;
+        let last = segments.last().map(|(n, _)| *n);
+        for (n, path) in segments.into_iter().filter(|(n, _)| *n >= first) {
+            self.each_record(&path, Some(n) == last, 0, |_, record| {
+                match serde_json::from_slice::<Frame>(&record) {
+                    Ok(frame) if frame.seq.is_none_or(|seq| seq > replica.seq) => replica.apply(&frame),
+                    _ => {}
+                }
+            })?;
+        }
+        Ok(replica)
+    }
+
+    /// A session's state as files, for another state directory to
+    /// `import`: its journal segments as they are, sealed or not, and a
+    /// fresh checkpoint of the screen they add up to.
+    pub fn export(&self, session_id: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+        let segments = self.segments(session_id)?;
+        let last = segments
+            .last()
+            .map(|(n, _)| *n)
+            .ok_or_else(|| anyhow!("Session '{}' has no journal in {:?}", session_id, self.root))?;
+        let replica = self.restore(session_id)?;
+        let mut files = BTreeMap::new();
+        for (n, path) in segments {
+            let sealed = path.extension().is_some_and(|ext| ext == "sealed");
+            let bytes = fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+            files.insert(segment_file(n, sealed), bytes);
+        }
+        let (name, bytes) = encode_checkpoint(&replica.checkpoint(last), self.key.as_ref())?;
+        files.insert(name.to_string(), bytes);
+        Ok(files)
+    }
+
+    /// Add a session exported from another state directory and restore it,
+    /// which proves the state whole and the key right; if it cannot be
+    /// restored, nothing is kept. Refuses to overwrite a session already
+    /// here.
+    pub fn import(&self, session_id: &str, files: &BTreeMap<String, Vec<u8>>) -> Result<Replica> {
+        // Names come from elsewhere, so only those a session can have
+        let allowed = |name: &str| segment_number(name).is_some() || [CHECKPOINT_FILE, SEALED_CHECKPOINT_FILE].contains(&name);
+        if let Some(name) = files.keys().find(|name| !allowed(name)) {
+            bail!("Unexpected file '{}' in session state", name);
+        }
+        let dir = self.session_dir(session_id);
+        if dir.exists() {
+            bail!("Session '{}' already has state in {:?}", session_id, self.root);
+        }
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+        let restored = files
+            .iter()
+            .try_for_each(|(name, bytes)| write_atomic(&dir.join(name), bytes))
+            .and_then(|()| self.restore(session_id));
+        if restored.is_err() {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        restored
+    }
+
+    /// Lines of output containing `query`, ignoring case, that started in
+    /// frames stamped at or after `since` (Unix time), oldest session
+    /// first. Lines are joined up across frames.
+    ///
+    /// An unsealed journal is looked up in its index, and only the frames
+    /// journaled after the index's last commit are read. Sealed journals,
+    /// and journals from before there was an index, are scanned: each of
+    /// their segments written since `since` is read and decoded frame by
+    /// frame, so the cost grows with them.
+    pub fn scan(&self, query: &str, since: Option<f64>) -> Result<Vec<Hit>> {
+        let needle = query.to_lowercase();
+        let mut hits = Vec::new();
+        for session in self.sessions()? {
+            let index = self.session_dir(&session).join(INDEX_DIR);
+            let (mut found, resume) = match index.is_dir() {
+                true => index::search(&index, &needle, since)?,
+                false => (Vec::new(), Resume::default()),
+            };
+            let segments = self.segments(&session)?;
+            let last = segments.last().map(|(n, _)| *n);
+            let mut lines = Lines::default();
+            for (segment, path) in segments.into_iter().filter(|(n, _)| *n >= resume.segment) {
+                // A segment last written before the cutoff has nothing newer
+                let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+                if let (Some(since), Some(modified)) = (since, modified) {
+                    if unix_time(modified) < since {
+                        continue;
+                    }
+                }
+                let from = if segment == resume.segment { resume.offset } else { 0 };
+                self.each_record(&path, Some(segment) == last, from, |at, record| {
+                    // Lines cut short by a crash are skipped
+                    let Ok(frame) = serde_json::from_slice::<Frame>(&record) else {
+                        return;
+                    };
+                    if (segment, at) == (resume.segment, resume.offset) {
+                        lines = Lines::resuming(&resume);
+                    }
+                    let pushed = lines.push(&frame, segment, at);
+                    found.extend(pushed.into_iter().filter(|line| line.matches(&needle, since)));
+                })?;
+            }
+            found.extend(lines.take().filter(|line| line.matches(&needle, since)));
+            hits.extend(found.into_iter().map(|line| Hit::new(&session, line)));
+        }
+        Ok(hits)
+    }
+}
+
+/// The screen and scrollback a session's frames add up to: what a
+/// checkpoint saves and a restore rebuilds.
+pub struct Replica {
+    emulator: Emulator,
+    /// Lines settled by a line feed, including those still on screen
+    scrollback: VecDeque<String>,
+    /// Seq of the last frame applied
+    pub seq: u64,
+}
+
+impl Replica {
+    pub fn new() -> Self {
+        Self {
+            emulator: Emulator::new(DEFAULT_SIZE.0, DEFAULT_SIZE.1),
+            scrollback: VecDeque::new(),
+            seq: 0,
+        }
+    }
+
+    fn from_checkpoint(checkpoint: &Checkpoint) -> Self {
+        Self {
+            emulator: Emulator::restore(&checkpoint.screen),
+            scrollback: checkpoint.scrollback.iter().cloned().collect(),
+            seq: checkpoint.seq,
+        }
+    }
+
+    pub fn apply(&mut self, frame: &Frame) {
+        if let Some(seq) = frame.seq {
+            self.seq = seq;
+        }
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                if let Some(data) = frame.bytes() {
+                    self.emulator.process(&data);
+                    self.scrollback.extend(self.emulator.take_committed_lines());
+                    let excess = self.scrollback.len().saturating_sub(SCROLLBACK_LINES);
+                    self.scrollback.drain(..excess);
+                }
+            }
+            FrameType::SessionStart | FrameType::Resize => {
+                if let (Some(cols), Some(rows)) = (frame.cols, frame.rows) {
+                    self.emulator.resize(cols, rows);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn screen(&self) -> ScreenState {
+        self.emulator.state()
+    }
+
+    pub fn scrollback(&self) -> impl Iterator<Item = &str> {
+        self.scrollback.iter().map(String::as_str)
+    }
+
+    /// The state so far, as a checkpoint taken before `segment`.
+    pub fn checkpoint(&self, segment: u64) -> Checkpoint {
+        Checkpoint {
+            seq: self.seq,
+            ts: unix_time(SystemTime::now()),
+            segment,
+            screen: self.screen(),
+            scrollback: self.scrollback.iter().cloned().collect(),
+        }
+    }
+}
+
+impl Default for Replica {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A session's state as of one frame, replacing the journal up to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Seq of the last frame it covers
+    pub seq: u64,
+    pub ts: f64,
+    /// First journal segment with frames after it
+    pub segment: u64,
+    pub screen: ScreenState,
+    pub scrollback: Vec<String>,
+}
+
+/// One journal segment as it is written.
+enum SegmentWriter {
+    /// With the bytes written so far
+    Plain(FrameLog, u64),
+    Sealed(SealedWriter<BufWriter<File>>),
+}
+
+impl SegmentWriter {
+    /// Start segment `n`, which must not exist yet.
+    fn create(dir: &Path, n: u64, key: Option<&StateKey>) -> Result<Self> {
+        let path = dir.join(segment_file(n, key.is_some()));
+        let file = create_private(&path, false)?;
+        let Some(key) = key else {
+            return Ok(SegmentWriter::Plain(FrameLog::from_file(file), 0));
+        };
+        Ok(SegmentWriter::Sealed(SealedWriter::new(BufWriter::new(file), key)?))
+    }
+
+    /// Append a frame; a plain segment also says where in it the frame
+    /// went.
+    fn record(&mut self, frame: &Frame) -> Result<Option<Range<u64>>> {
+        let json = frame.to_json()?;
+        match self {
+            SegmentWriter::Plain(log, written) => {
+                log.record_json(&json)?;
+                let start = *written;
+                *written += json.len() as u64 + 1;
+                Ok(Some(start..*written))
+            }
+            SegmentWriter::Sealed(sealed) => {
+                sealed.append(json.as_bytes())?;
+                sealed.get_mut().flush()?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            SegmentWriter::Plain(log, _) => log.finish(),
+            SegmentWriter::Sealed(sealed) => {
+                sealed.finish()?;
+                sealed.get_mut().flush()?;
+                sealed.get_ref().get_ref().sync_all()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A checkpoint as its file holds it, sealed as one record with a key,
+/// and the name of that file.
+fn encode_checkpoint(checkpoint: &Checkpoint, key: Option<&StateKey>) -> Result<(&'static str, Vec<u8>)> {
+    let json = serde_json::to_vec(checkpoint)?;
+    let Some(key) = key else {
+        return Ok((CHECKPOINT_FILE, json));
+    };
+    let mut sealed = SealedWriter::new(Vec::new(), key)?;
+    sealed.append(&json)?;
+    sealed.finish()?;
+    Ok((SEALED_CHECKPOINT_FILE, std::mem::take(sealed.get_mut())))
+}
+
+/// Written aside and renamed, so a crash leaves the old file in place.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut file = create_private(Path::new(&temp), true)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to replace {:?}", path))?;
+    Ok(())
+}
+
+/// A file only the user can read, replacing one already there only if
+/// `replace` is set.
+fn create_private(path: &Path, replace: bool) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(replace)
+        .truncate(replace)
+        .create_new(!replace)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))
+}
+
+/// A session's journal as it is written. With checkpoints, it follows the
+/// screen as frames go by and periodically saves it, starting a new
+/// segment and deleting the ones the checkpoint replaces.
+pub struct Journal {
+    dir: PathBuf,
+    key: Option<StateKey>,
+    segment: u64,
+    writer: SegmentWriter,
+    /// Full-text index of an unsealed journal
+    index: Option<JournalIndex>,
+    /// Interval, time of the last checkpoint, and the state to save
+    checkpoints: Option<(Duration, Instant, Replica)>,
+}
+
+impl Journal {
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        let span = self.writer.record(frame)?;
+        if let (Some(index), Some(span)) = (&mut self.index, span) {
+            index.record(frame, self.segment, span.start, span.end)?;
+        }
+        let Some((every, last, ref mut replica)) = self.checkpoints else {
+            return Ok(());
+        };
+        replica.apply(frame);
+        if last.elapsed() >= every {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Save the screen as of the last frame and compact the journal.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let Some((_, ref mut last, ref replica)) = self.checkpoints else {
+            return Ok(());
+        };
+        *last = Instant::now();
+        let next = self.segment + 1;
+        let writer = SegmentWriter::create(&self.dir, next, self.key.as_ref())?;
+        std::mem::replace(&mut self.writer, writer).finish()?;
+        self.segment = next;
+
+        let (name, bytes) = encode_checkpoint(&replica.checkpoint(next), self.key.as_ref())?;
+        write_atomic(&self.dir.join(name), &bytes)?;
+
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            let covered = entry
+                .file_name()
+                .to_str()
+                .and_then(segment_number)
+                .is_some_and(|n| n < next);
+            if covered {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        if let Some(index) = &mut self.index {
+            index.forget_before(next)?;
+        }
+        debug!("Checkpointed {:?} at seq {}", self.dir, replica.seq);
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.finish()?;
+        match &mut self.index {
+            Some(index) => index.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A journal segment read back one frame at a time.
+pub enum JournalReader {
+    Plain { reader: BufReader<File>, offset: u64 },
+    Sealed(SealedReader<BufReader<File>>),
+}
+
+impl JournalReader {
+    /// The next frame's JSON and its byte offset in the segment.
+    pub fn next_record(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        match self {
+            JournalReader::Plain { reader, offset } => {
+                let mut line = Vec::new();
+                let read = reader.read_until(b'\n', &mut line)?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                let at = *offset;
+                *offset += read as u64;
+                Ok(Some((at, line)))
+            }
+            JournalReader::Sealed(sealed) => sealed.next_record(),
+        }
+    }
+}
+
+/// A line of a journal's output that matched, found at the frame it
+/// starts in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Hit {
+    pub session: String,
+    pub ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Journal segment holding the frame
+    pub segment: u64,
+    /// Byte offset of the frame in the segment
+    pub offset: u64,
+    /// The matching line, escape sequences removed
+    pub text: String,
+}
+
+impl Hit {
+    fn new(session: &str, line: Line) -> Self {
+        Self {
+            session: session.to_string(),
+            ts: line.at.ts,
+            seq: line.at.seq,
+            segment: line.at.segment,
+            offset: line.at.offset,
+            text: line.text.trim().chars().take(SNIPPET_CHARS).collect(),
+        }
+    }
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs_f64()
+}
+
+/// Print the matches across the state directory, oldest session first.
+/// Returns whether anything matched.
+pub fn run(args: &SearchArgs) -> Result<bool> {
+    let since = args.since.map(|age| unix_time(SystemTime::now()) - age.as_secs_f64());
+    let key = args.state_key.as_deref().map(StateKey::load).transpose()?;
+    let mut hits = StateDir::open(&args.state_dir).with_key(key).scan(&args.query, since)?;
+    if let Some(limit) = args.limit {
+        hits.truncate(limit);
+    }
+    for hit in &hits {
+        if args.json {
+            println!("{}", serde_json::to_string(hit)?);
+            continue;
+        }
+        let time = Local
+            .timestamp_opt(hit.ts as i64, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let seq = hit.seq.map_or_else(|| "-".to_string(), |seq| seq.to_string());
+        println!("{}  {}  seq {}  @{}:{}  {}", hit.session, time, seq, hit.segment, hit.offset, hit.text);
+    }
+    Ok(!hits.is_empty())
+}
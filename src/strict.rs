@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use tokio::sync::mpsc;
+
+/// Exit status when `--ndjson-strict` catches non-frame output, as
+/// sysexits' EX_SOFTWARE.
+pub const STRICT_STATUS: i32 = 70;
+
+/// `--ndjson-strict`: stdout carries frames and nothing else. Frames are
+/// written to a private copy of the original stdout, and a pipe takes its
+/// place as file descriptor 1, so anything else written to stdout, by
+/// spectertty or a process it starts, arrives on the pipe instead of
+/// corrupting the stream.
+pub struct StrictStdout {
+    frames: File,
+}
+
+impl StrictStdout {
+    /// Take over stdout. Whatever is written to it from now on, other than
+    /// frames, comes out of the receiver.
+    pub fn claim() -> Result<(Self, mpsc::UnboundedReceiver<Vec<u8>>)> {
+        io::stdout().flush()?;
+        // SAFETY: plain descriptor calls; each result is checked and
+        // owned by exactly one File afterwards
+        let (frames, stray) = unsafe {
+            let frames = check(libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 3))?;
+            let mut pipe = [0 as RawFd; 2];
+            check(libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC))?;
+            // The copy on descriptor 1 is inherited by children, so their
+            // stray output is caught too
+            check(libc::dup2(pipe[1], libc::STDOUT_FILENO))?;
+            libc::close(pipe[1]);
+            (File::from_raw_fd(frames), File::from_raw_fd(pipe[0]))
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        // A blocking read, so a thread of its own rather than a runtime worker
+        std::thread::spawn(move || {
+            let mut stray = stray;
+            let mut buf = [0u8; 4096];
+            loop {
+                match stray.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok((Self { frames }, rx))
+    }
+
+    /// Write one encoded frame, refusing anything that is not exactly one
+    /// line.
+    pub fn write_frame(&mut self, line: &[u8]) -> Result<()> {
+        match line.split_last() {
+            Some((b'\n', body)) if !body.is_empty() && !body.contains(&b'\n') => {}
+            _ => bail!("--ndjson-strict: refusing to write a frame that is not exactly one line"),
+        }
+        self.frames.write_all(line)?;
+        Ok(())
+    }
+}
+
+/// Whether `file` is what stdout writes to, e.g. a `--log-file` of
+/// `/dev/stdout`, which would bypass the pipe.
+pub fn is_stdout(file: &File) -> bool {
+    match (file.metadata(), std::fs::metadata("/dev/stdout")) {
+        (Ok(file), Ok(stdout)) => (file.dev(), file.ino()) == (stdout.dev(), stdout.ino()),
+        _ => false,
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
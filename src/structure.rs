@@ -0,0 +1,146 @@
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::sync::OnceLock;
+
+/// Share of a block's lines that must be `key: value` pairs.
+const KEY_VALUE_RATIO: f64 = 0.8;
+
+/// Longest block analysed; bigger output is left to the agent.
+pub const MAX_BLOCK_LINES: usize = 500;
+
+/// Structure found in a block of output lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Structure {
+    /// `json`, `key_value` or `table`
+    pub kind: &'static str,
+    pub value: Value,
+}
+
+/// Look for JSON, a `key: value` block or a column-aligned table in
+/// `lines`, in that order.
+pub fn detect(lines: &[String]) -> Option<Structure> {
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.trim_end())
+        .skip_while(|line| line.is_empty())
+        .collect();
+    let end = lines.iter().rposition(|line| !line.is_empty())? + 1;
+    let lines = &lines[..end];
+    if lines.len() > MAX_BLOCK_LINES {
+        return None;
+    }
+
+    if let Some(value) = json(lines) {
+        return Some(Structure { kind: "json", value });
+    }
+    if let Some(value) = key_values(lines) {
+        return Some(Structure { kind: "key_value", value });
+    }
+    table(lines).map(|value| Structure { kind: "table", value })
+}
+
+/// The first JSON object or array in the lines, possibly spanning several,
+/// with any text before or after it ignored.
+fn json(lines: &[&str]) -> Option<Value> {
+    for (i, line) in lines.iter().enumerate() {
+        let Some(start) = line.find(['{', '[']) else {
+            continue;
+        };
+        let text = std::iter::once(&line[start..])
+            .chain(lines[i + 1..].iter().copied())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut values = serde_json::Deserializer::from_str(&text).into_iter::<Value>();
+        match values.next() {
+            Some(Ok(value @ (Value::Object(_) | Value::Array(_)))) if !is_empty(&value) => return Some(value),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => map.is_empty(),
+        Value::Array(items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+/// Two or more `key: value` (or `key = value`) lines as an object.
+fn key_values(lines: &[&str]) -> Option<Value> {
+    static KEY_VALUE: OnceLock<Regex> = OnceLock::new();
+    let pattern =
+        KEY_VALUE.get_or_init(|| Regex::new(r"^\s*([A-Za-z][\w .()/-]{0,39}?)\s*(?::|\s=)\s+(\S.*?)\s*$").unwrap());
+
+    let mut map = Map::new();
+    let mut matched = 0;
+    for line in lines {
+        if let Some(caps) = pattern.captures(line) {
+            matched += 1;
+            map.insert(caps[1].trim().to_string(), Value::String(caps[2].to_string()));
+        }
+    }
+    if matched < 2 || (matched as f64) < lines.len() as f64 * KEY_VALUE_RATIO {
+        return None;
+    }
+    Some(Value::Object(map))
+}
+
+/// Columns are the runs of character positions that are not blank in
+/// every line. A first line of upper-case labels becomes `columns`.
+fn table(lines: &[&str]) -> Option<Value> {
+    let rows: Vec<Vec<char>> = lines.iter().map(|line| line.chars().collect()).collect();
+    if rows.len() < 2 {
+        return None;
+    }
+    let width = rows.iter().map(Vec::len).max()?;
+    let blank: Vec<bool> = (0..width)
+        .map(|i| rows.iter().all(|row| row.get(i).is_none_or(|c| *c == ' ')))
+        .collect();
+
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, &blank) in blank.iter().chain([true].iter()).enumerate() {
+        match (blank, start) {
+            (false, None) => start = Some(i),
+            (true, Some(from)) => {
+                spans.push((from, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    // Prose lines up by accident far less often when there are more
+    // columns, more lines or gaps wider than one space
+    let wide_gap = spans.windows(2).any(|pair| pair[1].0 - pair[0].1 >= 2);
+    let likely = spans.len() >= 3 && rows.len() >= 3 || spans.len() >= 2 && wide_gap;
+    if !likely {
+        return None;
+    }
+
+    let mut cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            spans
+                .iter()
+                .map(|&(from, to)| {
+                    let end = to.min(row.len());
+                    row.get(from..end).map_or(String::new(), |cell| cell.iter().collect::<String>().trim().to_string())
+                })
+                .collect()
+        })
+        .collect();
+
+    let header = &cells[0];
+    let is_header = header.iter().all(|cell| {
+        !cell.is_empty() && cell.chars().any(char::is_alphabetic) && !cell.chars().any(char::is_lowercase)
+    });
+    if is_header {
+        let columns = cells.remove(0);
+        Some(json!({ "columns": columns, "rows": cells }))
+    } else {
+        Some(json!({ "rows": cells }))
+    }
+}
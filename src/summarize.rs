@@ -0,0 +1,193 @@
+use crate::frame::{Frame, FrameType};
+use crate::throttle::TAIL_BYTES;
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tracing::warn;
+
+/// Quiet time after which output counts as having paused.
+pub const PAUSE: Duration = Duration::from_millis(500);
+
+/// Longest a `--summarize-cmd` run may take before it is killed and the
+/// chunk's tail is sent instead.
+pub const SUMMARIZE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Output handed to `--summarize-cmd` in one run.
+struct Chunk {
+    text: String,
+    frames: u64,
+}
+
+/// Condenses long runs of output with `--summarize-cmd`, such as an LLM
+/// CLI. Output passes as usual until the command has written more than
+/// `--summarize-after` bytes without pausing; from there on it is taken
+/// out of the stream, piped to the summarizer in chunks of that size, and
+/// the summaries are sent as `summary` frames in its place. A pause (no
+/// output for `PAUSE`, or a `prompt` or `exit`) summarizes what is left
+/// and lets output through again.
+///
+/// Chunks are summarized one at a time, in order, on a task of their own,
+/// so the session does not wait on the summarizer.
+pub struct Summarizer {
+    threshold: usize,
+    /// Output bytes since the last pause
+    run_bytes: usize,
+    summarizing: bool,
+    last_output: Option<Instant>,
+    pending: Chunk,
+    chunks: Option<mpsc::UnboundedSender<Chunk>>,
+    summaries: mpsc::UnboundedReceiver<Frame>,
+}
+
+impl Summarizer {
+    pub fn new(command: String, threshold: usize) -> Self {
+        let (chunks, mut queue) = mpsc::unbounded_channel::<Chunk>();
+        let (done, summaries) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(chunk) = queue.recv().await {
+                if done.send(summarize(&command, chunk).await).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            threshold,
+            run_bytes: 0,
+            summarizing: false,
+            last_output: None,
+            pending: Chunk {
+                text: String::new(),
+                frames: 0,
+            },
+            chunks: Some(chunks),
+            summaries,
+        }
+    }
+
+    /// Look at a frame on its way to the sinks. True if it is output the
+    /// summary stands in for, which is kept but not streamed.
+    pub fn observe(&mut self, frame: &Frame) -> bool {
+        match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => {
+                let Some(text) = frame.text() else {
+                    return false;
+                };
+                // While output passes, a pause is noticed when it resumes
+                if !self.summarizing && self.last_output.is_some_and(|at| at.elapsed() >= PAUSE) {
+                    self.run_bytes = 0;
+                }
+                self.last_output = Some(Instant::now());
+                self.run_bytes += text.len();
+                if !self.summarizing && self.run_bytes <= self.threshold {
+                    return false;
+                }
+                self.summarizing = true;
+                self.pending.text.push_str(&text);
+                self.pending.frames += 1;
+                if self.pending.text.len() >= self.threshold {
+                    self.dispatch();
+                }
+                true
+            }
+            FrameType::Idle | FrameType::Prompt | FrameType::Exit => {
+                self.pause();
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn pause(&mut self) {
+        self.dispatch();
+        self.summarizing = false;
+        self.run_bytes = 0;
+    }
+
+    fn dispatch(&mut self) {
+        if self.pending.frames == 0 {
+            return;
+        }
+        let chunk = std::mem::replace(
+            &mut self.pending,
+            Chunk {
+                text: String::new(),
+                frames: 0,
+            },
+        );
+        if let Some(ref chunks) = self.chunks {
+            let _ = chunks.send(chunk);
+        }
+    }
+
+    /// The next summary, as soon as the summarizer has written it.
+    pub async fn next(&mut self) -> Option<Frame> {
+        loop {
+            // While output is taken over, the pause has to be waited for
+            let pause_at = self.last_output.filter(|_| self.summarizing).map(|at| at + PAUSE);
+            let Some(pause_at) = pause_at else {
+                return self.summaries.recv().await;
+            };
+            tokio::select! {
+                frame = self.summaries.recv() => return frame,
+                _ = sleep_until(pause_at) => self.pause(),
+            }
+        }
+    }
+
+    /// Summarize what is left and wait for every summary still to come.
+    pub async fn finish(&mut self) -> Vec<Frame> {
+        self.dispatch();
+        self.chunks = None;
+        let mut frames = Vec::new();
+        while let Some(frame) = self.summaries.recv().await {
+            frames.push(frame);
+        }
+        frames
+    }
+}
+
+/// Run `command` on one chunk. If it fails, the end of the chunk is sent
+/// instead, so the consumer still sees where the output got to.
+async fn summarize(command: &str, chunk: Chunk) -> Frame {
+    let stats = BTreeMap::from([
+        ("frames".to_string(), chunk.frames),
+        ("bytes".to_string(), chunk.text.len() as u64),
+    ]);
+    let frame = Frame::new(FrameType::Summary).with_stats(stats);
+    match tokio::time::timeout(SUMMARIZE_TIMEOUT, run(command, &chunk.text)).await {
+        Ok(Ok(summary)) => return frame.with_reason("summarize_cmd".to_string()).with_data(summary),
+        Ok(Err(e)) => warn!("--summarize-cmd failed: {}", e),
+        Err(_) => warn!("--summarize-cmd took over {}s, sending the output's tail", SUMMARIZE_TIMEOUT.as_secs()),
+    }
+    let start = (0..=chunk.text.len().saturating_sub(TAIL_BYTES))
+        .rev()
+        .find(|&i| chunk.text.is_char_boundary(i))
+        .unwrap_or(0);
+    frame.with_reason("summarize_failed".to_string()).with_data(chunk.text[start..].to_string())
+}
+
+async fn run(command: &str, text: &str) -> anyhow::Result<String> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = text.as_bytes().to_vec();
+    // Written alongside reading, so a summarizer that answers as it reads
+    // cannot deadlock on a full pipe
+    let writer = tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+    let output = child.wait_with_output().await?;
+    let _ = writer.await;
+    if !output.status.success() {
+        anyhow::bail!("exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
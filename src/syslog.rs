@@ -0,0 +1,308 @@
+use crate::frame::{Frame, FrameType};
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const APP_NAME: &str = "spectertty";
+
+/// Longest syslog message; common daemons drop or split anything bigger.
+const MAX_SYSLOG_MESSAGE: usize = 8 * 1024;
+
+/// Longest journal message; a datagram must fit the socket buffer.
+const MAX_JOURNAL_MESSAGE: usize = 64 * 1024;
+
+/// Messages kept for a log daemon that is down, oldest evicted first.
+const MAX_RETAINED: usize = 1024;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Frames as RFC 5424 syslog messages on the local syslog socket. The
+/// message is the frame's JSON, the MSGID its type, and the session ID,
+/// type and seq are repeated as structured data.
+pub struct SyslogSink {
+    socket: Destination,
+    hostname: String,
+}
+
+impl SyslogSink {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_else(|_| "-".to_string());
+        Ok(Self {
+            socket: Destination::connect(path.as_ref(), "syslog")?,
+            hostname,
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        let hostname = &self.hostname;
+        self.socket
+            .send(syslog_message(hostname, frame)?, |notice| syslog_message(hostname, notice));
+        Ok(())
+    }
+}
+
+fn syslog_message(hostname: &str, frame: &Frame) -> Result<Vec<u8>> {
+    let timestamp = DateTime::<Utc>::from_timestamp_micros((frame.ts * 1e6) as i64)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Micros, true);
+    let kind = type_name(frame);
+
+    let mut params = format!(r#"type="{}""#, kind);
+    if let Some(ref id) = frame.session_id {
+        params.push_str(&format!(r#" session_id="{}""#, escape_param(id)));
+    }
+    if let Some(seq) = frame.seq {
+        params.push_str(&format!(r#" seq="{}""#, seq));
+    }
+
+    // user facility
+    let message = format!(
+        "<{}>1 {} {} {} {} {} [{}@32473 {}] {}",
+        8 + severity(frame),
+        timestamp,
+        hostname,
+        APP_NAME,
+        std::process::id(),
+        kind,
+        APP_NAME,
+        params,
+        truncate(&frame.to_json()?, MAX_SYSLOG_MESSAGE),
+    );
+    Ok(message.into_bytes())
+}
+
+/// Frames as journal entries through journald's native protocol, with the
+/// frame's JSON as MESSAGE and SPECTERTTY_SESSION_ID, SPECTERTTY_TYPE and
+/// SPECTERTTY_SEQ fields for filtering with `journalctl`.
+pub struct JournaldSink {
+    socket: Destination,
+}
+
+impl JournaldSink {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            socket: Destination::connect(path.as_ref(), "journald")?,
+        })
+    }
+
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        self.socket.send(journal_entry(frame)?, journal_entry);
+        Ok(())
+    }
+}
+
+fn journal_entry(frame: &Frame) -> Result<Vec<u8>> {
+    let json = frame.to_json()?;
+    let mut entry = Vec::new();
+    field(&mut entry, "MESSAGE", truncate(&json, MAX_JOURNAL_MESSAGE));
+    field(&mut entry, "PRIORITY", &severity(frame).to_string());
+    field(&mut entry, "SYSLOG_IDENTIFIER", APP_NAME);
+    field(&mut entry, "SPECTERTTY_TYPE", &type_name(frame));
+    if let Some(ref id) = frame.session_id {
+        field(&mut entry, "SPECTERTTY_SESSION_ID", id);
+    }
+    if let Some(seq) = frame.seq {
+        field(&mut entry, "SPECTERTTY_SEQ", &seq.to_string());
+    }
+    Ok(entry)
+}
+
+/// A log daemon's datagram socket.
+///
+/// The daemon may restart or fall behind, so failed deliveries do not fail
+/// the session. Messages are retained instead, up to `MAX_RETAINED`, and
+/// reconnecting is retried with exponential backoff as later frames
+/// arrive. Once the daemon is back a `reconnect` entry describes the gap,
+/// then the retained messages follow in order.
+struct Destination {
+    socket: UnixDatagram,
+    path: PathBuf,
+    name: &'static str,
+    retained: VecDeque<Vec<u8>>,
+    outage: Option<Outage>,
+}
+
+struct Outage {
+    attempts: u64,
+    backoff: Duration,
+    next_attempt: Instant,
+    /// Messages evicted from `retained`
+    dropped: u64,
+}
+
+impl Destination {
+    fn connect(path: &Path, name: &'static str) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(path)
+            .with_context(|| format!("Failed to connect to {} at {:?}", name, path))?;
+        Ok(Self {
+            socket,
+            path: path.to_path_buf(),
+            name,
+            retained: VecDeque::new(),
+            outage: None,
+        })
+    }
+
+    /// Deliver `message`, or retain it while the daemon is unreachable.
+    /// `format` renders the `reconnect` notice the same way as frames.
+    fn send(&mut self, message: Vec<u8>, format: impl Fn(&Frame) -> Result<Vec<u8>>) {
+        self.retain(message);
+        if let Some(ref outage) = self.outage {
+            if Instant::now() < outage.next_attempt || !self.reconnect() {
+                return;
+            }
+            let outage = self.outage.take().expect("outage checked above");
+            let stats = BTreeMap::from([
+                ("attempts".to_string(), outage.attempts),
+                ("retained".to_string(), self.retained.len() as u64),
+                ("dropped".to_string(), outage.dropped),
+            ]);
+            info!("Reconnected to {}, replaying {} messages", self.name, self.retained.len());
+            let notice = Frame::new(FrameType::Reconnect)
+                .with_reason(self.name.to_string())
+                .with_stats(stats);
+            if let Ok(notice) = format(&notice) {
+                self.retained.push_front(notice);
+            }
+        }
+
+        while let Some(message) = self.retained.front() {
+            match self.socket.send(message) {
+                Ok(_) => {
+                    self.retained.pop_front();
+                }
+                Err(e) if is_transient(&e) => {
+                    self.fail(&e);
+                    return;
+                }
+                // A message the daemon will never take must not block the rest
+                Err(e) => {
+                    warn!("Failed to send frame to {}: {}", self.name, e);
+                    self.retained.pop_front();
+                }
+            }
+        }
+    }
+
+    fn retain(&mut self, message: Vec<u8>) {
+        if self.retained.len() == MAX_RETAINED {
+            self.retained.pop_front();
+            if let Some(ref mut outage) = self.outage {
+                outage.dropped += 1;
+            }
+        }
+        self.retained.push_back(message);
+    }
+
+    /// Open a fresh socket, as a restarted daemon has a new one at `path`.
+    fn reconnect(&mut self) -> bool {
+        let result = UnixDatagram::unbound().and_then(|socket| socket.connect(&self.path).map(|_| socket));
+        match result {
+            Ok(socket) => {
+                self.socket = socket;
+                true
+            }
+            Err(e) => {
+                self.fail(&e);
+                false
+            }
+        }
+    }
+
+    fn fail(&mut self, error: &io::Error) {
+        match self.outage {
+            Some(ref mut outage) => {
+                outage.attempts += 1;
+                outage.backoff = (outage.backoff * 2).min(MAX_BACKOFF);
+                outage.next_attempt = Instant::now() + outage.backoff;
+            }
+            None => {
+                warn!("Failed to send frame to {}, retrying: {}", self.name, error);
+                self.outage = Some(Outage {
+                    attempts: 0,
+                    backoff: INITIAL_BACKOFF,
+                    next_attempt: Instant::now() + INITIAL_BACKOFF,
+                    dropped: 0,
+                });
+            }
+        }
+    }
+}
+
+/// Errors a restarted or less busy daemon gets over.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotFound
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::WouldBlock
+    ) || error.raw_os_error() == Some(nix::errno::Errno::ENOBUFS as i32)
+}
+
+/// Syslog severity: errors and sandbox kills are `err`, session lifecycle
+/// and approvals `notice`, everything else `info`.
+fn severity(frame: &Frame) -> u8 {
+    match frame.frame_type {
+        FrameType::Error | FrameType::WriteError | FrameType::CapsuleKill => 3,
+        FrameType::Exit
+        | FrameType::Expired
+        | FrameType::Anomaly
+        | FrameType::ApprovalRequired
+        | FrameType::ApprovalGranted
+        | FrameType::ApprovalDenied => 5,
+        _ => 6,
+    }
+}
+
+fn type_name(frame: &Frame) -> String {
+    serde_json::to_value(&frame.frame_type)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Append a native protocol field, using the length-prefixed form for
+/// values spanning lines.
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+fn escape_param(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
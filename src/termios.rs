@@ -0,0 +1,98 @@
+use crate::frame::{Frame, FrameType};
+use nix::sys::termios::{self, InputFlags, LocalFlags, OutputFlags, SetArg, Termios};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::os::fd::BorrowedFd;
+
+/// Changes to the line discipline of the command's terminal, as `stty`
+/// makes them; flags left out stay as they are. `raw` goes first, so
+/// `{"raw": true, "isig": true}` is raw mode with Ctrl-C still a signal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct TermiosChange {
+    /// Raw mode (`stty raw -echo`) on, or back to cooked (`stty sane`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+    /// Echo typed input back to the terminal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    /// Line editing: input reaches the command a line at a time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icanon: Option<bool>,
+    /// Ctrl-C, Ctrl-\ and Ctrl-Z send signals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isig: Option<bool>,
+    /// Carriage returns in input become newlines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icrnl: Option<bool>,
+    /// Newlines in output become CR LF
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub onlcr: Option<bool>,
+}
+
+impl TermiosChange {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the change to the terminal behind `fd`, a PTY master or
+    /// slave, and return the settings it leaves.
+    pub fn apply(&self, fd: BorrowedFd) -> nix::Result<Termios> {
+        let mut settings = termios::tcgetattr(fd)?;
+        if self.is_empty() {
+            return Ok(settings);
+        }
+        match self.raw {
+            Some(true) => termios::cfmakeraw(&mut settings),
+            Some(false) => {
+                settings.input_flags |= InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::IXON;
+                settings.output_flags |= OutputFlags::OPOST | OutputFlags::ONLCR;
+                settings.local_flags |= LocalFlags::ICANON
+                    | LocalFlags::ISIG
+                    | LocalFlags::IEXTEN
+                    | LocalFlags::ECHO
+                    | LocalFlags::ECHOE
+                    | LocalFlags::ECHOK;
+            }
+            None => {}
+        }
+        let flags = [
+            (self.echo, LocalFlags::ECHO),
+            (self.icanon, LocalFlags::ICANON),
+            (self.isig, LocalFlags::ISIG),
+        ];
+        for (on, flag) in flags {
+            if let Some(on) = on {
+                settings.local_flags.set(flag, on);
+            }
+        }
+        if let Some(on) = self.icrnl {
+            settings.input_flags.set(InputFlags::ICRNL, on);
+        }
+        if let Some(on) = self.onlcr {
+            // Translation needs output processing on
+            settings.output_flags.set(OutputFlags::ONLCR, on);
+            if on {
+                settings.output_flags.insert(OutputFlags::OPOST);
+            }
+        }
+        termios::tcsetattr(fd, SetArg::TCSANOW, &settings)?;
+        Ok(settings)
+    }
+}
+
+/// A `termios` frame describing `settings`; `raw` is true when neither
+/// line editing, echo nor signals are on.
+pub fn termios_frame(settings: &Termios) -> Frame {
+    let local = settings.local_flags;
+    let state = json!({
+        "raw": !local.intersects(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG),
+        "echo": local.contains(LocalFlags::ECHO),
+        "icanon": local.contains(LocalFlags::ICANON),
+        "isig": local.contains(LocalFlags::ISIG),
+        "icrnl": settings.input_flags.contains(InputFlags::ICRNL),
+        "onlcr": settings.output_flags.contains(OutputFlags::OPOST | OutputFlags::ONLCR),
+    });
+    Frame::new(FrameType::Termios).with_structured(state)
+}
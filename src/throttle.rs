@@ -0,0 +1,190 @@
+use crate::frame::{Frame, FrameType};
+use crate::tokens::Tokenizer;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::info;
+
+/// How often suppressed output is summarized.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes of suppressed output a `summary` frame carries as its sample.
+pub const TAIL_BYTES: usize = 512;
+
+/// Keeps a runaway command from flooding the consumer: output frames past
+/// `--max-frames-per-sec` in any second, past the first `--sample-after`
+/// of the session, or past its `--token-budget`, are dropped and
+/// summarized instead. An `overflow` or `budget_exceeded` frame says when
+/// output starts being held back, and a `summary` frame every
+/// `SUMMARY_INTERVAL` counts what was dropped and shows how it ended.
+pub struct OutputLimiter {
+    max_per_sec: Option<u32>,
+    sample_after: Option<u64>,
+    /// `--token-budget` for the session's output, and how it is counted
+    budget: Option<(u64, Tokenizer)>,
+    /// Tokens of output passed so far
+    spent: u64,
+    interval: Interval,
+    /// The current one-second window and output frames passed in it
+    window_start: Instant,
+    in_window: u32,
+    /// Output frames passed in the whole session
+    passed: u64,
+    /// Why output is being dropped, while it is
+    suppressing: Option<&'static str>,
+    /// Dropped since the last summary
+    frames: u64,
+    bytes: u64,
+    tail: VecDeque<u8>,
+    /// Dropped in the whole session
+    total_frames: u64,
+    total_bytes: u64,
+}
+
+impl OutputLimiter {
+    pub fn new(max_per_sec: Option<u32>, sample_after: Option<u64>) -> Self {
+        let mut interval = tokio::time::interval_at(Instant::now() + SUMMARY_INTERVAL, SUMMARY_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            max_per_sec,
+            sample_after,
+            budget: None,
+            spent: 0,
+            interval,
+            window_start: Instant::now(),
+            in_window: 0,
+            passed: 0,
+            suppressing: None,
+            frames: 0,
+            bytes: 0,
+            tail: VecDeque::new(),
+            total_frames: 0,
+            total_bytes: 0,
+        }
+    }
+
+    /// Summarize the output once `budget` tokens of it have been passed.
+    pub fn token_budget(mut self, budget: u64, tokenizer: Tokenizer) -> Self {
+        self.budget = Some((budget, tokenizer));
+        self
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_per_sec.is_some() || self.sample_after.is_some() || self.budget.is_some()
+    }
+
+    /// Pass a frame on its way to the sinks, returning what to send now.
+    pub fn filter(&mut self, frame: Frame) -> Vec<Frame> {
+        if !self.enabled() || !matches!(frame.frame_type, FrameType::Stdout | FrameType::Stderr) {
+            return vec![frame];
+        }
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.in_window = 0;
+        }
+
+        let cost = self.budget.map_or(0, |(_, tokenizer)| tokenizer.frame_tokens(&frame).unwrap_or_default());
+        // Once over the budget, the rest of the session is summarized
+        let over_budget = self.budget.is_some_and(|(budget, _)| self.spent + cost > budget);
+        let reason = if over_budget || self.suppressing == Some("token_budget") {
+            Some("token_budget")
+        } else if self.sample_after.is_some_and(|limit| self.passed >= limit) {
+            Some("sample_after")
+        } else if self.max_per_sec.is_some_and(|limit| self.in_window >= limit) {
+            Some("rate_limit")
+        } else {
+            None
+        };
+        let Some(reason) = reason else {
+            // The rate is back under the limit: what was dropped is
+            // summarized before output resumes
+            let mut frames: Vec<Frame> = self.take_summary().into_iter().collect();
+            self.suppressing = None;
+            self.passed += 1;
+            self.in_window += 1;
+            self.spent += cost;
+            frames.push(frame);
+            return frames;
+        };
+
+        let mut frames = Vec::new();
+        if self.suppressing != Some(reason) {
+            info!("Output over --{}, summarizing", reason.replace('_', "-"));
+            frames.extend(self.take_summary());
+            self.suppressing = Some(reason);
+            frames.push(self.announce(reason, cost));
+        }
+        let data = frame.bytes().unwrap_or_default();
+        self.frames += 1;
+        self.bytes += data.len() as u64;
+        self.total_frames += 1;
+        self.total_bytes += data.len() as u64;
+        self.tail.extend(data.iter());
+        let excess = self.tail.len().saturating_sub(TAIL_BYTES);
+        self.tail.drain(..excess);
+        frames
+    }
+
+    /// The next periodic summary of dropped output.
+    pub async fn summary(&mut self) -> Frame {
+        if !self.enabled() {
+            return std::future::pending().await;
+        }
+        loop {
+            self.interval.tick().await;
+            if let Some(frame) = self.take_summary() {
+                return frame;
+            }
+        }
+    }
+
+    /// Whatever was dropped since the last summary, at the end of the session.
+    pub fn flush(&mut self) -> Option<Frame> {
+        self.take_summary()
+    }
+
+    fn take_summary(&mut self) -> Option<Frame> {
+        if self.frames == 0 {
+            return None;
+        }
+        let mut tail: Vec<u8> = std::mem::take(&mut self.tail).into();
+        // The sample starts on a character, not partway into one
+        let start = tail.iter().position(|byte| byte & 0xc0 != 0x80).unwrap_or(tail.len());
+        tail.drain(..start);
+        let stats = BTreeMap::from([
+            ("frames".to_string(), std::mem::take(&mut self.frames)),
+            ("bytes".to_string(), std::mem::take(&mut self.bytes)),
+            ("total_frames".to_string(), self.total_frames),
+            ("total_bytes".to_string(), self.total_bytes),
+        ]);
+        Some(
+            Frame::new(FrameType::Summary)
+                .with_reason(self.suppressing.unwrap_or_default().to_string())
+                .with_data(String::from_utf8_lossy(&tail).into_owned())
+                .with_stats(stats),
+        )
+    }
+
+    /// What marks the start of dropping for `reason`, on a frame costing
+    /// `cost` tokens.
+    fn announce(&self, reason: &str, cost: u64) -> Frame {
+        if let (Some((budget, _)), "token_budget") = (self.budget, reason) {
+            let stats = BTreeMap::from([
+                ("budget".to_string(), budget),
+                ("tokens".to_string(), self.spent),
+                ("next_tokens".to_string(), cost),
+            ]);
+            return Frame::new(FrameType::BudgetExceeded).with_reason(reason.to_string()).with_stats(stats);
+        }
+        let mut stats = BTreeMap::new();
+        if let Some(limit) = self.max_per_sec {
+            stats.insert("max_frames_per_sec".to_string(), limit as u64);
+        }
+        if let Some(limit) = self.sample_after {
+            stats.insert("sample_after".to_string(), limit);
+        }
+        stats.insert("passed_frames".to_string(), self.passed);
+        Frame::new(FrameType::Overflow).with_reason(reason.to_string()).with_stats(stats)
+    }
+}
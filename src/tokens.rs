@@ -0,0 +1,34 @@
+use crate::frame::Frame;
+use clap::ValueEnum;
+
+/// How `--tokenizer` estimates the tokens a language model would spend on
+/// text. Real tokenizers differ by model; these heuristics are cheap and
+/// close enough to budget a context window by.
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum)]
+pub enum Tokenizer {
+    /// One token per 4 characters, the usual average for English text
+    #[default]
+    Chars,
+    /// 4 tokens per 3 words
+    Words,
+    /// One token per 3 bytes, erring high for code, logs and non-Latin
+    /// scripts
+    Bytes,
+}
+
+impl Tokenizer {
+    /// Estimated tokens in `text`; anything non-empty is at least one.
+    pub fn estimate(self, text: &str) -> u64 {
+        match self {
+            Tokenizer::Chars => (text.chars().count() as u64).div_ceil(4),
+            Tokenizer::Words => (text.split_whitespace().count() as u64 * 4).div_ceil(3),
+            Tokenizer::Bytes => (text.len() as u64).div_ceil(3),
+        }
+        .max(u64::from(!text.is_empty()))
+    }
+
+    /// Estimated tokens in a frame's `data`, as it is delivered.
+    pub fn frame_tokens(self, frame: &Frame) -> Option<u64> {
+        frame.data.as_deref().map(|data| self.estimate(data))
+    }
+}
@@ -0,0 +1,148 @@
+use crate::cli::{TokenMode, TranscriptArgs};
+use crate::convert::{self, DEFAULT_SIZE};
+use crate::frame::{Frame, FrameType};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, Write};
+
+/// Turn a recording into a plain-text transcript for reading, or for an
+/// LLM to summarise: its output through the token processor, what was
+/// typed, signals and the exit, with the time into the session.
+pub async fn run(args: &TranscriptArgs) -> Result<()> {
+    if args.token_mode == TokenMode::Raw {
+        bail!("A transcript needs --token-mode compact or parsed");
+    }
+    let (frames, recorded_size) = convert::load(&args.input, args.from)?;
+    let (cols, rows) = recorded_size.unwrap_or(DEFAULT_SIZE);
+    let size = (args.cols.unwrap_or(cols), args.rows.unwrap_or(rows));
+    let mut processed = convert::process(frames, args.token_mode, size).await?;
+    convert::clean(&mut processed, &args.redact, &args.pii)?;
+
+    let text = render(&processed);
+    match args.output {
+        Some(ref path) => fs::write(path, text).with_context(|| format!("Failed to write {:?}", path))?,
+        None => io::stdout().lock().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Render processed frames as transcript lines. A line is stamped with
+/// the time since the first frame whenever the second differs from the
+/// last stamp; stderr lines start with `! `, input with `> ` and lines
+/// of a full-screen program's screen with `| `. Of a line being edited
+/// and of a full-screen program only the state they were left in is
+/// kept, and blank lines are dropped.
+pub fn render(frames: &[Frame]) -> String {
+    let mut transcript = Transcript {
+        start: frames.first().map_or(0.0, |frame| frame.ts),
+        stamp: None,
+        text: String::new(),
+        editing: None,
+        screen: None,
+    };
+    for frame in frames {
+        transcript.frame(frame);
+    }
+    transcript.flush();
+    transcript.text
+}
+
+struct Transcript<'a> {
+    start: f64,
+    /// Second of the last stamped line
+    stamp: Option<u64>,
+    text: String,
+    /// Latest line update, until output commits the line
+    editing: Option<&'a Frame>,
+    /// Latest full-screen snapshot
+    screen: Option<&'a Frame>,
+}
+
+impl<'a> Transcript<'a> {
+    fn frame(&mut self, frame: &'a Frame) {
+        match frame.frame_type {
+            FrameType::LineUpdate => self.editing = Some(frame),
+            FrameType::Snapshot => self.screen = Some(frame),
+            FrameType::Stdout | FrameType::Stderr => {
+                let text = frame.text().unwrap_or_default();
+                // Output that finishes the edited line replaces it; a
+                // progress line it does not finish stays
+                let edited = self.editing.and_then(|editing| editing.data.as_deref()).unwrap_or_default();
+                if !edited.trim().is_empty() && text.trim_start().starts_with(edited.trim()) {
+                    self.editing = None;
+                }
+                self.flush();
+                let marker = if frame.frame_type == FrameType::Stderr { "! " } else { "" };
+                self.lines(frame, marker, &text);
+            }
+            FrameType::Stdin => {
+                self.flush();
+                let typed = caret_notation(frame.text().as_deref().unwrap_or_default().trim_end_matches(['\r', '\n']));
+                self.line(frame, &format!("> {}", typed));
+            }
+            FrameType::Signal => {
+                self.flush();
+                self.line(frame, &format!("signal {}", frame.signal.as_deref().unwrap_or("?")));
+            }
+            FrameType::Exit => {
+                self.flush();
+                match frame.code {
+                    Some(code) => self.line(frame, &format!("exit {}", code)),
+                    None => self.line(frame, "exit"),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Write out the screen and edited line as they were last seen.
+    fn flush(&mut self) {
+        if let Some(screen) = self.screen.take() {
+            self.lines(screen, "| ", screen.data.as_deref().unwrap_or_default());
+        }
+        if let Some(editing) = self.editing.take() {
+            self.lines(editing, "", editing.data.as_deref().unwrap_or_default());
+        }
+    }
+
+    fn lines(&mut self, frame: &Frame, marker: &str, text: &str) {
+        for line in text.lines() {
+            let line = line.trim_end();
+            if !line.trim_start().is_empty() {
+                self.line(frame, &format!("{}{}", marker, line));
+            }
+        }
+    }
+
+    fn line(&mut self, frame: &Frame, line: &str) {
+        let second = (frame.ts - self.start).max(0.0) as u64;
+        if self.stamp != Some(second) {
+            self.stamp = Some(second);
+            self.text.push_str(&format_stamp(second));
+            self.text.push(' ');
+        }
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+}
+
+/// `[MM:SS]`, or `[H:MM:SS]` past the first hour.
+fn format_stamp(second: u64) -> String {
+    let (hours, minutes, seconds) = (second / 3600, second / 60 % 60, second % 60);
+    if hours > 0 {
+        format!("[{}:{:02}:{:02}]", hours, minutes, seconds)
+    } else {
+        format!("[{:02}:{:02}]", minutes, seconds)
+    }
+}
+
+/// Control characters as `^C`, `^[` and so on, so typed keys stay visible.
+fn caret_notation(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\0'..='\x1f' => format!("^{}", (c as u8 + 0x40) as char),
+            '\x7f' => "^?".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,268 @@
+use crate::cli::{CpArgs, FrameFormat};
+use crate::expect::EscapeStripper;
+use crate::frame::{Frame, FrameType};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use regex::Regex;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::time::Instant;
+
+/// Base64 sent per `input` command on upload.
+const UPLOAD_CHUNK_BYTES: usize = 4096;
+
+/// Received bytes between progress frames on download.
+const PROGRESS_BYTES: u64 = 64 * 1024;
+
+/// Printed around the transfer; `typed` splits them so the echo of the
+/// command line never matches.
+const BEGIN_MARKER: &str = "SPECTERTTY_BEGIN";
+const END_MARKER: &str = "SPECTERTTY_CP:";
+
+/// One side of a copy: a local file or a path in the session behind a
+/// control socket, written `SOCKET:PATH`.
+enum Endpoint {
+    Local(PathBuf),
+    Remote { socket: PathBuf, path: String },
+}
+
+impl Endpoint {
+    fn parse(spec: &str) -> Self {
+        match spec.split_once(':') {
+            Some((socket, path)) if !path.is_empty() && Path::new(socket).exists() => Endpoint::Remote {
+                socket: PathBuf::from(socket),
+                path: path.to_string(),
+            },
+            _ => Endpoint::Local(PathBuf::from(spec)),
+        }
+    }
+}
+
+/// Copy a file into or out of a running session by typing shell commands
+/// into it over its `--socket`: uploads are decoded from a base64 heredoc
+/// and downloads are read back from `base64` output. Progress is reported
+/// on stdout as `transfer` frames.
+pub async fn run(args: &CpArgs) -> Result<()> {
+    match (Endpoint::parse(&args.source), Endpoint::parse(&args.dest)) {
+        (Endpoint::Local(local), Endpoint::Remote { socket, path }) => {
+            let data = std::fs::read(&local).with_context(|| format!("Failed to read {:?}", local))?;
+            let mut conn = Connection::open(&socket, args).await?;
+            tokio::time::timeout(args.timeout, conn.upload(&data, &path))
+                .await
+                .map_err(|_| anyhow!("Upload did not finish within {:?}", args.timeout))?
+        }
+        (Endpoint::Remote { socket, path }, Endpoint::Local(local)) => {
+            let mut conn = Connection::open(&socket, args).await?;
+            let data = tokio::time::timeout(args.timeout, conn.download(&path))
+                .await
+                .map_err(|_| anyhow!("Download did not finish within {:?}", args.timeout))??;
+            std::fs::write(&local, data).with_context(|| format!("Failed to write {:?}", local))
+        }
+        (Endpoint::Local(_), Endpoint::Local(_)) => {
+            bail!("One side of the copy must be SOCKET:PATH, where SOCKET is a session's --socket")
+        }
+        (Endpoint::Remote { .. }, Endpoint::Remote { .. }) => bail!("Cannot copy between two sessions"),
+    }
+}
+
+/// A controller connection to a session's socket.
+struct Connection {
+    writer: OwnedWriteHalf,
+    reader: OwnedReadHalf,
+    format: FrameFormat,
+    /// Received bytes not yet decoded into a frame
+    pending: Vec<u8>,
+    /// Session output seen since connecting, escapes removed
+    output: String,
+    escapes: EscapeStripper,
+    started: Instant,
+}
+
+impl Connection {
+    async fn open(socket: &Path, args: &CpArgs) -> Result<Self> {
+        let stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("Failed to connect to {:?}", socket))?;
+        let (reader, writer) = stream.into_split();
+        let mut conn = Self {
+            writer,
+            reader,
+            format: args.format,
+            pending: Vec::new(),
+            output: String::new(),
+            escapes: EscapeStripper::default(),
+            started: Instant::now(),
+        };
+        if let (Some(client), Some(token)) = (&args.client, &args.token) {
+            conn.send(json!({"type": "auth", "client": client, "token": token})).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn send(&mut self, command: serde_json::Value) -> Result<()> {
+        let mut line = command.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn input(&mut self, data: String) -> Result<()> {
+        self.send(json!({"type": "input", "data": data})).await
+    }
+
+    async fn next_frame(&mut self) -> Result<Frame> {
+        loop {
+            if let Some((frame, used)) = Frame::decode(self.format, &self.pending)
+                .context("Unexpected frame from the session; does --format match its --format?")?
+            {
+                self.pending.drain(..used);
+                // Keepalives are answered here so long copies are not cut off
+                if frame.frame_type == FrameType::Ping {
+                    self.send(json!({"type": "pong", "id": frame.id})).await?;
+                    continue;
+                }
+                return Ok(frame);
+            }
+            if self.reader.read_buf(&mut self.pending).await? == 0 {
+                bail!("Session closed the connection");
+            }
+        }
+    }
+
+    /// Read frames until the session prints the end marker and return the
+    /// exit status of the copy command, reporting download progress.
+    async fn wait_for_end(&mut self, downloading: bool) -> Result<i32> {
+        let end = Regex::new(&format!("{}(\\d+)", END_MARKER))?;
+        let mut reported = 0;
+        loop {
+            let frame = self.next_frame().await?;
+            match frame.frame_type {
+                FrameType::Stdout => {
+                    let text = self.escapes.strip(&frame.text().unwrap_or_default());
+                    // Only rescan what the new text could complete
+                    let mut from = self.output.len().saturating_sub(END_MARKER.len() + 16);
+                    while !self.output.is_char_boundary(from) {
+                        from -= 1;
+                    }
+                    self.output.push_str(&text);
+                    if let Some(status) = end.captures(&self.output[from..]) {
+                        return Ok(status[1].parse()?);
+                    }
+
+                    if downloading {
+                        let received = self.output.find(BEGIN_MARKER).map_or(0, |start| {
+                            (self.output.len() - start - BEGIN_MARKER.len()) as u64 * 3 / 4
+                        });
+                        if received >= reported + PROGRESS_BYTES {
+                            reported = received;
+                            self.progress("progress", received, None)?;
+                        }
+                    }
+                }
+                FrameType::Error if frame.client.is_some() => {
+                    bail!("Session refused the copy: {}", frame.data.unwrap_or_default())
+                }
+                FrameType::ApprovalRequired => {
+                    eprintln!("Copy is waiting for two-person approval ({})", frame.id.unwrap_or_default());
+                }
+                FrameType::Exit => bail!("Session ended during the copy"),
+                _ => {}
+            }
+        }
+    }
+
+    async fn upload(&mut self, data: &[u8], path: &str) -> Result<()> {
+        let total = data.len() as u64;
+        let encoded = BASE64_STANDARD.encode(data);
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(76)
+            .map(|line| std::str::from_utf8(line).expect("base64 is ASCII"))
+            .collect();
+
+        self.input(format!("base64 -d > {} <<'SPECTERTTY_EOF'\n", shell_quote(path))).await?;
+        let mut chunk = String::new();
+        let mut sent = 0;
+        for line in lines {
+            chunk.push_str(line);
+            chunk.push('\n');
+            sent += line.len();
+            if chunk.len() >= UPLOAD_CHUNK_BYTES {
+                self.input(std::mem::take(&mut chunk)).await?;
+                self.progress("progress", (sent as u64 * 3 / 4).min(total), Some(total))?;
+            }
+        }
+        chunk.push_str("SPECTERTTY_EOF\n");
+        chunk.push_str(&format!("echo {}$?\n", typed(END_MARKER)));
+        self.input(chunk).await?;
+
+        let status = self.wait_for_end(false).await?;
+        if status != 0 {
+            bail!("Writing {} in the session failed with status {}", path, status);
+        }
+        self.progress("done", total, Some(total))
+    }
+
+    async fn download(&mut self, path: &str) -> Result<Vec<u8>> {
+        self.input(format!(
+            "echo {}; base64 < {}; echo {}$?\n",
+            typed(BEGIN_MARKER),
+            shell_quote(path),
+            typed(END_MARKER),
+        ))
+        .await?;
+
+        let status = self.wait_for_end(true).await?;
+        if status != 0 {
+            bail!("Reading {} in the session failed with status {}", path, status);
+        }
+
+        let start = self
+            .output
+            .find(BEGIN_MARKER)
+            .ok_or_else(|| anyhow!("Session output did not include the start of the file"))?
+            + BEGIN_MARKER.len();
+        let end = self.output[start..]
+            .find(END_MARKER)
+            .map_or(self.output.len(), |end| start + end);
+        let encoded: String = self.output[start..end]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let data = BASE64_STANDARD
+            .decode(encoded)
+            .context("Session output was not valid base64; is the session in raw or compact mode?")?;
+        self.progress("done", data.len() as u64, Some(data.len() as u64))?;
+        Ok(data)
+    }
+
+    fn progress(&self, reason: &str, bytes: u64, total: Option<u64>) -> Result<()> {
+        let mut stats = BTreeMap::from([("bytes".to_string(), bytes)]);
+        if let Some(total) = total {
+            stats.insert("total".to_string(), total);
+        }
+        let frame = Frame::new(FrameType::Transfer)
+            .with_reason(reason.to_string())
+            .with_stats(stats)
+            .with_duration(self.started.elapsed().as_millis() as u64);
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", frame.to_json()?)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// `marker` as shell words that print it but do not contain it.
+fn typed(marker: &str) -> String {
+    let (head, tail) = marker.split_at(marker.find('_').map_or(1, |i| i + 1));
+    format!("\"{}\"\"{}\"", head, tail)
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
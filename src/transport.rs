@@ -0,0 +1,676 @@
+use crate::chaos::{Chaos, ChaosSpec, Fault};
+use crate::cli::FrameFormat;
+use crate::control::{ClientId, ControlCommand, ControlEvent, ControlMessage};
+use crate::error::SessionError;
+use crate::frame::{Frame, FrameType};
+use crate::sampling::Sampler;
+use anyhow::Result;
+use anyhow::Context;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::BufRead;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, warn};
+
+/// Connection id reserved for the stdin control channel.
+pub const STDIN_CONN: u64 = 0;
+
+/// Connection ids handed out so far, shared by every transport so that
+/// clients stay distinguishable across them.
+static NEXT_CONN: AtomicU64 = AtomicU64::new(STDIN_CONN + 1);
+
+/// A connection id no other client has.
+pub fn next_conn_id() -> u64 {
+    NEXT_CONN.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Recent frames kept for clients that reconnect with `resume_from`.
+const RESUME_HISTORY_FRAMES: usize = 4096;
+
+/// Longest control command line a socket client may send, with room for
+/// a register or paste of several megabytes escaped as JSON. A longer
+/// line closes the connection.
+pub const MAX_CONTROL_LINE_BYTES: usize = 16 * 1024 * 1024;
+
+pub type AuthTokens = Arc<HashMap<String, String>>;
+
+type History = Arc<Mutex<VecDeque<Frame>>>;
+
+/// Requests a socket client makes about its own copy of the stream.
+#[derive(Debug)]
+pub enum StreamRequest {
+    /// Replay buffered frames after this seq
+    Replay(u64),
+    /// Thin the stream from now on, or send all of it again
+    Sample(Option<Sampler>),
+    /// Answer with a `pong` frame carrying this id
+    Ping(Option<String>),
+    /// The client sent something, so it is still there
+    Heard,
+    /// The client authenticated, so it may now see the stream
+    Admit,
+    /// Tell only this client it was refused
+    Refuse(SessionError),
+    /// Tell only this client why, then close the connection
+    Close(SessionError),
+}
+
+/// Server pings on idle socket connections, so peers that vanished
+/// without closing the connection are noticed and let go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keepalive {
+    /// Time between `ping` frames
+    pub interval: Duration,
+    /// Silence from the client after which its connection is closed
+    pub timeout: Duration,
+}
+
+/// How a socket treats its clients beyond the plain frame stream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+    /// Faults to inject, and the seed they are drawn from
+    pub chaos: Option<(ChaosSpec, u64)>,
+    pub keepalive: Option<Keepalive>,
+}
+
+/// Whether `token` is `name`'s token, compared in constant time so that
+/// response times do not give away how much of a guess was right.
+pub fn token_matches(tokens: &AuthTokens, name: &str, token: &str) -> bool {
+    tokens
+        .get(name)
+        .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes())))
+}
+
+/// Refuse to serve `what` on an address other machines can reach unless
+/// auth tokens are configured, since any peer could otherwise drive it.
+pub fn require_auth_off_loopback(addr: SocketAddr, tokens: &AuthTokens, what: &str) -> Result<()> {
+    if tokens.is_empty() && !addr.ip().is_loopback() {
+        return Err(anyhow::anyhow!(
+            "Refusing to serve {} on {} without --auth-token; bind to 127.0.0.1 or configure a token",
+            what,
+            addr
+        ));
+    }
+    Ok(())
+}
+
+pub fn parse_auth_tokens(specs: &[String]) -> Result<AuthTokens> {
+    let mut tokens = HashMap::new();
+    for spec in specs {
+        let (name, token) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid auth token '{}', expected NAME=TOKEN", spec))?;
+        if name.is_empty() || token.is_empty() {
+            return Err(anyhow::anyhow!("Invalid auth token '{}', expected NAME=TOKEN", spec));
+        }
+        tokens.insert(name.to_string(), token.to_string());
+    }
+    Ok(Arc::new(tokens))
+}
+
+/// Where a socket server takes its connections from.
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+type ReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+type WriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+impl Listener {
+    /// The next client's connection, split into its two directions.
+    async fn accept(&self) -> std::io::Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                debug!("Socket client connected from {}", peer);
+                stream.set_nodelay(true)?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+}
+
+/// Socket transport on a Unix socket or, with `--bind`, over TCP: every
+/// connected client receives the frame stream in the configured format and
+/// may send control commands back on the same connection as NDJSON.
+pub struct SocketServer {
+    /// The Unix socket file, removed when the server goes
+    path: Option<PathBuf>,
+    addr: Option<SocketAddr>,
+    frame_tx: broadcast::Sender<Frame>,
+    history: History,
+    accept_task: tokio::task::JoinHandle<()>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SocketServer {
+    pub fn bind<P: AsRef<Path>>(
+        path: P,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        format: FrameFormat,
+    ) -> Result<Self> {
+        Self::bind_with(path, tokens, control_tx, format, SocketOptions::default())
+    }
+
+    /// A socket for testing clients, whose frames are dropped, duplicated,
+    /// delayed or cut off as `chaos` describes. Connections draw their
+    /// faults from `seed` in the order they are accepted.
+    pub fn bind_with_chaos<P: AsRef<Path>>(
+        path: P,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        format: FrameFormat,
+        chaos: ChaosSpec,
+        seed: u64,
+    ) -> Result<Self> {
+        let options = SocketOptions {
+            chaos: Some((chaos, seed)),
+            keepalive: None,
+        };
+        Self::bind_with(path, tokens, control_tx, format, options)
+    }
+
+    pub fn bind_with<P: AsRef<Path>>(
+        path: P,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        format: FrameFormat,
+        options: SocketOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        info!("Listening for controllers on {:?}", path);
+        let mut server = Self::serve(Listener::Unix(listener), tokens, control_tx, format, options);
+        server.path = Some(path);
+        Ok(server)
+    }
+
+    /// The same protocol over TCP on `addr`. Addresses off the loopback
+    /// interface are refused unless auth tokens are configured.
+    pub fn bind_tcp(
+        addr: SocketAddr,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        format: FrameFormat,
+        options: SocketOptions,
+    ) -> Result<Self> {
+        require_auth_off_loopback(addr, &tokens, "the socket protocol")?;
+        let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+        info!("Listening for controllers on tcp://{}", addr);
+        let mut server = Self::serve(Listener::Tcp(listener), tokens, control_tx, format, options);
+        server.addr = Some(addr);
+        Ok(server)
+    }
+
+    fn serve(
+        listener: Listener,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        format: FrameFormat,
+        options: SocketOptions,
+    ) -> Self {
+        let SocketOptions { chaos, keepalive } = options;
+        let (frame_tx, _) = broadcast::channel::<Frame>(1024);
+
+        let dropped = Arc::new(AtomicU64::new(0));
+        let history = History::default();
+
+        let accept_frames = frame_tx.clone();
+        let accept_history = history.clone();
+        let accept_dropped = dropped.clone();
+        let accept_task = tokio::spawn(async move {
+            let mut seeds = fastrand::Rng::with_seed(chaos.map_or(0, |(_, seed)| seed));
+            loop {
+                let (reader, writer) = match listener.accept().await {
+                    Ok(halves) => halves,
+                    Err(e) => {
+                        warn!("Failed to accept socket client: {}", e);
+                        continue;
+                    }
+                };
+
+                let conn = next_conn_id();
+                debug!("Socket client conn-{} connected", conn);
+
+                let (stream_tx, stream_rx) = mpsc::unbounded_channel();
+                let writer_task = tokio::spawn(write_frames(
+                    conn,
+                    writer,
+                    format,
+                    tokens.is_empty(),
+                    accept_frames.subscribe(),
+                    stream_rx,
+                    accept_history.clone(),
+                    accept_dropped.clone(),
+                    chaos.map(|(spec, _)| Chaos::new(spec, seeds.u64(..))),
+                    keepalive,
+                ));
+
+                let mut control = ControlReader::new(conn, tokens.clone(), control_tx.clone());
+                control.stream_tx = Some(stream_tx);
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = read_control_lines(reader, control) => {}
+                        // A connection the writer gave up on is closed
+                        // both ways, so a silent peer cannot hold it open
+                        _ = writer_task => {}
+                    }
+                    debug!("Socket client conn-{} disconnected", conn);
+                });
+            }
+        });
+
+        Self {
+            path: None,
+            addr: None,
+            frame_tx,
+            history,
+            accept_task,
+            dropped,
+        }
+    }
+
+    /// The address TCP clients connect to, with the port picked if it was 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    pub fn broadcast(&self, frame: &Frame) {
+        {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == RESUME_HISTORY_FRAMES {
+                history.pop_front();
+            }
+            history.push_back(frame.clone());
+        }
+        // No subscribers is not an error; frames are simply not observed.
+        let _ = self.frame_tx.send(frame.clone());
+    }
+
+    /// Frames lost to clients that fell too far behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Stream frames to one socket client: live frames as they are broadcast,
+/// thinned if it subscribed to samples, and on `resume_from` the buffered
+/// frames it missed. Replayed frames can arrive after newer live ones, so
+/// clients order by `seq`. On a chaos socket every write risks a fault.
+/// With a keepalive, a client that stays silent too long is dropped.
+/// Until `admitted`, which a client without auth tokens configured starts
+/// out as, it is sent nothing but pings and its own rejections.
+#[allow(clippy::too_many_arguments)]
+async fn write_frames<W: AsyncWrite + Unpin>(
+    conn: u64,
+    mut writer: W,
+    format: FrameFormat,
+    mut admitted: bool,
+    mut frame_rx: broadcast::Receiver<Frame>,
+    mut stream_rx: mpsc::UnboundedReceiver<StreamRequest>,
+    history: History,
+    dropped: Arc<AtomicU64>,
+    mut chaos: Option<Chaos>,
+    keepalive: Option<Keepalive>,
+) {
+    let mut last_heard = Instant::now();
+    let mut next_ping = keepalive.map(|keepalive| last_heard + keepalive.interval);
+    let mut pings = 0u64;
+    // Live frames up to `last_sent` were already replayed
+    let mut last_sent = 0;
+    let mut first_live: Option<u64> = None;
+    let mut sampler: Option<Sampler> = None;
+    // When frames the sampler holds back are due
+    let mut flush_at: Option<Instant> = None;
+    loop {
+        let frames = tokio::select! {
+            frame = frame_rx.recv() => match frame {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) if !admitted => continue,
+                Ok(frame) => {
+                    let seq = frame.seq.unwrap_or_default();
+                    if seq != 0 && seq <= last_sent {
+                        continue;
+                    }
+                    last_sent = seq;
+                    first_live.get_or_insert(seq);
+                    match sampler {
+                        Some(ref mut sampler) => {
+                            let frames = sampler.offer(frame);
+                            flush_at = if sampler.has_pending() {
+                                Some(flush_at.unwrap_or_else(|| Instant::now() + sampler.period()))
+                            } else {
+                                None
+                            };
+                            frames
+                        }
+                        None => vec![frame],
+                    }
+                }
+                // Catch up from the resume buffer rather than leave a hole
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    let missed = replay(&history, last_sent, None);
+                    let replayed = missed.iter().filter(|frame| frame.seq.is_some_and(|seq| seq > last_sent)).count();
+                    let lost = n.saturating_sub(replayed as u64);
+                    warn!("Socket client conn-{} lagged by {} frames, replaying {}", conn, n, replayed);
+                    dropped.fetch_add(lost, Ordering::Relaxed);
+                    last_sent = missed.iter().filter_map(|frame| frame.seq).max().unwrap_or(last_sent);
+
+                    let stats = BTreeMap::from([("missed".to_string(), n), ("replayed".to_string(), replayed as u64)]);
+                    let notice = Frame::new(FrameType::Reconnect).with_reason("lagged".to_string()).with_stats(stats);
+                    let frames = std::iter::once(notice).chain(missed);
+                    match sampler {
+                        Some(ref mut sampler) => {
+                            let frames = frames.flat_map(|frame| sampler.offer(frame)).collect();
+                            if sampler.has_pending() {
+                                flush_at.get_or_insert_with(|| Instant::now() + sampler.period());
+                            }
+                            frames
+                        }
+                        None => frames.collect(),
+                    }
+                }
+                // Deliver what the sampler still holds before closing
+                Err(broadcast::error::RecvError::Closed) => match sampler.take() {
+                    Some(mut sampler) => sampler.flush(),
+                    None => break,
+                },
+            },
+            Some(request) = stream_rx.recv() => match request {
+                StreamRequest::Replay(after) => {
+                    let frames = replay(&history, after, first_live);
+                    if first_live.is_none() {
+                        last_sent = frames.last().and_then(|frame| frame.seq).unwrap_or(last_sent);
+                    }
+                    debug!("Replaying {} frames after seq {} to conn-{}", frames.len(), after, conn);
+                    frames
+                }
+                StreamRequest::Sample(next) => {
+                    flush_at = None;
+                    std::mem::replace(&mut sampler, next).map_or_else(Vec::new, |mut old| old.flush())
+                }
+                StreamRequest::Ping(id) => {
+                    let pong = Frame::new(FrameType::Pong);
+                    vec![match id {
+                        Some(id) => pong.with_id(id),
+                        None => pong,
+                    }]
+                }
+                StreamRequest::Heard => {
+                    last_heard = Instant::now();
+                    continue;
+                }
+                StreamRequest::Admit => {
+                    admitted = true;
+                    continue;
+                }
+                StreamRequest::Refuse(error) => vec![error.to_frame().with_client(format!("conn-{}", conn))],
+                StreamRequest::Close(error) => {
+                    if let Ok(encoded) = error.to_frame().with_client(format!("conn-{}", conn)).encode(format) {
+                        let _ = writer.write_all(&encoded).await;
+                    }
+                    let _ = writer.shutdown().await;
+                    return;
+                }
+            },
+            _ = sleep_until(next_ping.unwrap_or_else(Instant::now)), if next_ping.is_some() => {
+                let Some(keepalive) = keepalive else { continue };
+                if last_heard.elapsed() >= keepalive.timeout {
+                    warn!("Socket client conn-{} silent for {:?}, closing", conn, last_heard.elapsed());
+                    return;
+                }
+                pings += 1;
+                next_ping = Some(Instant::now() + keepalive.interval);
+                vec![Frame::new(FrameType::Ping).with_id(format!("keepalive-{}", pings))]
+            }
+            _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                flush_at = None;
+                sampler.as_mut().map_or_else(Vec::new, Sampler::flush)
+            }
+        };
+
+        for frame in frames {
+            let Ok(encoded) = frame.encode(format) else { continue };
+            let copies = match chaos.as_mut().map(Chaos::next_fault) {
+                None => 1,
+                Some(Fault::Send { copies, delay }) => {
+                    tokio::time::sleep(delay).await;
+                    copies
+                }
+                Some(Fault::Disconnect) => {
+                    debug!("Chaos disconnecting conn-{} at seq {:?}", conn, frame.seq);
+                    return;
+                }
+            };
+            for _ in 0..copies {
+                if writer.write_all(&encoded).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Buffered frames after `after` and before `until`, preceded by an error
+/// frame when some of them have already been evicted.
+fn replay(history: &History, after: u64, until: Option<u64>) -> Vec<Frame> {
+    let history = history.lock().unwrap();
+    let mut frames = Vec::new();
+    if let Some(oldest) = history.front().and_then(|frame| frame.seq) {
+        if oldest > after + 1 {
+            let error = SessionError::Transport(format!(
+                "frames {} to {} are no longer buffered",
+                after + 1,
+                oldest - 1
+            ));
+            frames.push(error.to_frame());
+        }
+    }
+    frames.extend(
+        history
+            .iter()
+            .filter(|frame| {
+                let seq = frame.seq.unwrap_or_default();
+                seq > after && until.is_none_or(|until| seq < until)
+            })
+            .cloned(),
+    );
+    frames
+}
+
+/// Per-connection control state: resolves `auth`, `resume_from` and
+/// `subscribe` commands locally and forwards everything else tagged with
+/// the client identity. With auth tokens configured, a socket client must
+/// `auth` before anything else it sends is accepted; stdin belongs to
+/// whoever started the session and needs no credential.
+pub struct ControlReader {
+    client: ClientId,
+    tokens: AuthTokens,
+    control_tx: mpsc::UnboundedSender<ControlEvent>,
+    /// Where this connection's stream requests go; stdin has none
+    stream_tx: Option<mpsc::UnboundedSender<StreamRequest>>,
+}
+
+impl ControlReader {
+    pub fn new(conn: u64, tokens: AuthTokens, control_tx: mpsc::UnboundedSender<ControlEvent>) -> Self {
+        Self {
+            client: ClientId { conn, name: None },
+            tokens,
+            control_tx,
+            stream_tx: None,
+        }
+    }
+
+    /// Handle one NDJSON line; returns false once the receiver is gone.
+    pub fn handle_line(&mut self, line: &str) -> bool {
+        if line.trim().is_empty() {
+            return true;
+        }
+        // Any line proves the client alive for the keepalive
+        if let Some(ref stream_tx) = self.stream_tx {
+            let _ = stream_tx.send(StreamRequest::Heard);
+        }
+
+        match ControlCommand::from_json(line) {
+            Ok(ControlCommand::Auth { client: name, token }) => {
+                if token_matches(&self.tokens, &name, &token) {
+                    info!("Client {} authenticated as {}", self.client.label(), name);
+                    self.client.name = Some(name);
+                    match self.stream_tx {
+                        Some(ref stream_tx) => stream_tx.send(StreamRequest::Admit).is_ok(),
+                        None => true,
+                    }
+                } else {
+                    warn!("Authentication failed for {} as {}", self.client.label(), name);
+                    self.reject(SessionError::Policy(format!("authentication failed for {}", name)))
+                }
+            }
+            // Already counted as a sign of life
+            Ok(ControlCommand::Pong { .. }) => true,
+            Ok(_) if self.needs_auth() => {
+                warn!("Unauthenticated command from {}", self.client.label());
+                self.reject(SessionError::Policy("authenticate with auth first".to_string()))
+            }
+            Ok(ControlCommand::ResumeFrom { seq }) => self.request("resume_from", StreamRequest::Replay(seq)),
+            Ok(ControlCommand::Ping { id }) => self.request("ping", StreamRequest::Ping(id)),
+            Ok(ControlCommand::Subscribe {
+                sample_rate,
+                summary_only,
+            }) => match Sampler::new(sample_rate, summary_only) {
+                Ok(sampler) => {
+                    debug!("{} subscribed with {:?}", self.client.label(), sampler);
+                    self.request("subscribe", StreamRequest::Sample(sampler))
+                }
+                Err(e) => self.reject(e),
+            },
+            Ok(command) => {
+                let message = ControlMessage {
+                    client: self.client.clone(),
+                    command,
+                };
+                self.control_tx.send(ControlEvent::Command(message)).is_ok()
+            }
+            Err(e) => {
+                warn!("Invalid control command from {}: {}", self.client.label(), e);
+                self.reject(SessionError::Transport(format!("invalid control command: {}", e)))
+            }
+        }
+    }
+
+    /// Whether this is a socket client that has yet to authenticate
+    /// against configured tokens.
+    fn needs_auth(&self) -> bool {
+        self.stream_tx.is_some() && !self.tokens.is_empty() && self.client.name.is_none()
+    }
+
+    fn request(&self, command: &str, request: StreamRequest) -> bool {
+        match self.stream_tx {
+            Some(ref stream_tx) => stream_tx.send(request).is_ok(),
+            None => self.reject(SessionError::InvalidRequest(format!(
+                "{} is only available on the socket",
+                command
+            ))),
+        }
+    }
+
+    /// Refuse a socket client and close its connection.
+    fn close(&self, error: SessionError) {
+        if let Some(ref stream_tx) = self.stream_tx {
+            let _ = stream_tx.send(StreamRequest::Close(error));
+        }
+    }
+
+    /// Report a refused command. A client that has yet to authenticate is
+    /// told alone, so it cannot put frames in front of everyone else.
+    fn reject(&self, error: SessionError) -> bool {
+        if let (true, Some(stream_tx)) = (self.needs_auth(), &self.stream_tx) {
+            return stream_tx.send(StreamRequest::Refuse(error)).is_ok();
+        }
+        let event = ControlEvent::Rejected {
+            client: self.client.clone(),
+            error,
+        };
+        self.control_tx.send(event).is_ok()
+    }
+}
+
+/// A socket connection ends with its reader, whichever side closed it.
+impl Drop for ControlReader {
+    fn drop(&mut self) {
+        if self.stream_tx.is_some() {
+            let _ = self.control_tx.send(ControlEvent::Disconnected(self.client.clone()));
+        }
+    }
+}
+
+async fn read_control_lines<R: AsyncRead + Unpin>(reader: R, mut control: ControlReader) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // Bounded, so an endless line cannot grow the buffer, even before auth
+        let limit = MAX_CONTROL_LINE_BYTES as u64 + 1;
+        match (&mut reader).take(limit).read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if line.len() > MAX_CONTROL_LINE_BYTES && !line.ends_with(b"\n") {
+            warn!("Control command from {} over {} bytes, closing", control.client.label(), MAX_CONTROL_LINE_BYTES);
+            control.close(SessionError::InvalidRequest(format!(
+                "control command longer than {} bytes",
+                MAX_CONTROL_LINE_BYTES
+            )));
+            // Read on while the error goes out, so closing with input
+            // unread does not reset the connection before it arrives
+            let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+            return;
+        }
+        let Ok(text) = std::str::from_utf8(&line) else { return };
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        if !control.handle_line(text.strip_suffix('\r').unwrap_or(text)) {
+            return;
+        }
+    }
+}
+
+/// Accept control commands on stdin. This uses a detached thread because a
+/// blocking stdin read cannot be cancelled and would stall runtime shutdown.
+pub fn spawn_stdin_control(tokens: AuthTokens, control_tx: mpsc::UnboundedSender<ControlEvent>) {
+    std::thread::spawn(move || {
+        let mut control = ControlReader::new(STDIN_CONN, tokens, control_tx);
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if !control.handle_line(&line) {
+                break;
+            }
+        }
+        debug!("Stdin control channel closed");
+    });
+}
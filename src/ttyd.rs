@@ -0,0 +1,449 @@
+use crate::control::{ClientId, ControlCommand, ControlEvent, ControlMessage};
+use crate::frame::{Frame, FrameType};
+use crate::http::{self, Request};
+use crate::transport::{next_conn_id, require_auth_off_loopback, token_matches, AuthTokens};
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+/// Output kept for browsers that connect mid-session, so they start from
+/// a recent screen rather than a blank one.
+const HISTORY_BYTES: usize = 64 * 1024;
+
+/// Largest message accepted from a browser.
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+/// How long a client has to send its handshake and first message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// GUID every WebSocket server appends to the client's key (RFC 6455).
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// What the server hands to every connected browser.
+#[derive(Debug, Clone)]
+enum Event {
+    Output(Vec<u8>),
+    Exit,
+}
+
+/// Message codes of the two browser terminal protocols, chosen by the
+/// WebSocket subprotocol the client asks for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dialect {
+    /// ttyd (`tty`): binary messages, raw output
+    Ttyd,
+    /// gotty (`webtty`): text messages, base64 output
+    Gotty,
+}
+
+impl Dialect {
+    fn from_protocols(requested: &str) -> Option<(Self, &'static str)> {
+        requested.split(',').map(str::trim).find_map(|protocol| match protocol {
+            "tty" => Some((Dialect::Ttyd, "tty")),
+            "webtty" => Some((Dialect::Gotty, "webtty")),
+            _ => None,
+        })
+    }
+
+    fn output(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Dialect::Ttyd => encode_frame(OPCODE_BINARY, &[b"0", data].concat()),
+            Dialect::Gotty => encode_frame(OPCODE_TEXT, format!("1{}", BASE64_STANDARD.encode(data)).as_bytes()),
+        }
+    }
+
+    fn title(self, title: &str) -> Vec<u8> {
+        match self {
+            Dialect::Ttyd => encode_frame(OPCODE_BINARY, format!("1{}", title).as_bytes()),
+            Dialect::Gotty => encode_frame(OPCODE_TEXT, format!("3{}", title).as_bytes()),
+        }
+    }
+
+    fn preferences(self) -> Vec<u8> {
+        match self {
+            Dialect::Ttyd => encode_frame(OPCODE_BINARY, b"2{}"),
+            Dialect::Gotty => encode_frame(OPCODE_TEXT, b"4{}"),
+        }
+    }
+}
+
+/// First message a client sends, with its credential and terminal size.
+#[derive(Debug, Default, Deserialize)]
+struct Hello {
+    #[serde(rename = "AuthToken", default)]
+    auth_token: String,
+    columns: Option<u16>,
+    rows: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Size {
+    columns: u16,
+    rows: u16,
+}
+
+struct Shared {
+    title: String,
+    tokens: AuthTokens,
+    control_tx: mpsc::UnboundedSender<ControlEvent>,
+    writable: bool,
+    events: broadcast::Sender<Event>,
+    history: Mutex<VecDeque<u8>>,
+}
+
+/// Serves the session to browser terminals speaking the ttyd or gotty
+/// WebSocket protocol, so their stock frontends can watch, and with
+/// `writable` type into, the session.
+///
+/// Every client shares the one session: output goes to all of them and a
+/// client joining late is first sent the most recent output. Clients are
+/// read-only unless `writable`. Hosts other than loopback are only served
+/// with auth tokens configured. With tokens, clients must present one the
+/// way ttyd does, as the base64 of `NAME:TOKEN`; the frontend fetches it
+/// from `/token` after an HTTP Basic login.
+pub struct TtydServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl TtydServer {
+    pub fn bind(
+        addr: SocketAddr,
+        title: String,
+        tokens: AuthTokens,
+        control_tx: mpsc::UnboundedSender<ControlEvent>,
+        writable: bool,
+    ) -> Result<Self> {
+        require_auth_off_loopback(addr, &tokens, "ttyd")?;
+        let listener = std::net::TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let addr = listener.local_addr()?;
+
+        let (events, _) = broadcast::channel(1024);
+        let shared = Arc::new(Shared {
+            title,
+            tokens,
+            control_tx,
+            writable,
+            events,
+            history: Mutex::new(VecDeque::new()),
+        });
+
+        let accept_shared = shared.clone();
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept ttyd client: {}", e);
+                        continue;
+                    }
+                };
+                let shared = accept_shared.clone();
+                tokio::spawn(async move {
+                    let conn = next_conn_id();
+                    debug!("ttyd client conn-{} connected from {}", conn, peer);
+                    if let Err(e) = serve(stream, conn, shared).await {
+                        debug!("ttyd client conn-{}: {}", conn, e);
+                    }
+                    debug!("ttyd client conn-{} disconnected", conn);
+                });
+            }
+        });
+
+        info!("Serving ttyd clients on {}", addr);
+        Ok(Self {
+            addr,
+            shared,
+            accept_task,
+        })
+    }
+
+    /// The address clients connect to, with the port picked if it was 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Pass terminal output on to every client; other frames have no
+    /// place in the protocol, except the exit which closes the clients.
+    pub fn broadcast(&self, frame: &Frame) {
+        let event = match frame.frame_type {
+            FrameType::Stdout | FrameType::Stderr => match frame.bytes() {
+                Some(data) => Event::Output(data.into_owned()),
+                None => return,
+            },
+            FrameType::Exit => Event::Exit,
+            _ => return,
+        };
+        // Recorded and sent under the lock, so a joining client sees each
+        // output exactly once, either replayed or live
+        let mut history = self.shared.history.lock().unwrap();
+        if let Event::Output(ref data) = event {
+            history.extend(data.iter().copied());
+            let excess = history.len().saturating_sub(HISTORY_BYTES);
+            history.drain(..excess);
+        }
+        let _ = self.shared.events.send(event);
+    }
+}
+
+impl Drop for TtydServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, conn: u64, shared: Arc<Shared>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, http::read_request(&mut reader))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request"))??;
+
+    let upgrade = request.header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    if request.path.ends_with("/token") {
+        return send_token(&mut writer, &request, &shared.tokens).await;
+    }
+    if !upgrade || !request.path.ends_with("/ws") {
+        let body = "spectertty serves the ttyd and gotty WebSocket protocols on /ws\n";
+        return http::respond(&mut writer, "404 Not Found", &[("Content-Type", "text/plain")], body.as_bytes()).await;
+    }
+    let Some(key) = request.header("sec-websocket-key") else {
+        let body = b"Missing Sec-WebSocket-Key\n";
+        return http::respond(&mut writer, "400 Bad Request", &[("Content-Type", "text/plain")], body).await;
+    };
+
+    let requested = request.header("sec-websocket-protocol").unwrap_or_default();
+    let (dialect, protocol) = match Dialect::from_protocols(requested) {
+        Some((dialect, protocol)) => (dialect, Some(protocol)),
+        None => (Dialect::Ttyd, None),
+    };
+    let accept = BASE64_STANDARD.encode(Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID)));
+    let mut headers = vec![("Upgrade", "websocket"), ("Connection", "Upgrade"), ("Sec-WebSocket-Accept", accept.as_str())];
+    if let Some(protocol) = protocol {
+        headers.push(("Sec-WebSocket-Protocol", protocol));
+    }
+    http::write_head(&mut writer, "101 Switching Protocols", &headers).await?;
+
+    // Messages are read on a task of their own, since a half-read frame
+    // cannot be abandoned when output is ready to go out
+    let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+    let read_task = tokio::spawn(async move {
+        loop {
+            let message = read_message(&mut reader).await;
+            let done = !matches!(message, Ok(Incoming::Message(_) | Incoming::Ping(_)));
+            if incoming_tx.send(message).is_err() || done {
+                break;
+            }
+        }
+    });
+    let mut client = ClientId { conn, name: None };
+    let result = session(&mut client, dialect, &shared, &mut writer, &mut incoming).await;
+    read_task.abort();
+    let _ = shared.control_tx.send(ControlEvent::Disconnected(client));
+    result
+}
+
+/// Run one client from its hello message until either side closes.
+async fn session(
+    client: &mut ClientId,
+    dialect: Dialect,
+    shared: &Shared,
+    writer: &mut OwnedWriteHalf,
+    incoming: &mut mpsc::UnboundedReceiver<io::Result<Incoming>>,
+) -> io::Result<()> {
+    let hello = match tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.recv()).await {
+        Ok(Some(Ok(Incoming::Message(message)))) => serde_json::from_slice::<Hello>(&message).unwrap_or_default(),
+        Ok(Some(Err(e))) => return Err(e),
+        _ => return Ok(()),
+    };
+    if !shared.tokens.is_empty() {
+        match authenticate(&shared.tokens, &hello.auth_token) {
+            Some(name) => {
+                info!("ttyd client {} authenticated as {}", client.label(), name);
+                client.name = Some(name);
+            }
+            None => {
+                warn!("ttyd client {} sent no valid credential", client.label());
+                return writer.write_all(&encode_close(1008)).await;
+            }
+        }
+    }
+    let forward = |command: ControlCommand| {
+        let message = ControlMessage {
+            client: client.clone(),
+            command,
+        };
+        let _ = shared.control_tx.send(ControlEvent::Command(message));
+    };
+    if let (true, Some(cols), Some(rows)) = (shared.writable, hello.columns, hello.rows) {
+        forward(ControlCommand::Resize { cols, rows });
+    }
+
+    let (mut events, recent) = {
+        let history = shared.history.lock().unwrap();
+        (shared.events.subscribe(), history.iter().copied().collect::<Vec<u8>>())
+    };
+    writer.write_all(&dialect.title(&shared.title)).await?;
+    writer.write_all(&dialect.preferences()).await?;
+    if !recent.is_empty() {
+        writer.write_all(&dialect.output(&recent)).await?;
+    }
+
+    // ttyd clients pause output while their terminal catches up
+    let mut paused = false;
+    loop {
+        tokio::select! {
+            message = incoming.recv() => {
+                let message = match message {
+                    Some(Ok(Incoming::Message(message))) => message,
+                    Some(Ok(Incoming::Ping(payload))) => {
+                        writer.write_all(&encode_frame(OPCODE_PONG, &payload)).await?;
+                        continue;
+                    }
+                    Some(Ok(Incoming::Close)) | None => return writer.write_all(&encode_close(1000)).await,
+                    Some(Err(e)) => return Err(e),
+                };
+                let Some((&code, payload)) = message.split_first() else { continue };
+                match (dialect, code) {
+                    (Dialect::Ttyd, b'0') | (Dialect::Gotty, b'1') if shared.writable => {
+                        forward(ControlCommand::Input { data: String::from_utf8_lossy(payload).into_owned() });
+                    }
+                    (Dialect::Ttyd, b'1') | (Dialect::Gotty, b'3') if shared.writable => {
+                        if let Ok(size) = serde_json::from_slice::<Size>(payload) {
+                            forward(ControlCommand::Resize { cols: size.columns, rows: size.rows });
+                        }
+                    }
+                    (Dialect::Ttyd, b'2') => paused = true,
+                    (Dialect::Ttyd, b'3') => paused = false,
+                    (Dialect::Gotty, b'2') => writer.write_all(&encode_frame(OPCODE_TEXT, b"2")).await?,
+                    _ => {}
+                }
+            }
+            event = events.recv(), if !paused => match event {
+                Ok(Event::Output(data)) => writer.write_all(&dialect.output(&data)).await?,
+                Ok(Event::Exit) | Err(broadcast::error::RecvError::Closed) => {
+                    return writer.write_all(&encode_close(1000)).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("ttyd client conn-{} fell behind and missed {} outputs", client.conn, n);
+                }
+            },
+        }
+    }
+}
+
+/// The client name a ttyd credential, base64 of `NAME:TOKEN`, belongs to.
+fn authenticate(tokens: &AuthTokens, credential: &str) -> Option<String> {
+    let decoded = BASE64_STANDARD.decode(credential.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (name, token) = decoded.split_once(':')?;
+    token_matches(tokens, name, token).then(|| name.to_string())
+}
+
+/// Answer the frontend's `/token` request: the credential for its
+/// WebSocket, once it has logged in with HTTP Basic auth.
+async fn send_token(writer: &mut OwnedWriteHalf, request: &Request, tokens: &AuthTokens) -> io::Result<()> {
+    let credential = match request.header("authorization").and_then(|value| value.strip_prefix("Basic ")) {
+        Some(credential) if authenticate(tokens, credential).is_some() => credential.trim(),
+        _ if tokens.is_empty() => "",
+        _ => {
+            let challenge = [("WWW-Authenticate", "Basic realm=\"spectertty\"")];
+            return http::respond(writer, "401 Unauthorized", &challenge, b"").await;
+        }
+    };
+    let body = serde_json::json!({ "token": credential }).to_string();
+    http::respond(writer, "200 OK", &[("Content-Type", "application/json")], body.as_bytes()).await
+}
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// What a client sent, with fragments put back together.
+#[derive(Debug)]
+enum Incoming {
+    Message(Vec<u8>),
+    Ping(Vec<u8>),
+    Close,
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Incoming> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let mut message = Vec::new();
+    loop {
+        let mut header = [0u8; 2];
+        reader.read_exact(&mut header).await?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        if header[1] & 0x80 == 0 {
+            return Err(invalid("unmasked client frame"));
+        }
+        let len = match header[1] & 0x7f {
+            126 => reader.read_u16().await? as usize,
+            127 => usize::try_from(reader.read_u64().await?).unwrap_or(usize::MAX),
+            len => len as usize,
+        };
+        if message.len().saturating_add(len) > MAX_MESSAGE_BYTES {
+            return Err(invalid("message too large"));
+        }
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        match opcode {
+            OPCODE_CLOSE => return Ok(Incoming::Close),
+            OPCODE_PING => return Ok(Incoming::Ping(payload)),
+            OPCODE_PONG => continue,
+            OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                message.extend_from_slice(&payload);
+                if fin {
+                    return Ok(Incoming::Message(message));
+                }
+            }
+            _ => return Err(invalid("unknown opcode")),
+        }
+    }
+}
+
+/// An unmasked, unfragmented server frame.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn encode_close(code: u16) -> Vec<u8> {
+    encode_frame(OPCODE_CLOSE, &code.to_be_bytes())
+}
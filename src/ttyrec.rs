@@ -0,0 +1,48 @@
+use anyhow::{anyhow, Result};
+use std::io::{ErrorKind, Read, Write};
+
+/// One ttyrec record: a timestamp and the bytes written to the terminal at
+/// that moment. ttyrec has no notion of input or window size.
+pub struct Record {
+    pub ts: f64,
+    pub data: Vec<u8>,
+}
+
+/// Read every record: a little-endian `sec, usec, len` header followed by
+/// `len` bytes of output.
+pub fn read_records<R: Read>(mut input: R) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut header = [0u8; 12];
+
+    loop {
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let field = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+        let (sec, usec, len) = (field(0), field(1), field(2));
+
+        let mut data = vec![0u8; len as usize];
+        input
+            .read_exact(&mut data)
+            .map_err(|_| anyhow!("Truncated ttyrec record {}", records.len() + 1))?;
+        records.push(Record {
+            ts: sec as f64 + usec as f64 / 1_000_000.0,
+            data,
+        });
+    }
+
+    Ok(records)
+}
+
+pub fn write_record<W: Write + ?Sized>(output: &mut W, ts: f64, data: &[u8]) -> Result<()> {
+    let sec = ts.trunc() as u32;
+    let usec = (ts.fract() * 1_000_000.0) as u32;
+    output.write_all(&sec.to_le_bytes())?;
+    output.write_all(&usec.to_le_bytes())?;
+    output.write_all(&(data.len() as u32).to_le_bytes())?;
+    output.write_all(data)?;
+    Ok(())
+}
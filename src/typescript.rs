@@ -0,0 +1,85 @@
+use crate::ttyrec::Record;
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// First line script(1) writes to a typescript, skipped by scriptreplay.
+const HEADER_PREFIX: &[u8] = b"Script started on ";
+
+/// Where the timing file for the typescript at `path` goes: beside it,
+/// with `.timing` appended.
+pub fn timing_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".timing");
+    PathBuf::from(name)
+}
+
+/// The `Script started on` line opening a typescript.
+pub fn header(command: Option<&str>, width: u16, height: u16) -> String {
+    let started = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%:z");
+    let term = std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string());
+    let command = command.map_or(String::new(), |command| format!("COMMAND=\"{}\" ", command));
+    format!(
+        "Script started on {} [{}TERM=\"{}\" COLUMNS=\"{}\" LINES=\"{}\"]\n",
+        started, command, term, width, height
+    )
+}
+
+/// The `Script done on` line closing a typescript.
+pub fn trailer(exit_code: Option<i32>) -> String {
+    let done = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%:z");
+    match exit_code {
+        Some(code) => format!("\nScript done on {} [COMMAND_EXIT_CODE=\"{}\"]\n", done, code),
+        None => format!("\nScript done on {}\n", done),
+    }
+}
+
+/// Append one chunk of output: the bytes to the typescript and a classic
+/// `delay length` line to the timing file, the delay being seconds since
+/// the previous chunk.
+pub fn write_chunk<W: Write + ?Sized, T: Write + ?Sized>(
+    log: &mut W,
+    timing: &mut T,
+    delay: f64,
+    data: &[u8],
+) -> Result<()> {
+    log.write_all(data)?;
+    writeln!(timing, "{:.6} {}", delay.max(0.0), data.len())?;
+    Ok(())
+}
+
+/// Read a typescript back as records timed from its start, using the
+/// classic timing file script(1) writes with `-t`.
+pub fn read_records<R: Read, T: BufRead>(mut log: R, timing: T) -> Result<Vec<Record>> {
+    let mut data = Vec::new();
+    log.read_to_end(&mut data)?;
+    let mut offset = 0;
+    if data.starts_with(HEADER_PREFIX) {
+        offset = data.iter().position(|&byte| byte == b'\n').map_or(data.len(), |end| end + 1);
+    }
+
+    let mut records = Vec::new();
+    let mut ts = 0.0;
+    for (i, line) in timing.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || anyhow!("Invalid timing line {}: {:?}", i + 1, line);
+        let (delay, len) = line.trim().split_once(' ').ok_or_else(invalid)?;
+        let delay: f64 = delay.parse().map_err(|_| invalid())?;
+        let len: usize = len.trim().parse().map_err(|_| invalid())?;
+        let end = offset + len;
+        if end > data.len() {
+            return Err(anyhow!("Typescript is shorter than timing line {} says", i + 1));
+        }
+        ts += delay;
+        records.push(Record {
+            ts,
+            data: data[offset..end].to_vec(),
+        });
+        offset = end;
+    }
+    Ok(records)
+}